@@ -17,6 +17,12 @@ pub enum KvsError {
     /// It indicated a corrupted log or a program bug.
     #[fail(display = "Unexpected command type")]
     UnexpectedCommandType,
+    /// The store is already opened by another process.
+    #[fail(display = "The store is locked by another process")]
+    StoreLocked {
+        /// ID of the process holding the lock, if it could be read.
+        pid: Option<u32>,
+    },
 }
 
 impl From<io::Error> for KvsError {
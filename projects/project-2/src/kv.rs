@@ -3,7 +3,10 @@ use std::fs::{self, File, OpenOptions};
 use std::io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
 use std::ops::Range;
 use std::path::{Path, PathBuf};
+use std::process;
+use std::str;
 
+use fs2::FileExt;
 use serde::{Deserialize, Serialize};
 use serde_json::Deserializer;
 
@@ -11,6 +14,7 @@ use crate::{KvsError, Result};
 use std::ffi::OsStr;
 
 const COMPACTION_THRESHOLD: u64 = 1024 * 1024;
+const LOCK_FILE: &str = "LOCK";
 
 /// The `KvStore` stores string key/value pairs.
 ///
@@ -18,6 +22,9 @@ const COMPACTION_THRESHOLD: u64 = 1024 * 1024;
 /// monotonically increasing generation numbers with a `log` extension name.
 /// A `BTreeMap` in memory stores the keys and the value locations for fast query.
 ///
+/// An open store holds an exclusive lock on the `LOCK` file of its directory,
+/// which records the ID of its process.
+///
 /// ```rust
 /// # use kvs::{KvStore, Result};
 /// # fn try_main() -> Result<()> {
@@ -41,6 +48,8 @@ pub struct KvStore {
     // the number of bytes representing "stale" commands that could be
     // deleted during a compaction.
     uncompacted: u64,
+    // the locked `LOCK` file, unlocked when it is closed.
+    _lock: File,
 }
 
 impl KvStore {
@@ -51,9 +60,12 @@ impl KvStore {
     /// # Errors
     ///
     /// It propagates I/O or deserialization errors during the log replay.
+    ///
+    /// `KvsError::StoreLocked` is returned if the store is already opened.
     pub fn open(path: impl Into<PathBuf>) -> Result<KvStore> {
         let path = path.into();
         fs::create_dir_all(&path)?;
+        let lock = lock_dir(&path)?;
 
         let mut readers = HashMap::new();
         let mut index = BTreeMap::new();
@@ -77,6 +89,7 @@ impl KvStore {
             current_gen,
             index,
             uncompacted,
+            _lock: lock,
         })
     }
 
@@ -224,6 +237,31 @@ fn new_log_file(
     Ok(writer)
 }
 
+/// Takes the lock of the directory and records the ID of this process in it.
+fn lock_dir(path: &Path) -> Result<File> {
+    let mut file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(path.join(LOCK_FILE))?;
+    if let Err(e) = file.try_lock_exclusive() {
+        if e.raw_os_error() == fs2::lock_contended_error().raw_os_error() {
+            // the holder may not have recorded its ID yet
+            let mut owner = Vec::new();
+            file.read_to_end(&mut owner)?;
+            let pid = str::from_utf8(&owner)
+                .ok()
+                .and_then(|owner| owner.parse().ok());
+            return Err(KvsError::StoreLocked { pid });
+        }
+        return Err(e.into());
+    }
+    file.set_len(0)?;
+    file.write_all(process::id().to_string().as_bytes())?;
+    Ok(file)
+}
+
 /// Returns sorted generation numbers in the given directory.
 fn sorted_gen_list(path: &Path) -> Result<Vec<u64>> {
     let mut gen_list: Vec<u64> = fs::read_dir(&path)?
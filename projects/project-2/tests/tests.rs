@@ -1,8 +1,8 @@
 use assert_cmd::prelude::*;
-use kvs::{KvStore, Result};
+use kvs::{KvStore, KvsError, Result};
 use predicates::ord::eq;
 use predicates::str::{contains, is_empty, PredicateStrExt};
-use std::process::Command;
+use std::process::{self, Command};
 use tempfile::TempDir;
 use walkdir::WalkDir;
 
@@ -254,6 +254,23 @@ fn remove_key() -> Result<()> {
     Ok(())
 }
 
+// Should refuse to open a store that is already open, naming the process holding it.
+#[test]
+fn lock_store() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    match KvStore::open(temp_dir.path()) {
+        Err(KvsError::StoreLocked { pid }) => assert_eq!(pid, Some(process::id())),
+        Err(e) => panic!("unexpected error: {}", e),
+        Ok(_) => panic!("the lock was not detected"),
+    }
+
+    // Open again once the store is dropped.
+    drop(store);
+    KvStore::open(temp_dir.path())?;
+    Ok(())
+}
+
 // Insert data until total size of the directory decreases.
 // Test data correctness after compaction.
 #[test]
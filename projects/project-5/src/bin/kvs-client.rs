@@ -1,8 +1,12 @@
 use clap::AppSettings;
-use kvs::{KvsClient, Result};
+use kvs::sharding::{self, HashRing, ShardedKvsClient};
+use kvs::{KvsClient, KvsError, Result};
 use std::net::SocketAddr;
+use std::ops::Bound;
 use std::process::exit;
+use std::time::Duration;
 use structopt::StructOpt;
+use tokio::prelude::future::Either;
 use tokio::prelude::*;
 
 #[derive(StructOpt, Debug)]
@@ -31,6 +35,16 @@ enum Command {
             parse(try_from_str)
         )]
         addr: SocketAddr,
+        #[structopt(
+            long,
+            help = "Spreads the keys over the servers at the given addresses instead of --addr",
+            value_name = "IP:PORT,...",
+            raw(use_delimiter = "true"),
+            parse(try_from_str)
+        )]
+        servers: Vec<SocketAddr>,
+        #[structopt(flatten)]
+        encoding: Encoding,
     },
     #[structopt(name = "set", about = "Set the value of a string key to a string")]
     Set {
@@ -38,6 +52,12 @@ enum Command {
         key: String,
         #[structopt(name = "VALUE", help = "The string value of the key")]
         value: String,
+        #[structopt(
+            long = "ex",
+            help = "Makes the key expire after the given number of seconds",
+            value_name = "SECONDS"
+        )]
+        ttl: Option<u64>,
         #[structopt(
             long,
             help = "Sets the server address",
@@ -46,6 +66,16 @@ enum Command {
             parse(try_from_str)
         )]
         addr: SocketAddr,
+        #[structopt(
+            long,
+            help = "Spreads the keys over the servers at the given addresses instead of --addr",
+            value_name = "IP:PORT,...",
+            raw(use_delimiter = "true"),
+            parse(try_from_str)
+        )]
+        servers: Vec<SocketAddr>,
+        #[structopt(flatten)]
+        encoding: Encoding,
     },
     #[structopt(name = "rm", about = "Remove a given string key")]
     Remove {
@@ -59,6 +89,75 @@ enum Command {
             parse(try_from_str)
         )]
         addr: SocketAddr,
+        #[structopt(
+            long,
+            help = "Spreads the keys over the servers at the given addresses instead of --addr",
+            value_name = "IP:PORT,...",
+            raw(use_delimiter = "true"),
+            parse(try_from_str)
+        )]
+        servers: Vec<SocketAddr>,
+        #[structopt(flatten)]
+        encoding: Encoding,
+    },
+    #[structopt(
+        name = "scan",
+        about = "List the key-value pairs with keys from START (inclusive) to END (exclusive)"
+    )]
+    Scan {
+        #[structopt(name = "START", help = "The first key, or the smallest key if absent")]
+        start: Option<String>,
+        #[structopt(name = "END", help = "The key to stop before, or none if absent")]
+        end: Option<String>,
+        #[structopt(long, help = "Lists at most this number of pairs", value_name = "N")]
+        limit: Option<usize>,
+        #[structopt(
+            long,
+            help = "Sets the server address",
+            value_name = "IP:PORT",
+            default_value = "127.0.0.1:4000",
+            parse(try_from_str)
+        )]
+        addr: SocketAddr,
+        #[structopt(flatten)]
+        encoding: Encoding,
+    },
+    #[structopt(name = "keys", about = "List the keys starting with a given prefix")]
+    Keys {
+        #[structopt(name = "PREFIX", help = "A key prefix", default_value = "")]
+        prefix: String,
+        #[structopt(
+            long,
+            help = "Sets the server address",
+            value_name = "IP:PORT",
+            default_value = "127.0.0.1:4000",
+            parse(try_from_str)
+        )]
+        addr: SocketAddr,
+        #[structopt(flatten)]
+        encoding: Encoding,
+    },
+    #[structopt(
+        name = "rebalance",
+        about = "Move the keys spread over some servers to where they go on other servers"
+    )]
+    Rebalance {
+        #[structopt(
+            long,
+            help = "The servers the keys are spread over",
+            value_name = "IP:PORT,...",
+            raw(use_delimiter = "true", required = "true"),
+            parse(try_from_str)
+        )]
+        from: Vec<SocketAddr>,
+        #[structopt(
+            long,
+            help = "The servers to spread the keys over",
+            value_name = "IP:PORT,...",
+            raw(use_delimiter = "true", required = "true"),
+            parse(try_from_str)
+        )]
+        to: Vec<SocketAddr>,
     },
 }
 
@@ -72,24 +171,223 @@ fn main() {
 
 fn run(opt: Opt) -> Result<()> {
     match opt.command {
-        Command::Get { key, addr } => {
-            let client = KvsClient::connect(addr);
-            if let (Some(value), _) = client.and_then(move |client| client.get(key)).wait()? {
-                println!("{}", value);
+        Command::Get {
+            key,
+            addr,
+            servers,
+            encoding,
+        } => {
+            let key = encoding.decode(&key)?;
+            let get = if servers.is_empty() {
+                let client = KvsClient::connect(addr);
+                Either::A(
+                    client
+                        .and_then(move |client| client.get_bytes(key))
+                        .map(|(value, _)| value),
+                )
+            } else {
+                let client = ShardedKvsClient::connect(HashRing::new(&servers));
+                Either::B(
+                    client
+                        .and_then(move |client| client.get_bytes(key))
+                        .map(|(value, _)| value),
+                )
+            };
+            if let Some(value) = get.wait()? {
+                println!("{}", encoding.encode(&value));
             } else {
                 println!("Key not found");
             }
         }
-        Command::Set { key, value, addr } => {
+        Command::Set {
+            key,
+            value,
+            ttl,
+            addr,
+            servers,
+            encoding,
+        } => {
+            let key = encoding.decode(&key)?;
+            let value = encoding.decode(&value)?;
+            let ttl = ttl.map(Duration::from_secs);
+            if servers.is_empty() {
+                let client = KvsClient::connect(addr);
+                client
+                    .and_then(move |client| match ttl {
+                        Some(ttl) => Either::A(client.set_with_ttl_bytes(key, value, ttl)),
+                        None => Either::B(client.set_bytes(key, value)),
+                    })
+                    .wait()?;
+            } else {
+                let client = ShardedKvsClient::connect(HashRing::new(&servers));
+                client
+                    .and_then(move |client| match ttl {
+                        Some(ttl) => Either::A(client.set_with_ttl_bytes(key, value, ttl)),
+                        None => Either::B(client.set_bytes(key, value)),
+                    })
+                    .wait()?;
+            }
+        }
+        Command::Remove {
+            key,
+            addr,
+            servers,
+            encoding,
+        } => {
+            let key = encoding.decode(&key)?;
+            if servers.is_empty() {
+                let client = KvsClient::connect(addr);
+                client
+                    .and_then(move |client| client.remove_bytes(key))
+                    .wait()?;
+            } else {
+                let client = ShardedKvsClient::connect(HashRing::new(&servers));
+                client
+                    .and_then(move |client| client.remove_bytes(key))
+                    .wait()?;
+            }
+        }
+        Command::Scan {
+            start,
+            end,
+            limit,
+            addr,
+            encoding,
+        } => {
+            let start = match start {
+                Some(start) => Bound::Included(encoding.decode(&start)?),
+                None => Bound::Unbounded,
+            };
+            let end = match end {
+                Some(end) => Bound::Excluded(encoding.decode(&end)?),
+                None => Bound::Unbounded,
+            };
             let client = KvsClient::connect(addr);
-            client
-                .and_then(move |client| client.set(key, value))
+            let (pairs, _) = client
+                .and_then(move |client| client.scan_bytes(start, end, limit))
                 .wait()?;
+            for (key, value) in pairs {
+                println!("{}\t{}", encoding.encode(&key), encoding.encode(&value));
+            }
         }
-        Command::Remove { key, addr } => {
+        Command::Keys {
+            prefix,
+            addr,
+            encoding,
+        } => {
+            let prefix = encoding.decode(&prefix)?;
             let client = KvsClient::connect(addr);
-            client.and_then(move |client| client.remove(key)).wait()?;
+            client
+                .and_then(move |client| {
+                    client.keys_bytes(prefix).for_each(move |key| {
+                        println!("{}", encoding.encode(&key));
+                        Ok(())
+                    })
+                })
+                .wait()?;
+        }
+        Command::Rebalance { from, to } => {
+            let moved = sharding::rebalance(&HashRing::new(&from), HashRing::new(&to)).wait()?;
+            println!("Moved {} keys", moved);
         }
     }
     Ok(())
 }
+
+/// How keys and values are given on the command line and printed.
+#[derive(StructOpt, Debug, Clone, Copy)]
+struct Encoding {
+    #[structopt(
+        long,
+        help = "Reads and prints keys and values as hexadecimal strings",
+        conflicts_with = "base64"
+    )]
+    hex: bool,
+    #[structopt(long, help = "Reads and prints keys and values as base64 strings")]
+    base64: bool,
+}
+
+impl Encoding {
+    /// Decodes a key or value given on the command line.
+    fn decode(self, s: &str) -> Result<Vec<u8>> {
+        if self.hex {
+            decode_hex(s).ok_or_else(|| KvsError::StringError(format!("Invalid hex: {}", s)))
+        } else if self.base64 {
+            decode_base64(s).ok_or_else(|| KvsError::StringError(format!("Invalid base64: {}", s)))
+        } else {
+            Ok(s.as_bytes().to_vec())
+        }
+    }
+
+    /// Encodes a key or value to be printed.
+    ///
+    /// Without `--hex` or `--base64`, invalid UTF-8 sequences are replaced with
+    /// `U+FFFD`.
+    fn encode(self, bytes: &[u8]) -> String {
+        if self.hex {
+            bytes.iter().map(|b| format!("{:02x}", b)).collect()
+        } else if self.base64 {
+            encode_base64(bytes)
+        } else {
+            String::from_utf8_lossy(bytes).into_owned()
+        }
+    }
+}
+
+fn decode_hex(s: &str) -> Option<Vec<u8>> {
+    s.as_bytes()
+        .chunks(2)
+        .map(|digits| match digits {
+            [high, low] => Some((hex_digit(*high)? << 4) | hex_digit(*low)?),
+            _ => None,
+        })
+        .collect()
+}
+
+fn hex_digit(c: u8) -> Option<u8> {
+    (c as char).to_digit(16).map(|digit| digit as u8)
+}
+
+const BASE64_CHARS: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+fn encode_base64(bytes: &[u8]) -> String {
+    let mut s = String::with_capacity(bytes.len() / 3 * 4 + 4);
+    for chunk in bytes.chunks(3) {
+        let n = chunk
+            .iter()
+            .enumerate()
+            .fold(0u32, |n, (i, &b)| n | u32::from(b) << (16 - 8 * i));
+        for i in 0..4 {
+            if i <= chunk.len() {
+                s.push(BASE64_CHARS[(n >> (18 - 6 * i) & 0x3f) as usize] as char);
+            } else {
+                s.push('=');
+            }
+        }
+    }
+    s
+}
+
+/// Decodes padded base64, rejecting padding before the last chunk and unused bits
+/// that are not zero, so that each byte string has a single encoding.
+fn decode_base64(s: &str) -> Option<Vec<u8>> {
+    let mut bytes = Vec::with_capacity(s.len() / 4 * 3);
+    let mut chunks = s.as_bytes().chunks(4).peekable();
+    while let Some(chunk) = chunks.next() {
+        let padding = chunk.iter().rev().take_while(|&&c| c == b'=').count();
+        if chunk.len() < 4 || padding > 2 || (padding > 0 && chunks.peek().is_some()) {
+            return None;
+        }
+        let mut n = 0u32;
+        for (i, &c) in chunk[..4 - padding].iter().enumerate() {
+            let digit = BASE64_CHARS.iter().position(|&d| d == c)? as u32;
+            n |= digit << (18 - 6 * i);
+        }
+        let n = n.to_be_bytes();
+        if n[4 - padding..].iter().any(|&b| b != 0) {
+            return None;
+        }
+        bytes.extend_from_slice(&n[1..4 - padding]);
+    }
+    Some(bytes)
+}
@@ -3,9 +3,16 @@ extern crate log;
 #[macro_use]
 extern crate clap;
 
+use kvs::percolator::TimestampOracle;
+use kvs::raft::{NodeId, RaftNode, RaftOptions, TcpTransport};
+use kvs::replication::Replica;
 use kvs::thread_pool::*;
-use kvs::{KvStore, KvsEngine, KvsServer, Result, SledKvsEngine};
+use kvs::{
+    Durability, KvStore, KvStoreOptions, KvsEngine, KvsError, KvsServer, LsmKvsEngine, LsmOptions,
+    MemoryKvsEngine, Result, SledKvsEngine,
+};
 use log::LevelFilter;
+use std::collections::HashMap;
 use std::env;
 use std::env::current_dir;
 use std::fs;
@@ -34,6 +41,71 @@ struct Opt {
         raw(possible_values = "&Engine::variants()")
     )]
     engine: Option<Engine>,
+    #[structopt(
+        long,
+        help = "Sets how durable a write must be before it is acknowledged: \
+                none, every-write, group-commit or periodic:<MILLISECONDS>",
+        value_name = "LEVEL",
+        parse(try_from_str)
+    )]
+    durability: Option<Durability>,
+    #[structopt(
+        long = "compaction-threshold",
+        help = "Sets how many bytes of stale data trigger a compaction (kvs engine only)",
+        value_name = "BYTES"
+    )]
+    compaction_threshold: Option<u64>,
+    #[structopt(
+        long = "compaction-ratio",
+        help = "Sets the stale/live data ratio that must be exceeded for a compaction (kvs engine only)",
+        value_name = "RATIO"
+    )]
+    compaction_ratio: Option<f64>,
+    #[structopt(
+        long = "max-log-size",
+        help = "Sets the log size after which a new log is started (kvs engine only)",
+        value_name = "BYTES"
+    )]
+    max_log_size: Option<u64>,
+    #[structopt(
+        long,
+        help = "Sets the number of log readers (kvs engine only)",
+        value_name = "N"
+    )]
+    readers: Option<u32>,
+    #[structopt(
+        long = "manual-compaction",
+        help = "Disables automatic compaction (kvs engine only)"
+    )]
+    manual_compaction: bool,
+    #[structopt(
+        long = "max-memory",
+        help = "Evicts the least recently used keys beyond the given size (memory engine only)",
+        value_name = "BYTES"
+    )]
+    max_memory: Option<u64>,
+    #[structopt(
+        long,
+        help = "Also serves as the timestamp oracle of Percolator transactions"
+    )]
+    oracle: bool,
+    #[structopt(
+        long,
+        help = "Runs a node of the Raft cluster of the servers at the given addresses, \
+                including the listening address",
+        value_name = "IP:PORT,...",
+        raw(use_delimiter = "true"),
+        parse(try_from_str)
+    )]
+    raft: Vec<SocketAddr>,
+    #[structopt(
+        long = "replica-of",
+        help = "Runs a read-only replica of the server at the given address",
+        value_name = "IP:PORT",
+        raw(conflicts_with = r#""raft""#),
+        parse(try_from_str)
+    )]
+    replica_of: Option<SocketAddr>,
 }
 
 arg_enum! {
@@ -41,7 +113,9 @@ arg_enum! {
     #[derive(Debug, Copy, Clone, PartialEq, Eq)]
     enum Engine {
         kvs,
-        sled
+        sled,
+        lsm,
+        memory
     }
 }
 
@@ -69,28 +143,152 @@ fn run(opt: Opt) -> Result<()> {
     info!("kvs-server {}", env!("CARGO_PKG_VERSION"));
     info!("Storage engine: {}", engine);
     info!("Listening on {}", opt.addr);
+    if let Some(durability) = opt.durability {
+        info!("Durability: {}", durability);
+    }
 
     // write engine to engine file
     fs::write(current_dir()?.join("engine"), format!("{}", engine))?;
 
+    let oracle = if opt.oracle {
+        info!("Serving as the timestamp oracle");
+        Some(TimestampOracle::open(current_dir()?.join("oracle"))?)
+    } else {
+        None
+    };
+
     let concurrency = num_cpus::get() as u32;
     match engine {
-        Engine::kvs => run_with(
-            KvStore::<RayonThreadPool>::open(env::current_dir()?, concurrency)?,
-            opt.addr,
-        ),
-        Engine::sled => run_with(
-            SledKvsEngine::<RayonThreadPool>::new(
+        Engine::kvs => {
+            let store = KvStore::<RayonThreadPool>::open_with_options(
+                env::current_dir()?,
+                concurrency,
+                &kvs_options(&opt),
+            )?;
+            // only the kvs engine can be followed by replicas and subscribers
+            let server = KvsServer::new(store.clone())
+                .with_replication_source()
+                .with_change_feed();
+            run_with(store, server, oracle, &opt.raft, opt.replica_of, opt.addr)
+        }
+        Engine::sled => {
+            let engine = SledKvsEngine::<RayonThreadPool>::with_durability(
                 sled::Db::start_default(env::current_dir()?)?,
                 concurrency,
-            )?,
-            opt.addr,
-        ),
+                opt.durability.unwrap_or(Durability::EveryWrite),
+            )?;
+            run_with(
+                engine.clone(),
+                KvsServer::new(engine),
+                oracle,
+                &opt.raft,
+                opt.replica_of,
+                opt.addr,
+            )
+        }
+        Engine::lsm => {
+            let engine = LsmKvsEngine::<RayonThreadPool>::open_with_options(
+                env::current_dir()?,
+                concurrency,
+                &lsm_options(&opt),
+            )?;
+            run_with(
+                engine.clone(),
+                KvsServer::new(engine),
+                oracle,
+                &opt.raft,
+                opt.replica_of,
+                opt.addr,
+            )
+        }
+        Engine::memory => {
+            let engine = match opt.max_memory {
+                Some(bytes) => {
+                    MemoryKvsEngine::<RayonThreadPool>::with_max_memory(concurrency, bytes)?
+                }
+                None => MemoryKvsEngine::<RayonThreadPool>::new(concurrency)?,
+            };
+            run_with(
+                engine.clone(),
+                KvsServer::new(engine),
+                oracle,
+                &opt.raft,
+                opt.replica_of,
+                opt.addr,
+            )
+        }
     }
 }
 
-pub fn run_with<E: KvsEngine>(engine: E, addr: SocketAddr) -> Result<()> {
-    let server = KvsServer::new(engine);
+fn kvs_options(opt: &Opt) -> KvStoreOptions {
+    let mut options = KvStoreOptions::new();
+    if let Some(bytes) = opt.compaction_threshold {
+        options.compaction_threshold(bytes);
+    }
+    if let Some(ratio) = opt.compaction_ratio {
+        options.compaction_ratio(ratio);
+    }
+    if let Some(bytes) = opt.max_log_size {
+        options.max_log_size(bytes);
+    }
+    if let Some(readers) = opt.readers {
+        options.readers(readers);
+    }
+    options.auto_compaction(!opt.manual_compaction);
+    if let Some(durability) = opt.durability {
+        options.durability(durability);
+    }
+    options
+}
+
+fn lsm_options(opt: &Opt) -> LsmOptions {
+    let mut options = LsmOptions::new();
+    if let Some(durability) = opt.durability {
+        options.durability(durability);
+    }
+    options
+}
+
+/// Runs the server of the engine, as a replica or a node of a Raft cluster if asked.
+pub fn run_with<E: KvsEngine>(
+    engine: E,
+    mut server: KvsServer<E>,
+    oracle: Option<TimestampOracle>,
+    cluster: &[SocketAddr],
+    primary: Option<SocketAddr>,
+    addr: SocketAddr,
+) -> Result<()> {
+    if let Some(oracle) = oracle {
+        server = server.with_oracle(oracle);
+    }
+    if let Some(primary) = primary {
+        info!("Replica of {}", primary);
+        let replica = Replica::start(engine.clone(), primary, current_dir()?.join("replica"))?;
+        server = server.with_replica(replica);
+    }
+    if !cluster.is_empty() {
+        // nodes are numbered after their position in the cluster
+        let id = match cluster.iter().position(|&member| member == addr) {
+            Some(pos) => pos as NodeId + 1,
+            None => {
+                return Err(KvsError::StringError(
+                    "The listening address is not in the Raft cluster".to_owned(),
+                ))
+            }
+        };
+        info!("Raft node {} of {}", id, cluster.len());
+        let addrs: HashMap<NodeId, SocketAddr> = (1..).zip(cluster.iter().cloned()).collect();
+        let members: Vec<NodeId> = addrs.keys().cloned().collect();
+        let node = RaftNode::open(
+            id,
+            &members,
+            current_dir()?.join("raft"),
+            engine,
+            TcpTransport::new(id, &addrs)?,
+            &RaftOptions::new(),
+        )?;
+        server = server.with_raft(node, addrs);
+    }
     server.run(addr)
 }
 
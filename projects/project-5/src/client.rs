@@ -1,13 +1,25 @@
 use crate::common::{Request, Response};
-use crate::KvsError;
+use crate::engines::{bound_into_bytes, pair_into_string};
+use crate::raft::{Message, NodeId};
+use crate::replication::{ChangeEvent, LogChunk, LogPosition, LogRecord};
+use crate::{KvsError, Result, WriteBatch};
 use std::net::SocketAddr;
+use std::ops::Bound;
+use std::time::Duration;
 use tokio::codec::{FramedRead, FramedWrite, LengthDelimitedCodec};
 use tokio::io::{ReadHalf, WriteHalf};
 use tokio::net::TcpStream;
+use tokio::prelude::future::{self, Either, Loop};
 use tokio::prelude::*;
 use tokio_serde_json::{ReadJson, WriteJson};
 
+/// Maximum number of times a request is redirected from a Raft node to another.
+const MAX_REDIRECTS: u32 = 8;
+
 /// Key value store client
+///
+/// Gets, sets and removes sent to a node of a Raft cluster that is not the leader
+/// are redirected to the leader. The client is then connected to the leader.
 pub struct KvsClient {
     read_json: ReadJson<FramedRead<ReadHalf<TcpStream>, LengthDelimitedCodec>, Response>,
     write_json: WriteJson<FramedWrite<WriteHalf<TcpStream>, LengthDelimitedCodec>, Request>,
@@ -32,8 +44,11 @@ impl KvsClient {
     }
 
     /// Get the value of a given key from the server.
-    pub fn get(self, key: String) -> impl Future<Item = (Option<String>, Self), Error = KvsError> {
-        self.send_request(Request::Get { key })
+    pub fn get_bytes(
+        self,
+        key: Vec<u8>,
+    ) -> impl Future<Item = (Option<Vec<u8>>, Self), Error = KvsError> {
+        self.send_request_to_leader(Request::Get { key })
             .and_then(move |(resp, client)| match resp {
                 Some(Response::Get(value)) => Ok((value, client)),
                 Some(Response::Err(msg)) => Err(KvsError::StringError(msg)),
@@ -42,9 +57,32 @@ impl KvsClient {
             })
     }
 
-    /// Set the value of a string key in the server.
-    pub fn set(self, key: String, value: String) -> impl Future<Item = Self, Error = KvsError> {
-        self.send_request(Request::Set { key, value })
+    /// Set the value of a key in the server.
+    pub fn set_bytes(
+        self,
+        key: Vec<u8>,
+        value: Vec<u8>,
+    ) -> impl Future<Item = Self, Error = KvsError> {
+        self.send_set(key, value, None)
+    }
+
+    /// Set the value of a key in the server that expires after the given time to live.
+    pub fn set_with_ttl_bytes(
+        self,
+        key: Vec<u8>,
+        value: Vec<u8>,
+        ttl: Duration,
+    ) -> impl Future<Item = Self, Error = KvsError> {
+        self.send_set(key, value, Some(ttl))
+    }
+
+    fn send_set(
+        self,
+        key: Vec<u8>,
+        value: Vec<u8>,
+        ttl: Option<Duration>,
+    ) -> impl Future<Item = Self, Error = KvsError> {
+        self.send_request_to_leader(Request::Set { key, value, ttl })
             .and_then(move |(resp, client)| match resp {
                 Some(Response::Set) => Ok(client),
                 Some(Response::Err(msg)) => Err(KvsError::StringError(msg)),
@@ -53,9 +91,9 @@ impl KvsClient {
             })
     }
 
-    /// Remove a string key in the server.
-    pub fn remove(self, key: String) -> impl Future<Item = Self, Error = KvsError> {
-        self.send_request(Request::Remove { key })
+    /// Remove a key in the server.
+    pub fn remove_bytes(self, key: Vec<u8>) -> impl Future<Item = Self, Error = KvsError> {
+        self.send_request_to_leader(Request::Remove { key })
             .and_then(move |(resp, client)| match resp {
                 Some(Response::Remove) => Ok(client),
                 Some(Response::Err(msg)) => Err(KvsError::StringError(msg)),
@@ -64,6 +102,337 @@ impl KvsClient {
             })
     }
 
+    /// Apply all writes of the batch atomically in the server, in one round trip.
+    pub fn write_batch(self, batch: WriteBatch) -> impl Future<Item = Self, Error = KvsError> {
+        self.send_request(Request::Batch { batch })
+            .and_then(move |(resp, client)| match resp {
+                Some(Response::Batch) => Ok(client),
+                Some(Response::Err(msg)) => Err(KvsError::StringError(msg)),
+                Some(_) => Err(KvsError::StringError("Invalid response".to_owned())),
+                None => Err(KvsError::StringError("No response received".to_owned())),
+            })
+    }
+
+    /// Set the value of a key to `new` in the server, or remove the key if `new` is
+    /// `None`, only if its current value is `expected`.
+    ///
+    /// It fails with `KvsError::CasConflict` and the current value otherwise.
+    pub fn compare_and_swap_bytes(
+        self,
+        key: Vec<u8>,
+        expected: Option<Vec<u8>>,
+        new: Option<Vec<u8>>,
+    ) -> impl Future<Item = Self, Error = KvsError> {
+        self.send_request(Request::CompareAndSwap { key, expected, new })
+            .and_then(move |(resp, client)| match resp {
+                Some(Response::CompareAndSwap) => Ok(client),
+                Some(Response::Conflict(current)) => Err(KvsError::CasConflict { current }),
+                Some(Response::Err(msg)) => Err(KvsError::StringError(msg)),
+                Some(_) => Err(KvsError::StringError("Invalid response".to_owned())),
+                None => Err(KvsError::StringError("No response received".to_owned())),
+            })
+    }
+
+    /// Set the value of a key in the server only if the key does not exist.
+    ///
+    /// It fails with `KvsError::CasConflict` and the current value otherwise.
+    pub fn set_if_absent_bytes(
+        self,
+        key: Vec<u8>,
+        value: Vec<u8>,
+    ) -> impl Future<Item = Self, Error = KvsError> {
+        self.send_set_if_absent(key, value, None)
+            .and_then(|(res, client)| res.map(|_| client))
+    }
+
+    /// Set the value of a key in the server that expires after the given time to
+    /// live, only if the key does not exist.
+    ///
+    /// It fails with `KvsError::CasConflict` and the current value otherwise.
+    pub fn set_if_absent_with_ttl_bytes(
+        self,
+        key: Vec<u8>,
+        value: Vec<u8>,
+        ttl: Duration,
+    ) -> impl Future<Item = Self, Error = KvsError> {
+        self.send_set_if_absent(key, value, Some(ttl))
+            .and_then(|(res, client)| res.map(|_| client))
+    }
+
+    /// Sets the value of a key only if the key does not exist.
+    ///
+    /// A key that exists is reported with `KvsError::CasConflict` along with the
+    /// client, which can still be used.
+    pub(crate) fn send_set_if_absent(
+        self,
+        key: Vec<u8>,
+        value: Vec<u8>,
+        ttl: Option<Duration>,
+    ) -> impl Future<Item = (Result<()>, Self), Error = KvsError> {
+        self.send_request(Request::SetIfAbsent { key, value, ttl })
+            .and_then(move |(resp, client)| match resp {
+                Some(Response::Set) => Ok((Ok(()), client)),
+                Some(Response::Conflict(current)) => {
+                    Ok((Err(KvsError::CasConflict { current }), client))
+                }
+                Some(Response::Err(msg)) => Err(KvsError::StringError(msg)),
+                Some(_) => Err(KvsError::StringError("Invalid response".to_owned())),
+                None => Err(KvsError::StringError("No response received".to_owned())),
+            })
+    }
+
+    /// Start a transaction on the connection.
+    ///
+    /// The following gets, sets and removes of the client are run in the transaction
+    /// until it is committed or rolled back. Other requests fail meanwhile.
+    pub fn begin(self) -> impl Future<Item = Self, Error = KvsError> {
+        self.send_request(Request::Begin)
+            .and_then(move |(resp, client)| match resp {
+                Some(Response::Begin) => Ok(client),
+                Some(Response::Err(msg)) => Err(KvsError::StringError(msg)),
+                Some(_) => Err(KvsError::StringError("Invalid response".to_owned())),
+                None => Err(KvsError::StringError("No response received".to_owned())),
+            })
+    }
+
+    /// Commit the transaction started on the connection.
+    ///
+    /// It fails with `KvsError::TransactionConflict` if a key read in the
+    /// transaction has been changed. The transaction is over either way.
+    pub fn commit(self) -> impl Future<Item = Self, Error = KvsError> {
+        self.send_request(Request::Commit)
+            .and_then(move |(resp, client)| match resp {
+                Some(Response::Commit) => Ok(client),
+                Some(Response::TransactionConflict) => Err(KvsError::TransactionConflict),
+                Some(Response::Err(msg)) => Err(KvsError::StringError(msg)),
+                Some(_) => Err(KvsError::StringError("Invalid response".to_owned())),
+                None => Err(KvsError::StringError("No response received".to_owned())),
+            })
+    }
+
+    /// Roll back the transaction started on the connection.
+    pub fn rollback(self) -> impl Future<Item = Self, Error = KvsError> {
+        self.send_request(Request::Rollback)
+            .and_then(move |(resp, client)| match resp {
+                Some(Response::Rollback) => Ok(client),
+                Some(Response::Err(msg)) => Err(KvsError::StringError(msg)),
+                Some(_) => Err(KvsError::StringError("Invalid response".to_owned())),
+                None => Err(KvsError::StringError("No response received".to_owned())),
+            })
+    }
+
+    /// Get a new timestamp from the timestamp oracle served by the server.
+    pub fn timestamp(self) -> impl Future<Item = (u64, Self), Error = KvsError> {
+        self.send_request(Request::Timestamp)
+            .and_then(move |(resp, client)| match resp {
+                Some(Response::Timestamp(ts)) => Ok((ts, client)),
+                Some(Response::Err(msg)) => Err(KvsError::StringError(msg)),
+                Some(_) => Err(KvsError::StringError("Invalid response".to_owned())),
+                None => Err(KvsError::StringError("No response received".to_owned())),
+            })
+    }
+
+    /// Send a message to the Raft node served by the server.
+    pub(crate) fn raft_message(
+        self,
+        from: NodeId,
+        message: Message,
+    ) -> impl Future<Item = Self, Error = KvsError> {
+        self.send_request(Request::Raft { from, message }).and_then(
+            move |(resp, client)| match resp {
+                Some(Response::Raft) => Ok(client),
+                Some(Response::Err(msg)) => Err(KvsError::StringError(msg)),
+                Some(_) => Err(KvsError::StringError("Invalid response".to_owned())),
+                None => Err(KvsError::StringError("No response received".to_owned())),
+            },
+        )
+    }
+
+    /// Read the log of the server from the given position on.
+    pub(crate) fn read_log(
+        self,
+        from: LogPosition,
+    ) -> impl Future<Item = (LogChunk, Self), Error = KvsError> {
+        self.send_request(Request::ReadLog { from })
+            .and_then(move |(resp, client)| match resp {
+                Some(Response::ReadLog(chunk)) => Ok((chunk, client)),
+                Some(Response::Err(msg)) => Err(KvsError::StringError(msg)),
+                Some(_) => Err(KvsError::StringError("Invalid response".to_owned())),
+                None => Err(KvsError::StringError("No response received".to_owned())),
+            })
+    }
+
+    /// Get up to `limit` pairs with keys from `start` on from the server, with the
+    /// expiry deadlines of the keys.
+    pub(crate) fn scan_records(
+        self,
+        start: Bound<Vec<u8>>,
+        limit: usize,
+    ) -> impl Future<Item = (Vec<LogRecord>, Self), Error = KvsError> {
+        self.send_request(Request::ScanRecords { start, limit })
+            .and_then(move |(resp, client)| match resp {
+                Some(Response::ScanRecords(records)) => Ok((records, client)),
+                Some(Response::Err(msg)) => Err(KvsError::StringError(msg)),
+                Some(_) => Err(KvsError::StringError("Invalid response".to_owned())),
+                None => Err(KvsError::StringError("No response received".to_owned())),
+            })
+    }
+
+    /// Get the key-value pairs with keys within the given bounds from the server,
+    /// in key order.
+    #[allow(clippy::type_complexity)]
+    pub fn scan_bytes(
+        self,
+        start: Bound<Vec<u8>>,
+        end: Bound<Vec<u8>>,
+        limit: Option<usize>,
+    ) -> impl Future<Item = (Vec<(Vec<u8>, Vec<u8>)>, Self), Error = KvsError> {
+        self.send_request(Request::Scan { start, end, limit })
+            .and_then(move |(resp, client)| match resp {
+                Some(Response::Scan(pairs)) => Ok((pairs, client)),
+                Some(Response::Err(msg)) => Err(KvsError::StringError(msg)),
+                Some(_) => Err(KvsError::StringError("Invalid response".to_owned())),
+                None => Err(KvsError::StringError("No response received".to_owned())),
+            })
+    }
+
+    /// Get the keys starting with the given prefix from the server, in order.
+    ///
+    /// The keys are received in chunks as the stream is polled. The client is
+    /// consumed by the stream.
+    pub fn keys_bytes(self, prefix: Vec<u8>) -> impl Stream<Item = Vec<u8>, Error = KvsError> {
+        self.send_streaming_request(Request::Keys { prefix })
+            .and_then(|resp| match resp {
+                Response::Keys(keys) => Ok(stream::iter_ok(keys)),
+                Response::Err(msg) => Err(KvsError::StringError(msg)),
+                _ => Err(KvsError::StringError("Invalid response".to_owned())),
+            })
+            .flatten()
+    }
+
+    /// Get the key-value pairs with keys starting with the given prefix from the
+    /// server, in key order.
+    ///
+    /// The pairs are received in chunks as the stream is polled. The client is
+    /// consumed by the stream.
+    pub fn scan_prefix_bytes(
+        self,
+        prefix: Vec<u8>,
+    ) -> impl Stream<Item = (Vec<u8>, Vec<u8>), Error = KvsError> {
+        self.send_streaming_request(Request::ScanPrefix { prefix })
+            .and_then(|resp| match resp {
+                Response::Pairs(pairs) => Ok(stream::iter_ok(pairs)),
+                Response::Err(msg) => Err(KvsError::StringError(msg)),
+                _ => Err(KvsError::StringError("Invalid response".to_owned())),
+            })
+            .flatten()
+    }
+
+    /// Subscribe to the writes committed on the server after the given sequence
+    /// number, then to the following writes as they are committed.
+    ///
+    /// To resume after the stream fails or the connection is lost, subscribe again
+    /// from the sequence number of the last event received. The client is consumed
+    /// by the stream.
+    pub fn subscribe(self, from_seq: u64) -> impl Stream<Item = ChangeEvent, Error = KvsError> {
+        self.send_streaming_request(Request::Subscribe { from_seq })
+            .and_then(|resp| match resp {
+                Response::Change(event) => Ok(event),
+                Response::Err(msg) => Err(KvsError::StringError(msg)),
+                _ => Err(KvsError::StringError("Invalid response".to_owned())),
+            })
+    }
+
+    /// Get the string value of a given string key from the server.
+    pub fn get(self, key: String) -> impl Future<Item = (Option<String>, Self), Error = KvsError> {
+        self.get_bytes(key.into_bytes())
+            .and_then(|(value, client)| Ok((value.map(String::from_utf8).transpose()?, client)))
+    }
+
+    /// Set the value of a string key to a string in the server.
+    pub fn set(self, key: String, value: String) -> impl Future<Item = Self, Error = KvsError> {
+        self.set_bytes(key.into_bytes(), value.into_bytes())
+    }
+
+    /// Set the value of a string key to a string in the server that expires after the
+    /// given time to live.
+    pub fn set_with_ttl(
+        self,
+        key: String,
+        value: String,
+        ttl: Duration,
+    ) -> impl Future<Item = Self, Error = KvsError> {
+        self.set_with_ttl_bytes(key.into_bytes(), value.into_bytes(), ttl)
+    }
+
+    /// Remove a string key in the server.
+    pub fn remove(self, key: String) -> impl Future<Item = Self, Error = KvsError> {
+        self.remove_bytes(key.into_bytes())
+    }
+
+    /// Set the value of a string key to `new` in the server, or remove the key if
+    /// `new` is `None`, only if its current value is `expected`.
+    pub fn compare_and_swap(
+        self,
+        key: String,
+        expected: Option<String>,
+        new: Option<String>,
+    ) -> impl Future<Item = Self, Error = KvsError> {
+        self.compare_and_swap_bytes(
+            key.into_bytes(),
+            expected.map(String::into_bytes),
+            new.map(String::into_bytes),
+        )
+    }
+
+    /// Set the value of a string key to a string in the server only if the key does
+    /// not exist.
+    pub fn set_if_absent(
+        self,
+        key: String,
+        value: String,
+    ) -> impl Future<Item = Self, Error = KvsError> {
+        self.set_if_absent_bytes(key.into_bytes(), value.into_bytes())
+    }
+
+    /// Get the pairs of string keys within the given bounds and their values from
+    /// the server, in key order.
+    pub fn scan(
+        self,
+        start: Bound<String>,
+        end: Bound<String>,
+        limit: Option<usize>,
+    ) -> impl Future<Item = (Vec<(String, String)>, Self), Error = KvsError> {
+        self.scan_bytes(bound_into_bytes(start), bound_into_bytes(end), limit)
+            .and_then(|(pairs, client)| {
+                let pairs = pairs
+                    .into_iter()
+                    .map(pair_into_string)
+                    .collect::<Result<_>>()?;
+                Ok((pairs, client))
+            })
+    }
+
+    /// Get the string keys starting with the given prefix from the server, in order.
+    ///
+    /// The client is consumed by the stream.
+    pub fn keys(self, prefix: String) -> impl Stream<Item = String, Error = KvsError> {
+        self.keys_bytes(prefix.into_bytes())
+            .and_then(|key| Ok(String::from_utf8(key)?))
+    }
+
+    /// Get the pairs of string keys starting with the given prefix and their values
+    /// from the server, in key order.
+    ///
+    /// The client is consumed by the stream.
+    pub fn scan_prefix(
+        self,
+        prefix: String,
+    ) -> impl Stream<Item = (String, String), Error = KvsError> {
+        self.scan_prefix_bytes(prefix.into_bytes())
+            .and_then(pair_into_string)
+    }
+
     fn send_request(
         self,
         req: Request,
@@ -85,4 +454,42 @@ impl KvsClient {
             })
             .map_err(|e| e.into())
     }
+
+    /// Sends a request, following the redirects of Raft nodes to their leader.
+    ///
+    /// It fails with `KvsError::NotLeader` if the leader is unknown.
+    fn send_request_to_leader(
+        self,
+        req: Request,
+    ) -> impl Future<Item = (Option<Response>, Self), Error = KvsError> {
+        future::loop_fn((self, 0), move |(client, redirects)| {
+            client
+                .send_request(req.clone())
+                .and_then(move |(resp, client)| match resp {
+                    Some(Response::NotLeader(Some(leader))) if redirects < MAX_REDIRECTS => {
+                        Either::A(
+                            KvsClient::connect(leader)
+                                .map(move |client| Loop::Continue((client, redirects + 1))),
+                        )
+                    }
+                    Some(Response::NotLeader(_)) => Either::B(future::err(KvsError::NotLeader)),
+                    resp => Either::B(future::ok(Loop::Break((resp, client)))),
+                })
+        })
+    }
+
+    /// Sends a request answered by a stream of responses, up to but not including
+    /// the terminating `Response::End`.
+    fn send_streaming_request(
+        self,
+        req: Request,
+    ) -> impl Stream<Item = Response, Error = KvsError> {
+        let read_json = self.read_json;
+        self.write_json
+            .send(req)
+            .map(move |_| read_json.map_err(KvsError::from))
+            .map_err(KvsError::from)
+            .flatten_stream()
+            .take_while(|resp| Ok(*resp != Response::End))
+    }
 }
@@ -1,16 +1,109 @@
+use crate::raft::{Message, NodeId};
+use crate::replication::{ChangeEvent, LogChunk, LogPosition, LogRecord};
+use crate::WriteBatch;
 use serde::{Deserialize, Serialize};
+use std::net::SocketAddr;
+use std::ops::Bound;
+use std::time::Duration;
 
-#[derive(Debug, Serialize, Deserialize)]
+/// After `Begin`, the `Get`, `Set` and `Remove` requests of a connection are run
+/// in a transaction until `Commit` or `Rollback`.
+///
+/// A node of a Raft cluster only serves `Get`, `Set` and `Remove`, and the `Raft`
+/// messages of the other nodes.
+///
+/// Replicas follow the log of their primary with `ReadLog`, and copy its store
+/// with `ScanRecords`. A replica rejects the writes of clients.
+///
+/// After `Subscribe`, the connection is only used to send the changes of the
+/// writes to the subscriber.
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum Request {
-    Get { key: String },
-    Set { key: String, value: String },
-    Remove { key: String },
+    Get {
+        key: Vec<u8>,
+    },
+    Set {
+        key: Vec<u8>,
+        value: Vec<u8>,
+        ttl: Option<Duration>,
+    },
+    Remove {
+        key: Vec<u8>,
+    },
+    Scan {
+        start: Bound<Vec<u8>>,
+        end: Bound<Vec<u8>>,
+        limit: Option<usize>,
+    },
+    Keys {
+        prefix: Vec<u8>,
+    },
+    ScanPrefix {
+        prefix: Vec<u8>,
+    },
+    Batch {
+        batch: WriteBatch,
+    },
+    CompareAndSwap {
+        key: Vec<u8>,
+        expected: Option<Vec<u8>>,
+        new: Option<Vec<u8>>,
+    },
+    SetIfAbsent {
+        key: Vec<u8>,
+        value: Vec<u8>,
+        ttl: Option<Duration>,
+    },
+    Begin,
+    Commit,
+    Rollback,
+    Timestamp,
+    Raft {
+        from: NodeId,
+        message: Message,
+    },
+    ReadLog {
+        from: LogPosition,
+    },
+    ScanRecords {
+        start: Bound<Vec<u8>>,
+        limit: usize,
+    },
+    Subscribe {
+        from_seq: u64,
+    },
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+/// Responses to `Keys` and `ScanPrefix` are streams of `Keys` or `Pairs` chunks
+/// terminated by `End`, or by `Err` if it fails. A subscription is answered with a
+/// `Change` for every write, and only ends with `Err`.
+///
+/// A failed compare-and-swap or set-if-absent is answered with `Conflict` and the
+/// current value, and
+/// a failed commit of a transaction with `TransactionConflict`. A node of a Raft
+/// cluster that is not the leader answers with `NotLeader` and the address of the
+/// leader if it knows it.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
 pub enum Response {
-    Get(Option<String>),
+    Get(Option<Vec<u8>>),
     Set,
     Remove,
+    Scan(Vec<(Vec<u8>, Vec<u8>)>),
+    Keys(Vec<Vec<u8>>),
+    Pairs(Vec<(Vec<u8>, Vec<u8>)>),
+    Batch,
+    CompareAndSwap,
+    Begin,
+    Commit,
+    Rollback,
+    Timestamp(u64),
+    Raft,
+    ReadLog(LogChunk),
+    ScanRecords(Vec<LogRecord>),
+    Change(ChangeEvent),
+    End,
+    Conflict(Option<Vec<u8>>),
+    TransactionConflict,
+    NotLeader(Option<SocketAddr>),
     Err(String),
 }
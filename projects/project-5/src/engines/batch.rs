@@ -0,0 +1,86 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+
+/// A list of writes applied atomically by `KvsEngine::write_batch`.
+///
+/// Either all or none of the writes survive a crash. Removing a key that doesn't
+/// exist is not an error in a batch, it does nothing instead.
+///
+/// ```rust
+/// # use kvs::{KvStore, KvsEngine, Result, WriteBatch};
+/// # use kvs::thread_pool::RayonThreadPool;
+/// # use tokio::prelude::*;
+/// # fn try_main() -> Result<()> {
+/// use std::env::current_dir;
+/// let store: KvStore<RayonThreadPool> = KvStore::open(current_dir()?, 2)?;
+/// let mut batch = WriteBatch::new();
+/// batch.set("object", "content").set("index", "object").remove("stale");
+/// store.write_batch(batch).wait()?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct WriteBatch {
+    ops: Vec<BatchOp>,
+}
+
+/// A write in a `WriteBatch`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) enum BatchOp {
+    Set { key: Vec<u8>, value: Vec<u8> },
+    Remove { key: Vec<u8> },
+}
+
+impl BatchOp {
+    pub(crate) fn key(&self) -> &[u8] {
+        match self {
+            BatchOp::Set { key, .. } | BatchOp::Remove { key } => key,
+        }
+    }
+}
+
+impl WriteBatch {
+    /// Creates an empty batch.
+    pub fn new() -> Self {
+        WriteBatch::default()
+    }
+
+    /// Sets the value of a key.
+    pub fn set(&mut self, key: impl Into<Vec<u8>>, value: impl Into<Vec<u8>>) -> &mut Self {
+        self.ops.push(BatchOp::Set {
+            key: key.into(),
+            value: value.into(),
+        });
+        self
+    }
+
+    /// Removes a key if it exists.
+    pub fn remove(&mut self, key: impl Into<Vec<u8>>) -> &mut Self {
+        self.ops.push(BatchOp::Remove { key: key.into() });
+        self
+    }
+
+    /// Returns the number of writes in the batch.
+    pub fn len(&self) -> usize {
+        self.ops.len()
+    }
+
+    /// Returns whether there are no writes in the batch.
+    pub fn is_empty(&self) -> bool {
+        self.ops.is_empty()
+    }
+
+    pub(crate) fn into_ops(self) -> Vec<BatchOp> {
+        self.ops
+    }
+
+    /// Returns the last write of each key, in the order they were added.
+    pub(crate) fn into_last_ops(self) -> Vec<BatchOp> {
+        let mut ops = self.ops;
+        let mut seen = HashSet::new();
+        ops.reverse();
+        ops.retain(|op| seen.insert(op.key().to_vec()));
+        ops.reverse();
+        ops
+    }
+}
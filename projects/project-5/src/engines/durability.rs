@@ -0,0 +1,127 @@
+use std::fmt;
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use crossbeam::channel::{self, RecvTimeoutError, Sender};
+
+use crate::{KvsError, Result};
+
+/// How durable a write must be before it is acknowledged.
+///
+/// It is parsed from and displayed as `none`, `every-write`, `group-commit` or
+/// `periodic:<MILLISECONDS>`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Durability {
+    /// Writes are only handed to the OS. They survive a crash of the process but
+    /// may be lost if the machine crashes.
+    None,
+    /// Writes are handed to the OS and synced to disk by a background thread at
+    /// the given interval.
+    Periodic(Duration),
+    /// Every write is synced to disk on its own before it is acknowledged.
+    EveryWrite,
+    /// Writes are synced to disk before they are acknowledged, and concurrent
+    /// writers share the same sync.
+    GroupCommit,
+}
+
+impl fmt::Display for Durability {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Durability::None => write!(f, "none"),
+            Durability::Periodic(interval) => write!(f, "periodic:{}", interval.as_millis()),
+            Durability::EveryWrite => write!(f, "every-write"),
+            Durability::GroupCommit => write!(f, "group-commit"),
+        }
+    }
+}
+
+impl FromStr for Durability {
+    type Err = KvsError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let invalid = || KvsError::StringError(format!("Invalid durability: {}", s));
+        match s {
+            "none" => Ok(Durability::None),
+            "every-write" => Ok(Durability::EveryWrite),
+            "group-commit" => Ok(Durability::GroupCommit),
+            _ if s.starts_with("periodic:") => s["periodic:".len()..]
+                .parse()
+                .map(|millis| Durability::Periodic(Duration::from_millis(millis)))
+                .map_err(|_| invalid()),
+            _ => Err(invalid()),
+        }
+    }
+}
+
+/// Shares syncs among concurrent writers.
+///
+/// Every write is given an increasing sequence number. A writer waiting for its
+/// write to be durable either finds it already covered by a sync of another
+/// writer or syncs itself, covering all the writes done so far.
+#[derive(Default)]
+pub(crate) struct GroupSync {
+    // sequence number of the last durable write
+    synced: Mutex<u64>,
+}
+
+impl GroupSync {
+    /// Makes sure the write with the given sequence number is durable.
+    ///
+    /// `sync` makes all the writes done so far durable and returns the sequence
+    /// number of the last write it covers. That number must be taken before the
+    /// sync starts.
+    pub(crate) fn wait<F>(&self, seq: u64, sync: F) -> Result<()>
+    where
+        F: FnOnce() -> Result<u64>,
+    {
+        let mut synced = self.synced.lock().unwrap();
+        if *synced < seq {
+            *synced = sync()?;
+        }
+        Ok(())
+    }
+}
+
+/// A background thread running a task at a fixed interval.
+///
+/// The thread is stopped when the handle is dropped.
+pub(crate) struct PeriodicTask {
+    // dropping the sender wakes up and stops the thread
+    sender: Option<Sender<()>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl PeriodicTask {
+    /// Spawns a thread with the given name running `task` every `interval`.
+    pub(crate) fn spawn<F>(name: &str, interval: Duration, mut task: F) -> Result<Self>
+    where
+        F: FnMut() + Send + 'static,
+    {
+        let (sender, receiver) = channel::bounded(0);
+        let thread = thread::Builder::new()
+            .name(name.to_owned())
+            .spawn(move || {
+                while let Err(RecvTimeoutError::Timeout) = receiver.recv_timeout(interval) {
+                    task();
+                }
+            })?;
+        Ok(PeriodicTask {
+            sender: Some(sender),
+            thread: Some(thread),
+        })
+    }
+}
+
+impl Drop for PeriodicTask {
+    fn drop(&mut self) {
+        self.sender.take();
+        if let Some(thread) = self.thread.take() {
+            if thread.join().is_err() {
+                error!("Periodic task thread panicked");
+            }
+        }
+    }
+}
@@ -0,0 +1,23 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// How often expired keys are swept by default.
+pub(crate) const DEFAULT_SWEEP_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Returns the current time in milliseconds since the Unix epoch.
+pub(crate) fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+/// Returns the deadline, in milliseconds since the Unix epoch, of a key set now
+/// with the given time to live.
+pub(crate) fn deadline_after(ttl: Duration) -> u64 {
+    now_millis().saturating_add(ttl.as_millis() as u64)
+}
+
+/// Whether a key with the given deadline has expired at `now`.
+pub(crate) fn is_expired(expires_at: Option<u64>, now: u64) -> bool {
+    expires_at.map_or(false, |expires_at| expires_at <= now)
+}
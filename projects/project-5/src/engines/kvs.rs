@@ -1,31 +1,205 @@
 use std::cell::RefCell;
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
 use std::ffi::OsStr;
-use std::fs::{self, File, OpenOptions};
 use std::io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::ops::Bound::{self, Excluded, Included, Unbounded};
 use std::ops::Range;
 use std::path::{Path, PathBuf};
+use std::process;
 use std::sync::atomic::{AtomicU64, Ordering};
-use std::sync::{Arc, Mutex};
+use std::sync::{Arc, Condvar, Mutex, PoisonError};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
 
-use crossbeam::queue::ArrayQueue;
+use crossbeam::channel::{self, Receiver, Sender};
+use crossbeam::queue::{ArrayQueue, SegQueue};
 use crossbeam_skiplist::SkipMap;
 use serde::{Deserialize, Serialize};
-use serde_json::Deserializer;
 use tokio::prelude::*;
-use tokio::sync::oneshot;
+use tokio::sync::{mpsc, oneshot};
 
-use super::KvsEngine;
+use super::{
+    bound_into_bytes, deadline_after, is_expired, now_millis, pair_into_string, BatchOp,
+    ChangeFeed, Durability, KvsEngine, PeriodicTask, ReplicationSource, WriteBatch,
+    DEFAULT_SWEEP_INTERVAL,
+};
+use crate::replication::{ChangeEvent, LogChunk, LogPosition, LogRecord};
 use crate::thread_pool::ThreadPool;
+use crate::vfs::{OsVfs, Vfs, VfsFile, VfsLock};
 use crate::{KvsError, Result};
 
-const COMPACTION_THRESHOLD: u64 = 1024 * 1024;
+const DEFAULT_COMPACTION_THRESHOLD: u64 = 1024 * 1024;
 
-/// The `KvStore` stores string key/value pairs.
+/// Magic bytes at the start of every log file in the binary format.
+const LOG_MAGIC: &[u8; 6] = b"KVSLOG";
+/// Version of the binary log format written by this version.
+///
+/// Version 1 didn't have expiry deadlines in set commands. Versions 2 and 3 didn't
+/// have sequence numbers, and version 2 didn't have batch commands either.
+const LOG_VERSION: u16 = 4;
+/// Length of the log file header: `LOG_MAGIC` followed by a little-endian `u16` version.
+const LOG_HEADER_LEN: u64 = 8;
+
+/// Magic bytes at the start of every hint file.
+const HINT_MAGIC: &[u8; 6] = b"KVSHNT";
+/// Version of the hint file format written by this version.
+///
+/// Hint files of other versions are ignored and their logs are replayed instead.
+const HINT_VERSION: u16 = 3;
+
+/// Name of the file listing the live logs.
+const MANIFEST_FILE: &str = "MANIFEST";
+/// Name of the file locked by the store opened for writing, which holds the PID of
+/// its process.
+const LOCK_FILE: &str = "LOCK";
+
+/// Length of the header in front of every log record: the payload length and
+/// the CRC32 checksum of the payload, both little-endian `u32`s.
+const RECORD_HEADER_LEN: u64 = 8;
+/// Number of bytes after a damaged record at the end of the active log in which
+/// another record is looked for, to tell a torn write from a corrupted length.
+const TORN_TAIL_SCAN_LEN: u64 = 1024 * 1024;
+
+/// Sequence number to read the latest versions of keys at.
+const LATEST_SEQ: u64 = u64::MAX;
+
+/// Number of bytes of records after which a replica is sent what has been read of
+/// the logs so far.
+const LOG_CHUNK_BYTES: u64 = 256 * 1024;
+
+type ChangeStream = Box<dyn Stream<Item = ChangeEvent, Error = KvsError> + Send>;
+
+/// Options to tune compaction, log rotation and reading of a `KvStore`.
+///
+/// ```rust
+/// # use kvs::{KvStore, KvStoreOptions, Result};
+/// # use kvs::thread_pool::RayonThreadPool;
+/// # fn try_main() -> Result<()> {
+/// use std::env::current_dir;
+/// let store: KvStore<RayonThreadPool> = KvStore::open_with_options(
+///     current_dir()?,
+///     4,
+///     KvStoreOptions::new()
+///         .compaction_threshold(64 * 1024 * 1024)
+///         .compaction_ratio(0.5)
+///         .readers(16),
+/// )?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Clone)]
+pub struct KvStoreOptions {
+    compaction_threshold: u64,
+    compaction_ratio: f64,
+    max_log_size: Option<u64>,
+    readers: Option<u32>,
+    auto_compaction: bool,
+    durability: Durability,
+    sweep_interval: Duration,
+    vfs: Arc<dyn Vfs>,
+}
+
+impl KvStoreOptions {
+    /// Creates the default options.
+    ///
+    /// A compaction is triggered once there are more than 1 MiB of stale commands,
+    /// whatever the amount of live data, and the active log is never rotated.
+    /// Writes are not synced to disk. Expired keys are swept every second. Files are
+    /// kept in the filesystem of the operating system.
+    pub fn new() -> Self {
+        KvStoreOptions {
+            compaction_threshold: DEFAULT_COMPACTION_THRESHOLD,
+            compaction_ratio: 0.0,
+            max_log_size: None,
+            readers: None,
+            auto_compaction: true,
+            durability: Durability::None,
+            sweep_interval: DEFAULT_SWEEP_INTERVAL,
+            vfs: Arc::new(OsVfs),
+        }
+    }
+
+    /// Sets how many bytes of stale commands there must be before a compaction
+    /// is triggered.
+    pub fn compaction_threshold(&mut self, bytes: u64) -> &mut Self {
+        self.compaction_threshold = bytes;
+        self
+    }
+
+    /// Sets the ratio of stale bytes to live bytes that must be exceeded, in addition
+    /// to the threshold, before a compaction is triggered.
+    pub fn compaction_ratio(&mut self, ratio: f64) -> &mut Self {
+        self.compaction_ratio = ratio;
+        self
+    }
+
+    /// Sets the size of the active log after which the following writes go to a log
+    /// with a new generation number.
+    pub fn max_log_size(&mut self, bytes: u64) -> &mut Self {
+        self.max_log_size = Some(bytes);
+        self
+    }
+
+    /// Sets how many readers keep log files open for concurrent reads.
+    ///
+    /// It defaults to the number of threads of the store. With fewer readers than
+    /// threads, reads wait for a reader to be free.
+    pub fn readers(&mut self, readers: u32) -> &mut Self {
+        self.readers = Some(readers);
+        self
+    }
+
+    /// Sets whether compactions are triggered automatically by writes.
+    ///
+    /// If disabled, compactions only run through `KvStore::compact`.
+    pub fn auto_compaction(&mut self, enabled: bool) -> &mut Self {
+        self.auto_compaction = enabled;
+        self
+    }
+
+    /// Sets how durable a write must be before it is acknowledged.
+    ///
+    /// Concurrent writes are committed in groups: each writer queues its command and
+    /// whoever holds the writer lock appends all queued commands with a single flush
+    /// and sync.
+    pub fn durability(&mut self, durability: Durability) -> &mut Self {
+        self.durability = durability;
+        self
+    }
+
+    /// Sets how often the background sweeper removes expired keys from the index.
+    ///
+    /// Expired keys are hidden from reads until then, and dropped from the logs by
+    /// compactions.
+    pub fn sweep_interval(&mut self, interval: Duration) -> &mut Self {
+        self.sweep_interval = interval;
+        self
+    }
+
+    /// Sets the filesystem the logs and hint files are kept in.
+    ///
+    /// It defaults to the filesystem of the operating system. A group of writes that
+    /// fails to be appended is dropped from the log, so the store stays consistent
+    /// after I/O errors.
+    pub fn vfs(&mut self, vfs: impl Vfs + 'static) -> &mut Self {
+        self.vfs = Arc::new(vfs);
+        self
+    }
+}
+
+impl Default for KvStoreOptions {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The `KvStore` stores key/value pairs of arbitrary bytes.
 ///
 /// Key/value pairs are persisted to disk in log files. Log files are named after
 /// monotonically increasing generation numbers with a `log` extension name.
-/// A skip list in memory stores the keys and the value locations for fast query.
+/// A skip list in memory stores the keys and the value locations for fast query,
+/// along with the older versions of keys that snapshots may read. Stale commands
+/// are cleared by compactions running in a background thread.
 ///
 /// ```rust
 /// # use kvs::{KvStore, Result};
@@ -45,11 +219,23 @@ const COMPACTION_THRESHOLD: u64 = 1024 * 1024;
 pub struct KvStore<P: ThreadPool> {
     // directory for the log and other data
     path: Arc<PathBuf>,
-    // map generation number to the file reader
-    index: Arc<SkipMap<String, CommandPos>>,
+    vfs: Arc<dyn Vfs>,
+    // map keys to their latest version, chaining the older ones live snapshots may read
+    index: Arc<SkipMap<Vec<u8>, Version>>,
     writer: Arc<Mutex<KvStoreWriter>>,
+    // writes waiting to be committed by the writer
+    queue: Arc<SegQueue<PendingWrite>>,
     thread_pool: P,
-    reader_pool: Arc<ArrayQueue<KvStoreReader>>,
+    reader_pool: Arc<ReaderPool>,
+    // stops the background compactor when the last clone is dropped
+    _compactor: Option<Arc<CompactorHandle>>,
+    // stops the background syncer when the last clone is dropped
+    _syncer: Option<Arc<PeriodicTask>>,
+    // stops the background sweeper when the last clone is dropped
+    _sweeper: Option<Arc<PeriodicTask>>,
+    // releases the lock of the directory when the last clone is dropped, once the
+    // background tasks above are stopped
+    _lock: Option<Arc<LockHandle>>,
 }
 
 impl<P: ThreadPool> KvStore<P> {
@@ -59,59 +245,356 @@ impl<P: ThreadPool> KvStore<P> {
     ///
     /// `concurrency` specifies how many threads at most can read the database at the same time.
     ///
+    /// A `MANIFEST` file lists the generations of the live logs and of the active
+    /// one. Logs it does not list, left by a crash in the middle of a compaction or
+    /// of a switch to a new log, are removed. Stores written before the manifest
+    /// load all their logs. A torn record at the end of the active log, left by a
+    /// crash in the middle of a write, is truncated away.
+    ///
+    /// The store holds an advisory lock on the `LOCK` file of the directory until
+    /// its last clone is dropped and its background tasks are stopped, so that no
+    /// other process or `KvStore` writes to the same logs.
+    ///
     /// # Errors
     ///
+    /// It returns `KvsError::StoreLocked` with the PID of the holder if the store is
+    /// already open for writing, in this process or another one.
+    ///
+    /// It returns `KvsError::Corruption` if a record in the middle of a log fails
+    /// its checksum or has a corrupted length.
+    ///
     /// It propagates I/O or deserialization errors during the log replay.
     pub fn open(path: impl Into<PathBuf>, concurrency: u32) -> Result<Self> {
-        let path = Arc::new(path.into());
-        fs::create_dir_all(&*path)?;
+        Self::open_with_options(path, concurrency, &KvStoreOptions::new())
+    }
+
+    /// Opens a `KvStore` with the given path and options.
+    ///
+    /// `concurrency` specifies the number of threads of the thread pool.
+    ///
+    /// See `KvStore::open` for the errors.
+    pub fn open_with_options(
+        path: impl Into<PathBuf>,
+        concurrency: u32,
+        options: &KvStoreOptions,
+    ) -> Result<Self> {
+        Self::open_in_mode(path.into(), concurrency, options, false)
+    }
+
+    /// Opens the `KvStore` with the given path for reading only.
+    ///
+    /// The lock of the directory is not taken, so any number of read-only stores can
+    /// be open alongside each other and the store open for writing. The store sees
+    /// the writes committed before it is opened, and no later ones. Nothing is
+    /// written to the directory: a torn record at the end of a log is skipped
+    /// rather than truncated, and the logs left over by a crash are left to the
+    /// writer.
+    ///
+    /// No background compactor or sweeper is started. Writes and compactions fail
+    /// with `KvsError::ReadOnly`. Reads may fail if a compaction of the writer
+    /// removes the logs they need in the meantime.
+    ///
+    /// # Errors
+    ///
+    /// It returns an I/O error of kind `NotFound` if the directory holds no store.
+    ///
+    /// See `KvStore::open` for the other errors.
+    pub fn open_read_only(path: impl Into<PathBuf>, concurrency: u32) -> Result<Self> {
+        Self::open_read_only_with_options(path, concurrency, &KvStoreOptions::new())
+    }
+
+    /// Opens the `KvStore` with the given path and options for reading only.
+    ///
+    /// See `KvStore::open_read_only`.
+    pub fn open_read_only_with_options(
+        path: impl Into<PathBuf>,
+        concurrency: u32,
+        options: &KvStoreOptions,
+    ) -> Result<Self> {
+        Self::open_in_mode(path.into(), concurrency, options, true)
+    }
+
+    fn open_in_mode(
+        path: PathBuf,
+        concurrency: u32,
+        options: &KvStoreOptions,
+        read_only: bool,
+    ) -> Result<Self> {
+        let path = Arc::new(path);
+        let vfs = Arc::clone(&options.vfs);
+        let lock = if read_only {
+            None
+        } else {
+            vfs.create_dir_all(&path)?;
+            Some(lock_dir(&*vfs, &path)?)
+        };
 
         let mut readers = BTreeMap::new();
         let index = Arc::new(SkipMap::new());
 
-        let gen_list = sorted_gen_list(&path)?;
+        let listed_gens = sorted_gen_list(&*vfs, &path)?;
+        let (gen_list, active_gen) = match Manifest::load(&*vfs, &path)? {
+            Some(manifest) => {
+                // logs written by a compaction or a switch to a new log interrupted
+                // before the manifest listed them
+                for &gen in &listed_gens {
+                    if !read_only && !manifest.live_gens.contains(&gen) {
+                        remove_log_files(&*vfs, &path, gen);
+                    }
+                }
+                (manifest.live_gens, Some(manifest.active_gen))
+            }
+            // stores written before the manifest
+            None => (listed_gens.clone(), listed_gens.last().cloned()),
+        };
         let mut uncompacted = 0;
+        let mut seq = 0;
 
         for &gen in &gen_list {
-            let mut reader = BufReaderWithPos::new(File::open(log_path(&path, gen))?)?;
-            uncompacted += load(gen, &mut reader, &*index)?;
+            if let Some(hint_uncompacted) = load_hint(&*vfs, &path, gen, &*index, &mut seq)? {
+                uncompacted += hint_uncompacted;
+                continue;
+            }
+            let mut reader = open_log(&*vfs, &path, gen)?;
+            let active = Some(gen) == active_gen;
+            uncompacted += load(
+                &*vfs,
+                &path,
+                gen,
+                active,
+                &mut reader,
+                &*index,
+                &mut seq,
+                read_only,
+            )?;
             readers.insert(gen, reader);
         }
+        let live = index.iter().map(|entry| entry.value().size()).sum();
+        let expiries = index
+            .iter()
+            .filter_map(|entry| Some((entry.value().cmd_pos?.expires_at?, entry.key().clone())))
+            .collect();
 
-        let current_gen = gen_list.last().unwrap_or(&0) + 1;
-        let writer = new_log_file(&path, current_gen)?;
+        let mut live_gens: BTreeSet<_> = gen_list.into_iter().collect();
+        let (current_gen, writer) = if read_only {
+            // The writer stays at the end of the last log, and never writes to it.
+            let gen = *live_gens.iter().next_back().ok_or_else(|| {
+                io::Error::new(io::ErrorKind::NotFound, "no log found in the directory")
+            })?;
+            let file = vfs.open(&log_path(&path, gen))?;
+            let len = file.len()?;
+            (gen, BufWriterWithPos::new(VfsWriter(Arc::from(file)), len))
+        } else {
+            // orphaned logs that failed to be removed are never reused
+            let gen = listed_gens.iter().chain(&live_gens).max().unwrap_or(&0) + 1;
+            let writer = new_log_file(&*vfs, &path, gen)?;
+            live_gens.insert(gen);
+            Manifest::new(&live_gens, gen).save(&*vfs, &path)?;
+            (gen, writer)
+        };
         let safe_point = Arc::new(AtomicU64::new(0));
 
         let reader = KvStoreReader {
             path: Arc::clone(&path),
+            vfs: Arc::clone(&vfs),
             safe_point,
             readers: RefCell::new(BTreeMap::new()),
         };
 
-        let writer = KvStoreWriter {
-            reader: reader.clone(),
+        let (sender, receiver) = channel::unbounded();
+        let writer = Arc::new(Mutex::new(KvStoreWriter {
             writer,
             current_gen,
+            seq,
             uncompacted,
+            live,
+            expiries,
+            snapshots: BTreeMap::new(),
+            versioned: HashSet::new(),
+            stale_logs: Vec::new(),
+            live_gens,
+            compacting: false,
+            lock,
+            subscribers: Vec::new(),
+            torn: false,
+            options: options.clone(),
+            compactor: sender.clone(),
+            path: Arc::clone(&path),
+            vfs: Arc::clone(&vfs),
+            index: Arc::clone(&index),
+        }));
+
+        let compactor = Compactor {
+            reader: reader.clone(),
+            writer: Arc::clone(&writer),
             path: Arc::clone(&path),
+            vfs: Arc::clone(&vfs),
             index: Arc::clone(&index),
         };
+        let compactor = if read_only {
+            None
+        } else {
+            let thread = thread::Builder::new()
+                .name("kvs-compactor".to_owned())
+                .spawn(move || compactor.run(receiver))?;
+            Some(Arc::new(CompactorHandle {
+                sender,
+                thread: Some(thread),
+            }))
+        };
+
+        let syncer = match options.durability {
+            Durability::Periodic(interval) if !read_only => {
+                let writer = Arc::clone(&writer);
+                let syncer = PeriodicTask::spawn("kvs-syncer", interval, move || {
+                    let res = writer.lock().unwrap().sync_handle();
+                    if let Err(e) = res.and_then(|file| Ok(file.sync_data()?)) {
+                        error!("Periodic sync failed: {}", e);
+                    }
+                })?;
+                Some(Arc::new(syncer))
+            }
+            _ => None,
+        };
+        let sweeper = if read_only {
+            None
+        } else {
+            let writer = Arc::clone(&writer);
+            let sweeper = PeriodicTask::spawn("kvs-sweeper", options.sweep_interval, move || {
+                writer.lock().unwrap().sweep_expired();
+            })?;
+            Some(Arc::new(sweeper))
+        };
+        let queue = Arc::new(SegQueue::new());
+        let lock = if read_only {
+            None
+        } else {
+            Some(Arc::new(LockHandle {
+                writer: Arc::clone(&writer),
+                queue: Arc::clone(&queue),
+            }))
+        };
 
         let thread_pool = P::new(concurrency)?;
-        let reader_pool = Arc::new(ArrayQueue::new(concurrency as usize));
-        for _ in 1..concurrency {
-            reader_pool.push(reader.clone()).unwrap();
+        let reader_count = options.readers.unwrap_or(concurrency).max(1);
+        let reader_pool = Arc::new(ReaderPool::new(reader_count));
+        for _ in 1..reader_count {
+            reader_pool.push(reader.clone());
         }
-        reader_pool.push(reader).unwrap();
+        reader_pool.push(reader);
 
         Ok(KvStore {
             path,
+            vfs,
             index,
-            writer: Arc::new(Mutex::new(writer)),
+            writer,
+            queue,
             thread_pool,
             reader_pool,
+            _compactor: compactor,
+            _syncer: syncer,
+            _sweeper: sweeper,
+            _lock: lock,
         })
     }
+
+    /// Compacts the logs in the background thread.
+    ///
+    /// The live commands are rewritten to a compaction log, along with a hint file
+    /// named after its generation with a `hint` extension name, which lists the keys
+    /// and value locations in that log. Opening the store loads the hint files
+    /// instead of replaying those logs. The compaction only commits to the manifest
+    /// once its log and hint file are durable, before the logs it replaces are
+    /// removed.
+    ///
+    /// The returned future completes when the compaction finishes.
+    pub fn compact(&self) -> Box<dyn Future<Item = (), Error = KvsError> + Send> {
+        let (tx, rx) = oneshot::channel();
+        self.writer.lock().unwrap().request_compaction(Some(tx));
+        Box::new(
+            rx.map_err(|e| KvsError::StringError(format!("{}", e)))
+                .flatten(),
+        )
+    }
+
+    /// Takes a read-only view of the store pinned to the last committed write.
+    ///
+    /// Reads through the snapshot see the store as it was at that moment, except
+    /// that keys expiring in the meantime are hidden. The logs needed by the
+    /// snapshot are kept by compactions until the last clone of it is dropped.
+    pub fn snapshot(&self) -> Snapshot<P> {
+        let seq = self.writer.lock().unwrap().pin_snapshot();
+        Snapshot {
+            seq,
+            index: Arc::clone(&self.index),
+            thread_pool: self.thread_pool.clone(),
+            reader_pool: Arc::clone(&self.reader_pool),
+            _handle: Arc::new(SnapshotHandle {
+                seq,
+                writer: Arc::clone(&self.writer),
+            }),
+        }
+    }
+
+    /// Queues the command to be committed by the writer.
+    fn write(&self, cmd: Command) -> Box<dyn Future<Item = (), Error = KvsError> + Send> {
+        let writer = self.writer.clone();
+        let queue = self.queue.clone();
+        let (tx, rx) = oneshot::channel();
+        queue.push(PendingWrite { cmd, done: tx });
+        self.thread_pool.spawn(move || {
+            writer.lock().unwrap().commit_queued(&queue);
+        });
+        Box::new(
+            rx.map_err(|e| KvsError::StringError(format!("{}", e)))
+                .flatten(),
+        )
+    }
+
+    /// Sets the value of a key with the given expiry deadline, or removes the key if
+    /// `new` is `None`, only if its current value is `expected`.
+    fn compare_and_write(
+        &self,
+        key: Vec<u8>,
+        expected: Option<Vec<u8>>,
+        new: Option<Vec<u8>>,
+        expires_at: Option<u64>,
+    ) -> Box<dyn Future<Item = (), Error = KvsError> + Send> {
+        let writer = self.writer.clone();
+        let queue = self.queue.clone();
+        let reader_pool = self.reader_pool.clone();
+        let index = self.index.clone();
+        let (tx, rx) = oneshot::channel();
+        self.thread_pool.spawn(move || {
+            let mut writer = writer.lock().unwrap();
+            writer.commit_queued(&queue);
+            let reader = reader_pool.pop();
+            let current = read_value(&reader, &index, &key, LATEST_SEQ);
+            reader_pool.push(reader);
+            let res = match (current, new) {
+                (Ok(current), _) if current != expected => Err(KvsError::CasConflict { current }),
+                (Ok(None), None) => Ok(()),
+                (Ok(_), new) => {
+                    let cmd = match new {
+                        Some(value) => Command::set(key, value, expires_at),
+                        None => Command::remove(key),
+                    };
+                    // committed as a group of its own so that no write queued in the
+                    // meantime comes between the comparison and this write
+                    writer.commit(vec![PendingWrite { cmd, done: tx }]);
+                    return;
+                }
+                (Err(e), _) => Err(e),
+            };
+            if tx.send(res).is_err() {
+                error!("Receiving end is dropped");
+            }
+        });
+        Box::new(
+            rx.map_err(|e| KvsError::StringError(format!("{}", e)))
+                .flatten(),
+        )
+    }
 }
 
 impl<P: ThreadPool> KvsEngine for KvStore<P> {
@@ -119,14 +602,51 @@ impl<P: ThreadPool> KvsEngine for KvStore<P> {
     ///
     /// If the key already exists, the previous value will be overwritten.
     ///
+    /// The returned future completes once the write is as durable as the options
+    /// of the store require.
+    ///
     /// # Errors
     ///
     /// It propagates I/O or serialization errors during writing the log.
-    fn set(&self, key: String, value: String) -> Box<dyn Future<Item = (), Error = KvsError> + Send> {
-        let writer = self.writer.clone();
+    fn set_bytes(
+        &self,
+        key: Vec<u8>,
+        value: Vec<u8>,
+    ) -> Box<dyn Future<Item = (), Error = KvsError> + Send> {
+        self.write(Command::set(key, value, None))
+    }
+
+    /// Sets the value of a key that expires after the given time to live.
+    ///
+    /// The expiry deadline is stored with the value in the log, so the key stays
+    /// expired after the store is reopened.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O or serialization errors during writing the log.
+    fn set_with_ttl_bytes(
+        &self,
+        key: Vec<u8>,
+        value: Vec<u8>,
+        ttl: Duration,
+    ) -> Box<dyn Future<Item = (), Error = KvsError> + Send> {
+        self.write(Command::set(key, value, Some(deadline_after(ttl))))
+    }
+
+    /// Gets the value of a given key.
+    ///
+    /// Returns `None` if the given key does not exist or has expired.
+    fn get_bytes(
+        &self,
+        key: Vec<u8>,
+    ) -> Box<dyn Future<Item = Option<Vec<u8>>, Error = KvsError> + Send> {
+        let reader_pool = self.reader_pool.clone();
+        let index = self.index.clone();
         let (tx, rx) = oneshot::channel();
         self.thread_pool.spawn(move || {
-            let res = writer.lock().unwrap().set(key, value);
+            let reader = reader_pool.pop();
+            let res = read_value(&reader, &index, &key, LATEST_SEQ);
+            reader_pool.push(reader);
             if tx.send(res).is_err() {
                 error!("Receiving end is dropped");
             }
@@ -137,30 +657,110 @@ impl<P: ThreadPool> KvsEngine for KvStore<P> {
         )
     }
 
-    /// Gets the string value of a given string key.
+    /// Removes a given key.
+    ///
+    /// # Error
+    ///
+    /// It returns `KvsError::KeyNotFound` if the given key is not found.
+    ///
+    /// It propagates I/O or serialization errors during writing the log.
+    fn remove_bytes(&self, key: Vec<u8>) -> Box<dyn Future<Item = (), Error = KvsError> + Send> {
+        self.write(Command::remove(key))
+    }
+
+    /// Applies all writes of the batch atomically.
+    ///
+    /// Only the last write of each key is kept, and the writes are appended to the
+    /// log as a single record.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O or serialization errors during writing the log.
+    fn write_batch(
+        &self,
+        batch: WriteBatch,
+    ) -> Box<dyn Future<Item = (), Error = KvsError> + Send> {
+        self.write(Command::batch(batch))
+    }
+
+    /// Sets or removes a key only if its current value is `expected`.
+    ///
+    /// The value is compared and written while holding the writer lock, after the
+    /// writes queued before are committed.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::CasConflict` with the current value if it is not the
+    /// expected one.
+    ///
+    /// It propagates I/O or serialization errors during writing the log.
+    fn compare_and_swap_bytes(
+        &self,
+        key: Vec<u8>,
+        expected: Option<Vec<u8>>,
+        new: Option<Vec<u8>>,
+    ) -> Box<dyn Future<Item = (), Error = KvsError> + Send> {
+        self.compare_and_write(key, expected, new, None)
+    }
+
+    /// Sets the value of a key that expires after the given time to live, only if
+    /// the key does not exist.
+    ///
+    /// Like `compare_and_swap_bytes`, the key is checked and written while holding
+    /// the writer lock.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::CasConflict` with the current value if the key exists.
+    ///
+    /// It propagates I/O or serialization errors during writing the log.
+    fn set_if_absent_with_ttl_bytes(
+        &self,
+        key: Vec<u8>,
+        value: Vec<u8>,
+        ttl: Duration,
+    ) -> Box<dyn Future<Item = (), Error = KvsError> + Send> {
+        self.compare_and_write(key, None, Some(value), Some(deadline_after(ttl)))
+    }
+
+    /// Applies the batch only if the keys read have the given values.
+    ///
+    /// Like `compare_and_swap_bytes`, the values are compared and the batch is
+    /// written while holding the writer lock, after the writes queued before are
+    /// committed.
     ///
-    /// Returns `None` if the given key does not exist.
-    fn get(&self, key: String) -> Box<dyn Future<Item = Option<String>, Error = KvsError> + Send> {
+    /// # Errors
+    ///
+    /// It returns `KvsError::TransactionConflict` if any key has another value.
+    ///
+    /// It propagates I/O or serialization errors during writing the log.
+    fn commit_transaction(
+        &self,
+        reads: Vec<(Vec<u8>, Option<Vec<u8>>)>,
+        batch: WriteBatch,
+    ) -> Box<dyn Future<Item = (), Error = KvsError> + Send> {
+        let writer = self.writer.clone();
+        let queue = self.queue.clone();
         let reader_pool = self.reader_pool.clone();
         let index = self.index.clone();
         let (tx, rx) = oneshot::channel();
         self.thread_pool.spawn(move || {
-            let res = (|| {
-                if let Some(cmd_pos) = index.get(&key) {
-                    let reader = reader_pool.pop().unwrap();
-                    let res = if let Command::Set { value, .. } =
-                        reader.read_command(*cmd_pos.value())?
-                    {
-                        Ok(Some(value))
-                    } else {
-                        Err(KvsError::UnexpectedCommandType)
-                    };
-                    reader_pool.push(reader).unwrap();
-                    res
+            let mut writer = writer.lock().unwrap();
+            writer.commit_queued(&queue);
+            let reader = reader_pool.pop();
+            let res = reads.iter().try_for_each(|(key, value)| {
+                if read_value(&reader, &index, key, LATEST_SEQ)? == *value {
+                    Ok(())
                 } else {
-                    Ok(None)
+                    Err(KvsError::TransactionConflict)
                 }
-            })();
+            });
+            reader_pool.push(reader);
+            if res.is_ok() {
+                let cmd = Command::batch(batch);
+                writer.commit(vec![PendingWrite { cmd, done: tx }]);
+                return;
+            }
             if tx.send(res).is_err() {
                 error!("Receiving end is dropped");
             }
@@ -171,18 +771,107 @@ impl<P: ThreadPool> KvsEngine for KvStore<P> {
         )
     }
 
-    /// Removes a given key.
+    /// Gets the key-value pairs with keys within the given bounds, in key order.
     ///
-    /// # Error
+    /// At most `limit` pairs are returned if it is given. Keys removed while the
+    /// scan is running may be left out.
+    fn scan_bytes(
+        &self,
+        start: Bound<Vec<u8>>,
+        end: Bound<Vec<u8>>,
+        limit: Option<usize>,
+    ) -> Box<dyn Future<Item = Vec<(Vec<u8>, Vec<u8>)>, Error = KvsError> + Send> {
+        scan_values(
+            &self.thread_pool,
+            &self.reader_pool,
+            &self.index,
+            (start, end, limit),
+            LATEST_SEQ,
+        )
+    }
+
+    /// Gets the keys starting with the given prefix, in order.
     ///
-    /// It returns `KvsError::KeyNotFound` if the given key is not found.
+    /// The keys are looked up in the index one by one as the stream is polled.
+    fn keys_bytes(
+        &self,
+        prefix: Vec<u8>,
+    ) -> Box<dyn Stream<Item = Vec<u8>, Error = KvsError> + Send> {
+        Box::new(stream::iter_ok(PrefixKeys {
+            index: self.index.clone(),
+            start: Included(prefix.clone()),
+            prefix,
+        }))
+    }
+}
+
+impl<P: ThreadPool> ReplicationSource for KvStore<P> {
+    /// Reads the records logged from the given position on, up to the end of the
+    /// logs or about 256 KiB of records.
     ///
-    /// It propagates I/O or serialization errors during writing the log.
-    fn remove(&self, key: String) -> Box<dyn Future<Item = (), Error = KvsError> + Send> {
+    /// If the log of the position has been removed by a compaction, the replica is
+    /// told to copy the store and to follow the logs from their current end.
+    fn read_log(
+        &self,
+        from: LogPosition,
+    ) -> Box<dyn Future<Item = LogChunk, Error = KvsError> + Send> {
+        let path = self.path.clone();
+        let vfs = self.vfs.clone();
         let writer = self.writer.clone();
         let (tx, rx) = oneshot::channel();
         self.thread_pool.spawn(move || {
-            let res = writer.lock().unwrap().remove(key);
+            let res = read_log_records(&*vfs, &path, from).map(|records| match records {
+                Some((records, next)) => LogChunk::Records { records, next },
+                None => {
+                    let writer = writer.lock().unwrap();
+                    let next = LogPosition {
+                        gen: writer.current_gen,
+                        offset: writer.writer.pos,
+                    };
+                    LogChunk::Compacted { next }
+                }
+            });
+            if tx.send(res).is_err() {
+                error!("Receiving end is dropped");
+            }
+        });
+        Box::new(
+            rx.map_err(|e| KvsError::StringError(format!("{}", e)))
+                .flatten(),
+        )
+    }
+
+    /// Gets up to `limit` pairs with keys from `start` on as `LogRecord::Set`s, in
+    /// key order, with the expiry deadlines of the keys.
+    fn scan_records(
+        &self,
+        start: Bound<Vec<u8>>,
+        limit: usize,
+    ) -> Box<dyn Future<Item = Vec<LogRecord>, Error = KvsError> + Send> {
+        let reader_pool = self.reader_pool.clone();
+        let index = self.index.clone();
+        let (tx, rx) = oneshot::channel();
+        self.thread_pool.spawn(move || {
+            let reader = reader_pool.pop();
+            let res = (|| {
+                let mut records = Vec::new();
+                for entry in index.range((start, Unbounded)) {
+                    if records.len() >= limit {
+                        break;
+                    }
+                    let key = entry.key();
+                    if let Some((value, expires_at)) = read_entry(&reader, &index, key, LATEST_SEQ)?
+                    {
+                        records.push(LogRecord::Set {
+                            key: key.clone(),
+                            value,
+                            expires_at,
+                        });
+                    }
+                }
+                Ok(records)
+            })();
+            reader_pool.push(reader);
             if tx.send(res).is_err() {
                 error!("Receiving end is dropped");
             }
@@ -194,41 +883,407 @@ impl<P: ThreadPool> KvsEngine for KvStore<P> {
     }
 }
 
-/// A single thread reader.
+impl<P: ThreadPool> ChangeFeed for KvStore<P> {
+    /// Streams the writes committed after the given sequence number, then the
+    /// following writes as they are committed.
+    ///
+    /// The writes up to the last committed one are read from the logs, one log at a
+    /// time, skipping the compaction logs. The following ones are sent by the writer
+    /// as it commits them, and are buffered in memory until the stream is polled.
+    ///
+    /// # Errors
+    ///
+    /// The stream fails with `KvsError::ChangesCompacted` if some of the writes to
+    /// read from the logs have been compacted away.
+    fn subscribe(&self, from_seq: u64) -> ChangeStream {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        let (gen_list, last_seq) = {
+            let mut writer = self.writer.lock().unwrap();
+            // listed while no write can be committed, so the writes up to `last_seq`
+            // are in the listed logs and the later ones are sent to the subscriber
+            let gen_list = match sorted_gen_list(&*self.vfs, &self.path) {
+                Ok(gen_list) => gen_list,
+                Err(e) => return Box::new(stream::once(Err(e))),
+            };
+            writer.subscribers.push(sender);
+            (gen_list, writer.seq)
+        };
+        let committed: ChangeStream = Box::new(
+            receiver
+                .map(stream::iter_ok)
+                .map_err(|e| KvsError::StringError(format!("{}", e)))
+                .flatten(),
+        );
+
+        let path = self.path.clone();
+        let vfs = self.vfs.clone();
+        let thread_pool = self.thread_pool.clone();
+        // Each step reads the changes of a log, from the sequence number of the last
+        // record read, and the last step hands over to the committed writes.
+        let steps = stream::unfold(
+            Some((gen_list.into_iter(), 0, committed)),
+            move |state| -> Option<Box<dyn Future<Item = _, Error = _> + Send>> {
+                let (mut gen_list, read, committed) = state?;
+                let seq = read.max(from_seq);
+                if seq >= last_seq {
+                    return Some(Box::new(future::ok((committed, None))));
+                }
+                let gen = match gen_list.next() {
+                    Some(gen) => gen,
+                    None => return Some(Box::new(future::err(KvsError::ChangesCompacted(seq)))),
+                };
+                let path = path.clone();
+                let vfs = vfs.clone();
+                let (tx, rx) = oneshot::channel();
+                thread_pool.spawn(move || {
+                    let res = read_changes(&*vfs, &path, gen, from_seq, read, last_seq);
+                    if tx.send(res).is_err() {
+                        error!("Receiving end is dropped");
+                    }
+                });
+                Some(Box::new(
+                    rx.map_err(|e| KvsError::StringError(format!("{}", e)))
+                        .flatten()
+                        .map(move |(changes, read)| {
+                            let changes: ChangeStream = Box::new(stream::iter_ok(changes));
+                            (changes, Some((gen_list, read, committed)))
+                        }),
+                ))
+            },
+        );
+        Box::new(steps.flatten())
+    }
+}
+
+/// Iterator over the keys with a prefix in the index, skipping expired ones.
 ///
-/// Each `KvStore` instance has its own `KvStoreReader` and
-/// `KvStoreReader`s open the same files separately. So the user
-/// can read concurrently through multiple `KvStore`s in different
-/// threads.
-struct KvStoreReader {
-    path: Arc<PathBuf>,
-    // generation of the latest compaction file
-    safe_point: Arc<AtomicU64>,
-    readers: RefCell<BTreeMap<u64, BufReaderWithPos<File>>>,
+/// Each key is looked up after the previous one, so keys set or removed during the
+/// iteration may or may not be seen.
+struct PrefixKeys {
+    index: Arc<SkipMap<Vec<u8>, Version>>,
+    prefix: Vec<u8>,
+    start: Bound<Vec<u8>>,
 }
 
-impl KvStoreReader {
-    /// Close file handles with generation number less than safe_point.
-    ///
-    /// `safe_point` is updated to the latest compaction gen after a compaction finishes.
-    /// The compaction generation contains the sum of all operations before it and the
-    /// in-memory index contains no entries with generation number less than safe_point.
-    /// So we can safely close those file handles and the stale files can be deleted.
-    fn close_stale_handles(&self) {
-        let mut readers = self.readers.borrow_mut();
-        while !readers.is_empty() {
-            let first_gen = *readers.keys().next().unwrap();
-            if self.safe_point.load(Ordering::SeqCst) <= first_gen {
-                break;
+impl Iterator for PrefixKeys {
+    type Item = Vec<u8>;
+
+    fn next(&mut self) -> Option<Vec<u8>> {
+        loop {
+            let entry = self.index.range((self.start.clone(), Unbounded)).next()?;
+            if !entry.key().starts_with(&self.prefix) {
+                return None;
+            }
+            self.start = Excluded(entry.key().clone());
+            let visible = entry.value().cmd_pos.map_or(false, |cmd_pos| {
+                !is_expired(cmd_pos.expires_at, now_millis())
+            });
+            if visible {
+                return Some(entry.key().clone());
             }
-            readers.remove(&first_gen);
+        }
+    }
+}
+
+/// Duplicates the error of a failed group commit for each write of the group.
+fn batch_error(e: &KvsError) -> KvsError {
+    match e {
+        KvsError::Io(e) => KvsError::Io(io::Error::new(e.kind(), e.to_string())),
+        e => KvsError::StringError(e.to_string()),
+    }
+}
+
+/// A read-only view of a `KvStore` pinned to a sequence number, taken by
+/// `KvStore::snapshot`.
+///
+/// ```rust
+/// # use kvs::{KvStore, KvsEngine, Result};
+/// # use kvs::thread_pool::RayonThreadPool;
+/// # use tokio::prelude::*;
+/// # fn try_main() -> Result<()> {
+/// use std::env::current_dir;
+/// let store: KvStore<RayonThreadPool> = KvStore::open(current_dir()?, 2)?;
+/// store.set("key".to_owned(), "old".to_owned()).wait()?;
+/// let snapshot = store.snapshot();
+/// store.set("key".to_owned(), "new".to_owned()).wait()?;
+/// assert_eq!(snapshot.get("key".to_owned()).wait()?, Some("old".to_owned()));
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone)]
+pub struct Snapshot<P: ThreadPool> {
+    seq: u64,
+    index: Arc<SkipMap<Vec<u8>, Version>>,
+    thread_pool: P,
+    reader_pool: Arc<ReaderPool>,
+    // releases the snapshot when the last clone is dropped
+    _handle: Arc<SnapshotHandle>,
+}
+
+impl<P: ThreadPool> Snapshot<P> {
+    /// Returns the sequence number of the last write seen by the snapshot.
+    pub fn seq(&self) -> u64 {
+        self.seq
+    }
+
+    /// Gets the value of a given key as of the snapshot.
+    ///
+    /// Returns `None` if the given key did not exist or has expired since.
+    pub fn get_bytes(
+        &self,
+        key: Vec<u8>,
+    ) -> Box<dyn Future<Item = Option<Vec<u8>>, Error = KvsError> + Send> {
+        let reader_pool = self.reader_pool.clone();
+        let index = self.index.clone();
+        let seq = self.seq;
+        let (tx, rx) = oneshot::channel();
+        self.thread_pool.spawn(move || {
+            let reader = reader_pool.pop();
+            let res = read_value(&reader, &index, &key, seq);
+            reader_pool.push(reader);
+            if tx.send(res).is_err() {
+                error!("Receiving end is dropped");
+            }
+        });
+        Box::new(
+            rx.map_err(|e| KvsError::StringError(format!("{}", e)))
+                .flatten(),
+        )
+    }
+
+    /// Gets the key-value pairs with keys within the given bounds as of the
+    /// snapshot, in key order.
+    ///
+    /// At most `limit` pairs are returned if it is given.
+    #[allow(clippy::type_complexity)]
+    pub fn scan_bytes(
+        &self,
+        start: Bound<Vec<u8>>,
+        end: Bound<Vec<u8>>,
+        limit: Option<usize>,
+    ) -> Box<dyn Future<Item = Vec<(Vec<u8>, Vec<u8>)>, Error = KvsError> + Send> {
+        scan_values(
+            &self.thread_pool,
+            &self.reader_pool,
+            &self.index,
+            (start, end, limit),
+            self.seq,
+        )
+    }
+
+    /// Gets the string value of a given string key as of the snapshot.
+    pub fn get(
+        &self,
+        key: String,
+    ) -> Box<dyn Future<Item = Option<String>, Error = KvsError> + Send> {
+        Box::new(
+            self.get_bytes(key.into_bytes())
+                .and_then(|value| Ok(value.map(String::from_utf8).transpose()?)),
+        )
+    }
+
+    /// Gets the pairs of string keys within the given bounds and their values as of
+    /// the snapshot, in key order.
+    pub fn scan(
+        &self,
+        start: Bound<String>,
+        end: Bound<String>,
+        limit: Option<usize>,
+    ) -> Box<dyn Future<Item = Vec<(String, String)>, Error = KvsError> + Send> {
+        Box::new(
+            self.scan_bytes(bound_into_bytes(start), bound_into_bytes(end), limit)
+                .and_then(|pairs| {
+                    pairs
+                        .into_iter()
+                        .map(pair_into_string)
+                        .collect::<Result<_>>()
+                }),
+        )
+    }
+}
+
+/// Releases a snapshot when the last clone of it is dropped.
+struct SnapshotHandle {
+    seq: u64,
+    writer: Arc<Mutex<KvStoreWriter>>,
+}
+
+impl Drop for SnapshotHandle {
+    fn drop(&mut self) {
+        self.writer.lock().unwrap().release_snapshot(self.seq);
+    }
+}
+
+/// Reads the values of the keys within the bounds visible at the sequence number in
+/// the thread pool.
+#[allow(clippy::type_complexity)]
+fn scan_values<P: ThreadPool>(
+    thread_pool: &P,
+    reader_pool: &Arc<ReaderPool>,
+    index: &Arc<SkipMap<Vec<u8>, Version>>,
+    (start, end, limit): (Bound<Vec<u8>>, Bound<Vec<u8>>, Option<usize>),
+    seq: u64,
+) -> Box<dyn Future<Item = Vec<(Vec<u8>, Vec<u8>)>, Error = KvsError> + Send> {
+    let reader_pool = reader_pool.clone();
+    let index = index.clone();
+    let (tx, rx) = oneshot::channel();
+    thread_pool.spawn(move || {
+        let reader = reader_pool.pop();
+        let res = (|| {
+            let mut pairs = Vec::new();
+            for entry in index.range((start, end)) {
+                if limit.map_or(false, |limit| pairs.len() >= limit) {
+                    break;
+                }
+                if let Some(value) = read_value(&reader, &index, entry.key(), seq)? {
+                    pairs.push((entry.key().clone(), value));
+                }
+            }
+            Ok(pairs)
+        })();
+        reader_pool.push(reader);
+        if tx.send(res).is_err() {
+            error!("Receiving end is dropped");
+        }
+    });
+    Box::new(
+        rx.map_err(|e| KvsError::StringError(format!("{}", e)))
+            .flatten(),
+    )
+}
+
+/// Reads the value of the key visible at the sequence number.
+///
+/// Returns `None` if the key does not exist or has expired.
+fn read_value(
+    reader: &KvStoreReader,
+    index: &SkipMap<Vec<u8>, Version>,
+    key: &[u8],
+    seq: u64,
+) -> Result<Option<Vec<u8>>> {
+    Ok(read_entry(reader, index, key, seq)?.map(|(value, _)| value))
+}
+
+/// Reads the value of the key visible at the sequence number and its expiry
+/// deadline.
+///
+/// Returns `None` if the key does not exist or has expired.
+fn read_entry(
+    reader: &KvStoreReader,
+    index: &SkipMap<Vec<u8>, Version>,
+    key: &[u8],
+    seq: u64,
+) -> Result<Option<(Vec<u8>, Option<u64>)>> {
+    loop {
+        let cmd_pos = match index.get(key).and_then(|entry| entry.value().at(seq)) {
+            Some(cmd_pos) => cmd_pos,
+            None => return Ok(None),
+        };
+        if is_expired(cmd_pos.expires_at, now_millis()) {
+            return Ok(None);
+        }
+        match reader.read_command(cmd_pos).map(|cmd| cmd.for_key(key)) {
+            Ok(Some(Command::Set {
+                value, expires_at, ..
+            })) => return Ok(Some((value, expires_at))),
+            Ok(_) => return Err(KvsError::UnexpectedCommandType),
+            // The log may be removed by a background compaction after we look up
+            // the position. Look up the key again in this case.
+            Err(KvsError::Io(ref e))
+                if e.kind() == io::ErrorKind::NotFound
+                    && index.get(key).and_then(|entry| entry.value().at(seq)) != Some(cmd_pos) =>
+            {
+                continue
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// The readers shared by the threads reading the logs.
+///
+/// There may be fewer readers than threads, in which case the threads block until
+/// a reader is returned.
+struct ReaderPool {
+    readers: ArrayQueue<KvStoreReader>,
+    // taken to wait for a reader, and to notify the waiters once one is returned
+    lock: Mutex<()>,
+    returned: Condvar,
+}
+
+impl ReaderPool {
+    fn new(capacity: u32) -> Self {
+        ReaderPool {
+            readers: ArrayQueue::new(capacity as usize),
+            lock: Mutex::new(()),
+            returned: Condvar::new(),
+        }
+    }
+
+    /// Takes a reader from the pool, waiting for one to be returned if all are in use.
+    fn pop(&self) -> KvStoreReader {
+        if let Ok(reader) = self.readers.pop() {
+            return reader;
+        }
+        let mut guard = self.lock.lock().unwrap();
+        loop {
+            // a reader returned before the lock was taken is found here, and one
+            // returned after it wakes this thread up
+            if let Ok(reader) = self.readers.pop() {
+                return reader;
+            }
+            guard = self.returned.wait(guard).unwrap();
+        }
+    }
+
+    /// Returns a reader to the pool.
+    fn push(&self, reader: KvStoreReader) {
+        self.readers.push(reader).unwrap();
+        let _guard = self.lock.lock().unwrap();
+        self.returned.notify_one();
+    }
+}
+
+/// A single thread reader.
+///
+/// Each `KvStore` instance has its own `KvStoreReader` and
+/// `KvStoreReader`s open the same files separately. So the user
+/// can read concurrently through multiple `KvStore`s in different
+/// threads.
+struct KvStoreReader {
+    path: Arc<PathBuf>,
+    vfs: Arc<dyn Vfs>,
+    // generation of the latest compaction file
+    safe_point: Arc<AtomicU64>,
+    readers: RefCell<BTreeMap<u64, (LogFormat, BufReaderWithPos<VfsReader>)>>,
+}
+
+impl KvStoreReader {
+    /// Close file handles with generation number less than safe_point.
+    ///
+    /// `safe_point` is updated to the latest compaction gen after a compaction finishes.
+    /// The compaction generation contains the sum of all operations before it and the
+    /// in-memory index contains no entries with generation number less than safe_point.
+    /// So we can safely close those file handles and the stale files can be deleted.
+    /// Only older versions read through snapshots still point to the stale files,
+    /// which are kept and reopened for them until the snapshots are dropped.
+    fn close_stale_handles(&self) {
+        let mut readers = self.readers.borrow_mut();
+        while !readers.is_empty() {
+            let first_gen = *readers.keys().next().unwrap();
+            if self.safe_point.load(Ordering::SeqCst) <= first_gen {
+                break;
+            }
+            readers.remove(&first_gen);
         }
     }
 
     /// Read the log file at the given `CommandPos`.
+    ///
+    /// `f` is also given the format of the log.
     fn read_and<F, R>(&self, cmd_pos: CommandPos, f: F) -> Result<R>
     where
-        F: FnOnce(io::Take<&mut BufReaderWithPos<File>>) -> Result<R>,
+        F: FnOnce(LogFormat, io::Take<&mut BufReaderWithPos<VfsReader>>) -> Result<R>,
     {
         self.close_stale_handles();
 
@@ -236,19 +1291,27 @@ impl KvStoreReader {
         // Open the file if we haven't opened it in this `KvStoreReader`.
         // We don't use entry API here because we want the errors to be propogated.
         if !readers.contains_key(&cmd_pos.gen) {
-            let reader = BufReaderWithPos::new(File::open(log_path(&self.path, cmd_pos.gen))?)?;
-            readers.insert(cmd_pos.gen, reader);
+            let mut reader = open_log(&*self.vfs, &self.path, cmd_pos.gen)?;
+            let format = read_log_format(&mut reader)?;
+            readers.insert(cmd_pos.gen, (format, reader));
         }
-        let reader = readers.get_mut(&cmd_pos.gen).unwrap();
+        let (format, reader) = readers.get_mut(&cmd_pos.gen).unwrap();
         reader.seek(SeekFrom::Start(cmd_pos.pos))?;
         let cmd_reader = reader.take(cmd_pos.len);
-        f(cmd_reader)
+        f(*format, cmd_reader)
     }
 
-    // Read the log file at the given `CommandPos` and deserialize it to `Command`.
+    // Read the log file at the given `CommandPos`, verify its checksum and
+    // deserialize it to `Command`.
     fn read_command(&self, cmd_pos: CommandPos) -> Result<Command> {
-        self.read_and(cmd_pos, |cmd_reader| {
-            Ok(serde_json::from_reader(cmd_reader)?)
+        self.read_and(cmd_pos, |format, mut cmd_reader| {
+            let mut record = Vec::with_capacity(cmd_pos.len as usize);
+            cmd_reader.read_to_end(&mut record)?;
+            let payload = decode_record(&record).ok_or(KvsError::Corruption {
+                gen: cmd_pos.gen,
+                offset: cmd_pos.pos,
+            })?;
+            Ok(format.decode(payload)?.1)
         })
     }
 }
@@ -257,6 +1320,7 @@ impl Clone for KvStoreReader {
     fn clone(&self) -> KvStoreReader {
         KvStoreReader {
             path: Arc::clone(&self.path),
+            vfs: Arc::clone(&self.vfs),
             safe_point: Arc::clone(&self.safe_point),
             // don't use other KvStoreReader's readers
             readers: RefCell::new(BTreeMap::new()),
@@ -264,129 +1328,730 @@ impl Clone for KvStoreReader {
     }
 }
 
+/// A write waiting in the queue to be committed.
+struct PendingWrite {
+    cmd: Command,
+    // receives the result once the write is committed
+    done: oneshot::Sender<Result<()>>,
+}
+
 struct KvStoreWriter {
-    reader: KvStoreReader,
-    writer: BufWriterWithPos<File>,
+    writer: BufWriterWithPos<VfsWriter>,
     current_gen: u64,
+    // sequence number of the last committed command
+    seq: u64,
     // the number of bytes representing "stale" commands that could be
     // deleted during a compaction
     uncompacted: u64,
+    // the number of bytes of the commands in the index
+    live: u64,
+    // deadlines of the keys set with a time to live, which may be outdated by
+    // later writes
+    expiries: BTreeSet<(u64, Vec<u8>)>,
+    // sequence numbers of the live snapshots, with how many there are of each
+    snapshots: BTreeMap<u64, usize>,
+    // keys that have older versions in the index, including removed keys
+    versioned: HashSet<Vec<u8>>,
+    // compaction generations whose older logs are kept for the snapshots taken up
+    // to the sequence number
+    stale_logs: Vec<(u64, u64)>,
+    // generations of the logs listed in the manifest
+    live_gens: BTreeSet<u64>,
+    // whether a compaction has been requested and has not finished yet
+    compacting: bool,
+    // the lock of the directory, held as long as the store may write to it, or
+    // `None` if the store is read-only
+    lock: Option<Box<dyn VfsLock>>,
+    // the subscribers sent the changes of the writes as they are committed
+    subscribers: Vec<mpsc::UnboundedSender<Vec<ChangeEvent>>>,
+    // whether the active log ends with a torn group of writes, so that the following
+    // writes must go to a new log
+    torn: bool,
+    options: KvStoreOptions,
+    compactor: Sender<CompactorMsg>,
     path: Arc<PathBuf>,
-    index: Arc<SkipMap<String, CommandPos>>,
+    vfs: Arc<dyn Vfs>,
+    index: Arc<SkipMap<Vec<u8>, Version>>,
 }
 
 impl KvStoreWriter {
-    fn set(&mut self, key: String, value: String) -> Result<()> {
-        let cmd = Command::set(key, value);
-        let pos = self.writer.pos;
-        serde_json::to_writer(&mut self.writer, &cmd)?;
+    /// Commits all writes in the queue as a group.
+    ///
+    /// The queue may turn out empty if the writes were already committed by another
+    /// writer.
+    fn commit_queued(&mut self, queue: &SegQueue<PendingWrite>) {
+        let mut batch = Vec::new();
+        while let Ok(write) = queue.pop() {
+            batch.push(write);
+        }
+        if !batch.is_empty() {
+            self.commit(batch);
+        }
+    }
+
+    /// Commits the writes as a group.
+    ///
+    /// The commands are appended to the log with a single flush, and a single sync
+    /// if the durability is `GroupCommit`. Then the index is updated and every
+    /// writer is sent its result.
+    ///
+    /// If the group fails to be appended, none of its writes is committed and what
+    /// was written of it is dropped from the log.
+    fn commit(&mut self, mut batch: Vec<PendingWrite>) {
+        if self.is_read_only() {
+            for write in batch {
+                if write.done.send(Err(KvsError::ReadOnly)).is_err() {
+                    error!("Receiving end is dropped");
+                }
+            }
+            return;
+        }
+        let mut positions = Vec::with_capacity(batch.len());
+        let (seq, pos) = (self.seq, self.writer.pos);
+        if let Err(e) = self.append(&mut batch, &mut positions) {
+            self.drop_failed_group(seq, pos);
+            for write in batch {
+                if write.done.send(Err(batch_error(&e))).is_err() {
+                    error!("Receiving end is dropped");
+                }
+            }
+            return;
+        }
+
+        let mut changes = Vec::new();
+        if !self.subscribers.is_empty() {
+            for (write, pos) in batch.iter().zip(&positions) {
+                if let Some((seq, _)) = pos {
+                    write.cmd.push_changes(*seq, &mut changes);
+                }
+            }
+        }
+
+        for (write, pos) in batch.into_iter().zip(positions) {
+            let res = match (write.cmd, pos) {
+                (Command::Batch(cmds), Some((seq, pos))) => {
+                    let cmd_pos = CommandPos::from((self.current_gen, pos)).in_batch(&cmds);
+                    self.apply(cmds, seq, cmd_pos);
+                    Ok(())
+                }
+                (cmd, Some((seq, pos))) => {
+                    self.apply(vec![cmd], seq, CommandPos::from((self.current_gen, pos)));
+                    Ok(())
+                }
+                // nothing is left of a batch that only removes missing keys
+                (Command::Batch(_), None) => Ok(()),
+                (_, None) => Err(KvsError::KeyNotFound),
+            };
+            if write.done.send(res).is_err() {
+                error!("Receiving end is dropped");
+            }
+        }
+
+        if !changes.is_empty() {
+            // subscribers whose stream is dropped are dropped too
+            self.subscribers
+                .retain_mut(|subscriber| subscriber.try_send(changes.clone()).is_ok());
+        }
+
+        // The writes are committed whatever happens here.
+        if let Err(e) = self.after_write() {
+            error!("Failed to rotate the log: {}", e);
+        }
+    }
+
+    /// Drops the group that failed to be appended from the position of the log it
+    /// started at, and reuses its sequence numbers.
+    ///
+    /// If the log cannot be truncated, the following writes go to a new log instead,
+    /// so that no record ever follows a torn one.
+    fn drop_failed_group(&mut self, seq: u64, pos: u64) {
+        self.seq = seq;
+        self.writer.discard_buffer(pos);
+        let path = log_path(&self.path, self.current_gen);
+        if let Err(e) = self.vfs.truncate(&path, pos) {
+            error!("Failed to truncate {:?} after a failed write: {}", path, e);
+            self.torn = true;
+        }
+    }
+
+    /// Applies the commands of a committed record at `cmd_pos` to the index.
+    fn apply(&mut self, cmds: Vec<Command>, seq: u64, cmd_pos: CommandPos) {
+        let mut referenced = false;
+        for cmd in cmds {
+            match cmd {
+                Command::Set {
+                    key, expires_at, ..
+                } => {
+                    if let Some(old_cmd) = self.index.get(&key) {
+                        self.uncompacted += old_cmd.value().size();
+                        self.live -= old_cmd.value().size();
+                    }
+                    let cmd_pos = cmd_pos.expiring_at(expires_at);
+                    self.live += cmd_pos.size();
+                    if let Some(expires_at) = expires_at {
+                        self.expiries.insert((expires_at, key.clone()));
+                    }
+                    self.put_version(key, seq, Some(cmd_pos));
+                    referenced = true;
+                }
+                Command::Remove { key } => {
+                    if let Some(old_cmd) = self.index.get(&key) {
+                        self.uncompacted += old_cmd.value().size();
+                        self.live -= old_cmd.value().size();
+                    }
+                    self.put_version(key, seq, None);
+                }
+                Command::Batch(_) => unreachable!("batches are never nested"),
+            }
+        }
+        if !referenced {
+            // a record that only removes keys can be deleted in the next compaction
+            // so we add its length to `uncompacted`
+            self.uncompacted += cmd_pos.len;
+        }
+    }
+
+    /// Replaces the version of the key in the index, keeping the older versions that
+    /// live snapshots may read.
+    fn put_version(&mut self, key: Vec<u8>, seq: u64, cmd_pos: Option<CommandPos>) {
+        let older = if self.snapshots.is_empty() {
+            None
+        } else {
+            let current = self
+                .index
+                .get(&key)
+                .map(|entry| Arc::new(entry.value().clone()));
+            retain_versions(current.as_ref(), seq, &self.snapshots)
+        };
+        self.set_version(
+            key,
+            Version {
+                seq,
+                cmd_pos,
+                older,
+            },
+        );
+    }
+
+    /// Stores the version of the key in the index, or removes the key if it was
+    /// removed and no older version is kept.
+    fn set_version(&mut self, key: Vec<u8>, version: Version) {
+        if version.older.is_some() {
+            self.versioned.insert(key.clone());
+        } else {
+            self.versioned.remove(&key);
+            if version.cmd_pos.is_none() {
+                self.index.remove(&key);
+                return;
+            }
+        }
+        self.index.insert(key, version);
+    }
+
+    /// Registers a snapshot of the last committed write and returns its sequence
+    /// number.
+    fn pin_snapshot(&mut self) -> u64 {
+        *self.snapshots.entry(self.seq).or_insert(0) += 1;
+        self.seq
+    }
+
+    /// Unregisters a snapshot, then drops the versions and removes the logs no live
+    /// snapshot needs anymore.
+    fn release_snapshot(&mut self, seq: u64) {
+        if let Some(count) = self.snapshots.get_mut(&seq) {
+            *count -= 1;
+            if *count == 0 {
+                self.snapshots.remove(&seq);
+            }
+        }
+
+        let keys: Vec<_> = self.versioned.iter().cloned().collect();
+        for key in keys {
+            let version = match self.index.get(&key) {
+                Some(entry) => entry.value().clone(),
+                None => continue,
+            };
+            let older = retain_versions(version.older.as_ref(), version.seq, &self.snapshots);
+            self.set_version(key, Version { older, ..version });
+        }
+
+        while let Some(&(seq, compaction_gen)) = self.stale_logs.first() {
+            if self.snapshots.range(..=seq).next().is_some() {
+                break;
+            }
+            self.stale_logs.remove(0);
+            if let Err(e) = remove_stale_logs(&*self.vfs, &self.path, compaction_gen) {
+                error!("Failed to remove stale logs: {}", e);
+            }
+        }
+    }
+
+    /// Appends the commands of the group to the log and makes them as durable as
+    /// required.
+    ///
+    /// Removes of keys that don't exist by then are dropped from batches. The
+    /// sequence number and position of each command are pushed to `positions`, or
+    /// `None` if nothing of it is left to write.
+    fn append(
+        &mut self,
+        batch: &mut [PendingWrite],
+        positions: &mut Vec<Option<(u64, Range<u64>)>>,
+    ) -> Result<()> {
+        if self.torn {
+            self.switch_log(self.current_gen + 1)?;
+            self.torn = false;
+        }
+        // whether keys exist after the earlier writes of the group
+        let mut exists = HashMap::new();
+        let now = now_millis();
+        for write in batch {
+            let index = &self.index;
+            let found = match &mut write.cmd {
+                Command::Batch(cmds) => {
+                    cmds.retain(|cmd| track_write(index, &mut exists, cmd, now));
+                    !cmds.is_empty()
+                }
+                cmd => track_write(index, &mut exists, cmd, now),
+            };
+            if !found {
+                positions.push(None);
+                continue;
+            }
+            let pos = self.writer.pos;
+            self.seq += 1;
+            write_record(&mut self.writer, &(self.seq, &write.cmd))?;
+            if self.options.durability == Durability::EveryWrite {
+                self.writer.sync_data()?;
+            }
+            positions.push(Some((self.seq, pos..self.writer.pos)));
+        }
         self.writer.flush()?;
-        if let Command::Set { key, .. } = cmd {
-            if let Some(old_cmd) = self.index.get(&key) {
-                self.uncompacted += old_cmd.value().len;
+        if self.options.durability == Durability::GroupCommit {
+            self.writer.sync_data()?;
+        }
+        Ok(())
+    }
+
+    /// Removes the keys that have expired from the index.
+    ///
+    /// Nothing is written to the log: expired commands are dropped by the next
+    /// compaction, and skipped when the logs are loaded again.
+    fn sweep_expired(&mut self) {
+        let now = now_millis();
+        while let Some((expires_at, key)) = self.expiries.iter().next().cloned() {
+            if expires_at > now {
+                break;
+            }
+            self.expiries.remove(&(expires_at, key.clone()));
+            // the key may have been set again since
+            let expired = current_pos(&self.index, &key)
+                .map_or(false, |cmd_pos| cmd_pos.expires_at == Some(expires_at));
+            if expired {
+                let size = self.index.get(&key).expect("key not found").value().size();
+                self.uncompacted += size;
+                self.live -= size;
+                self.put_version(key, self.seq, None);
             }
-            self.index
-                .insert(key, (self.current_gen, pos..self.writer.pos).into());
         }
 
-        if self.uncompacted > COMPACTION_THRESHOLD {
-            self.compact()?;
+        if let Err(e) = self.after_write() {
+            error!("Failed to rotate the log: {}", e);
         }
+    }
+
+    /// Returns a handle to sync the active log. Writes to older logs are already
+    /// synced.
+    fn sync_handle(&self) -> Result<Arc<dyn VfsFile>> {
+        Ok(self.writer.file())
+    }
+
+    /// Switches to a new active log.
+    ///
+    /// The previous log is synced first, even if the durability is `None`, so that
+    /// only the active log can end with a torn write. The new log is added to the
+    /// manifest before any write goes to it.
+    fn switch_log(&mut self, gen: u64) -> Result<()> {
+        let writer = new_log_file(&*self.vfs, &self.path, gen)?;
+        self.writer.sync_data()?;
+        let mut live_gens = self.live_gens.clone();
+        live_gens.insert(gen);
+        self.save_manifest(live_gens, gen)?;
+        self.writer = writer;
+        self.current_gen = gen;
         Ok(())
     }
 
-    fn remove(&mut self, key: String) -> Result<()> {
-        if self.index.contains_key(&key) {
-            let cmd = Command::remove(key);
-            let pos = self.writer.pos;
-            serde_json::to_writer(&mut self.writer, &cmd)?;
-            self.writer.flush()?;
-            if let Command::Remove { key } = cmd {
-                let old_cmd = self.index.remove(&key).expect("key not found");
-                self.uncompacted += old_cmd.value().len;
-                // the "remove" command itself can be deleted in the next compaction
-                // so we add its length to `uncompacted`
-                self.uncompacted += self.writer.pos - pos;
+    /// Saves the manifest listing the given logs, which become the live ones.
+    ///
+    /// If the manifest fails to be saved, the live logs stay as they were. The files
+    /// of the given logs must not be removed then, as the new manifest may still
+    /// have replaced the old one.
+    fn save_manifest(&mut self, live_gens: BTreeSet<u64>, active_gen: u64) -> Result<()> {
+        Manifest::new(&live_gens, active_gen).save(&*self.vfs, &self.path)?;
+        self.live_gens = live_gens;
+        Ok(())
+    }
+
+    /// Rotates the active log if it is full and triggers a compaction if there are
+    /// enough stale commands.
+    fn after_write(&mut self) -> Result<()> {
+        if self.is_read_only() {
+            return Ok(());
+        }
+        if let Some(max_log_size) = self.options.max_log_size {
+            if self.writer.pos >= max_log_size {
+                self.switch_log(self.current_gen + 1)?;
             }
+        }
+
+        let options = &self.options;
+        if options.auto_compaction
+            && self.uncompacted > options.compaction_threshold
+            && self.uncompacted as f64 > self.live as f64 * options.compaction_ratio
+        {
+            self.request_compaction(None);
+        }
+        Ok(())
+    }
 
-            if self.uncompacted > COMPACTION_THRESHOLD {
-                self.compact()?;
+    /// Asks the background compactor to compact the logs.
+    ///
+    /// Without `done`, nothing is requested if a compaction is already on its way.
+    /// Otherwise, the result of the compaction is sent to `done`.
+    fn request_compaction(&mut self, done: Option<oneshot::Sender<Result<()>>>) {
+        if self.is_read_only() {
+            if let Some(done) = done {
+                if done.send(Err(KvsError::ReadOnly)).is_err() {
+                    error!("Receiving end is dropped");
+                }
+            }
+            return;
+        }
+        if !self.compacting || done.is_some() {
+            self.compacting = true;
+            if self.compactor.send(CompactorMsg::Compact(done)).is_err() {
+                error!("Compactor is stopped");
             }
-            Ok(())
-        } else {
-            Err(KvsError::KeyNotFound)
         }
     }
 
-    /// Clears stale entries in the log.
-    fn compact(&mut self) -> Result<()> {
+    /// Returns whether the store is opened read-only, which never writes to the logs.
+    fn is_read_only(&self) -> bool {
+        self.lock.is_none()
+    }
+
+    /// Switches to a new log for the following writes and reserves a generation number
+    /// for the compaction log.
+    ///
+    /// All logs with a generation number less than the returned one are to be compacted.
+    /// Returns it with the number of stale bytes so far, which the compaction takes off
+    /// `uncompacted` once it commits.
+    fn rotate(&mut self) -> Result<(u64, u64)> {
         // increase current gen by 2. current_gen + 1 is for the compaction file
         let compaction_gen = self.current_gen + 1;
-        self.current_gen += 2;
-        self.writer = new_log_file(&self.path, self.current_gen)?;
+        self.switch_log(self.current_gen + 2)?;
+        Ok((compaction_gen, self.uncompacted))
+    }
+}
 
-        let mut compaction_writer = new_log_file(&self.path, compaction_gen)?;
+/// Returns the position of the latest command of the key, or `None` if the key
+/// does not exist.
+fn current_pos(index: &SkipMap<Vec<u8>, Version>, key: &[u8]) -> Option<CommandPos> {
+    index.get(key).and_then(|entry| entry.value().cmd_pos)
+}
 
-        let mut new_pos = 0; // pos in the new log file
+/// Records in `exists` whether the key of the command exists after it, given the
+/// earlier writes of the group.
+///
+/// Returns `false` if the command removes a key that doesn't exist.
+fn track_write(
+    index: &SkipMap<Vec<u8>, Version>,
+    exists: &mut HashMap<Vec<u8>, bool>,
+    cmd: &Command,
+    now: u64,
+) -> bool {
+    match cmd {
+        Command::Set { key, .. } => {
+            exists.insert(key.clone(), true);
+            true
+        }
+        Command::Remove { key } => {
+            let found = match exists.get(key) {
+                Some(&found) => found,
+                None => current_pos(index, key)
+                    .map_or(false, |cmd_pos| !is_expired(cmd_pos.expires_at, now)),
+            };
+            if found {
+                exists.insert(key.clone(), false);
+            }
+            found
+        }
+        Command::Batch(_) => unreachable!("batches are never nested"),
+    }
+}
+
+/// Message sent to the background compactor.
+enum CompactorMsg {
+    /// Compact the logs and send the result to the sender if any
+    Compact(Option<oneshot::Sender<Result<()>>>),
+    Shutdown,
+}
+
+/// The background worker that compacts the logs.
+///
+/// A compaction copies the live commands of all logs older than the active one into a
+/// new compaction log, while the writer keeps appending to the active log. The index
+/// entries are switched to the compaction log only if they haven't been changed during
+/// the copy.
+struct Compactor {
+    reader: KvStoreReader,
+    writer: Arc<Mutex<KvStoreWriter>>,
+    path: Arc<PathBuf>,
+    vfs: Arc<dyn Vfs>,
+    index: Arc<SkipMap<Vec<u8>, Version>>,
+}
+
+impl Compactor {
+    /// Runs compactions on request until the compactor is shut down.
+    fn run(self, receiver: Receiver<CompactorMsg>) {
+        for msg in receiver {
+            match msg {
+                CompactorMsg::Compact(done) => {
+                    let res = self.compact();
+                    if let Err(ref e) = res {
+                        error!("Compaction failed: {}", e);
+                    }
+                    self.writer.lock().unwrap().compacting = false;
+                    if let Some(done) = done {
+                        if done.send(res).is_err() {
+                            error!("Receiving end is dropped");
+                        }
+                    }
+                }
+                CompactorMsg::Shutdown => break,
+            }
+        }
+    }
+
+    /// Clears stale entries in the log.
+    fn compact(&self) -> Result<()> {
+        let (compaction_gen, rotated_uncompacted) = self.writer.lock().unwrap().rotate()?;
+
+        let mut compaction_writer = new_log_file(&*self.vfs, &self.path, compaction_gen)?;
+        let mut hint_writer = new_hint_file(&*self.vfs, &self.path, compaction_gen)?;
+
+        // the commands copied to the compaction log: key, sequence number, old and
+        // new position
+        let mut moved = Vec::new();
+        // the expired commands left out of the compaction log: key, sequence number
+        // and position
+        let mut expired = Vec::new();
+        let mut new_pos = compaction_writer.pos; // pos in the new log file
+        let now = now_millis();
         for entry in self.index.iter() {
-            let len = self.reader.read_and(*entry.value(), |mut entry_reader| {
-                Ok(io::copy(&mut entry_reader, &mut compaction_writer)?)
+            let seq = entry.value().seq;
+            let cmd_pos = match entry.value().cmd_pos {
+                Some(cmd_pos) => cmd_pos,
+                // removed keys are only kept in the index for snapshots
+                None => continue,
+            };
+            if cmd_pos.gen > compaction_gen {
+                // written to the active log after the rotation
+                continue;
+            }
+            if is_expired(cmd_pos.expires_at, now) {
+                expired.push((entry.key().clone(), seq, cmd_pos));
+                continue;
+            }
+            let len = self.reader.read_and(cmd_pos, |format, mut entry_reader| {
+                if format == LogFormat::Binary && !cmd_pos.is_batch() {
+                    Ok(io::copy(&mut entry_reader, &mut compaction_writer)?)
+                } else {
+                    // rewrite records of old-format logs in the current format, and
+                    // take the commands out of batches
+                    let mut record = Vec::with_capacity(cmd_pos.len as usize);
+                    entry_reader.read_to_end(&mut record)?;
+                    let payload = decode_record(&record).ok_or(KvsError::Corruption {
+                        gen: cmd_pos.gen,
+                        offset: cmd_pos.pos,
+                    })?;
+                    let cmd = format
+                        .decode(payload)?
+                        .1
+                        .for_key(entry.key())
+                        .ok_or(KvsError::UnexpectedCommandType)?;
+                    write_record(&mut compaction_writer, &(seq, &cmd))?;
+                    Ok(compaction_writer.pos - new_pos)
+                }
             })?;
-            self.index.insert(
-                entry.key().clone(),
-                (compaction_gen, new_pos..new_pos + len).into(),
-            );
+            let new_cmd_pos = CommandPos::from((compaction_gen, new_pos..new_pos + len))
+                .expiring_at(cmd_pos.expires_at);
+            let hint_entry = HintEntry::new(entry.key(), seq, new_cmd_pos);
+            write_record(&mut hint_writer, &hint_entry)?;
+            moved.push((entry.key().clone(), seq, cmd_pos, new_cmd_pos));
             new_pos += len;
         }
-        compaction_writer.flush()?;
+        // the hint must never point to log content that could still be lost
+        compaction_writer.sync_data()?;
+        commit_hint_file(&*self.vfs, &self.path, compaction_gen, hint_writer)?;
+
+        let snapshots_live = {
+            // The writer lock keeps the index from being changed while swapping.
+            let mut writer = self.writer.lock().unwrap();
+            // The compaction log replaces the older logs once the manifest lists it.
+            // Until then, a crash leaves it orphaned and the older logs are loaded.
+            let mut live_gens: BTreeSet<_> =
+                writer.live_gens.range(compaction_gen..).cloned().collect();
+            live_gens.insert(compaction_gen);
+            let active_gen = writer.current_gen;
+            writer.save_manifest(live_gens, active_gen)?;
+            // stale commands in the rotated logs are all dropped by this compaction
+            writer.uncompacted -= rotated_uncompacted;
+            for (key, seq, old_cmd_pos, new_cmd_pos) in moved {
+                let version = self
+                    .index
+                    .get(&key)
+                    .map(|entry| entry.value().clone())
+                    .filter(|version| version.seq == seq && version.cmd_pos == Some(old_cmd_pos));
+                if let Some(version) = version {
+                    // rewritten commands may change in length
+                    writer.live = writer.live + new_cmd_pos.size() - old_cmd_pos.size();
+                    let cmd_pos = Some(new_cmd_pos);
+                    self.index.insert(key, Version { cmd_pos, ..version });
+                }
+            }
+            for (key, seq, old_cmd_pos) in expired {
+                let unchanged = self.index.get(&key).map_or(false, |entry| {
+                    entry.value().seq == seq && entry.value().cmd_pos == Some(old_cmd_pos)
+                });
+                if unchanged {
+                    let last_seq = writer.seq;
+                    writer.put_version(key, last_seq, None);
+                    writer.live -= old_cmd_pos.size();
+                }
+            }
+            // older versions read by the live snapshots are still in the stale logs
+            if !writer.snapshots.is_empty() {
+                let last_seq = writer.seq;
+                writer.stale_logs.push((last_seq, compaction_gen));
+            }
+            !writer.snapshots.is_empty()
+        };
+
+        self.reader
+            .safe_point
+            .store(compaction_gen, Ordering::SeqCst);
+        self.reader.close_stale_handles();
+
+        if !snapshots_live {
+            remove_stale_logs(&*self.vfs, &self.path, compaction_gen)?;
+        }
+        Ok(())
+    }
+}
+
+/// Removes the logs and hint files older than the compaction generation.
+///
+/// Note that actually these files are not deleted immediately because `KvStoreReader`s
+/// still keep open file handles. When `KvStoreReader` is used next time, it will clear
+/// its stale file handles. On Unix, the files will be deleted after all the handles
+/// are closed. On Windows, the deletions below will fail and stale files are expected
+/// to be deleted in the next compaction.
+fn remove_stale_logs(vfs: &dyn Vfs, path: &Path, compaction_gen: u64) -> Result<()> {
+    let stale_gens = sorted_gen_list(vfs, path)?
+        .into_iter()
+        .filter(|&gen| gen < compaction_gen);
+    for stale_gen in stale_gens {
+        remove_log_files(vfs, path, stale_gen);
+    }
+    Ok(())
+}
 
-        self.reader
-            .safe_point
-            .store(compaction_gen, Ordering::SeqCst);
-        self.reader.close_stale_handles();
+/// Removes the log of the generation and its hint file, logging the files that
+/// cannot be deleted.
+fn remove_log_files(vfs: &dyn Vfs, path: &Path, gen: u64) {
+    let file_path = log_path(path, gen);
+    if let Err(e) = vfs.remove(&file_path) {
+        error!("{:?} cannot be deleted: {}", file_path, e);
+    }
+    // a failed compaction may have left its hint file uncommitted
+    for hint_path in &[hint_path(path, gen), hint_tmp_path(path, gen)] {
+        match vfs.remove(hint_path) {
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => error!("{:?} cannot be deleted: {}", hint_path, e),
+            Ok(()) => {}
+        }
+    }
+}
 
-        // remove stale log files
-        // Note that actually these files are not deleted immediately because `KvStoreReader`s
-        // still keep open file handles. When `KvStoreReader` is used next time, it will clear
-        // its stale file handles. On Unix, the files will be deleted after all the handles
-        // are closed. On Windows, the deletions below will fail and stale files are expected
-        // to be deleted in the next compaction.
+/// Handle to the background compactor thread, shared by all clones of a `KvStore`.
+///
+/// Dropping it stops the thread after the running compaction finishes.
+struct CompactorHandle {
+    sender: Sender<CompactorMsg>,
+    thread: Option<JoinHandle<()>>,
+}
 
-        let stale_gens = sorted_gen_list(&self.path)?
-            .into_iter()
-            .filter(|&gen| gen < compaction_gen);
-        for stale_gen in stale_gens {
-            let file_path = log_path(&self.path, stale_gen);
-            if let Err(e) = fs::remove_file(&file_path) {
-                error!("{:?} cannot be deleted: {}", file_path, e);
+impl Drop for CompactorHandle {
+    fn drop(&mut self) {
+        if self.sender.send(CompactorMsg::Shutdown).is_err() {
+            error!("Compactor is stopped");
+        }
+        if let Some(thread) = self.thread.take() {
+            if thread.join().is_err() {
+                error!("Compactor thread panicked");
             }
         }
-        self.uncompacted = 0;
+    }
+}
 
-        Ok(())
+/// Handle to the lock of the directory, shared by all clones of a `KvStore` opened
+/// for writing.
+///
+/// Dropping it commits the writes still queued and releases the lock at once, so
+/// the store can be opened again right away. Compare-and-swaps and transactions
+/// yet to run in the thread pool then fail with `KvsError::ReadOnly` rather than
+/// write to logs another store may have taken.
+struct LockHandle {
+    writer: Arc<Mutex<KvStoreWriter>>,
+    queue: Arc<SegQueue<PendingWrite>>,
+}
+
+impl Drop for LockHandle {
+    fn drop(&mut self) {
+        let mut writer = self.writer.lock().unwrap_or_else(PoisonError::into_inner);
+        writer.commit_queued(&self.queue);
+        writer.lock.take();
     }
 }
 
 /// Create a new log file with given generation number and add the reader to the readers map.
 ///
+/// The file header is written before any record, so that a failed write can never
+/// tear it.
+///
 /// Returns the writer to the log.
-fn new_log_file(path: &Path, gen: u64) -> Result<BufWriterWithPos<File>> {
-    let path = log_path(&path, gen);
-    let writer = BufWriterWithPos::new(
-        OpenOptions::new()
-            .create(true)
-            .write(true)
-            .append(true)
-            .open(&path)?,
-    )?;
+fn new_log_file(vfs: &dyn Vfs, path: &Path, gen: u64) -> Result<BufWriterWithPos<VfsWriter>> {
+    let file = vfs.create(&log_path(path, gen))?;
+    let mut writer = BufWriterWithPos::new(VfsWriter(Arc::from(file)), 0);
+    writer.write_all(LOG_MAGIC)?;
+    writer.write_all(&LOG_VERSION.to_le_bytes())?;
+    writer.flush()?;
     Ok(writer)
 }
 
+/// Opens the log of the given generation for reading.
+fn open_log(vfs: &dyn Vfs, path: &Path, gen: u64) -> Result<BufReaderWithPos<VfsReader>> {
+    let file = vfs.open(&log_path(path, gen))?;
+    BufReaderWithPos::new(VfsReader { file, pos: 0 })
+}
+
 /// Returns sorted generation numbers in the given directory
-fn sorted_gen_list(path: &Path) -> Result<Vec<u64>> {
-    let mut gen_list: Vec<u64> = fs::read_dir(&path)?
-        .flat_map(|res| -> Result<_> { Ok(res?.path()) })
-        .filter(|path| path.is_file() && path.extension() == Some("log".as_ref()))
+fn sorted_gen_list(vfs: &dyn Vfs, path: &Path) -> Result<Vec<u64>> {
+    let mut gen_list: Vec<u64> = vfs
+        .list(path)?
+        .into_iter()
+        .filter(|path| path.extension() == Some("log".as_ref()))
         .flat_map(|path| {
             path.file_name()
                 .and_then(OsStr::to_str)
@@ -399,68 +2064,837 @@ fn sorted_gen_list(path: &Path) -> Result<Vec<u64>> {
     Ok(gen_list)
 }
 
+/// Reads the records from the given position on for a replica, following the logs in
+/// the order of their generations.
+///
+/// Compaction logs are skipped, as they only repeat the writes of the logs before
+/// them.
+///
+/// Returns the records and the position following them, or `None` if the log of the
+/// position does not exist anymore.
+fn read_log_records(
+    vfs: &dyn Vfs,
+    path: &Path,
+    from: LogPosition,
+) -> Result<Option<(Vec<LogRecord>, LogPosition)>> {
+    // Listed before reading, so a log is complete if a later one is listed: the
+    // writer flushes every group of writes before switching to a new log.
+    let gen_list = sorted_gen_list(vfs, path)?;
+    if !gen_list.contains(&from.gen) {
+        return Ok(None);
+    }
+    let mut records = Vec::new();
+    let mut read = 0;
+    let mut pos = from;
+    loop {
+        let mut reader = match open_log(vfs, path, pos.gen) {
+            Ok(reader) => reader,
+            Err(KvsError::Io(ref e)) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        let file_len = reader.seek(SeekFrom::End(0))?;
+        let format = read_log_format(&mut reader)?;
+        pos.offset = pos.offset.max(format.records_start());
+        reader.seek(SeekFrom::Start(pos.offset))?;
+        while pos.offset < file_len && read < LOG_CHUNK_BYTES {
+            // the last record of the active log may be partly written
+            let record = match read_record(&mut reader, file_len - pos.offset)? {
+                Some(record) => record,
+                None => break,
+            };
+            let payload = decode_record(&record).ok_or(KvsError::Corruption {
+                gen: pos.gen,
+                offset: pos.offset,
+            })?;
+            records.push(format.decode(payload)?.1.into());
+            pos.offset += record.len() as u64;
+            read += record.len() as u64;
+        }
+        if pos.offset < file_len || read >= LOG_CHUNK_BYTES {
+            break;
+        }
+        let next_gen = gen_list
+            .iter()
+            .cloned()
+            .find(|&gen| gen > pos.gen && !is_compaction_log(vfs, path, gen));
+        match next_gen {
+            Some(gen) => pos = LogPosition { gen, offset: 0 },
+            None => break,
+        }
+    }
+    Ok(Some((records, pos)))
+}
+
+/// Returns whether the log of the generation is written by a compaction, which
+/// gives it a hint file.
+fn is_compaction_log(vfs: &dyn Vfs, path: &Path, gen: u64) -> bool {
+    vfs.exists(&hint_path(path, gen)) || vfs.exists(&hint_tmp_path(path, gen))
+}
+
+/// Reads the changes of the writes of the log with sequence numbers after
+/// `from_seq`, up to `last_seq`.
+///
+/// `read` is the sequence number of the last record read before the log. Compaction
+/// logs are skipped, and so are logs that have been removed since they were listed.
+///
+/// Returns the changes and the sequence number of the last record read.
+///
+/// # Errors
+///
+/// It returns `KvsError::ChangesCompacted` if the first record after `from_seq` does
+/// not follow the last record read, as the records between have been compacted away.
+fn read_changes(
+    vfs: &dyn Vfs,
+    path: &Path,
+    gen: u64,
+    from_seq: u64,
+    mut read: u64,
+    last_seq: u64,
+) -> Result<(Vec<ChangeEvent>, u64)> {
+    let mut changes = Vec::new();
+    if is_compaction_log(vfs, path, gen) {
+        return Ok((changes, read));
+    }
+    let mut reader = match open_log(vfs, path, gen) {
+        Ok(reader) => reader,
+        Err(KvsError::Io(ref e)) if e.kind() == io::ErrorKind::NotFound => {
+            return Ok((changes, read))
+        }
+        Err(e) => return Err(e),
+    };
+    let file_len = reader.seek(SeekFrom::End(0))?;
+    let format = read_log_format(&mut reader)?;
+    let mut offset = reader.seek(SeekFrom::Start(format.records_start()))?;
+    // the last record of the active log may be partly written
+    while let Some(record) = read_record(&mut reader, file_len - offset)? {
+        let payload = decode_record(&record).ok_or(KvsError::Corruption { gen, offset })?;
+        let (seq, cmd) = format.decode(payload)?;
+        // logs written before sequence numbers are numbered like when loaded
+        let seq = seq.unwrap_or(read + 1);
+        if seq > last_seq {
+            break;
+        }
+        if seq > read.max(from_seq) + 1 {
+            return Err(KvsError::ChangesCompacted(read.max(from_seq)));
+        }
+        if seq > from_seq {
+            cmd.push_changes(seq, &mut changes);
+        }
+        read = seq;
+        offset += record.len() as u64;
+    }
+    Ok((changes, read))
+}
+
 /// Load the whole log file and store value locations in the index map.
 ///
+/// If the active log ends with an incomplete record, or its last record fails its
+/// checksum, the write is considered torn by a crash and the log is truncated to the
+/// end of the last complete record, unless the store is read-only. Such a record
+/// followed by whole records has a corrupted length instead, which is reported as
+/// corruption. Older logs are synced before the next one is written, so such a
+/// record in one of them is always reported as corruption.
+///
 /// Returns how many bytes can be saved after a compaction.
+#[allow(clippy::too_many_arguments)]
 fn load(
+    vfs: &dyn Vfs,
+    path: &Path,
     gen: u64,
-    reader: &mut BufReaderWithPos<File>,
-    index: &SkipMap<String, CommandPos>,
+    active: bool,
+    reader: &mut BufReaderWithPos<VfsReader>,
+    index: &SkipMap<Vec<u8>, Version>,
+    last_seq: &mut u64,
+    read_only: bool,
 ) -> Result<u64> {
-    // To make sure we read from the beginning of the file
-    let mut pos = reader.seek(SeekFrom::Start(0))?;
-    let mut stream = Deserializer::from_reader(reader).into_iter::<Command>();
+    let file_len = reader.seek(SeekFrom::End(0))?;
+    if file_len < LOG_HEADER_LEN {
+        // Either the header of a new log or the first record of an old-format log
+        // is torn. There is nothing to load in both cases.
+        if file_len > 0 {
+            if !active {
+                return Err(KvsError::Corruption { gen, offset: 0 });
+            }
+            truncate_torn_tail(vfs, path, gen, 0, read_only)?;
+        }
+        return Ok(0);
+    }
+    let format = read_log_format(reader)?;
+    // To make sure we read from the beginning of the records
+    let mut pos = reader.seek(SeekFrom::Start(format.records_start()))?;
     let mut uncompacted = 0; // number of bytes that can be saved after a compaction
-    while let Some(cmd) = stream.next() {
-        let new_pos = stream.byte_offset() as u64;
-        match cmd? {
-            Command::Set { key, .. } => {
-                if let Some(old_cmd) = index.get(&key) {
-                    uncompacted += old_cmd.value().len;
-                }
-                index.insert(key, (gen, pos..new_pos).into());
+    let now = now_millis();
+    while pos < file_len {
+        let payload = match read_record(reader, file_len - pos)? {
+            Some(payload) => payload,
+            None => {
+                check_torn_tail(reader, gen, active, pos)?;
+                truncate_torn_tail(vfs, path, gen, pos, read_only)?;
+                break;
             }
-            Command::Remove { key } => {
-                if let Some(old_cmd) = index.remove(&key) {
-                    uncompacted += old_cmd.value().len;
+        };
+        let new_pos = reader.pos;
+        let payload = match decode_record(&payload) {
+            Some(payload) => payload,
+            None if new_pos == file_len => {
+                check_torn_tail(reader, gen, active, pos)?;
+                truncate_torn_tail(vfs, path, gen, pos, read_only)?;
+                break;
+            }
+            None => return Err(KvsError::Corruption { gen, offset: pos }),
+        };
+        let (seq, cmd) = format.decode(payload)?;
+        // records of old formats are numbered in the order they are loaded
+        let seq = seq.unwrap_or(*last_seq + 1);
+        *last_seq = (*last_seq).max(seq);
+        // a batch is a single record, so it is either loaded whole or truncated above
+        let (cmds, cmd_pos) = match cmd {
+            Command::Batch(cmds) => {
+                let cmd_pos = CommandPos::from((gen, pos..new_pos)).in_batch(&cmds);
+                (cmds, cmd_pos)
+            }
+            cmd => (vec![cmd], CommandPos::from((gen, pos..new_pos))),
+        };
+        let mut referenced = false;
+        for cmd in cmds {
+            match cmd {
+                Command::Set {
+                    key, expires_at, ..
+                } if !is_expired(expires_at, now) => {
+                    if let Some(old_cmd) = index.get(&key) {
+                        uncompacted += old_cmd.value().size();
+                    }
+                    index.insert(key, Version::new(seq, cmd_pos.expiring_at(expires_at)));
+                    referenced = true;
                 }
-                // the "remove" command itself can be deleted in the next compaction
-                // so we add its length to `uncompacted`
-                uncompacted += new_pos - pos;
+                // an expired set removes the key like a "remove" command
+                Command::Set { key, .. } | Command::Remove { key } => {
+                    if let Some(old_cmd) = index.remove(&key) {
+                        uncompacted += old_cmd.value().size();
+                    }
+                }
+                Command::Batch(_) => return Err(KvsError::UnexpectedCommandType),
             }
         }
+        if !referenced {
+            // the "remove" command itself can be deleted in the next compaction
+            // so we add its length to `uncompacted`
+            uncompacted += cmd_pos.len;
+        }
         pos = new_pos;
     }
     Ok(uncompacted)
 }
 
+/// Create a temporary hint file for the given compaction generation.
+///
+/// The hint is only visible to `load_hint` after `commit_hint_file`.
+fn new_hint_file(vfs: &dyn Vfs, path: &Path, gen: u64) -> Result<BufWriter<VfsWriter>> {
+    let file = vfs.create(&hint_tmp_path(path, gen))?;
+    let mut writer = BufWriter::new(VfsWriter(Arc::from(file)));
+    writer.write_all(HINT_MAGIC)?;
+    writer.write_all(&HINT_VERSION.to_le_bytes())?;
+    Ok(writer)
+}
+
+/// Makes the hint file durable and moves it to its final name.
+fn commit_hint_file(
+    vfs: &dyn Vfs,
+    path: &Path,
+    gen: u64,
+    mut writer: BufWriter<VfsWriter>,
+) -> Result<()> {
+    writer.flush()?;
+    writer.get_ref().0.sync_data()?;
+    vfs.rename(&hint_tmp_path(path, gen), &hint_path(path, gen))?;
+    Ok(())
+}
+
+/// Load the hint file of the given generation into the index map.
+///
+/// Returns `None` if the generation has no usable hint file and its log must be
+/// replayed instead. Otherwise, returns how many bytes can be saved after a compaction.
+fn load_hint(
+    vfs: &dyn Vfs,
+    path: &Path,
+    gen: u64,
+    index: &SkipMap<Vec<u8>, Version>,
+    last_seq: &mut u64,
+) -> Result<Option<u64>> {
+    let hint_path = hint_path(path, gen);
+    let hint = match read_file(vfs, &hint_path) {
+        Ok(hint) => hint,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    let log_len = vfs.open(&log_path(path, gen))?.len()?;
+    let entries = match parse_hint(&hint, gen, log_len) {
+        Some(entries) => entries,
+        None => {
+            warn!("{:?} is invalid, replaying the log instead", hint_path);
+            return Ok(None);
+        }
+    };
+
+    let mut uncompacted = 0;
+    let now = now_millis();
+    for entry in entries {
+        *last_seq = (*last_seq).max(entry.seq);
+        if is_expired(entry.expires_at, now) {
+            if let Some(old_cmd) = index.remove(&entry.key) {
+                uncompacted += old_cmd.value().size();
+            }
+            uncompacted += entry.len;
+            continue;
+        }
+        if let Some(old_cmd) = index.get(&entry.key) {
+            uncompacted += old_cmd.value().size();
+        }
+        let cmd_pos = CommandPos::from((gen, entry.pos..entry.pos + entry.len));
+        let version = Version::new(entry.seq, cmd_pos.expiring_at(entry.expires_at));
+        index.insert(entry.key, version);
+    }
+    Ok(Some(uncompacted))
+}
+
+/// Decodes all entries of a hint file.
+///
+/// Returns `None` if the hint is damaged or doesn't match the log of the generation.
+fn parse_hint(hint: &[u8], gen: u64, log_len: u64) -> Option<Vec<HintEntry>> {
+    let header_len = HINT_MAGIC.len() + 2;
+    if hint.len() < header_len
+        || &hint[..HINT_MAGIC.len()] != HINT_MAGIC
+        || hint[HINT_MAGIC.len()..header_len] != HINT_VERSION.to_le_bytes()
+    {
+        return None;
+    }
+    let mut entries = Vec::new();
+    let mut reader = &hint[header_len..];
+    while !reader.is_empty() {
+        let remaining = reader.len() as u64;
+        let record = read_record(&mut reader, remaining).ok()??;
+        let entry: HintEntry = bincode::deserialize(decode_record(&record)?).ok()?;
+        if entry.gen != gen || entry.pos + entry.len > log_len {
+            return None;
+        }
+        entries.push(entry);
+    }
+    Some(entries)
+}
+
+/// The live logs and the active one, saved whenever a log is added or a compaction
+/// commits.
+#[derive(Serialize, Deserialize, Debug)]
+struct Manifest {
+    // generations of the logs to load, in increasing order
+    live_gens: Vec<u64>,
+    // generation of the log the writer appends to
+    active_gen: u64,
+}
+
+impl Manifest {
+    fn new(live_gens: &BTreeSet<u64>, active_gen: u64) -> Self {
+        Manifest {
+            live_gens: live_gens.iter().cloned().collect(),
+            active_gen,
+        }
+    }
+
+    /// Loads the manifest, returning `None` if there is none yet.
+    fn load(vfs: &dyn Vfs, path: &Path) -> Result<Option<Self>> {
+        let record = match read_file(vfs, &path.join(MANIFEST_FILE)) {
+            Ok(record) => record,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        match decode_record(&record) {
+            Some(payload) => Ok(Some(bincode::deserialize(payload)?)),
+            None => Err(KvsError::StringError("Invalid manifest".to_owned())),
+        }
+    }
+
+    /// Replaces the manifest, so that either the old or the new one is found after a
+    /// crash.
+    ///
+    /// Syncing the directory also makes the logs and hint files created before
+    /// durable.
+    fn save(&self, vfs: &dyn Vfs, path: &Path) -> Result<()> {
+        let manifest_path = path.join(MANIFEST_FILE);
+        let tmp_path = manifest_path.with_extension("tmp");
+        let mut writer = BufWriter::new(VfsWriter(Arc::from(vfs.create(&tmp_path)?)));
+        write_record(&mut writer, self)?;
+        writer.flush()?;
+        writer.get_ref().0.sync_data()?;
+        vfs.rename(&tmp_path, &manifest_path)?;
+        vfs.sync_dir(path)?;
+        Ok(())
+    }
+}
+
+/// Checks that the record at `pos`, which runs past the end of the log or fails its
+/// checksum there, is a torn last record rather than one with a corrupted length.
+///
+/// Only the active log may end with a torn write. No whole record can follow a torn
+/// one, so any offset after `pos` at which a record passes its checksum means the
+/// length of the record at `pos` is wrong. Records are only looked for in the
+/// `TORN_TAIL_SCAN_LEN` bytes following `pos`.
+///
+/// # Errors
+///
+/// It returns `KvsError::Corruption` if the log is not the active one or a whole
+/// record follows.
+fn check_torn_tail<R: Read + Seek>(reader: &mut R, gen: u64, active: bool, pos: u64) -> Result<()> {
+    if !active {
+        return Err(KvsError::Corruption { gen, offset: pos });
+    }
+    let mut tail = Vec::new();
+    reader.seek(SeekFrom::Start(pos + 1))?;
+    reader.take(TORN_TAIL_SCAN_LEN).read_to_end(&mut tail)?;
+    for start in 0..tail.len() {
+        let rest = &tail[start..];
+        if (rest.len() as u64) < RECORD_HEADER_LEN {
+            break;
+        }
+        let payload_len = u32::from_le_bytes([rest[0], rest[1], rest[2], rest[3]]);
+        let len = RECORD_HEADER_LEN + u64::from(payload_len);
+        // records are never empty, and a run of zeros would pass as one
+        if payload_len == 0 || len > rest.len() as u64 {
+            continue;
+        }
+        if decode_record(&rest[..len as usize]).is_some() {
+            return Err(KvsError::Corruption { gen, offset: pos });
+        }
+    }
+    Ok(())
+}
+
+/// Truncates the log of the given generation to `len` bytes, dropping a torn record.
+///
+/// A read-only store leaves the log as is, as the record may still be being written.
+fn truncate_torn_tail(
+    vfs: &dyn Vfs,
+    path: &Path,
+    gen: u64,
+    len: u64,
+    read_only: bool,
+) -> Result<()> {
+    if read_only {
+        warn!(
+            "Found a torn record at offset {} of {}.log, skipping it",
+            len, gen
+        );
+        return Ok(());
+    }
+    warn!(
+        "Found a torn record at offset {} of {}.log, truncating the log",
+        len, gen
+    );
+    vfs.truncate(&log_path(path, gen), len)?;
+    Ok(())
+}
+
+/// Takes the lock of the directory of the store, writing the PID of this process
+/// into the lock file.
+///
+/// # Errors
+///
+/// It returns `KvsError::StoreLocked` with the PID of the holder if the lock is
+/// already held.
+fn lock_dir(vfs: &dyn Vfs, path: &Path) -> Result<Box<dyn VfsLock>> {
+    let lock_path = path.join(LOCK_FILE);
+    match vfs.lock(&lock_path, process::id().to_string().as_bytes()) {
+        Ok(lock) => Ok(lock),
+        Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+            // the holder writes its PID right after taking the lock
+            let pid = read_file(vfs, &lock_path)
+                .ok()
+                .and_then(|owner| String::from_utf8(owner).ok()?.parse().ok());
+            Err(KvsError::StoreLocked { pid })
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Reads the whole file.
+fn read_file(vfs: &dyn Vfs, path: &Path) -> io::Result<Vec<u8>> {
+    let mut data = Vec::new();
+    VfsReader {
+        file: vfs.open(path)?,
+        pos: 0,
+    }
+    .read_to_end(&mut data)?;
+    Ok(data)
+}
+
+/// Appends the command or hint entry in the binary format, framed with its length
+/// and checksum.
+fn write_record<W: Write, T: Serialize>(writer: &mut W, record: &T) -> Result<()> {
+    let payload = bincode::serialize(record)?;
+    writer.write_all(&(payload.len() as u32).to_le_bytes())?;
+    writer.write_all(&crc32fast::hash(&payload).to_le_bytes())?;
+    writer.write_all(&payload)?;
+    Ok(())
+}
+
+/// Reads the next whole record, header included, from the log.
+///
+/// `remaining` is the number of bytes left in the log. Returns `None` if the
+/// record is cut off before its end.
+fn read_record<R: Read>(reader: &mut R, remaining: u64) -> Result<Option<Vec<u8>>> {
+    if remaining < RECORD_HEADER_LEN {
+        return Ok(None);
+    }
+    let mut header = [0; RECORD_HEADER_LEN as usize];
+    reader.read_exact(&mut header)?;
+    let payload_len = u32::from_le_bytes([header[0], header[1], header[2], header[3]]);
+    let len = RECORD_HEADER_LEN + u64::from(payload_len);
+    if remaining < len {
+        return Ok(None);
+    }
+    let mut record = header.to_vec();
+    reader
+        .take(len - RECORD_HEADER_LEN)
+        .read_to_end(&mut record)?;
+    Ok(Some(record))
+}
+
+/// Checks the length and checksum of a whole record and returns its payload.
+///
+/// Returns `None` if the record is corrupted.
+fn decode_record(record: &[u8]) -> Option<&[u8]> {
+    if (record.len() as u64) < RECORD_HEADER_LEN {
+        return None;
+    }
+    let (header, payload) = record.split_at(RECORD_HEADER_LEN as usize);
+    let len = u32::from_le_bytes([header[0], header[1], header[2], header[3]]);
+    let checksum = u32::from_le_bytes([header[4], header[5], header[6], header[7]]);
+    if len as usize != payload.len() || crc32fast::hash(payload) != checksum {
+        return None;
+    }
+    Some(payload)
+}
+
+/// Reads the file header to find out the format of the log.
+///
+/// Every log starts with a magic and version header, and every record in it is a
+/// bincode-encoded command and its sequence number, framed with its length and a
+/// CRC32 checksum. Logs without the header are in the old JSON format, which is
+/// still readable and gets rewritten in the binary format by the next compaction.
+fn read_log_format<R: Read + Seek>(reader: &mut R) -> Result<LogFormat> {
+    let mut header = [0; LOG_HEADER_LEN as usize];
+    reader.seek(SeekFrom::Start(0))?;
+    match reader.read_exact(&mut header) {
+        Ok(()) => {}
+        Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(LogFormat::Binary),
+        Err(e) => return Err(e.into()),
+    }
+    if &header[..LOG_MAGIC.len()] != LOG_MAGIC {
+        return Ok(LogFormat::Json);
+    }
+    match u16::from_le_bytes([header[6], header[7]]) {
+        1 => Ok(LogFormat::BinaryV1),
+        2 | 3 => Ok(LogFormat::BinaryV3),
+        LOG_VERSION => Ok(LogFormat::Binary),
+        version => Err(KvsError::UnsupportedLogVersion(version)),
+    }
+}
+
 fn log_path(dir: &Path, gen: u64) -> PathBuf {
     dir.join(format!("{}.log", gen))
 }
 
+fn hint_path(dir: &Path, gen: u64) -> PathBuf {
+    dir.join(format!("{}.hint", gen))
+}
+
+fn hint_tmp_path(dir: &Path, gen: u64) -> PathBuf {
+    dir.join(format!("{}.hint.tmp", gen))
+}
+
+/// Encoding of the records in a log file
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum LogFormat {
+    /// JSON records without a file header, written by older versions
+    Json,
+    /// Bincode records without expiry deadlines after the version 1 file header
+    BinaryV1,
+    /// Bincode records without sequence numbers after the version 2 or 3 file header
+    BinaryV3,
+    /// Bincode records of the sequence number and the command after the file header
+    Binary,
+}
+
+impl LogFormat {
+    /// Offset of the first record in a log of this format.
+    fn records_start(self) -> u64 {
+        match self {
+            LogFormat::Json => 0,
+            LogFormat::BinaryV1 | LogFormat::BinaryV3 | LogFormat::Binary => LOG_HEADER_LEN,
+        }
+    }
+
+    /// Deserializes the payload of a record to its sequence number, if the format
+    /// has one, and its command.
+    fn decode(self, payload: &[u8]) -> Result<(Option<u64>, Command)> {
+        match self {
+            LogFormat::Json => Ok((None, serde_json::from_slice::<JsonCommand>(payload)?.into())),
+            LogFormat::BinaryV1 => Ok((None, bincode::deserialize::<CommandV1>(payload)?.into())),
+            LogFormat::BinaryV3 => Ok((None, bincode::deserialize(payload)?)),
+            LogFormat::Binary => {
+                let (seq, cmd) = bincode::deserialize(payload)?;
+                Ok((Some(seq), cmd))
+            }
+        }
+    }
+}
+
 /// Struct representing a command
 #[derive(Serialize, Deserialize, Debug)]
 enum Command {
-    Set { key: String, value: String },
-    Remove { key: String },
+    Set {
+        key: Vec<u8>,
+        value: Vec<u8>,
+        // milliseconds since the Unix epoch after which the key expires
+        expires_at: Option<u64>,
+    },
+    Remove {
+        key: Vec<u8>,
+    },
+    // sets and removes written as a single record, at most one per key
+    Batch(Vec<Command>),
 }
 
 impl Command {
-    fn set(key: String, value: String) -> Command {
-        Command::Set { key, value }
+    fn set(key: Vec<u8>, value: Vec<u8>, expires_at: Option<u64>) -> Command {
+        Command::Set {
+            key,
+            value,
+            expires_at,
+        }
     }
 
-    fn remove(key: String) -> Command {
+    fn remove(key: Vec<u8>) -> Command {
         Command::Remove { key }
     }
+
+    /// Returns the batch command with the last write of each key in the batch.
+    fn batch(batch: WriteBatch) -> Command {
+        let cmds = batch
+            .into_last_ops()
+            .into_iter()
+            .map(|op| match op {
+                BatchOp::Set { key, value } => Command::set(key, value, None),
+                BatchOp::Remove { key } => Command::remove(key),
+            })
+            .collect();
+        Command::Batch(cmds)
+    }
+
+    /// Pushes the changes of the writes of the command, committed with the given
+    /// sequence number.
+    fn push_changes(&self, seq: u64, changes: &mut Vec<ChangeEvent>) {
+        match self {
+            Command::Set {
+                key,
+                value,
+                expires_at,
+            } => changes.push(ChangeEvent::Set {
+                seq,
+                key: key.clone(),
+                value: value.clone(),
+                expires_at: *expires_at,
+            }),
+            Command::Remove { key } => changes.push(ChangeEvent::Remove {
+                seq,
+                key: key.clone(),
+            }),
+            Command::Batch(cmds) => {
+                for cmd in cmds {
+                    cmd.push_changes(seq, changes);
+                }
+            }
+        }
+    }
+
+    /// Returns the command of the given key, looking it up in batches.
+    fn for_key(self, key: &[u8]) -> Option<Command> {
+        match self {
+            Command::Batch(cmds) => cmds.into_iter().find(|cmd| match cmd {
+                Command::Set { key: k, .. } | Command::Remove { key: k } => k.as_slice() == key,
+                Command::Batch(_) => false,
+            }),
+            cmd => Some(cmd),
+        }
+    }
+}
+
+impl From<Command> for LogRecord {
+    fn from(cmd: Command) -> LogRecord {
+        match cmd {
+            Command::Set {
+                key,
+                value,
+                expires_at,
+            } => LogRecord::Set {
+                key,
+                value,
+                expires_at,
+            },
+            Command::Remove { key } => LogRecord::Remove { key },
+            Command::Batch(cmds) => LogRecord::Batch(cmds.into_iter().map(Into::into).collect()),
+        }
+    }
+}
+
+/// A command in the JSON format, which only holds string keys and values
+#[derive(Deserialize)]
+enum JsonCommand {
+    Set { key: String, value: String },
+    Remove { key: String },
+}
+
+impl From<JsonCommand> for Command {
+    fn from(cmd: JsonCommand) -> Command {
+        match cmd {
+            JsonCommand::Set { key, value } => {
+                Command::set(key.into_bytes(), value.into_bytes(), None)
+            }
+            JsonCommand::Remove { key } => Command::remove(key.into_bytes()),
+        }
+    }
+}
+
+/// A command in the version 1 binary format, which has no expiry deadlines
+#[derive(Deserialize)]
+enum CommandV1 {
+    Set { key: Vec<u8>, value: Vec<u8> },
+    Remove { key: Vec<u8> },
+}
+
+impl From<CommandV1> for Command {
+    fn from(cmd: CommandV1) -> Command {
+        match cmd {
+            CommandV1::Set { key, value } => Command::set(key, value, None),
+            CommandV1::Remove { key } => Command::remove(key),
+        }
+    }
+}
+
+/// An entry of a hint file: where the latest command of a key is in the compaction log
+#[derive(Serialize, Deserialize, Debug)]
+struct HintEntry {
+    key: Vec<u8>,
+    seq: u64,
+    gen: u64,
+    pos: u64,
+    len: u64,
+    expires_at: Option<u64>,
+}
+
+impl HintEntry {
+    fn new(key: &[u8], seq: u64, cmd_pos: CommandPos) -> HintEntry {
+        HintEntry {
+            key: key.to_vec(),
+            seq,
+            gen: cmd_pos.gen,
+            pos: cmd_pos.pos,
+            len: cmd_pos.len,
+            expires_at: cmd_pos.expires_at,
+        }
+    }
+}
+
+/// A version of a key in the index
+#[derive(Debug, Clone)]
+struct Version {
+    // sequence number of the command
+    seq: u64,
+    // `None` if the key was removed
+    cmd_pos: Option<CommandPos>,
+    // the previous version, kept as long as a snapshot may read it
+    older: Option<Arc<Version>>,
+}
+
+impl Version {
+    fn new(seq: u64, cmd_pos: CommandPos) -> Version {
+        Version {
+            seq,
+            cmd_pos: Some(cmd_pos),
+            older: None,
+        }
+    }
+
+    /// Returns the command of the version visible at the given sequence number.
+    fn at(&self, seq: u64) -> Option<CommandPos> {
+        let mut version = self;
+        while version.seq > seq {
+            version = version.older.as_ref()?;
+        }
+        version.cmd_pos
+    }
+
+    /// Returns the share of the log accounted to the version, or 0 if the key was
+    /// removed.
+    fn size(&self) -> u64 {
+        self.cmd_pos.map_or(0, CommandPos::size)
+    }
+}
+
+/// Returns the chain of versions from `version` on without the ones no live snapshot
+/// can read, given that `version` is replaced at the sequence number `replaced_at`.
+fn retain_versions(
+    version: Option<&Arc<Version>>,
+    replaced_at: u64,
+    snapshots: &BTreeMap<u64, usize>,
+) -> Option<Arc<Version>> {
+    let version = version?;
+    let older = retain_versions(version.older.as_ref(), version.seq, snapshots);
+    // a snapshot reads the version if it was taken before the version was replaced
+    if snapshots.range(version.seq..replaced_at).next().is_some() {
+        Some(Arc::new(Version {
+            older,
+            ..(**version).clone()
+        }))
+    } else {
+        older
+    }
 }
 
-/// Represents the position and length of a json-serialized command in the log
-#[derive(Debug, Clone, Copy)]
+/// Represents the position and length of a framed command in the log, and when the
+/// key it sets expires
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 struct CommandPos {
     gen: u64,
     pos: u64,
     len: u64,
+    expires_at: Option<u64>,
+    // number of keys set by the batch record, or 0 if the record is a single command
+    batch_sets: u64,
+}
+
+impl CommandPos {
+    fn expiring_at(self, expires_at: Option<u64>) -> Self {
+        CommandPos { expires_at, ..self }
+    }
+
+    /// Marks the position as a batch record with the given commands.
+    fn in_batch(self, cmds: &[Command]) -> Self {
+        let batch_sets = cmds
+            .iter()
+            .filter(|cmd| matches!(cmd, Command::Set { .. }))
+            .count() as u64;
+        CommandPos { batch_sets, ..self }
+    }
+
+    fn is_batch(self) -> bool {
+        self.batch_sets > 0
+    }
+
+    /// Returns the share of the record's length accounted to the key, which is the
+    /// whole length unless the record is a batch setting several keys.
+    fn size(self) -> u64 {
+        self.len / self.batch_sets.max(1)
+    }
 }
 
 impl From<(u64, Range<u64>)> for CommandPos {
@@ -469,6 +2903,8 @@ impl From<(u64, Range<u64>)> for CommandPos {
             gen,
             pos: range.start,
             len: range.end - range.start,
+            expires_at: None,
+            batch_sets: 0,
         }
     }
 }
@@ -503,22 +2939,44 @@ impl<R: Read + Seek> Seek for BufReaderWithPos<R> {
     }
 }
 
-struct BufWriterWithPos<W: Write + Seek> {
+struct BufWriterWithPos<W: Write> {
     writer: BufWriter<W>,
     pos: u64,
 }
 
-impl<W: Write + Seek> BufWriterWithPos<W> {
-    fn new(mut inner: W) -> Result<Self> {
-        let pos = inner.seek(SeekFrom::Current(0))?;
-        Ok(BufWriterWithPos {
+impl<W: Write> BufWriterWithPos<W> {
+    /// Wraps the writer, which is at the given position.
+    fn new(inner: W, pos: u64) -> Self {
+        BufWriterWithPos {
             writer: BufWriter::new(inner),
             pos,
-        })
+        }
+    }
+}
+
+impl BufWriterWithPos<VfsWriter> {
+    /// Flushes the buffer and syncs the file content to disk.
+    fn sync_data(&mut self) -> io::Result<()> {
+        self.writer.flush()?;
+        self.writer.get_ref().0.sync_data()
+    }
+
+    /// Returns another handle to the file.
+    fn file(&self) -> Arc<dyn VfsFile> {
+        Arc::clone(&self.writer.get_ref().0)
+    }
+
+    /// Drops the buffered bytes without writing them, and moves to the given
+    /// position.
+    fn discard_buffer(&mut self, pos: u64) {
+        let writer = BufWriter::new(VfsWriter(self.file()));
+        // `into_parts` gives the buffer back instead of flushing it on drop
+        let _ = std::mem::replace(&mut self.writer, writer).into_parts();
+        self.pos = pos;
     }
 }
 
-impl<W: Write + Seek> Write for BufWriterWithPos<W> {
+impl<W: Write> Write for BufWriterWithPos<W> {
     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
         let len = self.writer.write(buf)?;
         self.pos += len as u64;
@@ -530,9 +2988,47 @@ impl<W: Write + Seek> Write for BufWriterWithPos<W> {
     }
 }
 
-impl<W: Write + Seek> Seek for BufWriterWithPos<W> {
+/// Reads a `VfsFile` from a position, like a file opened for reading.
+struct VfsReader {
+    file: Box<dyn VfsFile>,
+    pos: u64,
+}
+
+impl Read for VfsReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = self.file.read_at(buf, self.pos)?;
+        self.pos += len as u64;
+        Ok(len)
+    }
+}
+
+impl Seek for VfsReader {
     fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
-        self.pos = self.writer.seek(pos)?;
+        let pos = match pos {
+            SeekFrom::Start(pos) => Some(pos),
+            SeekFrom::End(delta) => self.file.len()?.checked_add_signed(delta),
+            SeekFrom::Current(delta) => self.pos.checked_add_signed(delta),
+        };
+        self.pos = pos.ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "invalid seek to a negative position",
+            )
+        })?;
         Ok(self.pos)
     }
 }
+
+/// Appends to a `VfsFile`, like a file opened in append mode.
+struct VfsWriter(Arc<dyn VfsFile>);
+
+impl Write for VfsWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.append(buf)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
@@ -0,0 +1,71 @@
+use crate::{KvsError, Result};
+
+/// A Bloom filter of the keys of a table, telling when a key is surely not in it.
+///
+/// Like in LevelDB, the bits of a key are probed by double hashing: the CRC32 of
+/// the key, then that hash rotated, added once per probe.
+pub(super) struct BloomFilter {
+    bits: Vec<u8>,
+    probes: u32,
+}
+
+impl BloomFilter {
+    /// Builds the filter of the keys with the given hashes, with about
+    /// `bits_per_key` bits for each key.
+    pub(super) fn build(hashes: &[u32], bits_per_key: usize) -> Self {
+        // about 1% of false positives with 10 bits per key
+        let probes = ((bits_per_key as f64 * 0.69) as u32).clamp(1, 30);
+        let len = (hashes.len() * bits_per_key).max(64);
+        let mut filter = BloomFilter {
+            bits: vec![0; len.div_ceil(8)],
+            probes,
+        };
+        for &hash in hashes {
+            for bit in filter.probe_bits(hash) {
+                filter.bits[bit / 8] |= 1 << (bit % 8);
+            }
+        }
+        filter
+    }
+
+    /// Returns whether the key may have been added to the filter.
+    pub(super) fn may_contain(&self, key: &[u8]) -> bool {
+        self.probe_bits(key_hash(key))
+            .all(|bit| self.bits[bit / 8] & (1 << (bit % 8)) != 0)
+    }
+
+    /// Encodes the filter as its bits followed by the number of probes.
+    pub(super) fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = self.bits.clone();
+        bytes.push(self.probes as u8);
+        bytes
+    }
+
+    /// Decodes a filter encoded by `to_bytes`.
+    pub(super) fn from_bytes(mut bytes: Vec<u8>) -> Result<Self> {
+        let probes = match bytes.pop() {
+            Some(probes) if !bytes.is_empty() => u32::from(probes),
+            _ => return Err(KvsError::StringError("Invalid Bloom filter".to_owned())),
+        };
+        Ok(BloomFilter {
+            bits: bytes,
+            probes,
+        })
+    }
+
+    fn probe_bits(&self, hash: u32) -> impl Iterator<Item = usize> {
+        let len = self.bits.len() * 8;
+        let delta = hash.rotate_right(17);
+        let mut hash = hash;
+        (0..self.probes).map(move |_| {
+            let bit = hash as usize % len;
+            hash = hash.wrapping_add(delta);
+            bit
+        })
+    }
+}
+
+/// Returns the hash of a key to build a filter from.
+pub(super) fn key_hash(key: &[u8]) -> u32 {
+    crc32fast::hash(key)
+}
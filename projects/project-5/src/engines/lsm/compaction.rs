@@ -0,0 +1,277 @@
+use super::sstable::{Table, TableBuilder, TableIter};
+use super::{Entry, LsmOptions};
+use crate::engines::now_millis;
+use crate::Result;
+use std::ops::Bound::{self, Unbounded};
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+/// Number of levels of tables.
+pub(super) const LEVELS: usize = 7;
+
+/// Factor by which the target size of each level from level 1 on grows.
+const LEVEL_SIZE_MULTIPLIER: u64 = 10;
+
+/// A source of entries in key order.
+pub(super) type EntryIter = Box<dyn Iterator<Item = Result<(Vec<u8>, Entry)>> + Send>;
+
+/// Iterator merging the entries of several sources in key order.
+///
+/// Sources are given newest first: when several of them have an entry of the same
+/// key, only the entry of the first one is kept.
+pub(super) struct MergeIter {
+    sources: Vec<Source>,
+}
+
+/// A source of a `MergeIter` and its next entry.
+struct Source {
+    head: Option<(Vec<u8>, Entry)>,
+    iter: EntryIter,
+}
+
+impl Source {
+    fn advance(&mut self) -> Result<()> {
+        self.head = self.iter.next().transpose()?;
+        Ok(())
+    }
+}
+
+impl MergeIter {
+    pub(super) fn new(iters: Vec<EntryIter>) -> Result<Self> {
+        let mut sources = Vec::with_capacity(iters.len());
+        for iter in iters {
+            let mut source = Source { head: None, iter };
+            source.advance()?;
+            sources.push(source);
+        }
+        Ok(MergeIter { sources })
+    }
+
+    fn next_entry(&mut self) -> Result<Option<(Vec<u8>, Entry)>> {
+        // the first source with the smallest key
+        let first = self
+            .sources
+            .iter()
+            .enumerate()
+            .filter_map(|(i, source)| Some((i, &source.head.as_ref()?.0)))
+            .min_by(|(_, a), (_, b)| a.cmp(b))
+            .map(|(i, _)| i);
+        let first = match first {
+            Some(first) => first,
+            None => return Ok(None),
+        };
+        let (key, entry) = self.sources[first].head.take().unwrap();
+        self.sources[first].advance()?;
+        for source in &mut self.sources[first + 1..] {
+            if source.head.as_ref().map(|(other, _)| other) == Some(&key) {
+                source.advance()?;
+            }
+        }
+        Ok(Some((key, entry)))
+    }
+}
+
+impl Iterator for MergeIter {
+    type Item = Result<(Vec<u8>, Entry)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_entry().transpose()
+    }
+}
+
+/// Returns an iterator over the entries of the tables of a level from level 1 on,
+/// which are sorted and do not overlap, from the given key on.
+pub(super) fn level_iter(tables: Vec<Arc<Table>>, start: Bound<Vec<u8>>) -> EntryIter {
+    Box::new(
+        tables
+            .into_iter()
+            .flat_map(move |table| TableIter::new(table, start.clone())),
+    )
+}
+
+/// A compaction merging runs of tables into a level.
+pub(super) struct Compaction {
+    /// The runs of tables merged, newest first. Every table of level 0 is a run of
+    /// its own, and the tables of any other level form a single run.
+    pub(super) runs: Vec<Vec<Arc<Table>>>,
+    /// The level the merged tables are written to
+    pub(super) output_level: usize,
+    /// Whether no table left out of the compaction may hold older entries of the
+    /// keys, so that removed and expired keys can be dropped
+    pub(super) bottom: bool,
+}
+
+impl Compaction {
+    /// Picks the level most in need of a compaction, if any.
+    ///
+    /// Level 0 needs one once it has `level0_tables` tables, and any other level
+    /// once it exceeds its target size, which is `level_size_base` for level 1 and
+    /// ten times larger for every following level. The tables of level 0 are all
+    /// merged at once, while a single table of the other levels is, the oldest one,
+    /// so that their key ranges are compacted in turn. The tables of the next level
+    /// overlapping them are merged with them.
+    pub(super) fn pick(levels: &[Vec<Arc<Table>>], options: &LsmOptions) -> Option<Self> {
+        let mut best: Option<(usize, f64)> = None;
+        for (level, tables) in levels.iter().enumerate().take(LEVELS - 1) {
+            let score = if level == 0 {
+                tables.len() as f64 / options.level0_tables as f64
+            } else {
+                let target = options.level_size_base * LEVEL_SIZE_MULTIPLIER.pow(level as u32 - 1);
+                tables.iter().map(|table| table.size()).sum::<u64>() as f64 / target as f64
+            };
+            if score >= 1.0 && !matches!(best, Some((_, best)) if score <= best) {
+                best = Some((level, score));
+            }
+        }
+        let (level, _) = best?;
+
+        let inputs = if level == 0 {
+            levels[0].clone()
+        } else {
+            let oldest = levels[level].iter().min_by_key(|table| table.number())?;
+            vec![Arc::clone(oldest)]
+        };
+        let start = inputs.iter().map(|table| table.first_key()).min()?.to_vec();
+        let end = inputs.iter().map(|table| table.last_key()).max()?.to_vec();
+        let overlapping: Vec<_> = levels[level + 1]
+            .iter()
+            .filter(|table| table.overlaps(&start, &end))
+            .cloned()
+            .collect();
+        let bottom = levels[level + 2..]
+            .iter()
+            .flatten()
+            .all(|table| !table.overlaps(&start, &end));
+
+        let mut runs: Vec<_> = if level == 0 {
+            inputs.into_iter().map(|table| vec![table]).collect()
+        } else {
+            vec![inputs]
+        };
+        runs.push(overlapping);
+        Some(Compaction {
+            runs,
+            output_level: level + 1,
+            bottom,
+        })
+    }
+
+    /// Merges all the tables into the deepest level holding any, or level 1.
+    pub(super) fn all(levels: &[Vec<Arc<Table>>]) -> Self {
+        let output_level = levels
+            .iter()
+            .rposition(|tables| !tables.is_empty())
+            .unwrap_or(1)
+            .max(1);
+        let mut runs: Vec<_> = levels[0].iter().map(|table| vec![table.clone()]).collect();
+        runs.extend(levels[1..].iter().cloned());
+        Compaction {
+            runs,
+            output_level,
+            bottom: true,
+        }
+    }
+
+    /// Returns the tables merged.
+    pub(super) fn inputs(&self) -> impl Iterator<Item = &Arc<Table>> {
+        self.runs.iter().flatten()
+    }
+
+    /// Merges the tables into new tables.
+    pub(super) fn run(
+        &self,
+        path: &Path,
+        options: &LsmOptions,
+        next_number: &AtomicU64,
+    ) -> Result<Vec<Arc<Table>>> {
+        let runs = self
+            .runs
+            .iter()
+            .map(|run| level_iter(run.clone(), Unbounded))
+            .collect();
+        write_tables(
+            MergeIter::new(runs)?,
+            path,
+            options,
+            next_number,
+            options.table_size,
+            self.bottom,
+        )
+    }
+}
+
+/// Writes the entries, in key order, to new tables of about `table_size` bytes.
+///
+/// Removed and expired keys are dropped if `drop_deleted` is set. Tables written
+/// before a failure are removed.
+pub(super) fn write_tables(
+    entries: impl Iterator<Item = Result<(Vec<u8>, Entry)>>,
+    path: &Path,
+    options: &LsmOptions,
+    next_number: &AtomicU64,
+    table_size: u64,
+    drop_deleted: bool,
+) -> Result<Vec<Arc<Table>>> {
+    let mut tables = Vec::new();
+    let res = (|| {
+        let now = now_millis();
+        let mut builder: Option<TableBuilder> = None;
+        for item in entries {
+            let (key, entry) = item?;
+            if drop_deleted && !entry.is_live(now) {
+                continue;
+            }
+            if builder.is_none() {
+                builder = Some(TableBuilder::create(
+                    path,
+                    next_number.fetch_add(1, Ordering::SeqCst),
+                    options.block_size,
+                    options.bits_per_key,
+                )?);
+            }
+            let table = builder.as_mut().unwrap();
+            table.add(key, entry)?;
+            if table.size() >= table_size {
+                tables.push(Arc::new(builder.take().unwrap().finish()?));
+            }
+        }
+        if let Some(table) = builder {
+            tables.push(Arc::new(table.finish()?));
+        }
+        Ok(())
+    })();
+    match res {
+        Ok(()) => Ok(tables),
+        Err(e) => {
+            for table in tables {
+                table.mark_obsolete();
+            }
+            Err(e)
+        }
+    }
+}
+
+/// Returns the levels without the tables merged by the compaction, and with the new
+/// tables in its output level.
+pub(super) fn apply(
+    levels: &[Vec<Arc<Table>>],
+    compaction: &Compaction,
+    outputs: &[Arc<Table>],
+) -> Vec<Vec<Arc<Table>>> {
+    let merged: Vec<u64> = compaction.inputs().map(|table| table.number()).collect();
+    let mut levels: Vec<Vec<Arc<Table>>> = levels
+        .iter()
+        .map(|tables| {
+            tables
+                .iter()
+                .filter(|table| !merged.contains(&table.number()))
+                .cloned()
+                .collect()
+        })
+        .collect();
+    let output = &mut levels[compaction.output_level];
+    output.extend(outputs.iter().cloned());
+    output.sort_by(|a, b| a.first_key().cmp(b.first_key()));
+    levels
+}
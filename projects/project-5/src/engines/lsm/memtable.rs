@@ -0,0 +1,91 @@
+use super::Entry;
+use crossbeam_skiplist::SkipMap;
+use std::ops::Bound::{self, Excluded, Unbounded};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+/// The latest writes of keys in memory, also appended to a write-ahead log.
+///
+/// The memtable is read concurrently with the writes to it. Once full, it is frozen
+/// and written to a table of level 0 in the background.
+pub(super) struct MemTable {
+    map: SkipMap<Vec<u8>, Entry>,
+    // approximate number of bytes of the keys and entries
+    size: AtomicU64,
+    // number of the write-ahead log of the writes
+    wal: u64,
+}
+
+impl MemTable {
+    pub(super) fn new(wal: u64) -> Self {
+        MemTable {
+            map: SkipMap::new(),
+            size: AtomicU64::new(0),
+            wal,
+        }
+    }
+
+    /// Returns the entry of the key, if it has been written to the memtable.
+    pub(super) fn get(&self, key: &[u8]) -> Option<Entry> {
+        self.map.get(key).map(|entry| entry.value().clone())
+    }
+
+    /// Puts the entry of the key, replacing the previous one.
+    ///
+    /// Only the writer of the engine writes to the memtable.
+    pub(super) fn insert(&self, key: Vec<u8>, entry: Entry) {
+        let size = key.len() as u64 + entry.size();
+        self.map.insert(key, entry);
+        self.size.fetch_add(size, Ordering::SeqCst);
+    }
+
+    /// Returns the approximate number of bytes of the writes, counting the replaced
+    /// ones.
+    pub(super) fn size(&self) -> u64 {
+        self.size.load(Ordering::SeqCst)
+    }
+
+    pub(super) fn is_empty(&self) -> bool {
+        self.map.is_empty()
+    }
+
+    /// Returns the number of the write-ahead log of the writes.
+    pub(super) fn wal(&self) -> u64 {
+        self.wal
+    }
+}
+
+/// Iterator over the entries of a memtable from a key on, in key order.
+///
+/// Each entry is looked up after the previous one, so keys written during the
+/// iteration may or may not be seen.
+pub(super) struct MemTableIter {
+    mem: Arc<MemTable>,
+    // bound of the following entries, or `None` once they have all been read
+    next: Option<Bound<Vec<u8>>>,
+}
+
+impl MemTableIter {
+    pub(super) fn new(mem: Arc<MemTable>, start: Bound<Vec<u8>>) -> Self {
+        MemTableIter {
+            mem,
+            next: Some(start),
+        }
+    }
+}
+
+impl Iterator for MemTableIter {
+    type Item = (Vec<u8>, Entry);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let next = self.next.take()?;
+        let (key, entry) = self
+            .mem
+            .map
+            .range((next, Unbounded))
+            .next()
+            .map(|entry| (entry.key().clone(), entry.value().clone()))?;
+        self.next = Some(Excluded(key.clone()));
+        Some((key, entry))
+    }
+}
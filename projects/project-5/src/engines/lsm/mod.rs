@@ -0,0 +1,1103 @@
+use std::collections::HashSet;
+use std::ffi::OsStr;
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::ops::Bound::{self, Excluded, Included, Unbounded};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use crossbeam::channel::{self, Receiver, Sender};
+use serde::{Deserialize, Serialize};
+use tokio::prelude::*;
+use tokio::sync::oneshot;
+
+use self::compaction::{apply, level_iter, write_tables, Compaction, EntryIter, MergeIter, LEVELS};
+use self::memtable::{MemTable, MemTableIter};
+use self::sstable::{Table, TableIter};
+use self::wal::Wal;
+use super::{
+    deadline_after, is_expired, now_millis, BatchOp, Durability, GroupSync, KvsEngine,
+    PeriodicTask, WriteBatch,
+};
+use crate::thread_pool::ThreadPool;
+use crate::vfs::{OsVfs, Vfs};
+use crate::{KvsError, Result};
+
+mod bloom;
+mod compaction;
+mod memtable;
+mod sstable;
+mod wal;
+
+const DEFAULT_MEMTABLE_SIZE: u64 = 4 * 1024 * 1024;
+const DEFAULT_TABLE_SIZE: u64 = 2 * 1024 * 1024;
+const DEFAULT_BLOCK_SIZE: usize = 4 * 1024;
+const DEFAULT_BITS_PER_KEY: usize = 10;
+const DEFAULT_LEVEL0_TABLES: usize = 4;
+const DEFAULT_LEVEL_SIZE_BASE: u64 = 10 * 1024 * 1024;
+
+/// Name of the file listing the tables of every level.
+const MANIFEST_FILE: &str = "MANIFEST";
+
+/// Length of the header in front of every framed record: the payload length and
+/// the CRC32 checksum of the payload, both little-endian `u32`s.
+const FRAME_HEADER_LEN: u64 = 8;
+
+/// Options to tune the memtable, the tables and the compactions of an
+/// `LsmKvsEngine`.
+///
+/// ```rust
+/// # use kvs::{LsmKvsEngine, LsmOptions, Result};
+/// # use kvs::thread_pool::RayonThreadPool;
+/// # fn try_main() -> Result<()> {
+/// use std::env::current_dir;
+/// let engine: LsmKvsEngine<RayonThreadPool> = LsmKvsEngine::open_with_options(
+///     current_dir()?,
+///     4,
+///     LsmOptions::new()
+///         .memtable_size(16 * 1024 * 1024)
+///         .bits_per_key(12),
+/// )?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Clone)]
+pub struct LsmOptions {
+    memtable_size: u64,
+    table_size: u64,
+    block_size: usize,
+    bits_per_key: usize,
+    level0_tables: usize,
+    level_size_base: u64,
+    durability: Durability,
+}
+
+impl LsmOptions {
+    /// Creates the default options.
+    ///
+    /// Memtables are written to tables once they hold 4 MiB of writes, and tables
+    /// are cut in blocks of 4 KiB and at 2 MiB by compactions. Level 0 is compacted
+    /// once it has 4 tables, level 1 once it holds 10 MiB and every following level
+    /// once it holds ten times more than the previous one. Writes are not synced to
+    /// disk.
+    pub fn new() -> Self {
+        LsmOptions {
+            memtable_size: DEFAULT_MEMTABLE_SIZE,
+            table_size: DEFAULT_TABLE_SIZE,
+            block_size: DEFAULT_BLOCK_SIZE,
+            bits_per_key: DEFAULT_BITS_PER_KEY,
+            level0_tables: DEFAULT_LEVEL0_TABLES,
+            level_size_base: DEFAULT_LEVEL_SIZE_BASE,
+            durability: Durability::None,
+        }
+    }
+
+    /// Sets how many bytes of writes the memtable holds before it is written to a
+    /// table.
+    pub fn memtable_size(&mut self, bytes: u64) -> &mut Self {
+        self.memtable_size = bytes;
+        self
+    }
+
+    /// Sets the size of the tables written by compactions.
+    pub fn table_size(&mut self, bytes: u64) -> &mut Self {
+        self.table_size = bytes;
+        self
+    }
+
+    /// Sets the size of the blocks of the tables, read as a whole by lookups.
+    pub fn block_size(&mut self, bytes: usize) -> &mut Self {
+        self.block_size = bytes;
+        self
+    }
+
+    /// Sets how many bits per key the Bloom filters of the tables have.
+    pub fn bits_per_key(&mut self, bits: usize) -> &mut Self {
+        self.bits_per_key = bits;
+        self
+    }
+
+    /// Sets how many tables level 0 has before they are compacted into level 1.
+    pub fn level0_tables(&mut self, tables: usize) -> &mut Self {
+        self.level0_tables = tables.max(1);
+        self
+    }
+
+    /// Sets the size of level 1 above which it is compacted into level 2.
+    pub fn level_size_base(&mut self, bytes: u64) -> &mut Self {
+        self.level_size_base = bytes.max(1);
+        self
+    }
+
+    /// Sets how durable a write must be before it is acknowledged.
+    pub fn durability(&mut self, durability: Durability) -> &mut Self {
+        self.durability = durability;
+        self
+    }
+}
+
+impl Default for LsmOptions {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// A key value storage engine built on a log-structured merge-tree.
+///
+/// Writes go to a write-ahead log and to a memtable in memory. Once full, the
+/// memtable is frozen, a new one takes the following writes, and a background
+/// thread writes the frozen one to a sorted table file of level 0 before removing
+/// its log. Tables, named after increasing numbers with an `sst` extension name,
+/// have an index of their blocks and a Bloom filter of their keys, so a lookup
+/// reads at most one block of the tables whose filter may contain the key.
+///
+/// Tables are organized in levels by leveled compaction: the tables of level 0
+/// may overlap and are merged into level 1, while the tables of every following
+/// level have distinct key ranges and are merged one at a time into the next level
+/// once the level outgrows its target size. The `MANIFEST` file lists the tables of
+/// every level and is replaced atomically after each flush or compaction.
+///
+/// Unlike `KvStore`, only the memtables are held in memory, so the keys may not
+/// fit in memory. Keys set with a time to live carry their expiry deadline, and
+/// expired or removed keys are dropped when compactions reach the last level
+/// holding them.
+///
+/// ```rust
+/// # use kvs::{LsmKvsEngine, Result};
+/// # use kvs::thread_pool::RayonThreadPool;
+/// # use tokio::prelude::*;
+/// # fn try_main() -> Result<()> {
+/// use std::env::current_dir;
+/// use kvs::KvsEngine;
+/// let engine: LsmKvsEngine<RayonThreadPool> = LsmKvsEngine::open(current_dir()?, 2)?;
+/// engine.set("key".to_owned(), "value".to_owned()).wait()?;
+/// let val = engine.get("key".to_owned()).wait()?;
+/// assert_eq!(val, Some("value".to_owned()));
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone)]
+pub struct LsmKvsEngine<P: ThreadPool> {
+    shared: Arc<Shared>,
+    writer: Arc<Mutex<LsmWriter>>,
+    group_sync: Arc<GroupSync>,
+    thread_pool: P,
+    // stops the background worker when the last clone is dropped
+    _worker: Arc<WorkerHandle>,
+    // stops the background syncer when the last clone is dropped
+    _syncer: Option<Arc<PeriodicTask>>,
+}
+
+impl<P: ThreadPool> LsmKvsEngine<P> {
+    /// Opens an `LsmKvsEngine` with the given path.
+    ///
+    /// This will create a new directory if the given one does not exist.
+    ///
+    /// `concurrency` specifies the number of threads of the thread pool.
+    ///
+    /// The writes of the write-ahead logs not written to tables yet are replayed
+    /// and written to a table. A torn record at the end of a log, left by a crash in
+    /// the middle of a write, is ignored.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::Corruption` if a record in the middle of a log, or the
+    /// index or filter of a table, fails its checksum.
+    ///
+    /// It propagates I/O or deserialization errors during the recovery.
+    pub fn open(path: impl Into<PathBuf>, concurrency: u32) -> Result<Self> {
+        Self::open_with_options(path, concurrency, &LsmOptions::new())
+    }
+
+    /// Opens an `LsmKvsEngine` with the given path and options.
+    ///
+    /// See `LsmKvsEngine::open` for the errors.
+    pub fn open_with_options(
+        path: impl Into<PathBuf>,
+        concurrency: u32,
+        options: &LsmOptions,
+    ) -> Result<Self> {
+        let path = path.into();
+        fs::create_dir_all(&path)?;
+
+        let manifest = Manifest::load(&path)?.unwrap_or_default();
+        let (table_numbers, wal_numbers) = sorted_file_numbers(&path)?;
+        let last_number = table_numbers.iter().chain(&wal_numbers).max().unwrap_or(&0);
+        let next_number = AtomicU64::new(manifest.next_number.max(last_number + 1));
+
+        // tables written by a flush or compaction interrupted before the manifest
+        // listed them
+        let listed: HashSet<u64> = manifest.levels.iter().flatten().cloned().collect();
+        for number in table_numbers.iter().filter(|n| !listed.contains(n)) {
+            fs::remove_file(table_path(&path, *number))?;
+        }
+        let mut levels = vec![Vec::new(); LEVELS];
+        for (tables, numbers) in levels.iter_mut().zip(&manifest.levels) {
+            for &number in numbers {
+                tables.push(Arc::new(Table::open(&path, number)?));
+            }
+        }
+
+        let mem = Arc::new(MemTable::new(0));
+        for &number in wal_numbers.iter().filter(|&&n| n >= manifest.log_number) {
+            wal::replay(&path, number, &mem)?;
+        }
+        if !mem.is_empty() {
+            let entries = MemTableIter::new(mem, Unbounded).map(Ok);
+            for table in write_tables(entries, &path, options, &next_number, u64::MAX, false)? {
+                levels[0].insert(0, table);
+            }
+        }
+
+        let number = next_number.fetch_add(1, Ordering::SeqCst);
+        let wal = Wal::create(&path, number)?;
+        Manifest::new(next_number.load(Ordering::SeqCst), number, &levels).save(&path)?;
+        for &number in &wal_numbers {
+            fs::remove_file(wal_path(&path, number))?;
+        }
+
+        let shared = Arc::new(Shared {
+            path,
+            options: options.clone(),
+            state: RwLock::new(Arc::new(State {
+                mem: Arc::new(MemTable::new(number)),
+                imms: Vec::new(),
+                levels,
+            })),
+            next_number,
+        });
+
+        let (sender, receiver) = channel::unbounded();
+        let worker = Worker {
+            shared: Arc::clone(&shared),
+            log_number: number,
+        };
+        let thread = thread::Builder::new()
+            .name("lsm-compactor".to_owned())
+            .spawn(move || worker.run(receiver))?;
+        // compacts the levels left in need of it
+        if sender.send(WorkerMsg::Flush).is_err() {
+            error!("Worker is stopped");
+        }
+        let worker = Arc::new(WorkerHandle {
+            sender: sender.clone(),
+            thread: Some(thread),
+        });
+
+        let writer = Arc::new(Mutex::new(LsmWriter {
+            wal,
+            written: 0,
+            shared: Arc::clone(&shared),
+            worker: sender,
+        }));
+        let syncer = match options.durability {
+            Durability::Periodic(interval) => {
+                let writer = Arc::clone(&writer);
+                let syncer = PeriodicTask::spawn("lsm-syncer", interval, move || {
+                    let res = writer.lock().unwrap().wal.sync_handle();
+                    if let Err(e) = res.and_then(|file| Ok(file.sync_data()?)) {
+                        error!("Periodic sync failed: {}", e);
+                    }
+                })?;
+                Some(Arc::new(syncer))
+            }
+            _ => None,
+        };
+
+        Ok(LsmKvsEngine {
+            shared,
+            writer,
+            group_sync: Arc::new(GroupSync::default()),
+            thread_pool: P::new(concurrency)?,
+            _worker: worker,
+            _syncer: syncer,
+        })
+    }
+
+    /// Writes the memtable to a table and merges all the tables into a single level
+    /// in the background thread, dropping removed and expired keys.
+    ///
+    /// The returned future completes when the compaction finishes.
+    pub fn compact(&self) -> Box<dyn Future<Item = (), Error = KvsError> + Send> {
+        let (tx, rx) = oneshot::channel();
+        self.writer.lock().unwrap().request_compaction(tx);
+        Box::new(
+            rx.map_err(|e| KvsError::StringError(format!("{}", e)))
+                .flatten(),
+        )
+    }
+
+    /// Runs `prepare` on the current state and writes what it returns while holding
+    /// the writer lock.
+    ///
+    /// The returned future completes once the writes are as durable as the options
+    /// require.
+    fn write<F>(&self, prepare: F) -> Box<dyn Future<Item = (), Error = KvsError> + Send>
+    where
+        F: FnOnce(&State) -> Result<Vec<(Vec<u8>, Entry)>> + Send + 'static,
+    {
+        let writer = self.writer.clone();
+        let group_sync = self.group_sync.clone();
+        let durability = self.shared.options.durability;
+        let (tx, rx) = oneshot::channel();
+        self.thread_pool.spawn(move || {
+            let res = (|| {
+                let seq = {
+                    let mut writer = writer.lock().unwrap();
+                    let writes = prepare(&writer.shared.current())?;
+                    if writes.is_empty() {
+                        return Ok(());
+                    }
+                    writer.write(writes)?
+                };
+                if durability == Durability::GroupCommit {
+                    group_sync.wait(seq, || {
+                        let (file, written) = {
+                            let writer = writer.lock().unwrap();
+                            (writer.wal.sync_handle()?, writer.written)
+                        };
+                        file.sync_data()?;
+                        Ok(written)
+                    })?;
+                }
+                Ok(())
+            })();
+            if tx.send(res).is_err() {
+                error!("Receiving end is dropped");
+            }
+        });
+        Box::new(
+            rx.map_err(|e| KvsError::StringError(format!("{}", e)))
+                .flatten(),
+        )
+    }
+
+    /// Runs `f` on the current state in the thread pool.
+    fn read<F, T>(&self, f: F) -> Box<dyn Future<Item = T, Error = KvsError> + Send>
+    where
+        F: FnOnce(&State) -> Result<T> + Send + 'static,
+        T: Send + 'static,
+    {
+        let shared = self.shared.clone();
+        let (tx, rx) = oneshot::channel();
+        self.thread_pool.spawn(move || {
+            if tx.send(f(&shared.current())).is_err() {
+                error!("Receiving end is dropped");
+            }
+        });
+        Box::new(
+            rx.map_err(|e| KvsError::StringError(format!("{}", e)))
+                .flatten(),
+        )
+    }
+}
+
+impl<P: ThreadPool> KvsEngine for LsmKvsEngine<P> {
+    /// Sets the value of a key.
+    ///
+    /// If the key already exists, the previous value will be overwritten.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O or serialization errors during writing the log.
+    fn set_bytes(
+        &self,
+        key: Vec<u8>,
+        value: Vec<u8>,
+    ) -> Box<dyn Future<Item = (), Error = KvsError> + Send> {
+        self.write(move |_| Ok(vec![(key, Entry::value(value, None))]))
+    }
+
+    /// Sets the value of a key that expires after the given time to live.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O or serialization errors during writing the log.
+    fn set_with_ttl_bytes(
+        &self,
+        key: Vec<u8>,
+        value: Vec<u8>,
+        ttl: Duration,
+    ) -> Box<dyn Future<Item = (), Error = KvsError> + Send> {
+        let expires_at = deadline_after(ttl);
+        self.write(move |_| Ok(vec![(key, Entry::value(value, Some(expires_at)))]))
+    }
+
+    /// Gets the value of a given key.
+    ///
+    /// Returns `None` if the given key does not exist or has expired.
+    fn get_bytes(
+        &self,
+        key: Vec<u8>,
+    ) -> Box<dyn Future<Item = Option<Vec<u8>>, Error = KvsError> + Send> {
+        self.read(move |state| state.get_value(&key))
+    }
+
+    /// Removes a given key.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::KeyNotFound` if the given key is not found.
+    ///
+    /// It propagates I/O or serialization errors during writing the log.
+    fn remove_bytes(&self, key: Vec<u8>) -> Box<dyn Future<Item = (), Error = KvsError> + Send> {
+        self.write(move |state| match state.get_value(&key)? {
+            Some(_) => Ok(vec![(key, Entry::Tombstone)]),
+            None => Err(KvsError::KeyNotFound),
+        })
+    }
+
+    /// Applies all writes of the batch atomically.
+    ///
+    /// Only the last write of each key is kept, and the writes are appended to the
+    /// log as a single record.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O or serialization errors during writing the log.
+    fn write_batch(
+        &self,
+        batch: WriteBatch,
+    ) -> Box<dyn Future<Item = (), Error = KvsError> + Send> {
+        self.write(move |_| Ok(batch_entries(batch)))
+    }
+
+    /// Sets or removes a key only if its current value is `expected`.
+    ///
+    /// The value is compared and written while holding the writer lock.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::CasConflict` with the current value if it is not the
+    /// expected one.
+    ///
+    /// It propagates I/O or serialization errors during writing the log.
+    fn compare_and_swap_bytes(
+        &self,
+        key: Vec<u8>,
+        expected: Option<Vec<u8>>,
+        new: Option<Vec<u8>>,
+    ) -> Box<dyn Future<Item = (), Error = KvsError> + Send> {
+        self.write(move |state| {
+            let current = state.get_value(&key)?;
+            match (current, new) {
+                (current, _) if current != expected => Err(KvsError::CasConflict { current }),
+                (None, None) => Ok(Vec::new()),
+                (_, Some(value)) => Ok(vec![(key, Entry::value(value, None))]),
+                (_, None) => Ok(vec![(key, Entry::Tombstone)]),
+            }
+        })
+    }
+
+    /// Sets the value of a key that expires after the given time to live, only if
+    /// the key does not exist.
+    ///
+    /// The key is checked and written while holding the writer lock.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::CasConflict` with the current value if the key exists.
+    ///
+    /// It propagates I/O or serialization errors during writing the log.
+    fn set_if_absent_with_ttl_bytes(
+        &self,
+        key: Vec<u8>,
+        value: Vec<u8>,
+        ttl: Duration,
+    ) -> Box<dyn Future<Item = (), Error = KvsError> + Send> {
+        let expires_at = deadline_after(ttl);
+        self.write(move |state| match state.get_value(&key)? {
+            Some(current) => Err(KvsError::CasConflict {
+                current: Some(current),
+            }),
+            None => Ok(vec![(key, Entry::value(value, Some(expires_at)))]),
+        })
+    }
+
+    /// Applies the batch only if the keys read have the given values.
+    ///
+    /// Like `compare_and_swap_bytes`, the values are compared and the batch is
+    /// written while holding the writer lock.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::TransactionConflict` if any key has another value.
+    ///
+    /// It propagates I/O or serialization errors during writing the log.
+    fn commit_transaction(
+        &self,
+        reads: Vec<(Vec<u8>, Option<Vec<u8>>)>,
+        batch: WriteBatch,
+    ) -> Box<dyn Future<Item = (), Error = KvsError> + Send> {
+        self.write(move |state| {
+            for (key, value) in &reads {
+                if state.get_value(key)? != *value {
+                    return Err(KvsError::TransactionConflict);
+                }
+            }
+            Ok(batch_entries(batch))
+        })
+    }
+
+    /// Gets the key-value pairs with keys within the given bounds, in key order.
+    ///
+    /// At most `limit` pairs are returned if it is given. The memtables and the
+    /// tables are merged as they are when the scan starts.
+    fn scan_bytes(
+        &self,
+        start: Bound<Vec<u8>>,
+        end: Bound<Vec<u8>>,
+        limit: Option<usize>,
+    ) -> Box<dyn Future<Item = Vec<(Vec<u8>, Vec<u8>)>, Error = KvsError> + Send> {
+        self.read(move |state| {
+            let limit = limit.unwrap_or(usize::MAX);
+            let pairs = state.scan(start, end, limit)?;
+            Ok(pairs
+                .into_iter()
+                .map(|(key, value, _)| (key, value))
+                .collect())
+        })
+    }
+}
+
+/// Returns the entries written by the last write of each key of the batch.
+fn batch_entries(batch: WriteBatch) -> Vec<(Vec<u8>, Entry)> {
+    batch
+        .into_last_ops()
+        .into_iter()
+        .map(|op| match op {
+            BatchOp::Set { key, value } => (key, Entry::value(value, None)),
+            BatchOp::Remove { key } => (key, Entry::Tombstone),
+        })
+        .collect()
+}
+
+/// The latest write of a key in a memtable or a table.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+enum Entry {
+    Value {
+        value: Vec<u8>,
+        expires_at: Option<u64>,
+    },
+    /// The key has been removed
+    Tombstone,
+}
+
+impl Entry {
+    fn value(value: Vec<u8>, expires_at: Option<u64>) -> Entry {
+        Entry::Value { value, expires_at }
+    }
+
+    /// Returns the approximate number of bytes of the entry.
+    fn size(&self) -> u64 {
+        match self {
+            Entry::Value { value, .. } => value.len() as u64,
+            Entry::Tombstone => 0,
+        }
+    }
+
+    /// Whether the entry is a value that has not expired at `now`.
+    fn is_live(&self, now: u64) -> bool {
+        match self {
+            Entry::Value { expires_at, .. } => !is_expired(*expires_at, now),
+            Entry::Tombstone => false,
+        }
+    }
+}
+
+/// The memtables and tables read by the engine.
+///
+/// It is replaced as a whole when a memtable is frozen or tables are written, so
+/// readers keep reading the tables of the state they started with.
+struct State {
+    mem: Arc<MemTable>,
+    // frozen memtables waiting to be written to tables, newest first
+    imms: Vec<Arc<MemTable>>,
+    // the tables of every level, newest first in level 0 and in key order in the
+    // other levels
+    levels: Vec<Vec<Arc<Table>>>,
+}
+
+impl State {
+    /// Returns the latest entry of the key, looking at the memtables then at the
+    /// tables from the newest to the oldest.
+    fn get(&self, key: &[u8]) -> Result<Option<Entry>> {
+        let mems = std::iter::once(&self.mem).chain(&self.imms);
+        for mem in mems {
+            if let Some(entry) = mem.get(key) {
+                return Ok(Some(entry));
+            }
+        }
+        for table in &self.levels[0] {
+            if let Some(entry) = table.get(key)? {
+                return Ok(Some(entry));
+            }
+        }
+        for tables in &self.levels[1..] {
+            let i = tables.partition_point(|table| table.last_key() < key);
+            if let Some(table) = tables.get(i) {
+                if let Some(entry) = table.get(key)? {
+                    return Ok(Some(entry));
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    /// Returns the value of the key, or `None` if it has been removed or has expired.
+    fn get_value(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        Ok(match self.get(key)? {
+            Some(Entry::Value { value, expires_at }) if !is_expired(expires_at, now_millis()) => {
+                Some(value)
+            }
+            _ => None,
+        })
+    }
+
+    /// Gets up to `limit` keys within the bounds, with their values and expiry
+    /// deadlines, in key order.
+    #[allow(clippy::type_complexity)]
+    fn scan(
+        &self,
+        start: Bound<Vec<u8>>,
+        end: Bound<Vec<u8>>,
+        limit: usize,
+    ) -> Result<Vec<(Vec<u8>, Vec<u8>, Option<u64>)>> {
+        let now = now_millis();
+        let mut pairs = Vec::new();
+        for item in self.iter(start)? {
+            if pairs.len() >= limit {
+                break;
+            }
+            let (key, entry) = item?;
+            if !is_before_end(&end, &key) {
+                break;
+            }
+            match entry {
+                Entry::Value { value, expires_at } if !is_expired(expires_at, now) => {
+                    pairs.push((key, value, expires_at))
+                }
+                _ => {}
+            }
+        }
+        Ok(pairs)
+    }
+
+    /// Returns an iterator over the latest entries of the keys from `start` on.
+    fn iter(&self, start: Bound<Vec<u8>>) -> Result<MergeIter> {
+        let mut sources: Vec<EntryIter> = Vec::new();
+        for mem in std::iter::once(&self.mem).chain(&self.imms) {
+            sources.push(Box::new(
+                MemTableIter::new(Arc::clone(mem), start.clone()).map(Ok),
+            ));
+        }
+        for table in &self.levels[0] {
+            sources.push(Box::new(TableIter::new(Arc::clone(table), start.clone())));
+        }
+        for tables in &self.levels[1..] {
+            sources.push(level_iter(tables.clone(), start.clone()));
+        }
+        MergeIter::new(sources)
+    }
+}
+
+/// What the writer and the background worker share.
+struct Shared {
+    // directory for the logs, the tables and the manifest
+    path: PathBuf,
+    options: LsmOptions,
+    state: RwLock<Arc<State>>,
+    // number of the next write-ahead log or table
+    next_number: AtomicU64,
+}
+
+impl Shared {
+    fn current(&self) -> Arc<State> {
+        Arc::clone(&self.state.read().unwrap())
+    }
+
+    /// Replaces the state with the result of `f` on the current one.
+    fn update<F>(&self, f: F)
+    where
+        F: FnOnce(&State) -> State,
+    {
+        let mut state = self.state.write().unwrap();
+        *state = Arc::new(f(&state));
+    }
+}
+
+/// The only writer of the memtable and of its write-ahead log.
+struct LsmWriter {
+    wal: Wal,
+    // number of writes appended so far, for the group syncs
+    written: u64,
+    shared: Arc<Shared>,
+    worker: Sender<WorkerMsg>,
+}
+
+impl LsmWriter {
+    /// Appends the writes to the log as a single record and puts them in the
+    /// memtable, which is frozen once full.
+    ///
+    /// Returns the sequence number of the write.
+    fn write(&mut self, writes: Vec<(Vec<u8>, Entry)>) -> Result<u64> {
+        self.wal.append(&writes)?;
+        if self.shared.options.durability == Durability::EveryWrite {
+            self.wal.sync()?;
+        }
+        let mem = Arc::clone(&self.shared.current().mem);
+        for (key, entry) in writes {
+            mem.insert(key, entry);
+        }
+        self.written += 1;
+        if mem.size() >= self.shared.options.memtable_size {
+            // the write is done anyway, and switching is tried again by the next one
+            if let Err(e) = self.switch_memtable() {
+                error!("Switching memtable failed: {}", e);
+            }
+        }
+        Ok(self.written)
+    }
+
+    /// Freezes the memtable and starts a new one with a new log, then has the
+    /// frozen memtable written to a table.
+    fn switch_memtable(&mut self) -> Result<()> {
+        let number = self.shared.next_number.fetch_add(1, Ordering::SeqCst);
+        let wal = Wal::create(&self.shared.path, number)?;
+        if self.shared.options.durability != Durability::None {
+            // the syncs of the new log must cover the writes to the old one too
+            self.wal.sync()?;
+        }
+        self.wal = wal;
+        self.shared.update(|state| {
+            let mut imms = state.imms.clone();
+            imms.insert(0, Arc::clone(&state.mem));
+            State {
+                mem: Arc::new(MemTable::new(number)),
+                imms,
+                levels: state.levels.clone(),
+            }
+        });
+        if self.worker.send(WorkerMsg::Flush).is_err() {
+            error!("Worker is stopped");
+        }
+        Ok(())
+    }
+
+    /// Freezes the memtable and asks the worker to merge all the tables.
+    fn request_compaction(&mut self, done: oneshot::Sender<Result<()>>) {
+        if !self.shared.current().mem.is_empty() {
+            if let Err(e) = self.switch_memtable() {
+                if done.send(Err(e)).is_err() {
+                    error!("Receiving end is dropped");
+                }
+                return;
+            }
+        }
+        if self.worker.send(WorkerMsg::Compact(done)).is_err() {
+            error!("Worker is stopped");
+        }
+    }
+}
+
+/// Message sent to the background worker.
+enum WorkerMsg {
+    /// Write the frozen memtables to tables and run the compactions needed
+    Flush,
+    /// Also merge all the tables and send the result to the sender
+    Compact(oneshot::Sender<Result<()>>),
+    Shutdown,
+}
+
+/// The background worker writing frozen memtables to tables and compacting them.
+///
+/// It is the only one changing the tables of the levels, so they do not change
+/// while it compacts them.
+struct Worker {
+    shared: Arc<Shared>,
+    // number of the oldest write-ahead log not written to tables yet
+    log_number: u64,
+}
+
+impl Worker {
+    /// Runs flushes and compactions on request until the worker is shut down.
+    fn run(mut self, receiver: Receiver<WorkerMsg>) {
+        for msg in receiver {
+            match msg {
+                WorkerMsg::Flush => {
+                    if let Err(e) = self.flush() {
+                        error!("Flush failed: {}", e);
+                    }
+                }
+                WorkerMsg::Compact(done) => {
+                    let res = self.flush().and_then(|()| self.compact_all());
+                    if let Err(ref e) = res {
+                        error!("Compaction failed: {}", e);
+                    }
+                    if done.send(res).is_err() {
+                        error!("Receiving end is dropped");
+                    }
+                }
+                WorkerMsg::Shutdown => break,
+            }
+        }
+    }
+
+    /// Writes the frozen memtables to tables of level 0, the oldest first, then
+    /// compacts the levels in need of it.
+    fn flush(&mut self) -> Result<()> {
+        let shared = Arc::clone(&self.shared);
+        while let Some(mem) = shared.current().imms.last().cloned() {
+            let entries = MemTableIter::new(Arc::clone(&mem), Unbounded).map(Ok);
+            let tables = write_tables(
+                entries,
+                &shared.path,
+                &shared.options,
+                &shared.next_number,
+                u64::MAX,
+                false,
+            )?;
+            let mut levels = shared.current().levels.clone();
+            for table in tables {
+                levels[0].insert(0, table);
+            }
+            // the logs are numbered in the order of their memtables
+            self.save(mem.wal() + 1, &levels)?;
+            shared.update(|state| {
+                let mut imms = state.imms.clone();
+                imms.pop();
+                State {
+                    mem: Arc::clone(&state.mem),
+                    imms,
+                    levels,
+                }
+            });
+            let wal_path = wal_path(&shared.path, mem.wal());
+            if let Err(e) = fs::remove_file(&wal_path) {
+                error!("{:?} cannot be deleted: {}", wal_path, e);
+            }
+        }
+        while let Some(compaction) = Compaction::pick(&shared.current().levels, &shared.options) {
+            self.compact(&compaction)?;
+        }
+        Ok(())
+    }
+
+    /// Merges all the tables into a single level.
+    fn compact_all(&mut self) -> Result<()> {
+        let compaction = Compaction::all(&self.shared.current().levels);
+        if compaction.inputs().next().is_none() {
+            return Ok(());
+        }
+        self.compact(&compaction)
+    }
+
+    /// Runs the compaction and replaces its tables with the new ones.
+    ///
+    /// The files of the merged tables are removed once no reader uses them anymore.
+    fn compact(&mut self, compaction: &Compaction) -> Result<()> {
+        let shared = Arc::clone(&self.shared);
+        let outputs = compaction.run(&shared.path, &shared.options, &shared.next_number)?;
+        let levels = apply(&shared.current().levels, compaction, &outputs);
+        if let Err(e) = self.save(self.log_number, &levels) {
+            for table in &outputs {
+                table.mark_obsolete();
+            }
+            return Err(e);
+        }
+        shared.update(|state| State {
+            mem: Arc::clone(&state.mem),
+            imms: state.imms.clone(),
+            levels,
+        });
+        for table in compaction.inputs() {
+            table.mark_obsolete();
+        }
+        Ok(())
+    }
+
+    /// Saves the manifest with the given tables and oldest log to replay.
+    fn save(&mut self, log_number: u64, levels: &[Vec<Arc<Table>>]) -> Result<()> {
+        let next_number = self.shared.next_number.load(Ordering::SeqCst);
+        Manifest::new(next_number, log_number, levels).save(&self.shared.path)?;
+        self.log_number = log_number;
+        Ok(())
+    }
+}
+
+/// Handle to the background worker thread, shared by all clones of an
+/// `LsmKvsEngine`.
+///
+/// Dropping it stops the thread after the running flush or compaction finishes.
+/// The memtable is not written to a table, as its log is replayed by the next open.
+struct WorkerHandle {
+    sender: Sender<WorkerMsg>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Drop for WorkerHandle {
+    fn drop(&mut self) {
+        if self.sender.send(WorkerMsg::Shutdown).is_err() {
+            error!("Worker is stopped");
+        }
+        if let Some(thread) = self.thread.take() {
+            if thread.join().is_err() {
+                error!("Worker thread panicked");
+            }
+        }
+    }
+}
+
+/// The tables of every level and the logs to replay, saved after every flush and
+/// compaction.
+#[derive(Default, Serialize, Deserialize)]
+struct Manifest {
+    next_number: u64,
+    // the logs from this number on have not been written to tables
+    log_number: u64,
+    levels: Vec<Vec<u64>>,
+}
+
+impl Manifest {
+    fn new(next_number: u64, log_number: u64, levels: &[Vec<Arc<Table>>]) -> Self {
+        Manifest {
+            next_number,
+            log_number,
+            levels: levels
+                .iter()
+                .map(|tables| tables.iter().map(|table| table.number()).collect())
+                .collect(),
+        }
+    }
+
+    /// Loads the manifest, returning `None` if there is none yet.
+    fn load(path: &Path) -> Result<Option<Self>> {
+        let frame = match fs::read(path.join(MANIFEST_FILE)) {
+            Ok(frame) => frame,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        match decode_frame(&frame) {
+            Some(payload) => Ok(Some(bincode::deserialize(payload)?)),
+            None => Err(KvsError::StringError("Invalid manifest".to_owned())),
+        }
+    }
+
+    /// Replaces the manifest, so that either the old or the new one is found after a
+    /// crash.
+    ///
+    /// The new manifest is durable once this returns, so the logs it no longer
+    /// needs can be removed.
+    fn save(&self, path: &Path) -> Result<()> {
+        // the tables written since the last save must be found in the directory
+        // before they are listed
+        OsVfs.sync_dir(path)?;
+        let manifest_path = path.join(MANIFEST_FILE);
+        let tmp_path = manifest_path.with_extension("tmp");
+        let mut file = File::create(&tmp_path)?;
+        write_frame(&mut file, &bincode::serialize(self)?)?;
+        file.sync_all()?;
+        fs::rename(&tmp_path, &manifest_path)?;
+        OsVfs.sync_dir(path)?;
+        Ok(())
+    }
+}
+
+/// Returns the sorted numbers of the tables and of the write-ahead logs in the
+/// given directory.
+fn sorted_file_numbers(path: &Path) -> Result<(Vec<u64>, Vec<u64>)> {
+    let mut tables = Vec::new();
+    let mut wals = Vec::new();
+    for entry in fs::read_dir(path)? {
+        let path = entry?.path();
+        let number = path
+            .file_stem()
+            .and_then(OsStr::to_str)
+            .and_then(|s| s.parse::<u64>().ok());
+        match (number, path.extension().and_then(OsStr::to_str)) {
+            (Some(number), Some("sst")) => tables.push(number),
+            (Some(number), Some("wal")) => wals.push(number),
+            _ => {}
+        }
+    }
+    tables.sort_unstable();
+    wals.sort_unstable();
+    Ok((tables, wals))
+}
+
+/// Appends the payload framed with its length and checksum.
+///
+/// Returns the length of the frame.
+fn write_frame<W: Write>(writer: &mut W, payload: &[u8]) -> Result<u64> {
+    writer.write_all(&(payload.len() as u32).to_le_bytes())?;
+    writer.write_all(&crc32fast::hash(payload).to_le_bytes())?;
+    writer.write_all(payload)?;
+    Ok(FRAME_HEADER_LEN + payload.len() as u64)
+}
+
+/// Reads the next whole frame, header included.
+///
+/// `remaining` is the number of bytes left to read. Returns `None` if the frame is
+/// cut off before its end.
+fn read_frame<R: Read>(reader: &mut R, remaining: u64) -> Result<Option<Vec<u8>>> {
+    if remaining < FRAME_HEADER_LEN {
+        return Ok(None);
+    }
+    let mut header = [0; FRAME_HEADER_LEN as usize];
+    reader.read_exact(&mut header)?;
+    let payload_len = u32::from_le_bytes([header[0], header[1], header[2], header[3]]);
+    let len = FRAME_HEADER_LEN + u64::from(payload_len);
+    if remaining < len {
+        return Ok(None);
+    }
+    let mut frame = header.to_vec();
+    reader
+        .take(len - FRAME_HEADER_LEN)
+        .read_to_end(&mut frame)?;
+    Ok(Some(frame))
+}
+
+/// Checks the length and checksum of a whole frame and returns its payload.
+///
+/// Returns `None` if the frame is corrupted.
+fn decode_frame(frame: &[u8]) -> Option<&[u8]> {
+    if (frame.len() as u64) < FRAME_HEADER_LEN {
+        return None;
+    }
+    let (header, payload) = frame.split_at(FRAME_HEADER_LEN as usize);
+    let len = u32::from_le_bytes([header[0], header[1], header[2], header[3]]);
+    let checksum = u32::from_le_bytes([header[4], header[5], header[6], header[7]]);
+    if len as usize != payload.len() || crc32fast::hash(payload) != checksum {
+        return None;
+    }
+    Some(payload)
+}
+
+/// Whether the key is within the start bound.
+fn is_after_start(start: &Bound<Vec<u8>>, key: &[u8]) -> bool {
+    match start {
+        Included(start) => key >= start.as_slice(),
+        Excluded(start) => key > start.as_slice(),
+        Unbounded => true,
+    }
+}
+
+/// Whether the key is within the end bound.
+fn is_before_end(end: &Bound<Vec<u8>>, key: &[u8]) -> bool {
+    match end {
+        Included(end) => key <= end.as_slice(),
+        Excluded(end) => key < end.as_slice(),
+        Unbounded => true,
+    }
+}
+
+fn wal_path(dir: &Path, number: u64) -> PathBuf {
+    dir.join(format!("{}.wal", number))
+}
+
+fn table_path(dir: &Path, number: u64) -> PathBuf {
+    dir.join(format!("{}.sst", number))
+}
@@ -0,0 +1,352 @@
+use super::bloom::{self, BloomFilter};
+use super::{decode_frame, is_after_start, table_path, write_frame, Entry};
+use crate::{KvsError, Result};
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufWriter, Read, Seek, SeekFrom, Write};
+use std::ops::Bound::{self, Unbounded};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+
+/// Magic bytes at the start of every table file.
+const TABLE_MAGIC: &[u8; 6] = b"KVSSST";
+/// Version of the table format written by this version.
+const TABLE_VERSION: u16 = 1;
+/// Length of the table header: `TABLE_MAGIC` followed by a little-endian `u16`
+/// version.
+const TABLE_HEADER_LEN: u64 = 8;
+/// Length of the table footer: the offsets and lengths of the index and the filter,
+/// as little-endian `u64`s.
+const TABLE_FOOTER_LEN: u64 = 32;
+
+/// Where a data block is in its table, and the last key in it.
+#[derive(Serialize, Deserialize)]
+struct BlockHandle {
+    last_key: Vec<u8>,
+    offset: u64,
+    len: u64,
+}
+
+/// The index of the data blocks of a table, in key order.
+#[derive(Serialize, Deserialize)]
+struct TableIndex {
+    first_key: Vec<u8>,
+    blocks: Vec<BlockHandle>,
+}
+
+/// Writes the entries of a new table in key order.
+///
+/// A table starts with a magic and version header, followed by data blocks of the
+/// bincode-encoded keys and entries, the index of the blocks, the Bloom filter of
+/// the keys and a footer locating the index and the filter. Blocks, the index and
+/// the filter are framed with their length and a CRC32 checksum.
+pub(super) struct TableBuilder {
+    path: PathBuf,
+    number: u64,
+    writer: BufWriter<File>,
+    // offset of the next block
+    pos: u64,
+    block_size: usize,
+    bits_per_key: usize,
+    // the entries of the next block
+    block: Vec<(Vec<u8>, Entry)>,
+    block_bytes: usize,
+    first_key: Option<Vec<u8>>,
+    blocks: Vec<BlockHandle>,
+    hashes: Vec<u32>,
+}
+
+impl TableBuilder {
+    /// Creates the table with the given number.
+    pub(super) fn create(
+        path: &Path,
+        number: u64,
+        block_size: usize,
+        bits_per_key: usize,
+    ) -> Result<Self> {
+        let file = OpenOptions::new()
+            .create_new(true)
+            .write(true)
+            .open(table_path(path, number))?;
+        let mut writer = BufWriter::new(file);
+        writer.write_all(TABLE_MAGIC)?;
+        writer.write_all(&TABLE_VERSION.to_le_bytes())?;
+        Ok(TableBuilder {
+            path: path.to_owned(),
+            number,
+            writer,
+            pos: TABLE_HEADER_LEN,
+            block_size,
+            bits_per_key,
+            block: Vec::new(),
+            block_bytes: 0,
+            first_key: None,
+            blocks: Vec::new(),
+            hashes: Vec::new(),
+        })
+    }
+
+    /// Adds the entry of a key following the keys added before.
+    pub(super) fn add(&mut self, key: Vec<u8>, entry: Entry) -> Result<()> {
+        self.hashes.push(bloom::key_hash(&key));
+        if self.first_key.is_none() {
+            self.first_key = Some(key.clone());
+        }
+        self.block_bytes += key.len() + entry.size() as usize;
+        self.block.push((key, entry));
+        if self.block_bytes >= self.block_size {
+            self.finish_block()?;
+        }
+        Ok(())
+    }
+
+    /// Returns the approximate size of the table so far.
+    pub(super) fn size(&self) -> u64 {
+        self.pos + self.block_bytes as u64
+    }
+
+    /// Writes the index, the filter and the footer, syncs the table to disk and
+    /// opens it.
+    pub(super) fn finish(mut self) -> Result<Table> {
+        self.finish_block()?;
+        let index = TableIndex {
+            first_key: self.first_key.take().unwrap_or_default(),
+            blocks: self.blocks,
+        };
+        let index_offset = self.pos;
+        let index_len = write_frame(&mut self.writer, &bincode::serialize(&index)?)?;
+        let filter = BloomFilter::build(&self.hashes, self.bits_per_key);
+        let filter_offset = index_offset + index_len;
+        let filter_len = write_frame(&mut self.writer, &filter.to_bytes())?;
+        for n in &[index_offset, index_len, filter_offset, filter_len] {
+            self.writer.write_all(&n.to_le_bytes())?;
+        }
+        self.writer.flush()?;
+        self.writer.get_ref().sync_all()?;
+        Table::open(&self.path, self.number)
+    }
+
+    fn finish_block(&mut self) -> Result<()> {
+        let last_key = match self.block.last() {
+            Some((key, _)) => key.clone(),
+            None => return Ok(()),
+        };
+        let len = write_frame(&mut self.writer, &bincode::serialize(&self.block)?)?;
+        self.blocks.push(BlockHandle {
+            last_key,
+            offset: self.pos,
+            len,
+        });
+        self.pos += len;
+        self.block.clear();
+        self.block_bytes = 0;
+        Ok(())
+    }
+}
+
+/// An immutable sorted table of entries on disk.
+///
+/// The index and the filter are kept in memory, and data blocks are read on demand.
+/// A table compacted away is marked obsolete, and its file is removed once the last
+/// reader drops it.
+pub(super) struct Table {
+    path: PathBuf,
+    number: u64,
+    file: Mutex<File>,
+    size: u64,
+    first_key: Vec<u8>,
+    blocks: Vec<BlockHandle>,
+    filter: BloomFilter,
+    obsolete: AtomicBool,
+}
+
+impl Table {
+    /// Opens the table with the given number.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::Corruption` if the index or the filter fails its
+    /// checksum.
+    pub(super) fn open(path: &Path, number: u64) -> Result<Self> {
+        let path = table_path(path, number);
+        let mut file = File::open(&path)?;
+        let size = file.seek(SeekFrom::End(0))?;
+        let invalid = || KvsError::StringError(format!("Invalid table {}", number));
+        if size < TABLE_HEADER_LEN + TABLE_FOOTER_LEN {
+            return Err(invalid());
+        }
+        let mut header = [0; TABLE_HEADER_LEN as usize];
+        file.seek(SeekFrom::Start(0))?;
+        file.read_exact(&mut header)?;
+        if &header[..TABLE_MAGIC.len()] != TABLE_MAGIC {
+            return Err(invalid());
+        }
+        let version = u16::from_le_bytes([header[6], header[7]]);
+        if version != TABLE_VERSION {
+            return Err(KvsError::UnsupportedLogVersion(version));
+        }
+
+        let mut footer = [0; TABLE_FOOTER_LEN as usize];
+        file.seek(SeekFrom::Start(size - TABLE_FOOTER_LEN))?;
+        file.read_exact(&mut footer)?;
+        let mut fields = footer.chunks(8).map(|field| {
+            let mut bytes = [0; 8];
+            bytes.copy_from_slice(field);
+            u64::from_le_bytes(bytes)
+        });
+        let mut next_field = || fields.next().unwrap();
+        let (index_offset, index_len) = (next_field(), next_field());
+        let (filter_offset, filter_len) = (next_field(), next_field());
+        if index_offset + index_len > size || filter_offset + filter_len > size {
+            return Err(invalid());
+        }
+        let index: TableIndex =
+            bincode::deserialize(&read_payload(&mut file, number, index_offset, index_len)?)?;
+        let filter =
+            BloomFilter::from_bytes(read_payload(&mut file, number, filter_offset, filter_len)?)?;
+        Ok(Table {
+            path,
+            number,
+            file: Mutex::new(file),
+            size,
+            first_key: index.first_key,
+            blocks: index.blocks,
+            filter,
+            obsolete: AtomicBool::new(false),
+        })
+    }
+
+    pub(super) fn number(&self) -> u64 {
+        self.number
+    }
+
+    /// Returns the size of the table file.
+    pub(super) fn size(&self) -> u64 {
+        self.size
+    }
+
+    pub(super) fn first_key(&self) -> &[u8] {
+        &self.first_key
+    }
+
+    pub(super) fn last_key(&self) -> &[u8] {
+        self.blocks
+            .last()
+            .map_or(&self.first_key, |block| &block.last_key)
+    }
+
+    /// Returns whether some keys of the table are within `start..=end`.
+    pub(super) fn overlaps(&self, start: &[u8], end: &[u8]) -> bool {
+        self.first_key() <= end && self.last_key() >= start
+    }
+
+    /// Returns the entry of the key, if the table has one.
+    pub(super) fn get(&self, key: &[u8]) -> Result<Option<Entry>> {
+        if key < self.first_key() || !self.filter.may_contain(key) {
+            return Ok(None);
+        }
+        let i = self
+            .blocks
+            .partition_point(|block| block.last_key.as_slice() < key);
+        if i == self.blocks.len() {
+            return Ok(None);
+        }
+        let mut entries = self.read_block(i)?;
+        match entries.binary_search_by(|(entry_key, _)| entry_key.as_slice().cmp(key)) {
+            Ok(pos) => Ok(Some(entries.swap_remove(pos).1)),
+            Err(_) => Ok(None),
+        }
+    }
+
+    /// Marks the table as compacted away, so its file is removed once it is dropped.
+    pub(super) fn mark_obsolete(&self) {
+        self.obsolete.store(true, Ordering::SeqCst);
+    }
+
+    fn read_block(&self, i: usize) -> Result<Vec<(Vec<u8>, Entry)>> {
+        let block = &self.blocks[i];
+        let payload = {
+            let mut file = self.file.lock().unwrap();
+            read_payload(&mut file, self.number, block.offset, block.len)?
+        };
+        Ok(bincode::deserialize(&payload)?)
+    }
+}
+
+impl Drop for Table {
+    fn drop(&mut self) {
+        if self.obsolete.load(Ordering::SeqCst) {
+            if let Err(e) = fs::remove_file(&self.path) {
+                error!("Failed to remove table {}: {}", self.number, e);
+            }
+        }
+    }
+}
+
+/// Reads the framed payload at the given offset of the table.
+fn read_payload(file: &mut File, number: u64, offset: u64, len: u64) -> Result<Vec<u8>> {
+    let mut frame = vec![0; len as usize];
+    file.seek(SeekFrom::Start(offset))?;
+    file.read_exact(&mut frame)?;
+    match decode_frame(&frame) {
+        Some(payload) => Ok(payload.to_vec()),
+        None => Err(KvsError::Corruption {
+            gen: number,
+            offset,
+        }),
+    }
+}
+
+/// Iterator over the entries of a table from a key on, in key order.
+///
+/// Blocks are read one at a time as the iteration reaches them.
+pub(super) struct TableIter {
+    table: Arc<Table>,
+    // index of the next block to read
+    next_block: usize,
+    // the entries before it are skipped in the first block read
+    start: Bound<Vec<u8>>,
+    entries: std::vec::IntoIter<(Vec<u8>, Entry)>,
+}
+
+impl TableIter {
+    pub(super) fn new(table: Arc<Table>, start: Bound<Vec<u8>>) -> Self {
+        let next_block = table
+            .blocks
+            .partition_point(|block| !is_after_start(&start, &block.last_key));
+        TableIter {
+            table,
+            next_block,
+            start,
+            entries: Vec::new().into_iter(),
+        }
+    }
+}
+
+impl Iterator for TableIter {
+    type Item = Result<(Vec<u8>, Entry)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(entry) = self.entries.next() {
+                return Some(Ok(entry));
+            }
+            if self.next_block >= self.table.blocks.len() {
+                return None;
+            }
+            match self.table.read_block(self.next_block) {
+                Ok(mut entries) => {
+                    let start = std::mem::replace(&mut self.start, Unbounded);
+                    entries.retain(|(key, _)| is_after_start(&start, key));
+                    self.next_block += 1;
+                    self.entries = entries.into_iter();
+                }
+                Err(e) => {
+                    self.next_block = self.table.blocks.len();
+                    return Some(Err(e));
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,148 @@
+use super::memtable::MemTable;
+use super::{decode_frame, read_frame, wal_path, write_frame, Entry, FRAME_HEADER_LEN};
+use crate::vfs::{OsVfs, Vfs};
+use crate::{KvsError, Result};
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+/// Magic bytes at the start of every write-ahead log.
+const WAL_MAGIC: &[u8; 6] = b"KVSWAL";
+/// Version of the write-ahead log format written by this version.
+const WAL_VERSION: u16 = 1;
+/// Length of the write-ahead log header: `WAL_MAGIC` followed by a little-endian
+/// `u16` version.
+const WAL_HEADER_LEN: u64 = 8;
+/// Number of bytes after a record cut off by the end of the log in which another
+/// record is looked for, to tell a torn tail from a corrupted length.
+const TORN_TAIL_SCAN_LEN: u64 = 1024 * 1024;
+
+/// The write-ahead log of a memtable.
+///
+/// Every write, or batch of writes, is appended as a record of the bincode-encoded
+/// keys and entries, framed with its length and a CRC32 checksum, before it is put
+/// in the memtable.
+pub(super) struct Wal {
+    writer: BufWriter<File>,
+}
+
+impl Wal {
+    /// Creates the write-ahead log with the given number.
+    pub(super) fn create(path: &Path, number: u64) -> Result<Self> {
+        let file = OpenOptions::new()
+            .create_new(true)
+            .write(true)
+            .open(wal_path(path, number))?;
+        let mut writer = BufWriter::new(file);
+        writer.write_all(WAL_MAGIC)?;
+        writer.write_all(&WAL_VERSION.to_le_bytes())?;
+        writer.flush()?;
+        // the synced writes would be lost with the log if it wasn't in the directory
+        OsVfs.sync_dir(path)?;
+        Ok(Wal { writer })
+    }
+
+    /// Appends the writes as a single record and hands it to the OS.
+    pub(super) fn append(&mut self, writes: &[(Vec<u8>, Entry)]) -> Result<()> {
+        write_frame(&mut self.writer, &bincode::serialize(writes)?)?;
+        self.writer.flush()?;
+        Ok(())
+    }
+
+    /// Syncs the appended records to disk.
+    pub(super) fn sync(&self) -> Result<()> {
+        self.writer.get_ref().sync_data()?;
+        Ok(())
+    }
+
+    /// Returns a handle to sync the log without holding it.
+    pub(super) fn sync_handle(&self) -> Result<File> {
+        Ok(self.writer.get_ref().try_clone()?)
+    }
+}
+
+/// Puts the writes of the write-ahead log with the given number in the memtable.
+///
+/// A record torn by a crash at the end of the log is ignored, as its write was
+/// never acknowledged.
+///
+/// # Errors
+///
+/// It returns `KvsError::Corruption` if a record in the middle of the log fails its
+/// checksum, or if its length runs past the end of the log while whole records
+/// follow it.
+pub(super) fn replay(path: &Path, number: u64, mem: &MemTable) -> Result<()> {
+    let mut reader = BufReader::new(File::open(wal_path(path, number))?);
+    let len = reader.seek(SeekFrom::End(0))?;
+    reader.seek(SeekFrom::Start(0))?;
+    let mut header = [0; WAL_HEADER_LEN as usize];
+    match reader.read_exact(&mut header) {
+        Ok(()) => {}
+        // torn before the end of the header, so nothing was written after it
+        Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(()),
+        Err(e) => return Err(e.into()),
+    }
+    if &header[..WAL_MAGIC.len()] != WAL_MAGIC {
+        return Err(KvsError::StringError(format!(
+            "Invalid write-ahead log {}",
+            number
+        )));
+    }
+    let version = u16::from_le_bytes([header[6], header[7]]);
+    if version != WAL_VERSION {
+        return Err(KvsError::UnsupportedLogVersion(version));
+    }
+
+    let mut offset = WAL_HEADER_LEN;
+    while let Some(frame) = read_frame(&mut reader, len - offset)? {
+        let frame_end = offset + frame.len() as u64;
+        let payload = match decode_frame(&frame) {
+            Some(payload) => payload,
+            // a torn write of the last record
+            None if frame_end == len => break,
+            None => {
+                return Err(KvsError::Corruption {
+                    gen: number,
+                    offset,
+                })
+            }
+        };
+        let writes: Vec<(Vec<u8>, Entry)> = bincode::deserialize(payload)?;
+        for (key, entry) in writes {
+            mem.insert(key, entry);
+        }
+        offset = frame_end;
+    }
+    if offset < len && has_frame_after(&mut reader, offset)? {
+        return Err(KvsError::Corruption {
+            gen: number,
+            offset,
+        });
+    }
+    Ok(())
+}
+
+/// Returns whether a whole record starts shortly after the offset of a record cut
+/// off by the end of the log, in which case its length was corrupted rather than
+/// torn by a crash.
+fn has_frame_after<R: Read + Seek>(reader: &mut R, offset: u64) -> Result<bool> {
+    let mut tail = Vec::new();
+    reader.seek(SeekFrom::Start(offset + 1))?;
+    reader.take(TORN_TAIL_SCAN_LEN).read_to_end(&mut tail)?;
+    for start in 0..tail.len() {
+        let rest = &tail[start..];
+        if (rest.len() as u64) < FRAME_HEADER_LEN {
+            break;
+        }
+        let payload_len = u32::from_le_bytes([rest[0], rest[1], rest[2], rest[3]]);
+        let len = FRAME_HEADER_LEN + u64::from(payload_len);
+        // records are never empty, and a run of zeros would pass as one
+        if payload_len == 0 || len > rest.len() as u64 {
+            continue;
+        }
+        if decode_frame(&rest[..len as usize]).is_some() {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
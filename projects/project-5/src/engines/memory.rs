@@ -0,0 +1,494 @@
+use super::{
+    deadline_after, is_expired, now_millis, BatchOp, KvsEngine, PeriodicTask, WriteBatch,
+    DEFAULT_SWEEP_INTERVAL,
+};
+use crate::thread_pool::ThreadPool;
+use crate::{KvsError, Result};
+use crossbeam_skiplist::SkipMap;
+use std::collections::{BTreeMap, BTreeSet};
+use std::mem;
+use std::ops::Bound;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
+use tokio::prelude::*;
+use tokio::sync::oneshot;
+
+/// A key value storage engine keeping the keys in memory only.
+///
+/// The keys are held in a concurrent skip list that is read without locking, while
+/// writes are applied one at a time. Nothing is persisted: the keys are gone once
+/// the last clone of the engine is dropped.
+///
+/// With a memory limit, the least recently used keys are evicted once the keys and
+/// values take more bytes than the limit, so the engine can serve as a cache. Sets
+/// and gets count as uses of a key, scans do not. Expired keys are removed by a
+/// background sweeper.
+///
+/// ```rust
+/// # use kvs::{MemoryKvsEngine, Result};
+/// # use kvs::thread_pool::RayonThreadPool;
+/// # use tokio::prelude::*;
+/// # fn try_main() -> Result<()> {
+/// use kvs::KvsEngine;
+/// let cache: MemoryKvsEngine<RayonThreadPool> =
+///     MemoryKvsEngine::with_max_memory(2, 64 * 1024 * 1024)?;
+/// cache.set("key".to_owned(), "value".to_owned()).wait()?;
+/// let val = cache.get("key".to_owned()).wait()?;
+/// assert_eq!(val, Some("value".to_owned()));
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone)]
+pub struct MemoryKvsEngine<P: ThreadPool> {
+    pool: P,
+    map: Arc<SkipMap<Vec<u8>, Entry>>,
+    writer: Arc<Mutex<MemoryWriter>>,
+    // source of the stamps of the uses of the keys, increasing with every use
+    clock: Arc<AtomicU64>,
+    // stops the background sweeper when the last clone is dropped
+    _sweeper: Arc<PeriodicTask>,
+}
+
+impl<P: ThreadPool> MemoryKvsEngine<P> {
+    /// Creates an empty `MemoryKvsEngine` without memory limit.
+    ///
+    /// Operations are run in the given thread pool. `concurrency` specifies the number of
+    /// threads in the thread pool.
+    pub fn new(concurrency: u32) -> Result<Self> {
+        Self::create(concurrency, None)
+    }
+
+    /// Creates an empty `MemoryKvsEngine` evicting the least recently used keys once
+    /// the keys and values take more than `max_memory` bytes.
+    ///
+    /// A value larger than the limit on its own is evicted as soon as it is set.
+    pub fn with_max_memory(concurrency: u32, max_memory: u64) -> Result<Self> {
+        Self::create(concurrency, Some(max_memory))
+    }
+
+    fn create(concurrency: u32, max_memory: Option<u64>) -> Result<Self> {
+        let map = Arc::new(SkipMap::new());
+        let clock = Arc::new(AtomicU64::new(0));
+        let writer = Arc::new(Mutex::new(MemoryWriter {
+            map: Arc::clone(&map),
+            clock: Arc::clone(&clock),
+            max_memory,
+            used_memory: 0,
+            recency: BTreeMap::new(),
+            expiries: BTreeSet::new(),
+        }));
+        let sweeper = {
+            let writer = Arc::clone(&writer);
+            PeriodicTask::spawn("memory-sweeper", DEFAULT_SWEEP_INTERVAL, move || {
+                writer.lock().unwrap().sweep_expired();
+            })?
+        };
+        Ok(MemoryKvsEngine {
+            pool: P::new(concurrency)?,
+            map,
+            writer,
+            clock,
+            _sweeper: Arc::new(sweeper),
+        })
+    }
+
+    /// Returns how many bytes the keys and values take.
+    pub fn used_memory(&self) -> u64 {
+        self.writer.lock().unwrap().used_memory
+    }
+
+    /// Runs `f` with the writer in the thread pool, then evicts keys if the memory
+    /// limit is exceeded.
+    fn write<F, T>(&self, f: F) -> Box<dyn Future<Item = T, Error = KvsError> + Send>
+    where
+        F: FnOnce(&mut MemoryWriter) -> Result<T> + Send + 'static,
+        T: Send + 'static,
+    {
+        let writer = self.writer.clone();
+        let (tx, rx) = oneshot::channel();
+        self.pool.spawn(move || {
+            let res = {
+                let mut writer = writer.lock().unwrap();
+                let res = f(&mut writer);
+                writer.evict();
+                res
+            };
+            if tx.send(res).is_err() {
+                error!("Receiving end is dropped");
+            }
+        });
+        Box::new(
+            rx.map_err(|e| KvsError::StringError(format!("{}", e)))
+                .flatten(),
+        )
+    }
+
+    /// Gets up to `limit` keys within the bounds, with their values and expiry
+    /// deadlines, in key order, in the thread pool.
+    #[allow(clippy::type_complexity)]
+    fn scan_entries(
+        &self,
+        start: Bound<Vec<u8>>,
+        end: Bound<Vec<u8>>,
+        limit: usize,
+    ) -> Box<dyn Future<Item = Vec<(Vec<u8>, Vec<u8>, Option<u64>)>, Error = KvsError> + Send> {
+        let map = self.map.clone();
+        let (tx, rx) = oneshot::channel();
+        self.pool.spawn(move || {
+            let now = now_millis();
+            let entries = map
+                .range((start, end))
+                .filter_map(|entry| {
+                    let (value, expires_at) = entry.value().live(now)?;
+                    Some((entry.key().clone(), value, expires_at))
+                })
+                .take(limit)
+                .collect();
+            if tx.send(Ok(entries)).is_err() {
+                error!("Receiving end is dropped");
+            }
+        });
+        Box::new(
+            rx.map_err(|e| KvsError::StringError(format!("{}", e)))
+                .flatten(),
+        )
+    }
+}
+
+impl<P: ThreadPool> KvsEngine for MemoryKvsEngine<P> {
+    fn set_bytes(
+        &self,
+        key: Vec<u8>,
+        value: Vec<u8>,
+    ) -> Box<dyn Future<Item = (), Error = KvsError> + Send> {
+        self.write(move |writer| {
+            writer.set(key, value, None);
+            Ok(())
+        })
+    }
+
+    fn set_with_ttl_bytes(
+        &self,
+        key: Vec<u8>,
+        value: Vec<u8>,
+        ttl: Duration,
+    ) -> Box<dyn Future<Item = (), Error = KvsError> + Send> {
+        let expires_at = deadline_after(ttl);
+        self.write(move |writer| {
+            writer.set(key, value, Some(expires_at));
+            Ok(())
+        })
+    }
+
+    /// Gets the value of a given key, counting as a use of the key.
+    ///
+    /// Returns `None` if the given key does not exist or has expired.
+    fn get_bytes(
+        &self,
+        key: Vec<u8>,
+    ) -> Box<dyn Future<Item = Option<Vec<u8>>, Error = KvsError> + Send> {
+        let map = self.map.clone();
+        let clock = self.clock.clone();
+        let (tx, rx) = oneshot::channel();
+        self.pool.spawn(move || {
+            let value = map.get(&key).and_then(|entry| {
+                let entry = entry.value();
+                let (value, _) = entry.live(now_millis())?;
+                entry
+                    .used
+                    .fetch_max(clock.fetch_add(1, Ordering::SeqCst), Ordering::SeqCst);
+                Some(value)
+            });
+            if tx.send(Ok(value)).is_err() {
+                error!("Receiving end is dropped");
+            }
+        });
+        Box::new(
+            rx.map_err(|e| KvsError::StringError(format!("{}", e)))
+                .flatten(),
+        )
+    }
+
+    fn remove_bytes(&self, key: Vec<u8>) -> Box<dyn Future<Item = (), Error = KvsError> + Send> {
+        self.write(move |writer| {
+            if writer.remove(&key) {
+                Ok(())
+            } else {
+                Err(KvsError::KeyNotFound)
+            }
+        })
+    }
+
+    /// Applies all writes of the batch while holding the writer lock.
+    ///
+    /// Concurrent reads may see some writes of the batch before the others.
+    fn write_batch(
+        &self,
+        batch: WriteBatch,
+    ) -> Box<dyn Future<Item = (), Error = KvsError> + Send> {
+        self.write(move |writer| {
+            writer.apply(batch);
+            Ok(())
+        })
+    }
+
+    /// Sets or removes a key only if its current value is `expected`.
+    ///
+    /// The value is compared and written while holding the writer lock.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::CasConflict` with the current value if it is not the
+    /// expected one.
+    fn compare_and_swap_bytes(
+        &self,
+        key: Vec<u8>,
+        expected: Option<Vec<u8>>,
+        new: Option<Vec<u8>>,
+    ) -> Box<dyn Future<Item = (), Error = KvsError> + Send> {
+        self.write(move |writer| {
+            let current = writer.value(&key);
+            if current != expected {
+                return Err(KvsError::CasConflict { current });
+            }
+            match new {
+                Some(value) => writer.set(key, value, None),
+                None => {
+                    writer.remove(&key);
+                }
+            }
+            Ok(())
+        })
+    }
+
+    /// Sets the value of a key that expires after the given time to live, only if
+    /// the key does not exist.
+    ///
+    /// The key is checked and written while holding the writer lock.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::CasConflict` with the current value if the key exists.
+    fn set_if_absent_with_ttl_bytes(
+        &self,
+        key: Vec<u8>,
+        value: Vec<u8>,
+        ttl: Duration,
+    ) -> Box<dyn Future<Item = (), Error = KvsError> + Send> {
+        let expires_at = deadline_after(ttl);
+        self.write(move |writer| {
+            if let Some(current) = writer.value(&key) {
+                return Err(KvsError::CasConflict {
+                    current: Some(current),
+                });
+            }
+            writer.set(key, value, Some(expires_at));
+            Ok(())
+        })
+    }
+
+    /// Applies the batch only if the keys read have the given values.
+    ///
+    /// Like `compare_and_swap_bytes`, the values are compared and the batch is
+    /// applied while holding the writer lock.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::TransactionConflict` if any key has another value.
+    fn commit_transaction(
+        &self,
+        reads: Vec<(Vec<u8>, Option<Vec<u8>>)>,
+        batch: WriteBatch,
+    ) -> Box<dyn Future<Item = (), Error = KvsError> + Send> {
+        self.write(move |writer| {
+            if reads.iter().any(|(key, value)| writer.value(key) != *value) {
+                return Err(KvsError::TransactionConflict);
+            }
+            writer.apply(batch);
+            Ok(())
+        })
+    }
+
+    fn scan_bytes(
+        &self,
+        start: Bound<Vec<u8>>,
+        end: Bound<Vec<u8>>,
+        limit: Option<usize>,
+    ) -> Box<dyn Future<Item = Vec<(Vec<u8>, Vec<u8>)>, Error = KvsError> + Send> {
+        Box::new(
+            self.scan_entries(start, end, limit.unwrap_or(usize::MAX))
+                .map(|entries| {
+                    entries
+                        .into_iter()
+                        .map(|(key, value, _)| (key, value))
+                        .collect()
+                }),
+        )
+    }
+}
+
+/// The value of a key and the stamps of its uses.
+///
+/// Setting a key again replaces the value of its entry in place, since replacing
+/// an entry of a `SkipMap` leaves a moment when readers find none for the key.
+struct Entry {
+    current: RwLock<Value>,
+    // stamp of the last use of the key
+    used: AtomicU64,
+    // stamp the key is listed under in the recency list of the writer
+    listed: AtomicU64,
+}
+
+impl Entry {
+    fn new(value: Value, stamp: u64) -> Self {
+        Entry {
+            current: RwLock::new(value),
+            used: AtomicU64::new(stamp),
+            listed: AtomicU64::new(stamp),
+        }
+    }
+
+    /// Returns the value and its expiry deadline, or `None` if it has expired at
+    /// `now`.
+    fn live(&self, now: u64) -> Option<(Vec<u8>, Option<u64>)> {
+        let current = self.current.read().unwrap();
+        if is_expired(current.expires_at, now) {
+            return None;
+        }
+        Some((current.bytes.clone(), current.expires_at))
+    }
+}
+
+/// A value and its expiry deadline.
+struct Value {
+    bytes: Vec<u8>,
+    expires_at: Option<u64>,
+}
+
+/// The only writer of the map, keeping track of the memory used.
+struct MemoryWriter {
+    map: Arc<SkipMap<Vec<u8>, Entry>>,
+    clock: Arc<AtomicU64>,
+    max_memory: Option<u64>,
+    // the number of bytes of the keys and values in the map
+    used_memory: u64,
+    // keys by the stamp they are listed under, which may be older than their last
+    // use; only kept with a memory limit
+    recency: BTreeMap<u64, Vec<u8>>,
+    // deadlines of the keys set with a time to live, which may be outdated by
+    // later writes
+    expiries: BTreeSet<(u64, Vec<u8>)>,
+}
+
+impl MemoryWriter {
+    /// Returns the value of the key, or `None` if it does not exist or has expired.
+    fn value(&self, key: &[u8]) -> Option<Vec<u8>> {
+        let (value, _) = self.map.get(key)?.value().live(now_millis())?;
+        Some(value)
+    }
+
+    /// Sets the key, replacing the value of its entry in place if it has one.
+    ///
+    /// The entry is then listed under its previous stamp, and used at the new one.
+    fn set(&mut self, key: Vec<u8>, value: Vec<u8>, expires_at: Option<u64>) {
+        let stamp = self.clock.fetch_add(1, Ordering::SeqCst);
+        if let Some(expires_at) = expires_at {
+            self.expiries.insert((expires_at, key.clone()));
+        }
+        let value = Value {
+            bytes: value,
+            expires_at,
+        };
+        if let Some(entry) = self.map.get(&key) {
+            let entry = entry.value();
+            self.used_memory += value.bytes.len() as u64;
+            let replaced = mem::replace(&mut *entry.current.write().unwrap(), value);
+            self.used_memory -= replaced.bytes.len() as u64;
+            entry.used.fetch_max(stamp, Ordering::SeqCst);
+            return;
+        }
+        self.used_memory += (key.len() + value.bytes.len()) as u64;
+        if self.max_memory.is_some() {
+            self.recency.insert(stamp, key.clone());
+        }
+        self.map.insert(key, Entry::new(value, stamp));
+    }
+
+    /// Removes the key.
+    ///
+    /// Returns whether it existed and had not expired.
+    fn remove(&mut self, key: &[u8]) -> bool {
+        let entry = match self.map.remove(key) {
+            Some(entry) => entry,
+            None => return false,
+        };
+        let entry = entry.value();
+        let current = entry.current.read().unwrap();
+        self.used_memory -= (key.len() + current.bytes.len()) as u64;
+        self.recency.remove(&entry.listed.load(Ordering::SeqCst));
+        !is_expired(current.expires_at, now_millis())
+    }
+
+    /// Applies the last write of each key of the batch.
+    fn apply(&mut self, batch: WriteBatch) {
+        for op in batch.into_last_ops() {
+            match op {
+                BatchOp::Set { key, value } => self.set(key, value, None),
+                BatchOp::Remove { key } => {
+                    self.remove(&key);
+                }
+            }
+        }
+    }
+
+    /// Evicts the least recently used keys until the keys and values fit in the
+    /// memory limit.
+    ///
+    /// A key used since it was listed is listed again under its last use instead.
+    fn evict(&mut self) {
+        let max_memory = match self.max_memory {
+            Some(max_memory) => max_memory,
+            None => return,
+        };
+        while self.used_memory > max_memory {
+            let (listed, key) = match self.recency.pop_first() {
+                Some(oldest) => oldest,
+                None => return,
+            };
+            let used = match self.map.get(&key) {
+                Some(entry) => entry.value().used.load(Ordering::SeqCst),
+                None => continue,
+            };
+            if used > listed {
+                self.map
+                    .get(&key)
+                    .unwrap()
+                    .value()
+                    .listed
+                    .store(used, Ordering::SeqCst);
+                self.recency.insert(used, key);
+            } else {
+                self.remove(&key);
+            }
+        }
+    }
+
+    /// Removes the keys that have expired.
+    fn sweep_expired(&mut self) {
+        let now = now_millis();
+        while let Some((expires_at, key)) = self.expiries.iter().next().cloned() {
+            if expires_at > now {
+                break;
+            }
+            self.expiries.remove(&(expires_at, key.clone()));
+            // the key may have been set again since
+            let expired = self.map.get(&key).is_some_and(|entry| {
+                entry.value().current.read().unwrap().expires_at == Some(expires_at)
+            });
+            if expired {
+                self.remove(&key);
+            }
+        }
+    }
+}
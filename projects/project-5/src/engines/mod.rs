@@ -1,28 +1,338 @@
-pub use self::kvs::KvStore;
+pub(crate) use self::batch::BatchOp;
+pub use self::batch::WriteBatch;
+pub use self::durability::Durability;
+pub(crate) use self::durability::{GroupSync, PeriodicTask};
+pub(crate) use self::expiry::{deadline_after, is_expired, now_millis, DEFAULT_SWEEP_INTERVAL};
+pub use self::kvs::{KvStore, KvStoreOptions, Snapshot};
+pub use self::lsm::{LsmKvsEngine, LsmOptions};
+pub use self::memory::MemoryKvsEngine;
 pub use self::sled::SledKvsEngine;
-use crate::KvsError;
+pub use self::transaction::Transaction;
+use crate::replication::{ChangeEvent, LogChunk, LogPosition, LogRecord};
+use crate::{KvsError, Result};
+use std::ops::Bound::{self, Excluded, Included, Unbounded};
+use std::time::Duration;
 
-use tokio::prelude::Future;
+use tokio::prelude::*;
 
+mod batch;
+mod durability;
+mod expiry;
 mod kvs;
+mod lsm;
+mod memory;
 mod sled;
+mod transaction;
+
+/// Number of pairs read at a time by the default `KvsEngine::scan_prefix_bytes`.
+const SCAN_PREFIX_CHUNK_LEN: usize = 128;
 
 /// Trait for a key value storage engine.
+///
+/// Keys and values are arbitrary byte strings. The methods taking and returning
+/// `String`s are wrappers of the byte string ones, and fail with `KvsError::Utf8`
+/// if a key or value read back is not valid UTF-8.
 pub trait KvsEngine: Clone + Send + 'static {
+    /// Sets the value of a key.
+    ///
+    /// If the key already exists, the previous value will be overwritten.
+    fn set_bytes(
+        &self,
+        key: Vec<u8>,
+        value: Vec<u8>,
+    ) -> Box<dyn Future<Item = (), Error = KvsError> + Send>;
+
+    /// Sets the value of a key that expires after the given time to live.
+    ///
+    /// An expired key is treated as if it does not exist, and is removed in the
+    /// background afterwards.
+    fn set_with_ttl_bytes(
+        &self,
+        key: Vec<u8>,
+        value: Vec<u8>,
+        ttl: Duration,
+    ) -> Box<dyn Future<Item = (), Error = KvsError> + Send>;
+
+    /// Gets the value of a given key.
+    ///
+    /// Returns `None` if the given key does not exist.
+    fn get_bytes(
+        &self,
+        key: Vec<u8>,
+    ) -> Box<dyn Future<Item = Option<Vec<u8>>, Error = KvsError> + Send>;
+
+    /// Removes a given key.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::KeyNotFound` if the given key is not found.
+    fn remove_bytes(&self, key: Vec<u8>) -> Box<dyn Future<Item = (), Error = KvsError> + Send>;
+
+    /// Applies all writes of the batch atomically.
+    fn write_batch(&self, batch: WriteBatch)
+        -> Box<dyn Future<Item = (), Error = KvsError> + Send>;
+
+    /// Sets the value of a key to `new`, or removes the key if `new` is `None`, only
+    /// if its current value is `expected`. `None` expects the key not to exist.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::CasConflict` with the current value if it is not the
+    /// expected one.
+    fn compare_and_swap_bytes(
+        &self,
+        key: Vec<u8>,
+        expected: Option<Vec<u8>>,
+        new: Option<Vec<u8>>,
+    ) -> Box<dyn Future<Item = (), Error = KvsError> + Send>;
+
+    /// Sets the value of a key only if it does not exist.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::CasConflict` with the current value if the key exists.
+    fn set_if_absent_bytes(
+        &self,
+        key: Vec<u8>,
+        value: Vec<u8>,
+    ) -> Box<dyn Future<Item = (), Error = KvsError> + Send> {
+        self.compare_and_swap_bytes(key, None, Some(value))
+    }
+
+    /// Sets the value of a key that expires after the given time to live, only if
+    /// the key does not exist.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::CasConflict` with the current value if the key exists.
+    fn set_if_absent_with_ttl_bytes(
+        &self,
+        key: Vec<u8>,
+        value: Vec<u8>,
+        ttl: Duration,
+    ) -> Box<dyn Future<Item = (), Error = KvsError> + Send>;
+
+    /// Starts a transaction on the engine.
+    fn begin(&self) -> Transaction<Self> {
+        Transaction::new(self.clone())
+    }
+
+    /// Applies all writes of the batch atomically only if every key in `reads` has
+    /// the given value, `None` meaning the key does not exist.
+    ///
+    /// It is how a `Transaction` commits.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::TransactionConflict` if any key has another value.
+    fn commit_transaction(
+        &self,
+        reads: Vec<(Vec<u8>, Option<Vec<u8>>)>,
+        batch: WriteBatch,
+    ) -> Box<dyn Future<Item = (), Error = KvsError> + Send>;
+
+    /// Gets the key-value pairs with keys within the given bounds, in key order.
+    ///
+    /// At most `limit` pairs are returned if it is given.
+    #[allow(clippy::type_complexity)]
+    fn scan_bytes(
+        &self,
+        start: Bound<Vec<u8>>,
+        end: Bound<Vec<u8>>,
+        limit: Option<usize>,
+    ) -> Box<dyn Future<Item = Vec<(Vec<u8>, Vec<u8>)>, Error = KvsError> + Send>;
+
+    /// Gets the key-value pairs with keys starting with the given prefix, in key order.
+    ///
+    /// The pairs are read lazily in chunks as the stream is polled.
+    fn scan_prefix_bytes(
+        &self,
+        prefix: Vec<u8>,
+    ) -> Box<dyn Stream<Item = (Vec<u8>, Vec<u8>), Error = KvsError> + Send> {
+        let engine = self.clone();
+        let chunks = stream::unfold(Some(Included(prefix.clone())), move |start| {
+            let prefix = prefix.clone();
+            start.map(|start| {
+                engine
+                    .scan_bytes(start, Unbounded, Some(SCAN_PREFIX_CHUNK_LEN))
+                    .map(move |mut pairs| {
+                        let full = pairs.len() == SCAN_PREFIX_CHUNK_LEN;
+                        let len = pairs
+                            .iter()
+                            .take_while(|(key, _)| key.starts_with(&prefix))
+                            .count();
+                        let next = if full && len == pairs.len() {
+                            Some(Excluded(pairs[len - 1].0.clone()))
+                        } else {
+                            None
+                        };
+                        pairs.truncate(len);
+                        (stream::iter_ok(pairs), next)
+                    })
+            })
+        });
+        Box::new(chunks.flatten())
+    }
+
+    /// Gets the keys starting with the given prefix, in order.
+    fn keys_bytes(
+        &self,
+        prefix: Vec<u8>,
+    ) -> Box<dyn Stream<Item = Vec<u8>, Error = KvsError> + Send> {
+        Box::new(self.scan_prefix_bytes(prefix).map(|(key, _)| key))
+    }
+
     /// Sets the value of a string key to a string.
     ///
     /// If the key already exists, the previous value will be overwritten.
-    fn set(&self, key: String, value: String) -> Box<dyn Future<Item = (), Error = KvsError> + Send>;
+    fn set(
+        &self,
+        key: String,
+        value: String,
+    ) -> Box<dyn Future<Item = (), Error = KvsError> + Send> {
+        self.set_bytes(key.into_bytes(), value.into_bytes())
+    }
+
+    /// Sets the value of a string key to a string that expires after the given time
+    /// to live.
+    fn set_with_ttl(
+        &self,
+        key: String,
+        value: String,
+        ttl: Duration,
+    ) -> Box<dyn Future<Item = (), Error = KvsError> + Send> {
+        self.set_with_ttl_bytes(key.into_bytes(), value.into_bytes(), ttl)
+    }
 
     /// Gets the string value of a given string key.
     ///
     /// Returns `None` if the given key does not exist.
-    fn get(&self, key: String) -> Box<dyn Future<Item = Option<String>, Error = KvsError> + Send>;
+    fn get(&self, key: String) -> Box<dyn Future<Item = Option<String>, Error = KvsError> + Send> {
+        Box::new(
+            self.get_bytes(key.into_bytes())
+                .and_then(|value| Ok(value.map(String::from_utf8).transpose()?)),
+        )
+    }
 
-    /// Removes a given key.
+    /// Removes a given string key.
     ///
     /// # Errors
     ///
     /// It returns `KvsError::KeyNotFound` if the given key is not found.
-    fn remove(&self, key: String) -> Box<dyn Future<Item = (), Error = KvsError> + Send>;
+    fn remove(&self, key: String) -> Box<dyn Future<Item = (), Error = KvsError> + Send> {
+        self.remove_bytes(key.into_bytes())
+    }
+
+    /// Sets the value of a string key to `new`, or removes the key if `new` is `None`,
+    /// only if its current value is `expected`.
+    ///
+    /// See `KvsEngine::compare_and_swap_bytes` for the errors.
+    fn compare_and_swap(
+        &self,
+        key: String,
+        expected: Option<String>,
+        new: Option<String>,
+    ) -> Box<dyn Future<Item = (), Error = KvsError> + Send> {
+        self.compare_and_swap_bytes(
+            key.into_bytes(),
+            expected.map(String::into_bytes),
+            new.map(String::into_bytes),
+        )
+    }
+
+    /// Sets the value of a string key to a string only if the key does not exist.
+    ///
+    /// See `KvsEngine::set_if_absent_bytes` for the errors.
+    fn set_if_absent(
+        &self,
+        key: String,
+        value: String,
+    ) -> Box<dyn Future<Item = (), Error = KvsError> + Send> {
+        self.set_if_absent_bytes(key.into_bytes(), value.into_bytes())
+    }
+
+    /// Gets the pairs of string keys within the given bounds and their values, in
+    /// key order.
+    ///
+    /// At most `limit` pairs are returned if it is given.
+    fn scan(
+        &self,
+        start: Bound<String>,
+        end: Bound<String>,
+        limit: Option<usize>,
+    ) -> Box<dyn Future<Item = Vec<(String, String)>, Error = KvsError> + Send> {
+        Box::new(
+            self.scan_bytes(bound_into_bytes(start), bound_into_bytes(end), limit)
+                .and_then(|pairs| {
+                    pairs
+                        .into_iter()
+                        .map(pair_into_string)
+                        .collect::<Result<_>>()
+                }),
+        )
+    }
+
+    /// Gets the pairs of string keys starting with the given prefix and their values,
+    /// in key order.
+    fn scan_prefix(
+        &self,
+        prefix: String,
+    ) -> Box<dyn Stream<Item = (String, String), Error = KvsError> + Send> {
+        Box::new(
+            self.scan_prefix_bytes(prefix.into_bytes())
+                .and_then(pair_into_string),
+        )
+    }
+
+    /// Gets the string keys starting with the given prefix, in order.
+    fn keys(&self, prefix: String) -> Box<dyn Stream<Item = String, Error = KvsError> + Send> {
+        Box::new(
+            self.keys_bytes(prefix.into_bytes())
+                .and_then(|key| Ok(String::from_utf8(key)?)),
+        )
+    }
+}
+
+/// Trait for an engine keeping a log of its writes, which replicas can follow.
+pub trait ReplicationSource: KvsEngine {
+    /// Reads the writes logged from the given position on, for a replica of the
+    /// engine to apply them in order.
+    fn read_log(
+        &self,
+        from: LogPosition,
+    ) -> Box<dyn Future<Item = LogChunk, Error = KvsError> + Send>;
+
+    /// Gets up to `limit` pairs with keys from `start` on as `LogRecord::Set`s, in
+    /// key order, for a replica to copy the engine.
+    fn scan_records(
+        &self,
+        start: Bound<Vec<u8>>,
+        limit: usize,
+    ) -> Box<dyn Future<Item = Vec<LogRecord>, Error = KvsError> + Send>;
+}
+
+/// Trait for an engine streaming its writes to subscribers as they are committed.
+pub trait ChangeFeed: KvsEngine {
+    /// Streams the writes committed after the given sequence number, in commit order,
+    /// then the following writes as they are committed.
+    ///
+    /// The stream only ends with an error.
+    fn subscribe(
+        &self,
+        from_seq: u64,
+    ) -> Box<dyn Stream<Item = ChangeEvent, Error = KvsError> + Send>;
+}
+
+/// Converts a bound of a string key to a bound of the key bytes.
+pub(crate) fn bound_into_bytes(bound: Bound<String>) -> Bound<Vec<u8>> {
+    match bound {
+        Included(key) => Included(key.into_bytes()),
+        Excluded(key) => Excluded(key.into_bytes()),
+        Unbounded => Unbounded,
+    }
+}
+
+/// Converts a key-value pair of byte strings to a pair of strings.
+pub(crate) fn pair_into_string((key, value): (Vec<u8>, Vec<u8>)) -> Result<(String, String)> {
+    Ok((String::from_utf8(key)?, String::from_utf8(value)?))
 }
@@ -1,14 +1,55 @@
+use super::{
+    deadline_after, is_expired, now_millis, BatchOp, Durability, GroupSync, PeriodicTask,
+    WriteBatch, DEFAULT_SWEEP_INTERVAL,
+};
 use crate::thread_pool::ThreadPool;
 use crate::{KvsEngine, KvsError, Result};
-use sled::Db;
+use sled::{Batch, Db, Tree};
+use std::ops::Bound;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
 use tokio::prelude::*;
 use tokio::sync::oneshot;
 
+/// Name of the tree holding the values, each prefixed with its expiry deadline.
+const DATA_TREE: &[u8] = b"kvs-data";
+/// Name of the tree listing the keys set with a time to live, each prefixed with
+/// its deadline so that they are in the order they expire.
+const EXPIRIES_TREE: &[u8] = b"kvs-expiries";
+/// Name of the tree holding the version of the format of the store.
+const META_TREE: &[u8] = b"kvs-meta";
+const FORMAT_KEY: &[u8] = b"format";
+/// Version of the format listing the keys in `EXPIRIES_TREE` by deadline.
+///
+/// In version 1, `EXPIRIES_TREE` mapped the keys to their deadlines. Before it, the
+/// values were kept in the default tree and their deadlines only in
+/// `EXPIRIES_TREE`.
+const FORMAT_VERSION: u8 = 2;
+/// Length of the expiry deadline prefixing a stored value.
+const DEADLINE_LEN: usize = 8;
+
 /// Wrapper of `sled::Db`
+///
+/// Values are stored prefixed with their expiry deadline, so a key and its deadline
+/// always change together. The keys set with a time to live are also listed by
+/// deadline in a separate tree, which a background sweeper goes through to remove
+/// the expired keys. That list is only a hint: the sweeper only removes a value
+/// that is still stored with the listed deadline.
 #[derive(Clone)]
 pub struct SledKvsEngine<P: ThreadPool> {
     pool: P,
     db: Db,
+    data: Arc<Tree>,
+    expiries: Arc<Expiries>,
+    // taken shared by the writes of single keys and exclusively by transactions,
+    // which compare several keys before writing
+    commit_lock: Arc<RwLock<()>>,
+    flusher: Arc<Flusher>,
+    // stops the background flusher when the last clone is dropped
+    _periodic_flusher: Option<Arc<PeriodicTask>>,
+    // stops the background sweeper when the last clone is dropped
+    _sweeper: Arc<PeriodicTask>,
 }
 
 impl<P: ThreadPool> SledKvsEngine<P> {
@@ -16,22 +57,131 @@ impl<P: ThreadPool> SledKvsEngine<P> {
     ///
     /// Operations are run in the given thread pool. `concurrency` specifies the number of
     /// threads in the thread pool.
+    ///
+    /// Every write is flushed to disk before it is acknowledged.
     pub fn new(db: Db, concurrency: u32) -> Result<Self> {
+        Self::with_durability(db, concurrency, Durability::EveryWrite)
+    }
+
+    /// Creates a `SledKvsEngine` from `sled::Db` with the given durability of writes.
+    ///
+    /// A store written by a version before the values were stored with their
+    /// deadlines is upgraded first.
+    pub fn with_durability(db: Db, concurrency: u32, durability: Durability) -> Result<Self> {
         let pool = P::new(concurrency)?;
-        Ok(SledKvsEngine { pool, db })
+        let flusher = Arc::new(Flusher {
+            durability,
+            written: AtomicU64::new(0),
+            group_sync: GroupSync::default(),
+        });
+        let data = db.open_tree(DATA_TREE.to_vec())?;
+        let expiries = Arc::new(Expiries {
+            tree: db.open_tree(EXPIRIES_TREE.to_vec())?,
+        });
+        upgrade(&db, &data, &expiries.tree)?;
+        let periodic_flusher = match durability {
+            Durability::Periodic(interval) => {
+                let db = db.clone();
+                let task = PeriodicTask::spawn("sled-flusher", interval, move || {
+                    if let Err(e) = db.flush() {
+                        error!("Periodic flush failed: {}", e);
+                    }
+                })?;
+                Some(Arc::new(task))
+            }
+            _ => None,
+        };
+        let sweeper = {
+            let data = Arc::clone(&data);
+            let expiries = Arc::clone(&expiries);
+            PeriodicTask::spawn("sled-sweeper", DEFAULT_SWEEP_INTERVAL, move || {
+                if let Err(e) = expiries.sweep(&data) {
+                    error!("Sweeping expired keys failed: {}", e);
+                }
+            })?
+        };
+        Ok(SledKvsEngine {
+            pool,
+            db,
+            data,
+            expiries,
+            commit_lock: Arc::new(RwLock::new(())),
+            flusher,
+            _periodic_flusher: periodic_flusher,
+            _sweeper: Arc::new(sweeper),
+        })
+    }
+
+    /// Sets the value of a key with the given expiry deadline, or removes the key if
+    /// `new` is `None`, only if its current value is `expected`.
+    ///
+    /// The stored value is swapped with `Tree::cas`, and compared again if another
+    /// write changed it in between.
+    fn compare_and_write(
+        &self,
+        key: Vec<u8>,
+        expected: Option<Vec<u8>>,
+        new: Option<Vec<u8>>,
+        expires_at: Option<u64>,
+    ) -> Box<dyn Future<Item = (), Error = KvsError> + Send> {
+        let db = self.db.clone();
+        let data = self.data.clone();
+        let expiries = self.expiries.clone();
+        let commit_lock = self.commit_lock.clone();
+        let flusher = self.flusher.clone();
+        let (tx, rx) = oneshot::channel();
+        self.pool.spawn(move || {
+            let res = (|| {
+                {
+                    let _lock = commit_lock.read().unwrap();
+                    if let Some(expires_at) = expires_at {
+                        expiries.list(&key, expires_at)?;
+                    }
+                    let new = new.map(|value| encode_value(&value, expires_at));
+                    loop {
+                        let stored = data.get(&key)?;
+                        let current = live_value(stored.as_ref(), now_millis())?;
+                        if current != expected {
+                            return Err(KvsError::CasConflict { current });
+                        }
+                        let old = stored.as_ref().map(|stored| &stored[..]);
+                        if data.cas(&key, old, new.clone())?.is_ok() {
+                            break;
+                        }
+                    }
+                }
+                flusher.wait(&db)
+            })();
+            if tx.send(res).is_err() {
+                error!("Receiving end is dropped");
+            }
+        });
+        Box::new(
+            rx.map_err(|e| KvsError::StringError(format!("{}", e)))
+                .flatten(),
+        )
     }
 }
 
 impl<P: ThreadPool> KvsEngine for SledKvsEngine<P> {
-    fn set(&self, key: String, value: String) -> Box<dyn Future<Item = (), Error = KvsError> + Send> {
+    fn set_bytes(
+        &self,
+        key: Vec<u8>,
+        value: Vec<u8>,
+    ) -> Box<dyn Future<Item = (), Error = KvsError> + Send> {
         let db = self.db.clone();
+        let data = self.data.clone();
+        let commit_lock = self.commit_lock.clone();
+        let flusher = self.flusher.clone();
         let (tx, rx) = oneshot::channel();
         self.pool.spawn(move || {
-            let res = db
-                .set(key, value.into_bytes())
-                .and_then(|_| db.flush())
-                .map(|_| ())
-                .map_err(KvsError::from);
+            let res = (|| {
+                {
+                    let _lock = commit_lock.read().unwrap();
+                    data.set(&key, encode_value(&value, None))?;
+                }
+                flusher.wait(&db)
+            })();
             if tx.send(res).is_err() {
                 error!("Receiving end is dropped");
             }
@@ -42,16 +192,71 @@ impl<P: ThreadPool> KvsEngine for SledKvsEngine<P> {
         )
     }
 
-    fn get(&self, key: String) -> Box<dyn Future<Item = Option<String>, Error = KvsError> + Send> {
+    /// Lists the key with its deadline for the sweeper, then sets the value with
+    /// its deadline.
+    fn set_with_ttl_bytes(
+        &self,
+        key: Vec<u8>,
+        value: Vec<u8>,
+        ttl: Duration,
+    ) -> Box<dyn Future<Item = (), Error = KvsError> + Send> {
         let db = self.db.clone();
+        let data = self.data.clone();
+        let expiries = self.expiries.clone();
+        let commit_lock = self.commit_lock.clone();
+        let flusher = self.flusher.clone();
         let (tx, rx) = oneshot::channel();
         self.pool.spawn(move || {
-            let res = (move || {
-                Ok(db
-                    .get(key)?
-                    .map(|i_vec| AsRef::<[u8]>::as_ref(&i_vec).to_vec())
-                    .map(String::from_utf8)
-                    .transpose()?)
+            let res = (|| {
+                {
+                    let _lock = commit_lock.read().unwrap();
+                    let expires_at = deadline_after(ttl);
+                    expiries.list(&key, expires_at)?;
+                    data.set(&key, encode_value(&value, Some(expires_at)))?;
+                }
+                flusher.wait(&db)
+            })();
+            if tx.send(res).is_err() {
+                error!("Receiving end is dropped");
+            }
+        });
+        Box::new(
+            rx.map_err(|e| KvsError::StringError(format!("{}", e)))
+                .flatten(),
+        )
+    }
+
+    fn get_bytes(
+        &self,
+        key: Vec<u8>,
+    ) -> Box<dyn Future<Item = Option<Vec<u8>>, Error = KvsError> + Send> {
+        let data = self.data.clone();
+        let (tx, rx) = oneshot::channel();
+        self.pool.spawn(move || {
+            let res = (move || live_value(data.get(&key)?, now_millis()))();
+            if tx.send(res).is_err() {
+                error!("Receiving end is dropped");
+            }
+        });
+        Box::new(
+            rx.map_err(|e| KvsError::StringError(format!("{}", e)))
+                .flatten(),
+        )
+    }
+
+    fn remove_bytes(&self, key: Vec<u8>) -> Box<dyn Future<Item = (), Error = KvsError> + Send> {
+        let db = self.db.clone();
+        let data = self.data.clone();
+        let commit_lock = self.commit_lock.clone();
+        let flusher = self.flusher.clone();
+        let (tx, rx) = oneshot::channel();
+        self.pool.spawn(move || {
+            let res = (|| {
+                {
+                    let _lock = commit_lock.read().unwrap();
+                    live_value(data.del(&key)?, now_millis())?.ok_or(KvsError::KeyNotFound)?;
+                }
+                flusher.wait(&db)
             })();
             if tx.send(res).is_err() {
                 error!("Receiving end is dropped");
@@ -63,14 +268,84 @@ impl<P: ThreadPool> KvsEngine for SledKvsEngine<P> {
         )
     }
 
-    fn remove(&self, key: String) -> Box<dyn Future<Item = (), Error = KvsError> + Send> {
+    /// Applies all writes of the batch atomically as a `sled::Batch`.
+    fn write_batch(
+        &self,
+        batch: WriteBatch,
+    ) -> Box<dyn Future<Item = (), Error = KvsError> + Send> {
         let db = self.db.clone();
+        let data = self.data.clone();
+        let commit_lock = self.commit_lock.clone();
+        let flusher = self.flusher.clone();
         let (tx, rx) = oneshot::channel();
         self.pool.spawn(move || {
             let res = (|| {
-                db.del(key)?.ok_or(KvsError::KeyNotFound)?;
-                db.flush()?;
-                Ok(())
+                {
+                    let _lock = commit_lock.read().unwrap();
+                    data.apply_batch(sled_batch(batch))?;
+                }
+                flusher.wait(&db)
+            })();
+            if tx.send(res).is_err() {
+                error!("Receiving end is dropped");
+            }
+        });
+        Box::new(
+            rx.map_err(|e| KvsError::StringError(format!("{}", e)))
+                .flatten(),
+        )
+    }
+
+    /// Compares the value and swaps it for the new one with `Tree::cas`.
+    ///
+    /// An expired key compares as absent.
+    fn compare_and_swap_bytes(
+        &self,
+        key: Vec<u8>,
+        expected: Option<Vec<u8>>,
+        new: Option<Vec<u8>>,
+    ) -> Box<dyn Future<Item = (), Error = KvsError> + Send> {
+        self.compare_and_write(key, expected, new, None)
+    }
+
+    /// Lists the key with its deadline for the sweeper, then sets it with
+    /// `Tree::cas` if it is absent.
+    ///
+    /// An expired key counts as absent.
+    fn set_if_absent_with_ttl_bytes(
+        &self,
+        key: Vec<u8>,
+        value: Vec<u8>,
+        ttl: Duration,
+    ) -> Box<dyn Future<Item = (), Error = KvsError> + Send> {
+        self.compare_and_write(key, None, Some(value), Some(deadline_after(ttl)))
+    }
+
+    /// Compares the values read and applies the batch while holding the commit lock
+    /// exclusively, so that no other write comes in between.
+    fn commit_transaction(
+        &self,
+        reads: Vec<(Vec<u8>, Option<Vec<u8>>)>,
+        batch: WriteBatch,
+    ) -> Box<dyn Future<Item = (), Error = KvsError> + Send> {
+        let db = self.db.clone();
+        let data = self.data.clone();
+        let commit_lock = self.commit_lock.clone();
+        let flusher = self.flusher.clone();
+        let (tx, rx) = oneshot::channel();
+        self.pool.spawn(move || {
+            let res = (|| {
+                {
+                    let _lock = commit_lock.write().unwrap();
+                    let now = now_millis();
+                    for (key, value) in reads {
+                        if live_value(data.get(&key)?, now)? != value {
+                            return Err(KvsError::TransactionConflict);
+                        }
+                    }
+                    data.apply_batch(sled_batch(batch))?;
+                }
+                flusher.wait(&db)
             })();
             if tx.send(res).is_err() {
                 error!("Receiving end is dropped");
@@ -81,4 +356,212 @@ impl<P: ThreadPool> KvsEngine for SledKvsEngine<P> {
                 .flatten(),
         )
     }
+
+    fn scan_bytes(
+        &self,
+        start: Bound<Vec<u8>>,
+        end: Bound<Vec<u8>>,
+        limit: Option<usize>,
+    ) -> Box<dyn Future<Item = Vec<(Vec<u8>, Vec<u8>)>, Error = KvsError> + Send> {
+        let data = self.data.clone();
+        let (tx, rx) = oneshot::channel();
+        self.pool.spawn(move || {
+            let res = (move || {
+                let mut pairs = Vec::new();
+                let now = now_millis();
+                for item in data.range::<Vec<u8>, _>((start, end)) {
+                    if limit.map_or(false, |limit| pairs.len() >= limit) {
+                        break;
+                    }
+                    let (key, stored) = item?;
+                    if let Some(value) = live_value(Some(stored), now)? {
+                        pairs.push((key, value));
+                    }
+                }
+                Ok(pairs)
+            })();
+            if tx.send(res).is_err() {
+                error!("Receiving end is dropped");
+            }
+        });
+        Box::new(
+            rx.map_err(|e| KvsError::StringError(format!("{}", e)))
+                .flatten(),
+        )
+    }
+}
+
+/// Converts the batch to a `sled::Batch` of values without deadlines.
+fn sled_batch(batch: WriteBatch) -> Batch {
+    let mut sled_batch = Batch::default();
+    for op in batch.into_ops() {
+        match op {
+            BatchOp::Set { key, value } => sled_batch.set(&key, encode_value(&value, None)),
+            BatchOp::Remove { key } => sled_batch.del(&key),
+        }
+    }
+    sled_batch
+}
+
+/// Upgrades a store in a format before `FORMAT_VERSION`.
+///
+/// The values of a store without a version are moved to `DATA_TREE`, with their
+/// deadlines. They are copied, and only removed from the default tree once the
+/// copies are flushed and the version is recorded, so an upgrade interrupted by a
+/// crash starts over. The deadlines listed by key are then listed by deadline.
+fn upgrade(db: &Db, data: &Tree, expiries: &Tree) -> Result<()> {
+    let meta = db.open_tree(META_TREE.to_vec())?;
+    let version = meta.get(FORMAT_KEY)?.map(|version| version[0]);
+    if version == Some(FORMAT_VERSION) {
+        return Ok(());
+    }
+    if version.is_none() {
+        move_to_data_tree(db, data, expiries, &meta)?;
+    }
+
+    // entries listed by deadline have no value, so an interrupted upgrade goes on
+    // with the ones left
+    for item in expiries.iter() {
+        let (key, deadline) = item?;
+        if deadline.is_empty() {
+            continue;
+        }
+        expiries.set(expiry_key(decode_deadline(&deadline), &key), vec![])?;
+        expiries.del(&key)?;
+    }
+    db.flush()?;
+    meta.set(FORMAT_KEY, vec![FORMAT_VERSION])?;
+    db.flush()?;
+    Ok(())
+}
+
+/// Moves the values of the default tree to `DATA_TREE`, and records version 1 of
+/// the format.
+fn move_to_data_tree(db: &Db, data: &Tree, expiries: &Tree, meta: &Tree) -> Result<()> {
+    let mut old_keys = Batch::default();
+    for item in db.iter() {
+        let (key, value) = item?;
+        let expires_at = expiries
+            .get(&key)?
+            .map(|deadline| decode_deadline(&deadline));
+        data.set(&key, encode_value(&value, expires_at))?;
+        old_keys.del(&key);
+    }
+    db.flush()?;
+    meta.set(FORMAT_KEY, vec![1])?;
+    db.flush()?;
+    db.apply_batch(old_keys)?;
+    Ok(())
+}
+
+/// Prefixes the value with its expiry deadline, or with zero if it has none.
+fn encode_value(value: &[u8], expires_at: Option<u64>) -> Vec<u8> {
+    let mut stored = Vec::with_capacity(DEADLINE_LEN + value.len());
+    stored.extend_from_slice(&expires_at.unwrap_or(0).to_be_bytes());
+    stored.extend_from_slice(value);
+    stored
+}
+
+/// Splits a stored value into its expiry deadline and the value.
+fn decode_value(stored: &[u8]) -> Result<(Option<u64>, &[u8])> {
+    if stored.len() < DEADLINE_LEN {
+        return Err(KvsError::StringError("Invalid stored value".to_owned()));
+    }
+    let (deadline, value) = stored.split_at(DEADLINE_LEN);
+    let expires_at = Some(decode_deadline(deadline)).filter(|&deadline| deadline != 0);
+    Ok((expires_at, value))
+}
+
+/// Returns the value of a stored value, or `None` if there is none or it has
+/// expired at `now`.
+fn live_value(stored: Option<impl AsRef<[u8]>>, now: u64) -> Result<Option<Vec<u8>>> {
+    match stored {
+        Some(stored) => {
+            let (expires_at, value) = decode_value(stored.as_ref())?;
+            Ok(Some(value.to_vec()).filter(|_| !is_expired(expires_at, now)))
+        }
+        None => Ok(None),
+    }
+}
+
+/// The keys set with a time to live, listed by expiry deadline, in milliseconds
+/// since the Unix epoch.
+///
+/// A key is listed before it is set with a deadline, and stays listed after it is
+/// set again until the sweeper reaches the deadline. The sweeper removes a key with
+/// `Tree::cas`, so a write coming in between is never lost.
+struct Expiries {
+    tree: Arc<Tree>,
+}
+
+impl Expiries {
+    /// Lists the key to be swept out at the deadline.
+    fn list(&self, key: &[u8], expires_at: u64) -> Result<()> {
+        self.tree.set(expiry_key(expires_at, key), vec![])?;
+        Ok(())
+    }
+
+    /// Removes the keys whose deadline has passed, going through the listed
+    /// deadlines in order until one is still to come.
+    fn sweep(&self, data: &Tree) -> Result<()> {
+        let now = now_millis();
+        for item in self.tree.iter() {
+            let (expiry_key, _) = item?;
+            let (deadline, key) = expiry_key.split_at(DEADLINE_LEN);
+            let deadline = decode_deadline(deadline);
+            if deadline > now {
+                break;
+            }
+            if let Some(stored) = data.get(key)? {
+                // a key set again since it was listed keeps its new value
+                if decode_value(&stored)?.0 == Some(deadline) {
+                    // the swap fails if the key is set again in the meantime
+                    let _ = data.cas(key, Some(&stored[..]), None::<Vec<u8>>)?;
+                }
+            }
+            self.tree.del(&expiry_key)?;
+        }
+        Ok(())
+    }
+}
+
+/// Prefixes the key with its deadline.
+fn expiry_key(expires_at: u64, key: &[u8]) -> Vec<u8> {
+    let mut expiry_key = Vec::with_capacity(DEADLINE_LEN + key.len());
+    expiry_key.extend_from_slice(&expires_at.to_be_bytes());
+    expiry_key.extend_from_slice(key);
+    expiry_key
+}
+
+fn decode_deadline(deadline: &[u8]) -> u64 {
+    let mut bytes = [0; 8];
+    bytes.copy_from_slice(deadline);
+    u64::from_be_bytes(bytes)
+}
+
+/// Flushes writes to disk as the durability requires.
+struct Flusher {
+    durability: Durability,
+    // the number of finished writes
+    written: AtomicU64,
+    group_sync: GroupSync,
+}
+
+impl Flusher {
+    /// Waits until a write that has just finished is as durable as required.
+    fn wait(&self, db: &Db) -> Result<()> {
+        let seq = self.written.fetch_add(1, Ordering::SeqCst) + 1;
+        match self.durability {
+            Durability::EveryWrite => {
+                db.flush()?;
+            }
+            Durability::GroupCommit => self.group_sync.wait(seq, || {
+                let last_seq = self.written.load(Ordering::SeqCst);
+                db.flush()?;
+                Ok(last_seq)
+            })?,
+            Durability::None | Durability::Periodic(_) => {}
+        }
+        Ok(())
+    }
 }
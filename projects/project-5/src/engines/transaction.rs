@@ -0,0 +1,139 @@
+use super::WriteBatch;
+use crate::{KvsEngine, KvsError};
+use std::collections::{BTreeMap, HashMap};
+use std::mem;
+use std::sync::{Arc, Mutex};
+use tokio::prelude::*;
+
+/// A transaction on a `KvsEngine` with optimistic concurrency control.
+///
+/// Writes are buffered until the transaction commits, and are seen by its own reads.
+/// Every key read is read once from the engine and remembered. The commit applies
+/// the writes atomically only if every key read still has the value read, so
+/// committed transactions are serializable. Otherwise it fails with
+/// `KvsError::TransactionConflict`, nothing is written, and the transaction can be
+/// retried from the start.
+///
+/// Dropping a transaction without committing it rolls it back.
+///
+/// ```rust
+/// # use kvs::{KvStore, KvsEngine, Result};
+/// # use kvs::thread_pool::RayonThreadPool;
+/// # use tokio::prelude::*;
+/// # fn try_main() -> Result<()> {
+/// use std::env::current_dir;
+/// let store: KvStore<RayonThreadPool> = KvStore::open(current_dir()?, 2)?;
+/// let txn = store.begin();
+/// let from = txn.get("from".to_owned()).wait()?.unwrap_or_default();
+/// let to = txn.get("to".to_owned()).wait()?.unwrap_or_default();
+/// txn.set("from".to_owned(), String::new());
+/// txn.set("to".to_owned(), to + &from);
+/// txn.commit().wait()?;
+/// # Ok(())
+/// # }
+/// ```
+pub struct Transaction<E: KvsEngine> {
+    engine: E,
+    state: Arc<Mutex<TransactionState>>,
+}
+
+#[derive(Default)]
+struct TransactionState {
+    // values read from the engine, `None` if the key did not exist
+    reads: HashMap<Vec<u8>, Option<Vec<u8>>>,
+    // buffered writes, `None` removing the key
+    writes: BTreeMap<Vec<u8>, Option<Vec<u8>>>,
+}
+
+impl<E: KvsEngine> Transaction<E> {
+    pub(crate) fn new(engine: E) -> Self {
+        Transaction {
+            engine,
+            state: Arc::default(),
+        }
+    }
+
+    /// Gets the value of a given key as seen by the transaction.
+    ///
+    /// Returns `None` if the given key does not exist.
+    pub fn get_bytes(
+        &self,
+        key: Vec<u8>,
+    ) -> Box<dyn Future<Item = Option<Vec<u8>>, Error = KvsError> + Send> {
+        {
+            let state = self.state.lock().unwrap();
+            if let Some(value) = state.writes.get(&key).or_else(|| state.reads.get(&key)) {
+                return Box::new(future::ok(value.clone()));
+            }
+        }
+        let state = self.state.clone();
+        Box::new(self.engine.get_bytes(key.clone()).map(move |value| {
+            let mut state = state.lock().unwrap();
+            if let Some(value) = state.writes.get(&key) {
+                // written by the transaction while reading
+                return value.clone();
+            }
+            // the first read of a key wins if it is read concurrently
+            state.reads.entry(key).or_insert(value).clone()
+        }))
+    }
+
+    /// Sets the value of a key when the transaction commits.
+    pub fn set_bytes(&self, key: Vec<u8>, value: Vec<u8>) {
+        self.state.lock().unwrap().writes.insert(key, Some(value));
+    }
+
+    /// Removes a key when the transaction commits.
+    ///
+    /// Unlike `KvsEngine::remove`, removing a key that does not exist is not an
+    /// error, it does nothing instead.
+    pub fn remove_bytes(&self, key: Vec<u8>) {
+        self.state.lock().unwrap().writes.insert(key, None);
+    }
+
+    /// Gets the string value of a given string key as seen by the transaction.
+    pub fn get(
+        &self,
+        key: String,
+    ) -> Box<dyn Future<Item = Option<String>, Error = KvsError> + Send> {
+        Box::new(
+            self.get_bytes(key.into_bytes())
+                .and_then(|value| Ok(value.map(String::from_utf8).transpose()?)),
+        )
+    }
+
+    /// Sets the value of a string key to a string when the transaction commits.
+    pub fn set(&self, key: String, value: String) {
+        self.set_bytes(key.into_bytes(), value.into_bytes())
+    }
+
+    /// Removes a string key when the transaction commits.
+    pub fn remove(&self, key: String) {
+        self.remove_bytes(key.into_bytes())
+    }
+
+    /// Applies the writes of the transaction atomically if every key it read still
+    /// has the value read.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::TransactionConflict` if any of them has been changed.
+    pub fn commit(self) -> Box<dyn Future<Item = (), Error = KvsError> + Send> {
+        let (reads, writes) = {
+            let mut state = self.state.lock().unwrap();
+            (mem::take(&mut state.reads), mem::take(&mut state.writes))
+        };
+        let mut batch = WriteBatch::new();
+        for (key, value) in writes {
+            match value {
+                Some(value) => batch.set(key, value),
+                None => batch.remove(key),
+            };
+        }
+        let reads = reads.into_iter().collect();
+        self.engine.commit_transaction(reads, batch)
+    }
+
+    /// Discards the writes of the transaction.
+    pub fn rollback(self) {}
+}
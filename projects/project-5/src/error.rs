@@ -11,9 +11,60 @@ pub enum KvsError {
     /// Serialization or deserialization error
     #[fail(display = "serde_json error: {}", _0)]
     Serde(#[cause] serde_json::Error),
+    /// Bincode serialization or deserialization error
+    #[fail(display = "bincode error: {}", _0)]
+    Bincode(#[cause] bincode::Error),
     /// Removing non-existent key error
     #[fail(display = "Key not found")]
     KeyNotFound,
+    /// Compare-and-swap conflict error.
+    /// The current value of the key is not the expected one.
+    #[fail(display = "Compare and swap conflict")]
+    CasConflict {
+        /// Current value of the key, or `None` if it does not exist
+        current: Option<Vec<u8>>,
+    },
+    /// Transaction conflict error.
+    /// A key read by the transaction has been changed before it committed.
+    #[fail(display = "Transaction conflict")]
+    TransactionConflict,
+    /// The key is locked by a Percolator transaction that may still be running.
+    #[fail(display = "Key is locked by another transaction")]
+    KeyLocked,
+    /// The Raft node is not the leader of its cluster, or stopped being the leader
+    /// before the request was committed.
+    #[fail(display = "Not the leader of the Raft cluster")]
+    NotLeader,
+    /// The server is a replica of another server, or the store is opened read-only,
+    /// and does not accept writes.
+    #[fail(display = "Writes are not accepted by a read-only store or replica")]
+    ReadOnly,
+    /// Some of the writes following the sequence number a subscription starts from
+    /// are not in the logs anymore, as they have been compacted away.
+    #[fail(display = "Changes after sequence number {} have been compacted", _0)]
+    ChangesCompacted(u64),
+    /// A log record failed its checksum.
+    /// It indicates the log is damaged somewhere other than its tail.
+    #[fail(
+        display = "Corrupted log record in generation {} at offset {}",
+        gen, offset
+    )]
+    Corruption {
+        /// Generation of the damaged log
+        gen: u64,
+        /// Offset of the damaged record in the log
+        offset: u64,
+    },
+    /// The store is opened for writing by another process, or by another `KvStore`
+    /// of this one.
+    #[fail(display = "The store is locked by another process or store")]
+    StoreLocked {
+        /// PID of the process holding the lock, if it has written it yet
+        pid: Option<u32>,
+    },
+    /// The log file was written in a format version this version cannot read.
+    #[fail(display = "Unsupported log format version {}", _0)]
+    UnsupportedLogVersion(u16),
     /// Unexpected command type error.
     /// It indicated a corrupted log or a program bug.
     #[fail(display = "Unexpected command type")]
@@ -41,6 +92,12 @@ impl From<serde_json::Error> for KvsError {
     }
 }
 
+impl From<bincode::Error> for KvsError {
+    fn from(err: bincode::Error) -> KvsError {
+        KvsError::Bincode(err)
+    }
+}
+
 impl From<FromUtf8Error> for KvsError {
     fn from(err: FromUtf8Error) -> KvsError {
         KvsError::Utf8(err)
@@ -5,7 +5,10 @@
 extern crate log;
 
 pub use client::KvsClient;
-pub use engines::{KvStore, KvsEngine, SledKvsEngine};
+pub use engines::{
+    ChangeFeed, Durability, KvStore, KvStoreOptions, KvsEngine, LsmKvsEngine, LsmOptions,
+    MemoryKvsEngine, ReplicationSource, SledKvsEngine, Snapshot, Transaction, WriteBatch,
+};
 pub use error::{KvsError, Result};
 pub use server::KvsServer;
 
@@ -13,5 +16,10 @@ mod client;
 mod common;
 mod engines;
 mod error;
+pub mod percolator;
+pub mod raft;
+pub mod replication;
 mod server;
+pub mod sharding;
 pub mod thread_pool;
+pub mod vfs;
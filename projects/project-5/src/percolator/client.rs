@@ -0,0 +1,683 @@
+use super::{data_key, decode_ts, latest_key, lock_key, write_key, Lock, Write, WriteKind};
+use crate::{KvsClient, KvsError, Result};
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+use std::ops::Bound::{self, Excluded, Included};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::prelude::future::{self, Either, Loop};
+use tokio::prelude::*;
+
+/// How long the locks of a transaction are kept by default before another
+/// transaction may roll it back.
+const DEFAULT_LOCK_TTL: Duration = Duration::from_secs(3);
+
+/// Number of write records read at a time when looking for the version of a key.
+const WRITE_SCAN_CHUNK_LEN: usize = 16;
+
+type BoxFuture<T> = Box<dyn Future<Item = T, Error = KvsError> + Send>;
+
+/// The writes of a row, `None` removing the column.
+type RowWrites = Vec<(Vec<u8>, Option<Vec<u8>>)>;
+
+/// A client running Percolator transactions on the shards of a deployment.
+///
+/// Each key is stored on the shard picked by a hash of the key, so all clients must
+/// be given the same shards in the same order.
+#[derive(Clone)]
+pub struct PercolatorClient {
+    oracle: SocketAddr,
+    shards: Arc<Vec<SocketAddr>>,
+    lock_ttl: Duration,
+}
+
+impl PercolatorClient {
+    /// Creates a client of the timestamp oracle and the shards at the given
+    /// addresses.
+    ///
+    /// # Panics
+    ///
+    /// Panics if no shards are given.
+    pub fn new(oracle: SocketAddr, shards: Vec<SocketAddr>) -> Self {
+        assert!(!shards.is_empty(), "no shards given");
+        PercolatorClient {
+            oracle,
+            shards: Arc::new(shards),
+            lock_ttl: DEFAULT_LOCK_TTL,
+        }
+    }
+
+    /// Sets how long the locks of a transaction are kept before another transaction
+    /// may roll it back.
+    ///
+    /// It should be longer than committing a transaction takes. It is 3 seconds by
+    /// default.
+    pub fn lock_ttl(&mut self, ttl: Duration) -> &mut Self {
+        self.lock_ttl = ttl;
+        self
+    }
+
+    /// Starts a transaction reading a snapshot at a new timestamp.
+    pub fn begin(&self) -> impl Future<Item = PercolatorTransaction, Error = KvsError> {
+        let client = self.clone();
+        self.timestamp().map(move |start_ts| PercolatorTransaction {
+            client,
+            start_ts,
+            writes: Mutex::default(),
+        })
+    }
+
+    fn timestamp(&self) -> BoxFuture<u64> {
+        Box::new(
+            KvsClient::connect(self.oracle)
+                .and_then(|client| client.timestamp())
+                .map(|(ts, _)| ts),
+        )
+    }
+
+    fn shard(&self, key: &[u8]) -> SocketAddr {
+        self.shards[crc32fast::hash(key) as usize % self.shards.len()]
+    }
+
+    /// Reads a column of the key from its shard.
+    fn read(&self, key: &[u8], column_key: Vec<u8>) -> BoxFuture<Option<Vec<u8>>> {
+        Box::new(
+            KvsClient::connect(self.shard(key))
+                .and_then(|client| client.get_bytes(column_key))
+                .map(|(value, _)| value),
+        )
+    }
+
+    fn read_lock(&self, key: &[u8]) -> BoxFuture<Option<Lock>> {
+        Box::new(
+            self.read(key, lock_key(key))
+                .and_then(|lock| Ok(lock.map(|lock| bincode::deserialize(&lock)).transpose()?)),
+        )
+    }
+
+    /// Reads the write records of the key from `start` to the one committed at
+    /// `oldest_ts`, newest first, with their commit timestamps.
+    fn read_writes(
+        &self,
+        key: &[u8],
+        start: Bound<Vec<u8>>,
+        oldest_ts: u64,
+        limit: Option<usize>,
+    ) -> BoxFuture<Vec<(u64, Write)>> {
+        let end = Included(write_key(key, oldest_ts));
+        Box::new(
+            KvsClient::connect(self.shard(key))
+                .and_then(move |client| client.scan_bytes(start, end, limit))
+                .and_then(|(pairs, _)| {
+                    pairs
+                        .into_iter()
+                        .map(|(column_key, write)| {
+                            Ok((decode_ts(&column_key), bincode::deserialize(&write)?))
+                        })
+                        .collect::<Result<_>>()
+                }),
+        )
+    }
+
+    /// Reads the value of the key committed last at or before the timestamp.
+    fn read_version(&self, key: Vec<u8>, ts: u64) -> BoxFuture<Option<Vec<u8>>> {
+        let client = self.clone();
+        Box::new(future::loop_fn(
+            Included(write_key(&key, ts)),
+            move |start| {
+                let client = client.clone();
+                let key = key.clone();
+                client
+                    .read_writes(&key, start, 0, Some(WRITE_SCAN_CHUNK_LEN))
+                    .and_then(move |writes| -> BoxFuture<Loop<_, _>> {
+                        let full = writes.len() == WRITE_SCAN_CHUNK_LEN;
+                        let next = writes
+                            .last()
+                            .map(|(commit_ts, _)| Excluded(write_key(&key, *commit_ts)));
+                        let write = writes
+                            .into_iter()
+                            .map(|(_, write)| write)
+                            .find(|write| write.kind != WriteKind::Rollback);
+                        match write {
+                            Some(Write {
+                                start_ts,
+                                kind: WriteKind::Put,
+                            }) => Box::new(
+                                client.read(&key, data_key(&key, start_ts)).map(Loop::Break),
+                            ),
+                            Some(_) => Box::new(future::ok(Loop::Break(None))),
+                            None if full => Box::new(future::ok(Loop::Continue(next.unwrap()))),
+                            None => Box::new(future::ok(Loop::Break(None))),
+                        }
+                    })
+            },
+        ))
+    }
+
+    /// Changes the row of the key atomically in a transaction on its shard.
+    ///
+    /// `f` is given the lock of the key and the largest timestamp of its write
+    /// column, and returns the columns to write. The shard transaction fails with
+    /// `KvsError::TransactionConflict` if either of them is changed meanwhile.
+    fn update_row<F>(&self, key: &[u8], f: F) -> BoxFuture<()>
+    where
+        F: FnOnce(Option<Lock>, u64) -> Result<RowWrites> + Send + 'static,
+    {
+        let lock_key = lock_key(key);
+        let latest_key = latest_key(key);
+        Box::new(
+            KvsClient::connect(self.shard(key))
+                .and_then(|client| client.begin())
+                .and_then(move |client| client.get_bytes(lock_key))
+                .and_then(move |(lock, client)| {
+                    client
+                        .get_bytes(latest_key)
+                        .map(|(latest, client)| (lock, latest, client))
+                })
+                .and_then(move |(lock, latest, client)| -> Result<_> {
+                    let lock = lock.map(|lock| bincode::deserialize(&lock)).transpose()?;
+                    let latest = latest.map_or(0, |latest| decode_ts_value(&latest));
+                    Ok((f(lock, latest)?, client))
+                })
+                .and_then(|(writes, client)| {
+                    stream::iter_ok(writes).fold(
+                        client,
+                        |client, (column_key, value)| match value {
+                            Some(value) => Either::A(client.set_bytes(column_key, value)),
+                            None => Either::B(client.remove_bytes(column_key)),
+                        },
+                    )
+                })
+                .and_then(|client| client.commit())
+                .map(|_| ()),
+        )
+    }
+
+    /// Locks the key for the transaction and stores its new value.
+    ///
+    /// A lock left by another transaction is resolved first if it can be.
+    fn prewrite_row(&self, key: Vec<u8>, value: Option<Vec<u8>>, lock: Lock) -> BoxFuture<()> {
+        let client = self.clone();
+        Box::new(self.read_lock(&key).and_then(move |current| {
+            let resolved = match current {
+                Some(current) => {
+                    Either::A(client.resolve(key.clone(), current).map_err(|e| match e {
+                        KvsError::KeyLocked => KvsError::TransactionConflict,
+                        e => e,
+                    }))
+                }
+                None => Either::B(future::ok(())),
+            };
+            resolved.and_then(move |_| {
+                let start_ts = lock.start_ts;
+                let lock = bincode::serialize(&lock);
+                client.update_row(&key.clone(), move |current, latest| {
+                    // written since the transaction started
+                    if current.is_some() || latest >= start_ts {
+                        return Err(KvsError::TransactionConflict);
+                    }
+                    let mut writes = vec![(lock_key(&key), Some(lock?))];
+                    if let Some(value) = value {
+                        writes.push((data_key(&key, start_ts), Some(value)));
+                    }
+                    Ok(writes)
+                })
+            })
+        }))
+    }
+
+    /// Stores the write record of the transaction and removes its lock.
+    ///
+    /// If the key is not locked by the transaction anymore, it fails with
+    /// `KvsError::TransactionConflict` for the primary, and does nothing for a
+    /// secondary as it has been rolled forward.
+    fn commit_row(
+        &self,
+        key: Vec<u8>,
+        start_ts: u64,
+        commit_ts: u64,
+        kind: WriteKind,
+        primary: bool,
+    ) -> BoxFuture<()> {
+        self.update_row(&key.clone(), move |lock, latest| {
+            if lock.map_or(true, |lock| lock.start_ts != start_ts) {
+                return if primary {
+                    Err(KvsError::TransactionConflict)
+                } else {
+                    Ok(Vec::new())
+                };
+            }
+            let write = Write { start_ts, kind };
+            Ok(vec![
+                (
+                    write_key(&key, commit_ts),
+                    Some(bincode::serialize(&write)?),
+                ),
+                (
+                    latest_key(&key),
+                    Some(encode_ts_value(latest.max(commit_ts))),
+                ),
+                (lock_key(&key), None),
+            ])
+        })
+    }
+
+    /// Stores a rollback record of the transaction, and removes its lock and value
+    /// if the key is locked by it.
+    ///
+    /// It fails with `KvsError::TransactionConflict` if the key is expected to be
+    /// locked by the transaction but is not, or the other way around.
+    fn rollback_row(&self, key: Vec<u8>, start_ts: u64, locked: bool) -> BoxFuture<()> {
+        self.update_row(&key.clone(), move |lock, latest| {
+            let owned = lock.map_or(false, |lock| lock.start_ts == start_ts);
+            if owned != locked {
+                return Err(KvsError::TransactionConflict);
+            }
+            rollback_writes(&key, start_ts, latest, owned)
+        })
+    }
+
+    /// Removes the lock and value of the transaction from the key if it is locked by
+    /// the transaction.
+    fn cleanup_row(&self, key: Vec<u8>, start_ts: u64) -> BoxFuture<()> {
+        self.update_row(&key.clone(), move |lock, latest| {
+            if lock.map_or(true, |lock| lock.start_ts != start_ts) {
+                return Ok(Vec::new());
+            }
+            rollback_writes(&key, start_ts, latest, true)
+        })
+    }
+
+    /// Looks up whether the transaction holding the lock is committed, rolled back
+    /// or may still be running, from its primary.
+    ///
+    /// A transaction whose primary lock has expired is rolled back. So is a
+    /// transaction that has not locked its primary, so that it never can.
+    fn status(&self, lock: &Lock) -> BoxFuture<TransactionStatus> {
+        let client = self.clone();
+        let primary = lock.primary.clone();
+        let start_ts = lock.start_ts;
+        Box::new(
+            self.read_lock(&primary)
+                .and_then(move |primary_lock| -> BoxFuture<_> {
+                    match primary_lock {
+                        Some(primary_lock) if primary_lock.start_ts == start_ts => {
+                            Box::new(client.timestamp().and_then(move |now_ts| -> BoxFuture<_> {
+                                if primary_lock.is_expired(now_ts) {
+                                    Box::new(
+                                        client
+                                            .rollback_row(primary, start_ts, true)
+                                            .map(|_| TransactionStatus::RolledBack),
+                                    )
+                                } else {
+                                    Box::new(future::ok(TransactionStatus::Running))
+                                }
+                            }))
+                        }
+                        _ => {
+                            let start = Included(write_key(&primary, u64::MAX));
+                            Box::new(
+                                client
+                                    .read_writes(&primary, start, start_ts, None)
+                                    .and_then(move |writes| -> BoxFuture<_> {
+                                        let write = writes
+                                            .into_iter()
+                                            .find(|(_, write)| write.start_ts == start_ts);
+                                        match write {
+                                            Some((_, write))
+                                                if write.kind == WriteKind::Rollback =>
+                                            {
+                                                Box::new(future::ok(TransactionStatus::RolledBack))
+                                            }
+                                            Some((commit_ts, _)) => Box::new(future::ok(
+                                                TransactionStatus::Committed(commit_ts),
+                                            )),
+                                            None => Box::new(
+                                                client
+                                                    .rollback_row(primary, start_ts, false)
+                                                    .map(|_| TransactionStatus::RolledBack),
+                                            ),
+                                        }
+                                    }),
+                            )
+                        }
+                    }
+                }),
+        )
+    }
+
+    /// Resolves a lock on the key left by another transaction, rolling the key
+    /// forward or back as the transaction is committed or rolled back.
+    ///
+    /// It fails with `KvsError::KeyLocked` if the transaction may still be running.
+    fn resolve(&self, key: Vec<u8>, lock: Lock) -> BoxFuture<()> {
+        let client = self.clone();
+        Box::new(self.status(&lock).and_then(move |status| match status {
+            TransactionStatus::Committed(commit_ts) => {
+                client.commit_row(key, lock.start_ts, commit_ts, lock.kind, false)
+            }
+            // the primary is rolled back when the status is looked up
+            TransactionStatus::RolledBack if key == lock.primary => Box::new(future::ok(())),
+            TransactionStatus::RolledBack => client.rollback_row(key, lock.start_ts, true),
+            TransactionStatus::Running => Box::new(future::err(KvsError::KeyLocked)),
+        }))
+    }
+}
+
+enum TransactionStatus {
+    Committed(u64),
+    RolledBack,
+    Running,
+}
+
+/// Returns the writes rolling back the transaction on the key.
+fn rollback_writes(key: &[u8], start_ts: u64, latest: u64, locked: bool) -> Result<RowWrites> {
+    let rollback = Write {
+        start_ts,
+        kind: WriteKind::Rollback,
+    };
+    let mut writes = vec![
+        (
+            write_key(key, start_ts),
+            Some(bincode::serialize(&rollback)?),
+        ),
+        (latest_key(key), Some(encode_ts_value(latest.max(start_ts)))),
+    ];
+    if locked {
+        writes.push((lock_key(key), None));
+        writes.push((data_key(key, start_ts), None));
+    }
+    Ok(writes)
+}
+
+fn encode_ts_value(ts: u64) -> Vec<u8> {
+    ts.to_be_bytes().to_vec()
+}
+
+fn decode_ts_value(value: &[u8]) -> u64 {
+    let mut ts = [0; 8];
+    ts.copy_from_slice(value);
+    u64::from_be_bytes(ts)
+}
+
+/// A Percolator transaction reading a snapshot of the shards at its start timestamp.
+///
+/// Writes are buffered until the transaction commits, and are seen by its own
+/// reads. The commit fails with `KvsError::TransactionConflict` if a key written
+/// by the transaction has been written by another transaction since it started,
+/// or is locked by a running one.
+///
+/// A read fails with `KvsError::KeyLocked` if the key is locked by a transaction
+/// started before that may still be running. It can be retried after a while: the
+/// lock is resolved once the other transaction finishes or its lock expires.
+pub struct PercolatorTransaction {
+    client: PercolatorClient,
+    start_ts: u64,
+    writes: Mutex<BTreeMap<Vec<u8>, Option<Vec<u8>>>>,
+}
+
+impl PercolatorTransaction {
+    /// Returns the start timestamp of the transaction.
+    pub fn start_ts(&self) -> u64 {
+        self.start_ts
+    }
+
+    /// Gets the value of a given key as seen by the transaction.
+    ///
+    /// Returns `None` if the given key does not exist.
+    pub fn get_bytes(
+        &self,
+        key: Vec<u8>,
+    ) -> Box<dyn Future<Item = Option<Vec<u8>>, Error = KvsError> + Send> {
+        if let Some(value) = self.writes.lock().unwrap().get(&key) {
+            return Box::new(future::ok(value.clone()));
+        }
+        let client = self.client.clone();
+        let start_ts = self.start_ts;
+        Box::new(future::loop_fn((), move |()| {
+            let client = client.clone();
+            let key = key.clone();
+            client
+                .read_lock(&key)
+                .and_then(move |lock| -> BoxFuture<_> {
+                    match lock {
+                        // the transaction holding it may commit before the start
+                        Some(lock) if lock.start_ts <= start_ts => {
+                            Box::new(client.resolve(key, lock).then(|res| match res {
+                                // resolved concurrently by another transaction
+                                Ok(()) | Err(KvsError::TransactionConflict) => {
+                                    Ok(Loop::Continue(()))
+                                }
+                                Err(e) => Err(e),
+                            }))
+                        }
+                        _ => Box::new(client.read_version(key, start_ts).map(Loop::Break)),
+                    }
+                })
+        }))
+    }
+
+    /// Sets the value of a key when the transaction commits.
+    pub fn set_bytes(&self, key: Vec<u8>, value: Vec<u8>) {
+        self.writes.lock().unwrap().insert(key, Some(value));
+    }
+
+    /// Removes a key when the transaction commits.
+    ///
+    /// Removing a key that does not exist is not an error, it does nothing instead.
+    pub fn remove_bytes(&self, key: Vec<u8>) {
+        self.writes.lock().unwrap().insert(key, None);
+    }
+
+    /// Gets the string value of a given string key as seen by the transaction.
+    pub fn get(
+        &self,
+        key: String,
+    ) -> Box<dyn Future<Item = Option<String>, Error = KvsError> + Send> {
+        Box::new(
+            self.get_bytes(key.into_bytes())
+                .and_then(|value| Ok(value.map(String::from_utf8).transpose()?)),
+        )
+    }
+
+    /// Sets the value of a string key to a string when the transaction commits.
+    pub fn set(&self, key: String, value: String) {
+        self.set_bytes(key.into_bytes(), value.into_bytes())
+    }
+
+    /// Removes a string key when the transaction commits.
+    pub fn remove(&self, key: String) {
+        self.remove_bytes(key.into_bytes())
+    }
+
+    /// Commits the transaction in two phases.
+    ///
+    /// The transaction is committed once its primary is. Secondaries that fail to be
+    /// committed afterwards are rolled forward by the next transactions reading them.
+    pub fn commit(self) -> Box<dyn Future<Item = (), Error = KvsError> + Send> {
+        Box::new(
+            self.prewrite()
+                .and_then(PrewrittenTransaction::commit_primary)
+                .and_then(CommittedTransaction::commit_secondaries),
+        )
+    }
+
+    /// Runs the first phase of the commit, locking the written keys and storing
+    /// their new values.
+    ///
+    /// If it fails, the locks taken so far are removed.
+    pub fn prewrite(
+        self,
+    ) -> Box<dyn Future<Item = PrewrittenTransaction, Error = KvsError> + Send> {
+        let client = self.client;
+        let start_ts = self.start_ts;
+        let writes: Vec<_> = self.writes.into_inner().unwrap().into_iter().collect();
+        let keys: Vec<_> = writes
+            .iter()
+            .map(|(key, value)| {
+                let kind = if value.is_some() {
+                    WriteKind::Put
+                } else {
+                    WriteKind::Delete
+                };
+                (key.clone(), kind)
+            })
+            .collect();
+        let primary = match keys.first() {
+            Some((primary, _)) => primary.clone(),
+            None => {
+                return Box::new(future::ok(PrewrittenTransaction {
+                    client,
+                    start_ts,
+                    keys,
+                }))
+            }
+        };
+        let ttl_millis = client.lock_ttl.as_millis() as u64;
+        let prewrites = {
+            let client = client.clone();
+            stream::iter_ok(writes).for_each(move |(key, value)| {
+                let lock = Lock {
+                    primary: primary.clone(),
+                    start_ts,
+                    ttl_millis,
+                    kind: if value.is_some() {
+                        WriteKind::Put
+                    } else {
+                        WriteKind::Delete
+                    },
+                };
+                client.prewrite_row(key, value, lock)
+            })
+        };
+        Box::new(prewrites.then(move |res| match res {
+            Ok(()) => Either::A(future::ok(PrewrittenTransaction {
+                client,
+                start_ts,
+                keys,
+            })),
+            Err(e) => Either::B(cleanup(&client, start_ts, keys).then(|_| Err(e))),
+        }))
+    }
+
+    /// Discards the writes of the transaction.
+    pub fn rollback(self) {}
+}
+
+/// Removes the locks of the transaction from the keys.
+fn cleanup(
+    client: &PercolatorClient,
+    start_ts: u64,
+    keys: Vec<(Vec<u8>, WriteKind)>,
+) -> impl Future<Item = (), Error = KvsError> {
+    let cleanups: Vec<_> = keys
+        .into_iter()
+        .map(|(key, _)| {
+            client.cleanup_row(key, start_ts).then(|res| {
+                if let Err(e) = res {
+                    warn!("Failed to remove a lock of an aborted transaction: {}", e);
+                }
+                Ok(())
+            })
+        })
+        .collect();
+    future::join_all(cleanups).map(|_| ())
+}
+
+/// A Percolator transaction whose written keys are all locked.
+///
+/// Dropping it without committing leaves the locks behind, as a crashed client
+/// would. The transaction is rolled back by the next transactions meeting them once
+/// they expire.
+pub struct PrewrittenTransaction {
+    client: PercolatorClient,
+    start_ts: u64,
+    // the written keys, the primary first
+    keys: Vec<(Vec<u8>, WriteKind)>,
+}
+
+impl PrewrittenTransaction {
+    /// Returns the start timestamp of the transaction.
+    pub fn start_ts(&self) -> u64 {
+        self.start_ts
+    }
+
+    /// Commits the primary at a new timestamp, which commits the transaction.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::TransactionConflict` if the transaction has been rolled
+    /// back by another one, in which case its remaining locks are removed.
+    pub fn commit_primary(
+        self,
+    ) -> Box<dyn Future<Item = CommittedTransaction, Error = KvsError> + Send> {
+        let client = self.client;
+        let start_ts = self.start_ts;
+        let mut keys = self.keys;
+        if keys.is_empty() {
+            return Box::new(future::ok(CommittedTransaction {
+                client,
+                start_ts,
+                commit_ts: start_ts,
+                secondaries: keys,
+            }));
+        }
+        let (primary, kind) = keys.remove(0);
+        Box::new(client.timestamp().and_then(move |commit_ts| {
+            client
+                .commit_row(primary, start_ts, commit_ts, kind, true)
+                .then(move |res| match res {
+                    Ok(()) => Either::A(future::ok(CommittedTransaction {
+                        client,
+                        start_ts,
+                        commit_ts,
+                        secondaries: keys,
+                    })),
+                    Err(e) => Either::B(cleanup(&client, start_ts, keys).then(|_| Err(e))),
+                })
+        }))
+    }
+}
+
+/// A Percolator transaction whose primary is committed.
+///
+/// The transaction is committed. Dropping it without committing the secondaries
+/// leaves their locks behind, as a crashed client would, and they are rolled
+/// forward by the next transactions meeting them.
+pub struct CommittedTransaction {
+    client: PercolatorClient,
+    start_ts: u64,
+    commit_ts: u64,
+    secondaries: Vec<(Vec<u8>, WriteKind)>,
+}
+
+impl CommittedTransaction {
+    /// Returns the commit timestamp of the transaction.
+    pub fn commit_ts(&self) -> u64 {
+        self.commit_ts
+    }
+
+    /// Commits the secondaries.
+    ///
+    /// Failures are only logged, as the secondaries are rolled forward later anyway.
+    pub fn commit_secondaries(self) -> Box<dyn Future<Item = (), Error = KvsError> + Send> {
+        let client = self.client;
+        let start_ts = self.start_ts;
+        let commit_ts = self.commit_ts;
+        let commits: Vec<_> = self
+            .secondaries
+            .into_iter()
+            .map(|(key, kind)| {
+                client
+                    .commit_row(key, start_ts, commit_ts, kind, false)
+                    .then(|res| {
+                        if let Err(e) = res {
+                            warn!("Failed to commit a secondary: {}", e);
+                        }
+                        Ok(())
+                    })
+            })
+            .collect();
+        Box::new(future::join_all(commits).map(|_| ()))
+    }
+}
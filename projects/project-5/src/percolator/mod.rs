@@ -0,0 +1,119 @@
+//! Percolator-style distributed transactions across the shards of a deployment.
+//!
+//! Keys are spread over several `kvs-server`s, the shards, and a `kvs-server`
+//! started with `--oracle` hands out the timestamps. A transaction reads a snapshot
+//! at its start timestamp and commits in two phases:
+//!
+//! 1. Prewrite: every written key is locked and its new value is stored at the
+//!    start timestamp. The first key is the primary, and the locks of the other
+//!    keys, the secondaries, point to it. It fails if a key is locked or has been
+//!    written since the transaction started.
+//! 2. Commit: the write record of the primary is stored at a commit timestamp and
+//!    its lock is removed. From then on the transaction is committed, and the
+//!    secondaries are committed the same way.
+//!
+//! Each step changes one row of a shard atomically in a transaction of the shard's
+//! engine. The rows are stored as columns of the key, each in a key of its own:
+//!
+//! - lock: the lock of a transaction that has prewritten the key
+//! - data: the value written by the transaction started at a timestamp
+//! - write: the start timestamp of the transaction committed at a timestamp, or
+//!   a rollback record of the transaction started at the timestamp
+//! - latest: the largest timestamp in the write column
+//!
+//! A client crashing between the phases leaves locks behind. A transaction meeting
+//! such a lock looks up the primary: if the primary is committed, the key is
+//! rolled forward; if the primary lock has outlived its time to live, the
+//! transaction is rolled back.
+//!
+//! The shards should only be used for Percolator transactions.
+
+use serde::{Deserialize, Serialize};
+
+mod client;
+mod oracle;
+
+pub use self::client::{
+    CommittedTransaction, PercolatorClient, PercolatorTransaction, PrewrittenTransaction,
+};
+pub(crate) use self::oracle::physical_millis;
+pub use self::oracle::TimestampOracle;
+
+const LOCK_COLUMN: u8 = b'l';
+const DATA_COLUMN: u8 = b'd';
+const WRITE_COLUMN: u8 = b'w';
+const LATEST_COLUMN: u8 = b'v';
+
+/// The lock of a key prewritten by a transaction.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct Lock {
+    primary: Vec<u8>,
+    start_ts: u64,
+    ttl_millis: u64,
+    kind: WriteKind,
+}
+
+impl Lock {
+    /// Whether the lock has outlived its time to live at the timestamp.
+    fn is_expired(&self, now_ts: u64) -> bool {
+        physical_millis(now_ts) >= physical_millis(self.start_ts) + self.ttl_millis
+    }
+}
+
+/// A record of the write column.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct Write {
+    start_ts: u64,
+    kind: WriteKind,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+enum WriteKind {
+    Put,
+    Delete,
+    Rollback,
+}
+
+/// Returns the key of a column of the key.
+///
+/// The key is prefixed with its length so that the columns of a key never share a
+/// prefix with the columns of a longer key.
+fn column_key(column: u8, key: &[u8]) -> Vec<u8> {
+    let mut column_key = Vec::with_capacity(key.len() + 13);
+    column_key.push(column);
+    column_key.extend_from_slice(&(key.len() as u32).to_be_bytes());
+    column_key.extend_from_slice(key);
+    column_key
+}
+
+/// Returns the key of a column of the key at a timestamp.
+///
+/// Timestamps are stored inverted, so the larger ones come first in key order.
+fn column_key_at(column: u8, key: &[u8], ts: u64) -> Vec<u8> {
+    let mut column_key = column_key(column, key);
+    column_key.extend_from_slice(&(!ts).to_be_bytes());
+    column_key
+}
+
+fn lock_key(key: &[u8]) -> Vec<u8> {
+    column_key(LOCK_COLUMN, key)
+}
+
+fn latest_key(key: &[u8]) -> Vec<u8> {
+    column_key(LATEST_COLUMN, key)
+}
+
+fn data_key(key: &[u8], start_ts: u64) -> Vec<u8> {
+    column_key_at(DATA_COLUMN, key, start_ts)
+}
+
+fn write_key(key: &[u8], commit_ts: u64) -> Vec<u8> {
+    column_key_at(WRITE_COLUMN, key, commit_ts)
+}
+
+/// Returns the timestamp of a key returned by `column_key_at`.
+fn decode_ts(column_key: &[u8]) -> u64 {
+    let mut ts = [0; 8];
+    ts.copy_from_slice(&column_key[column_key.len() - 8..]);
+    !u64::from_be_bytes(ts)
+}
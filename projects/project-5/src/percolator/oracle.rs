@@ -0,0 +1,101 @@
+use crate::engines::now_millis;
+use crate::vfs::{OsVfs, Vfs};
+use crate::{KvsError, Result};
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+/// Number of low bits of a timestamp counting the timestamps allocated in the same
+/// millisecond.
+const LOGICAL_BITS: u32 = 18;
+
+/// How far ahead of the timestamps handed out the persisted bound is moved, in
+/// milliseconds.
+const RESERVE_MILLIS: u64 = 3000;
+
+/// Allocates strictly increasing timestamps for Percolator transactions.
+///
+/// A timestamp is the physical time in milliseconds since the Unix epoch shifted
+/// left by 18 bits, plus a logical counter for the timestamps allocated in the same
+/// millisecond. So the age of a lock can be told from the timestamps.
+///
+/// The oracle persists a bound of the physical time it may hand out, some time
+/// ahead, and starts from that bound when it is opened again. Timestamps keep
+/// increasing over restarts even if the clock goes backwards.
+pub struct TimestampOracle {
+    path: PathBuf,
+    state: Mutex<OracleState>,
+}
+
+struct OracleState {
+    // the last timestamp handed out
+    last: u64,
+    // the persisted bound of the physical time of timestamps
+    reserved_until: u64,
+}
+
+impl TimestampOracle {
+    /// Opens the oracle persisting its state in the file at the given path.
+    ///
+    /// The file is created if it does not exist.
+    pub fn open(path: impl Into<PathBuf>) -> Result<TimestampOracle> {
+        let path = path.into();
+        let reserved_until = match fs::read(&path) {
+            Ok(bytes) => {
+                if bytes.len() != 8 {
+                    return Err(KvsError::StringError(format!(
+                        "Invalid timestamp oracle file {:?}",
+                        path
+                    )));
+                }
+                let mut reserved_until = [0; 8];
+                reserved_until.copy_from_slice(&bytes);
+                u64::from_le_bytes(reserved_until)
+            }
+            Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => 0,
+            Err(e) => return Err(e.into()),
+        };
+        Ok(TimestampOracle {
+            path,
+            state: Mutex::new(OracleState {
+                last: reserved_until << LOGICAL_BITS,
+                reserved_until,
+            }),
+        })
+    }
+
+    /// Returns a timestamp larger than all the timestamps handed out before.
+    pub fn timestamp(&self) -> Result<u64> {
+        let mut state = self.state.lock().unwrap();
+        let ts = (now_millis() << LOGICAL_BITS).max(state.last + 1);
+        if physical_millis(ts) >= state.reserved_until {
+            let reserved_until = physical_millis(ts) + RESERVE_MILLIS;
+            self.persist(reserved_until)?;
+            state.reserved_until = reserved_until;
+        }
+        state.last = ts;
+        Ok(ts)
+    }
+
+    /// Replaces the persisted bound atomically and durably.
+    fn persist(&self, reserved_until: u64) -> Result<()> {
+        let tmp_path = self.path.with_extension("tmp");
+        let mut file = File::create(&tmp_path)?;
+        file.write_all(&reserved_until.to_le_bytes())?;
+        file.sync_all()?;
+        fs::rename(&tmp_path, &self.path)?;
+        // the rename is only durable once the directory entry is
+        let dir = match self.path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+        OsVfs.sync_dir(dir)?;
+        Ok(())
+    }
+}
+
+/// Returns the physical time of a timestamp, in milliseconds since the Unix epoch.
+pub(crate) fn physical_millis(ts: u64) -> u64 {
+    ts >> LOGICAL_BITS
+}
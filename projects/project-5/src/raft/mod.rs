@@ -0,0 +1,121 @@
+//! Replication of a key value store over a cluster of nodes with the Raft consensus
+//! algorithm.
+//!
+//! A cluster usually has 3 or 5 nodes, and keeps working as long as a majority of
+//! them can reach each other. The nodes elect a leader, which appends the sets and
+//! removes of clients to its log and replicates the log to the other nodes, the
+//! followers. An entry is committed once it is stored by a majority of the nodes,
+//! and every node applies the committed entries to its own `KvsEngine` in log
+//! order.
+//!
+//! Once enough entries have been applied, a node takes a snapshot of its engine and
+//! drops the entries covered by the snapshot from its log. A follower too far
+//! behind the leader is sent the snapshot instead of the entries, a chunk at a time.
+//!
+//! The nodes exchange `Message`s through a `Transport`. `kvs-server`s started with
+//! `--raft` send them to each other over TCP with `TcpTransport`.
+
+use serde::{Deserialize, Serialize};
+
+mod node;
+mod storage;
+mod transport;
+
+pub use self::node::{RaftNode, RaftOptions};
+pub use self::transport::TcpTransport;
+
+/// The identifier of a node in a Raft cluster.
+pub type NodeId = u64;
+
+/// Sends the messages of a Raft node to the other nodes of its cluster.
+///
+/// Messages may be lost, duplicated or reordered. The receiving side passes every
+/// message it gets to `RaftNode::step` of its node.
+pub trait Transport: Send + 'static {
+    /// Sends a message to the given node without waiting for it to be delivered.
+    fn send(&self, to: NodeId, msg: Message);
+}
+
+/// A message from a Raft node to another node of its cluster.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Message {
+    // the term of the sender
+    term: u64,
+    kind: MessageKind,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+enum MessageKind {
+    /// A candidate asks for the vote of the node.
+    RequestVote { last_index: u64, last_term: u64 },
+    /// The answer to `RequestVote`.
+    Vote { granted: bool },
+    /// The leader replicates the entries following the one at `prev_index`, or only
+    /// keeps the follower from starting an election if there are none.
+    Append {
+        prev_index: u64,
+        prev_term: u64,
+        entries: Vec<Entry>,
+        commit: u64,
+    },
+    /// The answer to `Append` and to the last chunk of `InstallSnapshot`.
+    ///
+    /// If it succeeded, the log of the follower matches the log of the leader up to
+    /// `index`. Otherwise, `index` is where the logs may match.
+    AppendReply { success: bool, index: u64 },
+    /// The leader replaces the state of a follower missing entries it has dropped.
+    ///
+    /// The snapshot is sent in chunks of its pairs, from the pair at `offset` on,
+    /// and the last chunk is `done`.
+    InstallSnapshot {
+        index: u64,
+        term: u64,
+        offset: u64,
+        pairs: Vec<(Vec<u8>, Vec<u8>)>,
+        done: bool,
+    },
+    /// The answer to the other chunks of `InstallSnapshot`: the follower has the
+    /// pairs of the snapshot at `index` up to `offset`.
+    SnapshotReply { index: u64, offset: u64 },
+}
+
+/// An entry of the replicated log.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Entry {
+    index: u64,
+    term: u64,
+    command: Command,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+enum Command {
+    /// Appended by a new leader and for reads, to learn which entries are committed.
+    Noop,
+    Set {
+        key: Vec<u8>,
+        value: Vec<u8>,
+    },
+    Remove {
+        key: Vec<u8>,
+    },
+}
+
+impl Command {
+    /// Number of bytes of the key and value of the command.
+    fn size(&self) -> usize {
+        match self {
+            Command::Noop => 0,
+            Command::Set { key, value } => key.len() + value.len(),
+            Command::Remove { key } => key.len(),
+        }
+    }
+}
+
+/// The content of the engine after applying the log up to an entry.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+struct Snapshot {
+    // index and term of the last entry applied
+    index: u64,
+    term: u64,
+    pairs: Vec<(Vec<u8>, Vec<u8>)>,
+}
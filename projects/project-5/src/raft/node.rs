@@ -0,0 +1,1042 @@
+use super::storage::{HardState, Storage};
+use super::{Command, Entry, Message, MessageKind, NodeId, Snapshot, Transport};
+use crate::engines::PeriodicTask;
+use crate::{KvsEngine, KvsError, Result, WriteBatch};
+use crossbeam::channel::{self, Receiver, Sender};
+use rand::Rng;
+use std::collections::{HashMap, HashSet};
+use std::ops::Bound::Unbounded;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, Weak};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+use tokio::prelude::*;
+use tokio::sync::oneshot;
+
+/// Maximum number of entries sent in one `Append` message.
+const MAX_APPEND_ENTRIES: usize = 64;
+/// Number of bytes of keys and values after which no more entries or snapshot pairs
+/// are added to a message.
+///
+/// Messages are sent as JSON, where a byte takes up to 4 characters, so they stay
+/// under the 8 MiB frames of the connections between servers.
+const MAX_MESSAGE_BYTES: usize = 256 * 1024;
+
+/// Options of a `RaftNode`.
+///
+/// Every node of a cluster should be given the same options.
+#[derive(Debug, Clone)]
+pub struct RaftOptions {
+    tick: Duration,
+    election_ticks: u32,
+    heartbeat_ticks: u32,
+    snapshot_threshold: u64,
+}
+
+impl RaftOptions {
+    /// Creates the default options.
+    ///
+    /// The node ticks every 50 milliseconds. A leader sends heartbeats every 2 ticks,
+    /// and a follower that does not hear from a leader for 10 to 20 ticks starts an
+    /// election. A snapshot is taken every 1024 applied entries.
+    pub fn new() -> Self {
+        RaftOptions {
+            tick: Duration::from_millis(50),
+            election_ticks: 10,
+            heartbeat_ticks: 2,
+            snapshot_threshold: 1024,
+        }
+    }
+
+    /// Sets the interval between the ticks of the node, in which the other timeouts
+    /// are counted.
+    pub fn tick(&mut self, interval: Duration) -> &mut Self {
+        self.tick = interval;
+        self
+    }
+
+    /// Sets how many ticks a follower waits for the leader before it starts an
+    /// election.
+    ///
+    /// The actual timeout is picked at random between the given number of ticks and
+    /// twice as many, so that the nodes rarely start elections at the same time.
+    pub fn election_ticks(&mut self, ticks: u32) -> &mut Self {
+        self.election_ticks = ticks.max(1);
+        self
+    }
+
+    /// Sets how many ticks there are between the heartbeats of a leader.
+    ///
+    /// It should be well below the election ticks.
+    pub fn heartbeat_ticks(&mut self, ticks: u32) -> &mut Self {
+        self.heartbeat_ticks = ticks.max(1);
+        self
+    }
+
+    /// Sets how many entries are applied between two snapshots.
+    pub fn snapshot_threshold(&mut self, entries: u64) -> &mut Self {
+        self.snapshot_threshold = entries.max(1);
+        self
+    }
+}
+
+impl Default for RaftOptions {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// A node of a Raft cluster, applying the writes committed by the cluster to a
+/// `KvsEngine`.
+///
+/// Only the leader accepts reads and writes. The other nodes fail them with
+/// `KvsError::NotLeader`, and `leader` tells which node to ask instead.
+///
+/// The node keeps its term, vote, log and snapshot in a directory of its own. The
+/// engine should not be written by anything else, and should be reopened with the
+/// same directory after a restart: the node brings it back to its snapshot and
+/// applies the committed entries again.
+///
+/// Clones of a node share the same state. The node stops when the last clone is
+/// dropped.
+#[derive(Clone)]
+pub struct RaftNode<E: KvsEngine> {
+    id: NodeId,
+    core: Arc<Mutex<RaftCore>>,
+    engine: E,
+    // stops the ticker and the applier when the last clone is dropped, after the
+    // core they work on
+    _ticker: Arc<PeriodicTask>,
+    _applier: Arc<ApplierHandle>,
+}
+
+impl<E: KvsEngine> RaftNode<E> {
+    /// Opens the node `id` of the cluster with the given members, keeping its state
+    /// in the directory at the given path.
+    ///
+    /// `members` are the identifiers of all the nodes of the cluster, including
+    /// `id`, and must be the same for every node.
+    pub fn open(
+        id: NodeId,
+        members: &[NodeId],
+        path: impl Into<PathBuf>,
+        engine: E,
+        transport: impl Transport,
+        options: &RaftOptions,
+    ) -> Result<Self> {
+        let (storage, hard_state, snapshot, log) = Storage::open(path.into())?;
+        let (sender, receiver) = channel::unbounded();
+        let (snapshot_index, snapshot_term) = (snapshot.index, snapshot.term);
+        if snapshot_index > 0 {
+            // the engine may be ahead of the snapshot or have lost writes, and the
+            // committed entries following it are applied again anyway
+            sender.send(Apply::Restore(snapshot)).unwrap();
+        }
+
+        let mut core = RaftCore {
+            id,
+            peers: members.iter().cloned().filter(|&peer| peer != id).collect(),
+            options: options.clone(),
+            storage,
+            term: hard_state.term,
+            voted_for: hard_state.voted_for,
+            role: Role::Follower,
+            leader: None,
+            log,
+            snapshot_index,
+            snapshot_term,
+            commit_index: snapshot_index,
+            applying: snapshot_index,
+            elapsed: 0,
+            election_timeout: 0,
+            waiters: HashMap::new(),
+            incoming: None,
+            outgoing: None,
+            transport: Box::new(transport),
+            applier: sender,
+        };
+        core.reset_election_timer();
+        let core = Arc::new(Mutex::new(core));
+
+        let applier = Applier {
+            engine: engine.clone(),
+            core: Arc::downgrade(&core),
+            snapshot_threshold: options.snapshot_threshold,
+            snapshot_index,
+        };
+        let thread = thread::Builder::new()
+            .name("raft-applier".to_owned())
+            .spawn(move || applier.run(receiver))?;
+        let applier = Arc::new(ApplierHandle {
+            thread: Some(thread),
+        });
+
+        let ticker = {
+            let core = Arc::downgrade(&core);
+            PeriodicTask::spawn("raft-ticker", options.tick, move || {
+                if let Some(core) = core.upgrade() {
+                    if let Err(e) = core.lock().unwrap().tick() {
+                        error!("Raft node {} failed to tick: {}", id, e);
+                    }
+                }
+            })?
+        };
+
+        Ok(RaftNode {
+            id,
+            core,
+            engine,
+            _ticker: Arc::new(ticker),
+            _applier: applier,
+        })
+    }
+
+    /// Handles a message received from another node.
+    pub fn step(&self, from: NodeId, msg: Message) {
+        if let Err(e) = self.core.lock().unwrap().step(from, msg) {
+            error!(
+                "Raft node {} failed to handle a message from {}: {}",
+                self.id, from, e
+            );
+        }
+    }
+
+    /// Returns the identifier of the node.
+    pub fn id(&self) -> NodeId {
+        self.id
+    }
+
+    /// Returns the leader of the current term, if the node knows it.
+    pub fn leader(&self) -> Option<NodeId> {
+        self.core.lock().unwrap().leader
+    }
+
+    /// Returns the current term of the node.
+    pub fn term(&self) -> u64 {
+        self.core.lock().unwrap().term
+    }
+
+    /// Gets the value of a given key, as left by every write committed before.
+    ///
+    /// The node first commits an empty entry to make sure it is still the leader.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::NotLeader` if the node is not the leader.
+    pub fn get_bytes(
+        &self,
+        key: Vec<u8>,
+    ) -> Box<dyn Future<Item = Option<Vec<u8>>, Error = KvsError> + Send> {
+        let engine = self.engine.clone();
+        Box::new(
+            self.propose(Command::Noop)
+                .and_then(move |_| engine.get_bytes(key)),
+        )
+    }
+
+    /// Sets the value of a key on all the nodes.
+    ///
+    /// The returned future completes once the write is committed and applied to the
+    /// engine of this node.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::NotLeader` if the node is not the leader, or stopped
+    /// being the leader before the write was committed. The write may still be
+    /// committed by the next leader in the latter case.
+    pub fn set_bytes(
+        &self,
+        key: Vec<u8>,
+        value: Vec<u8>,
+    ) -> Box<dyn Future<Item = (), Error = KvsError> + Send> {
+        self.propose(Command::Set { key, value })
+    }
+
+    /// Removes a given key on all the nodes.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::KeyNotFound` if the given key is not found, and
+    /// `KvsError::NotLeader` as `set_bytes` does.
+    pub fn remove_bytes(
+        &self,
+        key: Vec<u8>,
+    ) -> Box<dyn Future<Item = (), Error = KvsError> + Send> {
+        self.propose(Command::Remove { key })
+    }
+
+    /// Gets the string value of a given string key.
+    pub fn get(
+        &self,
+        key: String,
+    ) -> Box<dyn Future<Item = Option<String>, Error = KvsError> + Send> {
+        Box::new(
+            self.get_bytes(key.into_bytes())
+                .and_then(|value| Ok(value.map(String::from_utf8).transpose()?)),
+        )
+    }
+
+    /// Sets the value of a string key to a string on all the nodes.
+    pub fn set(
+        &self,
+        key: String,
+        value: String,
+    ) -> Box<dyn Future<Item = (), Error = KvsError> + Send> {
+        self.set_bytes(key.into_bytes(), value.into_bytes())
+    }
+
+    /// Removes a given string key on all the nodes.
+    pub fn remove(&self, key: String) -> Box<dyn Future<Item = (), Error = KvsError> + Send> {
+        self.remove_bytes(key.into_bytes())
+    }
+
+    /// Appends a command to the log, and completes once it is applied.
+    fn propose(&self, command: Command) -> Box<dyn Future<Item = (), Error = KvsError> + Send> {
+        let (tx, rx) = oneshot::channel();
+        if let Err(e) = self.core.lock().unwrap().propose(command, tx) {
+            error!("Raft node {} failed to propose: {}", self.id, e);
+        }
+        Box::new(
+            rx.map_err(|e| KvsError::StringError(format!("{}", e)))
+                .flatten(),
+        )
+    }
+}
+
+enum Role {
+    Follower,
+    Candidate {
+        // the other nodes that voted for the node
+        votes: HashSet<NodeId>,
+    },
+    Leader {
+        progress: HashMap<NodeId, Progress>,
+    },
+}
+
+/// What the leader knows of the log of a follower.
+struct Progress {
+    // index of the next entry to send
+    next: u64,
+    // index up to which the log is known to match the log of the leader
+    matched: u64,
+    // index of the snapshot being sent and number of its pairs the follower has
+    snapshot: Option<(u64, u64)>,
+}
+
+/// The state of a node, changed by ticks, messages and proposals.
+struct RaftCore {
+    id: NodeId,
+    // the other nodes of the cluster
+    peers: Vec<NodeId>,
+    options: RaftOptions,
+    storage: Storage,
+    term: u64,
+    voted_for: Option<NodeId>,
+    role: Role,
+    leader: Option<NodeId>,
+    // entries following the snapshot
+    log: Vec<Entry>,
+    snapshot_index: u64,
+    snapshot_term: u64,
+    commit_index: u64,
+    // index of the last entry handed to the applier
+    applying: u64,
+    // ticks since the last heartbeat sent or heard
+    elapsed: u32,
+    election_timeout: u32,
+    // proposals waiting to be applied, by index, with the term they were proposed in
+    waiters: HashMap<u64, (u64, oneshot::Sender<Result<()>>)>,
+    // the chunks of a snapshot received so far from the leader
+    incoming: Option<Snapshot>,
+    // the snapshot being sent to followers, kept rather than loaded for every chunk
+    outgoing: Option<Snapshot>,
+    transport: Box<dyn Transport>,
+    applier: Sender<Apply>,
+}
+
+impl RaftCore {
+    fn tick(&mut self) -> Result<()> {
+        self.elapsed += 1;
+        match self.role {
+            Role::Leader { .. } => {
+                if self.elapsed >= self.options.heartbeat_ticks {
+                    self.elapsed = 0;
+                    self.broadcast_append()?;
+                }
+            }
+            _ => {
+                if self.elapsed >= self.election_timeout {
+                    self.campaign()?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn propose(&mut self, command: Command, done: oneshot::Sender<Result<()>>) -> Result<()> {
+        if !matches!(self.role, Role::Leader { .. }) {
+            let _ = done.send(Err(KvsError::NotLeader));
+            return Ok(());
+        }
+        let entry = Entry {
+            index: self.last_index() + 1,
+            term: self.term,
+            command,
+        };
+        let index = entry.index;
+        if let Err(e) = self.append(vec![entry]) {
+            let _ = done.send(Err(e));
+            return Ok(());
+        }
+        self.waiters.insert(index, (self.term, done));
+        self.maybe_commit()?;
+        self.broadcast_append()
+    }
+
+    fn step(&mut self, from: NodeId, msg: Message) -> Result<()> {
+        if msg.term > self.term {
+            let leader = match msg.kind {
+                MessageKind::Append { .. } | MessageKind::InstallSnapshot { .. } => Some(from),
+                _ => None,
+            };
+            self.become_follower(msg.term, leader)?;
+        } else if msg.term < self.term {
+            // tell a stale leader or candidate about the current term
+            match msg.kind {
+                MessageKind::RequestVote { .. } => {
+                    self.send(from, MessageKind::Vote { granted: false })
+                }
+                MessageKind::Append { .. } | MessageKind::InstallSnapshot { .. } => self.send(
+                    from,
+                    MessageKind::AppendReply {
+                        success: false,
+                        index: 0,
+                    },
+                ),
+                _ => {}
+            }
+            return Ok(());
+        }
+
+        match msg.kind {
+            MessageKind::RequestVote {
+                last_index,
+                last_term,
+            } => self.handle_request_vote(from, last_index, last_term),
+            MessageKind::Vote { granted } => self.handle_vote(from, granted),
+            MessageKind::Append {
+                prev_index,
+                prev_term,
+                entries,
+                commit,
+            } => self.handle_append(from, prev_index, prev_term, entries, commit),
+            MessageKind::AppendReply { success, index } => {
+                self.handle_append_reply(from, success, index)
+            }
+            MessageKind::InstallSnapshot {
+                index,
+                term,
+                offset,
+                pairs,
+                done,
+            } => self.handle_snapshot(from, Snapshot { index, term, pairs }, offset, done),
+            MessageKind::SnapshotReply { index, offset } => {
+                self.handle_snapshot_reply(from, index, offset)
+            }
+        }
+    }
+
+    fn campaign(&mut self) -> Result<()> {
+        self.term += 1;
+        self.voted_for = Some(self.id);
+        self.save_hard_state()?;
+        self.role = Role::Candidate {
+            votes: HashSet::new(),
+        };
+        self.leader = None;
+        self.reset_election_timer();
+        info!(
+            "Raft node {} starts an election in term {}",
+            self.id, self.term
+        );
+        if self.peers.is_empty() {
+            return self.become_leader();
+        }
+        let (last_index, last_term) = (self.last_index(), self.last_term());
+        for &peer in &self.peers {
+            self.send(
+                peer,
+                MessageKind::RequestVote {
+                    last_index,
+                    last_term,
+                },
+            );
+        }
+        Ok(())
+    }
+
+    fn become_follower(&mut self, term: u64, leader: Option<NodeId>) -> Result<()> {
+        if term > self.term {
+            self.term = term;
+            self.voted_for = None;
+            self.save_hard_state()?;
+        }
+        self.role = Role::Follower;
+        self.leader = leader;
+        self.outgoing = None;
+        self.reset_election_timer();
+        Ok(())
+    }
+
+    fn become_leader(&mut self) -> Result<()> {
+        info!("Raft node {} is the leader of term {}", self.id, self.term);
+        let next = self.last_index() + 1;
+        self.role = Role::Leader {
+            progress: self
+                .peers
+                .iter()
+                .map(|&peer| {
+                    let progress = Progress {
+                        next,
+                        matched: 0,
+                        snapshot: None,
+                    };
+                    (peer, progress)
+                })
+                .collect(),
+        };
+        self.leader = Some(self.id);
+        self.elapsed = 0;
+        // entries of earlier terms are only known to be committed once an entry of
+        // the current term is
+        self.append(vec![Entry {
+            index: next,
+            term: self.term,
+            command: Command::Noop,
+        }])?;
+        self.maybe_commit()?;
+        self.broadcast_append()
+    }
+
+    fn handle_request_vote(&mut self, from: NodeId, last_index: u64, last_term: u64) -> Result<()> {
+        let up_to_date = (last_term, last_index) >= (self.last_term(), self.last_index());
+        let granted = up_to_date && self.voted_for.map_or(true, |voted_for| voted_for == from);
+        if granted {
+            self.voted_for = Some(from);
+            self.save_hard_state()?;
+            self.elapsed = 0;
+        }
+        self.send(from, MessageKind::Vote { granted });
+        Ok(())
+    }
+
+    fn handle_vote(&mut self, from: NodeId, granted: bool) -> Result<()> {
+        let quorum = self.quorum();
+        if let Role::Candidate { votes } = &mut self.role {
+            if granted {
+                votes.insert(from);
+            }
+            if votes.len() + 1 >= quorum {
+                return self.become_leader();
+            }
+        }
+        Ok(())
+    }
+
+    fn handle_append(
+        &mut self,
+        from: NodeId,
+        mut prev_index: u64,
+        mut prev_term: u64,
+        mut entries: Vec<Entry>,
+        commit: u64,
+    ) -> Result<()> {
+        if !matches!(self.role, Role::Follower) {
+            self.become_follower(self.term, Some(from))?;
+        }
+        self.leader = Some(from);
+        self.elapsed = 0;
+
+        let last_index = prev_index + entries.len() as u64;
+        if prev_index < self.snapshot_index {
+            // the entries covered by the snapshot are committed, so they match
+            let covered = (self.snapshot_index - prev_index) as usize;
+            entries.drain(..covered.min(entries.len()));
+            prev_index = self.snapshot_index;
+            prev_term = self.snapshot_term;
+        }
+        if self.term_at(prev_index) != Some(prev_term) {
+            let index = self.last_index().min(prev_index - 1);
+            self.send(
+                from,
+                MessageKind::AppendReply {
+                    success: false,
+                    index,
+                },
+            );
+            return Ok(());
+        }
+
+        // skip the entries already in the log, and drop the conflicting ones
+        let present = entries
+            .iter()
+            .take_while(|entry| self.term_at(entry.index) == Some(entry.term))
+            .count();
+        entries.drain(..present);
+        if let Some(first) = entries.first() {
+            let first_index = first.index;
+            if first_index <= self.last_index() {
+                self.log
+                    .truncate((first_index - self.snapshot_index - 1) as usize);
+                self.storage.rewrite_log(&self.log)?;
+                self.fail_waiters(|index| index >= first_index);
+            }
+            self.append(entries)?;
+        }
+
+        let last_index = last_index.max(self.snapshot_index);
+        if commit > self.commit_index {
+            self.commit_index = commit.min(last_index).max(self.commit_index);
+            self.apply();
+        }
+        self.send(
+            from,
+            MessageKind::AppendReply {
+                success: true,
+                index: last_index,
+            },
+        );
+        Ok(())
+    }
+
+    fn handle_append_reply(&mut self, from: NodeId, success: bool, index: u64) -> Result<()> {
+        let last_index = self.last_index();
+        let progress = match &mut self.role {
+            Role::Leader { progress } => match progress.get_mut(&from) {
+                Some(progress) => progress,
+                None => return Ok(()),
+            },
+            _ => return Ok(()),
+        };
+        if success {
+            progress.snapshot = None;
+            progress.matched = progress.matched.max(index);
+            progress.next = progress.next.max(index + 1);
+            let behind = progress.next <= last_index;
+            if let Role::Leader { progress } = &self.role {
+                let snapshot_index = self.snapshot_index;
+                if progress.values().all(|p| p.next > snapshot_index) {
+                    self.outgoing = None;
+                }
+            }
+            self.maybe_commit()?;
+            if behind {
+                self.send_append(from)?;
+            }
+        } else {
+            progress.next = (index + 1)
+                .min(progress.next.saturating_sub(1))
+                .max(progress.matched + 1);
+            self.send_append(from)?;
+        }
+        Ok(())
+    }
+
+    /// Receives a chunk of a snapshot, starting at its pair at `offset`, and installs
+    /// the snapshot once its last chunk is received.
+    fn handle_snapshot(
+        &mut self,
+        from: NodeId,
+        chunk: Snapshot,
+        offset: u64,
+        done: bool,
+    ) -> Result<()> {
+        if !matches!(self.role, Role::Follower) {
+            self.become_follower(self.term, Some(from))?;
+        }
+        self.leader = Some(from);
+        self.elapsed = 0;
+
+        let index = chunk.index;
+        if index > self.commit_index {
+            // chunks are lost, duplicated or reordered like any message, and the
+            // pairs of a snapshot are the same on every node
+            let mut snapshot = match self.incoming.take() {
+                Some(incoming) if (incoming.index, incoming.term) == (index, chunk.term) => {
+                    incoming
+                }
+                _ => Snapshot {
+                    index,
+                    term: chunk.term,
+                    pairs: Vec::new(),
+                },
+            };
+            let next = snapshot.pairs.len() as u64 == offset;
+            if next {
+                snapshot.pairs.extend(chunk.pairs);
+            }
+            if !next || !done {
+                let offset = snapshot.pairs.len() as u64;
+                self.incoming = Some(snapshot);
+                self.send(from, MessageKind::SnapshotReply { index, offset });
+                return Ok(());
+            }
+
+            info!("Raft node {} installs a snapshot at {}", self.id, index);
+            // the entries following the snapshot are kept if the log agrees with it
+            if self.term_at(index) == Some(snapshot.term) {
+                self.log.drain(..(index - self.snapshot_index) as usize);
+            } else {
+                self.log.clear();
+            }
+            self.storage.save_snapshot(&snapshot)?;
+            self.storage.rewrite_log(&self.log)?;
+            self.snapshot_index = index;
+            self.snapshot_term = snapshot.term;
+            self.commit_index = index;
+            self.applying = index;
+
+            // the results of the proposals covered by the snapshot are unknown
+            self.fail_waiters(|waiter| waiter <= index);
+            self.applier.send(Apply::Restore(snapshot)).unwrap();
+        }
+        self.send(
+            from,
+            MessageKind::AppendReply {
+                success: true,
+                index: self.commit_index,
+            },
+        );
+        Ok(())
+    }
+
+    fn handle_snapshot_reply(&mut self, from: NodeId, index: u64, offset: u64) -> Result<()> {
+        let snapshot_index = self.snapshot_index;
+        match &mut self.role {
+            Role::Leader { progress } => match progress.get_mut(&from) {
+                // replies to duplicated chunks are dropped, so that each chunk is sent
+                // once more at most
+                Some(progress)
+                    if progress.next <= snapshot_index
+                        && progress.snapshot != Some((index, offset)) =>
+                {
+                    progress.snapshot = Some((index, offset));
+                }
+                _ => return Ok(()),
+            },
+            _ => return Ok(()),
+        }
+        self.send_append(from)
+    }
+
+    /// Drops the entries covered by a snapshot taken by the applier.
+    fn compact(&mut self, snapshot: Snapshot) -> Result<()> {
+        if snapshot.index <= self.snapshot_index {
+            return Ok(());
+        }
+        self.storage.save_snapshot(&snapshot)?;
+        self.log
+            .drain(..(snapshot.index - self.snapshot_index) as usize);
+        self.storage.rewrite_log(&self.log)?;
+        self.snapshot_index = snapshot.index;
+        self.snapshot_term = snapshot.term;
+        Ok(())
+    }
+
+    /// Commits the entries of the current term stored by a majority of the nodes.
+    fn maybe_commit(&mut self) -> Result<()> {
+        let progress = match &self.role {
+            Role::Leader { progress } => progress,
+            _ => return Ok(()),
+        };
+        let quorum = self.quorum();
+        let mut committed = None;
+        for index in (self.commit_index + 1..=self.last_index()).rev() {
+            if self.term_at(index) != Some(self.term) {
+                break;
+            }
+            let stored = 1 + progress.values().filter(|p| p.matched >= index).count();
+            if stored >= quorum {
+                committed = Some(index);
+                break;
+            }
+        }
+        if let Some(index) = committed {
+            self.commit_index = index;
+            self.apply();
+        }
+        Ok(())
+    }
+
+    /// Hands the committed entries to the applier.
+    fn apply(&mut self) {
+        if self.applying >= self.commit_index {
+            return;
+        }
+        let start = (self.applying - self.snapshot_index) as usize;
+        let end = (self.commit_index - self.snapshot_index) as usize;
+        let mut entries = Vec::with_capacity(end - start);
+        for entry in &self.log[start..end] {
+            let done = match self.waiters.remove(&entry.index) {
+                // another leader replaced the proposal
+                Some((term, done)) if term != entry.term => {
+                    let _ = done.send(Err(KvsError::NotLeader));
+                    None
+                }
+                waiter => waiter.map(|(_, done)| done),
+            };
+            entries.push((entry.clone(), done));
+        }
+        self.applying = self.commit_index;
+        self.applier.send(Apply::Entries(entries)).unwrap();
+    }
+
+    /// Fails the proposals waiting for the entries at the matching indexes, which
+    /// have been lost or whose results are unknown.
+    fn fail_waiters(&mut self, lost: impl Fn(u64) -> bool) {
+        let indexes: Vec<u64> = self
+            .waiters
+            .keys()
+            .cloned()
+            .filter(|&index| lost(index))
+            .collect();
+        for index in indexes {
+            let (_, done) = self.waiters.remove(&index).unwrap();
+            let _ = done.send(Err(KvsError::NotLeader));
+        }
+    }
+
+    fn broadcast_append(&mut self) -> Result<()> {
+        for peer in self.peers.clone() {
+            self.send_append(peer)?;
+        }
+        Ok(())
+    }
+
+    fn send_append(&mut self, to: NodeId) -> Result<()> {
+        let (next, sending) = match &self.role {
+            Role::Leader { progress } => (progress[&to].next, progress[&to].snapshot),
+            _ => return Ok(()),
+        };
+        if next <= self.snapshot_index {
+            if self.outgoing.as_ref().map(|snapshot| snapshot.index) != Some(self.snapshot_index) {
+                self.outgoing = Some(self.storage.load_snapshot()?);
+            }
+            let snapshot = self.outgoing.as_ref().unwrap();
+            // a new snapshot is sent from its start
+            let offset = match sending {
+                Some((index, offset)) if index == snapshot.index => offset,
+                _ => 0,
+            };
+            let pairs = snapshot.pairs.iter().skip(offset as usize).cloned();
+            let pairs = take_bytes(pairs, |pair| pair.0.len() + pair.1.len());
+            let done = offset + pairs.len() as u64 >= snapshot.pairs.len() as u64;
+            self.send(
+                to,
+                MessageKind::InstallSnapshot {
+                    index: snapshot.index,
+                    term: snapshot.term,
+                    offset,
+                    pairs,
+                    done,
+                },
+            );
+        } else {
+            let prev_index = next - 1;
+            let start = (next - self.snapshot_index - 1) as usize;
+            let end = self.log.len().min(start + MAX_APPEND_ENTRIES);
+            let entries = self.log[start.min(end)..end].iter().cloned();
+            self.send(
+                to,
+                MessageKind::Append {
+                    prev_index,
+                    prev_term: self.term_at(prev_index).unwrap(),
+                    entries: take_bytes(entries, |entry| entry.command.size()),
+                    commit: self.commit_index,
+                },
+            );
+        }
+        Ok(())
+    }
+
+    fn send(&self, to: NodeId, kind: MessageKind) {
+        self.transport.send(
+            to,
+            Message {
+                term: self.term,
+                kind,
+            },
+        );
+    }
+
+    /// Persists entries and appends them to the log.
+    fn append(&mut self, entries: Vec<Entry>) -> Result<()> {
+        self.storage.append(&entries)?;
+        self.log.extend(entries);
+        Ok(())
+    }
+
+    fn save_hard_state(&mut self) -> Result<()> {
+        self.storage.save_hard_state(&HardState {
+            term: self.term,
+            voted_for: self.voted_for,
+        })
+    }
+
+    fn reset_election_timer(&mut self) {
+        let ticks = self.options.election_ticks;
+        self.elapsed = 0;
+        self.election_timeout = rand::thread_rng().gen_range(ticks, 2 * ticks);
+    }
+
+    fn quorum(&self) -> usize {
+        (self.peers.len() + 1) / 2 + 1
+    }
+
+    fn last_index(&self) -> u64 {
+        self.log
+            .last()
+            .map_or(self.snapshot_index, |entry| entry.index)
+    }
+
+    fn last_term(&self) -> u64 {
+        self.log
+            .last()
+            .map_or(self.snapshot_term, |entry| entry.term)
+    }
+
+    /// Returns the term of the entry at the index, if it is in the log or is the
+    /// last entry covered by the snapshot.
+    fn term_at(&self, index: u64) -> Option<u64> {
+        if index < self.snapshot_index {
+            None
+        } else if index == self.snapshot_index {
+            Some(self.snapshot_term)
+        } else {
+            self.log
+                .get((index - self.snapshot_index - 1) as usize)
+                .map(|entry| entry.term)
+        }
+    }
+}
+
+/// Takes the items until their sizes add up to more than `MAX_MESSAGE_BYTES`,
+/// keeping at least one.
+fn take_bytes<T>(items: impl Iterator<Item = T>, size: impl Fn(&T) -> usize) -> Vec<T> {
+    let mut total = 0;
+    let mut taken = Vec::new();
+    for item in items {
+        total += size(&item);
+        if total > MAX_MESSAGE_BYTES && !taken.is_empty() {
+            break;
+        }
+        taken.push(item);
+    }
+    taken
+}
+
+/// Work for the applier thread.
+enum Apply {
+    /// Committed entries, with the senders of the proposals waiting for them.
+    Entries(Vec<(Entry, Option<oneshot::Sender<Result<()>>>)>),
+    /// Replaces the content of the engine with a snapshot.
+    Restore(Snapshot),
+}
+
+/// Applies the committed entries to the engine in a background thread, and takes
+/// the snapshots.
+struct Applier<E: KvsEngine> {
+    engine: E,
+    core: Weak<Mutex<RaftCore>>,
+    snapshot_threshold: u64,
+    snapshot_index: u64,
+}
+
+impl<E: KvsEngine> Applier<E> {
+    /// Runs until the node is dropped.
+    fn run(mut self, receiver: Receiver<Apply>) {
+        for apply in receiver {
+            match apply {
+                Apply::Entries(entries) => {
+                    let mut last = None;
+                    for (entry, done) in entries {
+                        let res = self.apply(entry.command);
+                        match done {
+                            Some(done) => {
+                                let _ = done.send(res);
+                            }
+                            None => match res {
+                                Ok(()) | Err(KvsError::KeyNotFound) => {}
+                                Err(e) => error!("Failed to apply a Raft entry: {}", e),
+                            },
+                        }
+                        last = Some((entry.index, entry.term));
+                    }
+                    if let Some((index, term)) = last {
+                        if index.saturating_sub(self.snapshot_index) >= self.snapshot_threshold {
+                            if let Err(e) = self.take_snapshot(index, term) {
+                                error!("Failed to take a Raft snapshot: {}", e);
+                            }
+                        }
+                    }
+                }
+                Apply::Restore(snapshot) => {
+                    self.snapshot_index = snapshot.index;
+                    if let Err(e) = self.restore(snapshot) {
+                        error!("Failed to restore a Raft snapshot: {}", e);
+                    }
+                }
+            }
+        }
+    }
+
+    fn apply(&self, command: Command) -> Result<()> {
+        match command {
+            Command::Noop => Ok(()),
+            Command::Set { key, value } => self.engine.set_bytes(key, value).wait(),
+            Command::Remove { key } => self.engine.remove_bytes(key).wait(),
+        }
+    }
+
+    fn take_snapshot(&mut self, index: u64, term: u64) -> Result<()> {
+        let pairs = self.engine.scan_bytes(Unbounded, Unbounded, None).wait()?;
+        self.snapshot_index = index;
+        if let Some(core) = self.core.upgrade() {
+            core.lock()
+                .unwrap()
+                .compact(Snapshot { index, term, pairs })?;
+        }
+        Ok(())
+    }
+
+    /// Replaces the content of the engine atomically with the snapshot.
+    fn restore(&self, snapshot: Snapshot) -> Result<()> {
+        let keys: HashSet<Vec<u8>> = snapshot.pairs.iter().map(|(key, _)| key.clone()).collect();
+        let mut batch = WriteBatch::new();
+        for key in self.engine.keys_bytes(Vec::new()).wait() {
+            let key = key?;
+            if !keys.contains(&key) {
+                batch.remove(key);
+            }
+        }
+        for (key, value) in snapshot.pairs {
+            batch.set(key, value);
+        }
+        self.engine.write_batch(batch).wait()
+    }
+}
+
+struct ApplierHandle {
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Drop for ApplierHandle {
+    fn drop(&mut self) {
+        if let Some(thread) = self.thread.take() {
+            if thread.join().is_err() {
+                error!("Raft applier thread panicked");
+            }
+        }
+    }
+}
@@ -0,0 +1,198 @@
+use super::{Entry, NodeId, Snapshot};
+use crate::vfs::{OsVfs, Vfs};
+use crate::{KvsError, Result};
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+const STATE_FILE: &str = "state";
+const SNAPSHOT_FILE: &str = "snapshot";
+const LOG_FILE: &str = "log";
+/// Length of the header of a log entry: the little-endian `u32` length of the
+/// encoded entry followed by its CRC32 checksum.
+const ENTRY_HEADER_LEN: usize = 8;
+/// Number of bytes after a damaged entry in which another entry is looked for, to
+/// tell a torn tail from a corrupted log.
+const TORN_TAIL_SCAN_LEN: usize = 1024 * 1024;
+
+/// The term and the vote of a node, which must not be forgotten over restarts.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub(super) struct HardState {
+    pub(super) term: u64,
+    pub(super) voted_for: Option<NodeId>,
+}
+
+/// The persistent state of a Raft node, in files of its directory.
+///
+/// The hard state and the snapshot are replaced atomically by renaming a new file
+/// over the old one. Entries are appended to the log file, each framed with its
+/// length and checksum, and the file is rewritten when entries are dropped from the
+/// log.
+pub(super) struct Storage {
+    path: PathBuf,
+    log: BufWriter<File>,
+}
+
+impl Storage {
+    /// Opens the storage in the directory at the given path, creating it if needed,
+    /// and loads the hard state, the snapshot and the entries following the
+    /// snapshot.
+    ///
+    /// An entry partially written by a crash is dropped from the end of the log.
+    ///
+    /// # Errors
+    ///
+    /// It returns an error if an entry in the middle of the log fails its checksum.
+    pub(super) fn open(path: PathBuf) -> Result<(Storage, HardState, Snapshot, Vec<Entry>)> {
+        fs::create_dir_all(&path)?;
+        let hard_state = match read_file(&path.join(STATE_FILE))? {
+            Some(bytes) => bincode::deserialize(&bytes)?,
+            None => HardState::default(),
+        };
+        let snapshot = load_snapshot(&path)?;
+
+        let mut entries: Vec<Entry> = Vec::new();
+        if let Some(bytes) = read_file(&path.join(LOG_FILE))? {
+            let mut offset = 0;
+            while offset < bytes.len() {
+                let entry = match decode_entry(&bytes[offset..]) {
+                    Some((entry, len)) => {
+                        offset += len;
+                        entry
+                    }
+                    // a torn write of the last entry, which was never acknowledged
+                    None if !has_entry_after(&bytes[offset..]) => {
+                        warn!("Dropping the torn tail of the Raft log at {}", offset);
+                        break;
+                    }
+                    None => {
+                        return Err(KvsError::StringError(format!(
+                            "Corrupted Raft log entry at offset {}",
+                            offset
+                        )))
+                    }
+                };
+                // entries covered by the snapshot are left if a crash interrupted
+                // the compaction of the log
+                if entry.index > snapshot.index {
+                    entries.push(entry);
+                }
+            }
+        }
+
+        let log = write_log(&path, &entries)?;
+        Ok((Storage { path, log }, hard_state, snapshot, entries))
+    }
+
+    pub(super) fn save_hard_state(&mut self, hard_state: &HardState) -> Result<()> {
+        write_atomically(
+            &self.path.join(STATE_FILE),
+            &bincode::serialize(hard_state)?,
+        )
+    }
+
+    pub(super) fn save_snapshot(&mut self, snapshot: &Snapshot) -> Result<()> {
+        write_atomically(
+            &self.path.join(SNAPSHOT_FILE),
+            &bincode::serialize(snapshot)?,
+        )
+    }
+
+    pub(super) fn load_snapshot(&self) -> Result<Snapshot> {
+        load_snapshot(&self.path)
+    }
+
+    /// Appends entries to the log file and syncs it.
+    pub(super) fn append(&mut self, entries: &[Entry]) -> Result<()> {
+        for entry in entries {
+            self.log.write_all(&encode_entry(entry)?)?;
+        }
+        self.log.flush()?;
+        self.log.get_ref().sync_data()?;
+        Ok(())
+    }
+
+    /// Replaces the log file with one holding the given entries.
+    pub(super) fn rewrite_log(&mut self, entries: &[Entry]) -> Result<()> {
+        self.log = write_log(&self.path, entries)?;
+        Ok(())
+    }
+}
+
+/// Replaces the log file with one holding the given entries, and opens it for
+/// appending.
+fn write_log(path: &Path, entries: &[Entry]) -> Result<BufWriter<File>> {
+    let log_path = path.join(LOG_FILE);
+    let mut bytes = Vec::new();
+    for entry in entries {
+        bytes.extend_from_slice(&encode_entry(entry)?);
+    }
+    write_atomically(&log_path, &bytes)?;
+    Ok(BufWriter::new(
+        OpenOptions::new().append(true).open(&log_path)?,
+    ))
+}
+
+fn load_snapshot(path: &Path) -> Result<Snapshot> {
+    match read_file(&path.join(SNAPSHOT_FILE))? {
+        Some(bytes) => Ok(bincode::deserialize(&bytes)?),
+        None => Ok(Snapshot::default()),
+    }
+}
+
+/// Reads a file, returning `None` if it does not exist.
+fn read_file(path: &Path) -> Result<Option<Vec<u8>>> {
+    match fs::read(path) {
+        Ok(bytes) => Ok(Some(bytes)),
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Replaces the content of a file, so that either the old or the new content is
+/// found after a crash.
+fn write_atomically(path: &Path, bytes: &[u8]) -> Result<()> {
+    let tmp_path = path.with_extension("tmp");
+    let mut file = File::create(&tmp_path)?;
+    file.write_all(bytes)?;
+    file.sync_all()?;
+    fs::rename(&tmp_path, path)?;
+    // the old content would come back after a power loss if the rename was lost
+    OsVfs.sync_dir(path.parent().expect("a file of the storage directory"))?;
+    Ok(())
+}
+
+/// Encodes an entry with its header.
+fn encode_entry(entry: &Entry) -> Result<Vec<u8>> {
+    let payload = bincode::serialize(entry)?;
+    let mut bytes = Vec::with_capacity(ENTRY_HEADER_LEN + payload.len());
+    bytes.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+    bytes.extend_from_slice(&crc32fast::hash(&payload).to_le_bytes());
+    bytes.extend_from_slice(&payload);
+    Ok(bytes)
+}
+
+/// Decodes the entry at the start of the bytes, returning it with its length,
+/// header included.
+///
+/// Returns `None` if the entry is cut off or fails its checksum.
+fn decode_entry(bytes: &[u8]) -> Option<(Entry, usize)> {
+    if bytes.len() < ENTRY_HEADER_LEN {
+        return None;
+    }
+    let len = u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as usize;
+    let checksum = u32::from_le_bytes([bytes[4], bytes[5], bytes[6], bytes[7]]);
+    let payload = bytes[ENTRY_HEADER_LEN..].get(..len)?;
+    if crc32fast::hash(payload) != checksum {
+        return None;
+    }
+    let entry = bincode::deserialize(payload).ok()?;
+    Some((entry, ENTRY_HEADER_LEN + len))
+}
+
+/// Returns whether a whole entry starts in the bytes following the first byte of a
+/// damaged entry, in which case they are not the torn tail of the log.
+fn has_entry_after(bytes: &[u8]) -> bool {
+    (1..bytes.len().min(TORN_TAIL_SCAN_LEN)).any(|start| decode_entry(&bytes[start..]).is_some())
+}
@@ -0,0 +1,68 @@
+use super::{Message, NodeId, Transport};
+use crate::{KvsClient, Result};
+use crossbeam::channel::{self, Sender, TrySendError};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::thread;
+use tokio::prelude::*;
+
+/// Maximum number of messages waiting to be sent to a node.
+const MAX_PENDING_MESSAGES: usize = 256;
+
+/// A `Transport` sending messages to the `kvs-server`s of the other nodes, as
+/// requests of the client protocol.
+///
+/// Each node is sent its messages in order over a connection of its own, by a
+/// background thread. Messages are dropped if the node can't be reached or too many
+/// are waiting.
+pub struct TcpTransport {
+    senders: HashMap<NodeId, Sender<Message>>,
+}
+
+impl TcpTransport {
+    /// Creates the transport of the node `id` of the cluster whose nodes listen at
+    /// the given addresses.
+    pub fn new(id: NodeId, addrs: &HashMap<NodeId, SocketAddr>) -> Result<Self> {
+        let mut senders = HashMap::new();
+        for (&peer, &addr) in addrs {
+            if peer == id {
+                continue;
+            }
+            let (sender, receiver) = channel::bounded(MAX_PENDING_MESSAGES);
+            thread::Builder::new()
+                .name(format!("raft-sender-{}", peer))
+                .spawn(move || {
+                    let mut connection = None;
+                    // stops when the transport is dropped
+                    for msg in receiver {
+                        let client = match connection.take() {
+                            Some(client) => client,
+                            None => match KvsClient::connect(addr).wait() {
+                                Ok(client) => client,
+                                Err(e) => {
+                                    debug!("Failed to connect to Raft node {}: {}", peer, e);
+                                    continue;
+                                }
+                            },
+                        };
+                        match client.raft_message(id, msg).wait() {
+                            Ok(client) => connection = Some(client),
+                            Err(e) => debug!("Failed to send to Raft node {}: {}", peer, e),
+                        }
+                    }
+                })?;
+            senders.insert(peer, sender);
+        }
+        Ok(TcpTransport { senders })
+    }
+}
+
+impl Transport for TcpTransport {
+    fn send(&self, to: NodeId, msg: Message) {
+        if let Some(sender) = self.senders.get(&to) {
+            if let Err(TrySendError::Full(_)) = sender.try_send(msg) {
+                debug!("Dropping a message to Raft node {}", to);
+            }
+        }
+    }
+}
@@ -0,0 +1,129 @@
+//! Asynchronous primary/replica replication by shipping the log of a `KvStore`.
+//!
+//! A replica follows the log of its primary from a position, the generation and
+//! offset of the next record to read, and applies the writes to its own
+//! `KvsEngine` in log order. Logs are followed in the order of their generations.
+//! The compaction logs are skipped, as they only repeat the writes of the logs
+//! before them.
+//!
+//! The replica persists the position it has applied the writes up to, and resumes
+//! from it after a restart or a lost connection. If the log of the position has
+//! been compacted away on the primary in the meantime, the replica copies the whole
+//! store of the primary instead, then follows the log from where it ended when the
+//! copy started.
+//!
+//! Replication is asynchronous: a write is acknowledged by the primary before any
+//! replica has it, and the replicas lag behind. A `kvs-server` started with
+//! `--replica-of` serves reads of its replica and rejects writes.
+//!
+//! Other systems, like search indexers, can subscribe to the writes of a store
+//! instead. They are sent the `ChangeEvent`s of the writes committed after a
+//! sequence number, first read from the logs, then as the writes are committed. A
+//! subscriber resumes from the sequence number of the last event it has seen,
+//! provided the writes after it have not been compacted away.
+//!
+//! The primary serves its log with `KvsServer::with_replication_source`, and its
+//! writes to subscribers with `KvsServer::with_change_feed`, which need an engine
+//! implementing `ReplicationSource` and `ChangeFeed`.
+
+use serde::{Deserialize, Serialize};
+
+mod replica;
+
+pub use self::replica::Replica;
+
+/// A position in the logs of a `KvStore`.
+///
+/// The default position is before the first log, which a new replica always
+/// copies the store from.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LogPosition {
+    /// Generation of the log
+    pub gen: u64,
+    /// Offset of the next record in the log
+    pub offset: u64,
+}
+
+/// A write read from the log of a primary.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum LogRecord {
+    /// Sets the value of a key, which expires at the given time in milliseconds
+    /// since the Unix epoch if there is one.
+    Set {
+        /// The key
+        key: Vec<u8>,
+        /// The value
+        value: Vec<u8>,
+        /// The expiry deadline of the key
+        expires_at: Option<u64>,
+    },
+    /// Removes a key.
+    Remove {
+        /// The key
+        key: Vec<u8>,
+    },
+    /// Sets and removes applied atomically, whose sets never expire.
+    Batch(Vec<LogRecord>),
+}
+
+/// The answer of a primary to a replica reading its log.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum LogChunk {
+    /// The records following the position read from, which are none if the replica
+    /// has caught up.
+    Records {
+        /// The records in log order
+        records: Vec<LogRecord>,
+        /// The position to read the following records from
+        next: LogPosition,
+    },
+    /// The log of the position has been compacted away. The replica has to copy the
+    /// store, then follow the log from `next`.
+    Compacted {
+        /// The end of the log when the copy starts
+        next: LogPosition,
+    },
+}
+
+/// A committed write sent to a subscriber.
+///
+/// Every record of the log has a sequence number, one more than the record before
+/// it. The writes of a batch share the sequence number of their record.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum ChangeEvent {
+    /// Sets the value of a key, which expires at the given time in milliseconds
+    /// since the Unix epoch if there is one.
+    Set {
+        /// The sequence number of the write
+        seq: u64,
+        /// The key
+        key: Vec<u8>,
+        /// The value
+        value: Vec<u8>,
+        /// The expiry deadline of the key
+        expires_at: Option<u64>,
+    },
+    /// Removes a key.
+    Remove {
+        /// The sequence number of the write
+        seq: u64,
+        /// The key
+        key: Vec<u8>,
+    },
+}
+
+impl ChangeEvent {
+    /// Returns the sequence number of the write.
+    pub fn seq(&self) -> u64 {
+        match self {
+            ChangeEvent::Set { seq, .. } | ChangeEvent::Remove { seq, .. } => *seq,
+        }
+    }
+
+    /// Returns the key written.
+    pub fn key(&self) -> &[u8] {
+        match self {
+            ChangeEvent::Set { key, .. } | ChangeEvent::Remove { key, .. } => key,
+        }
+    }
+}
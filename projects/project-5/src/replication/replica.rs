@@ -0,0 +1,243 @@
+use super::{LogChunk, LogPosition, LogRecord};
+use crate::engines::{now_millis, PeriodicTask};
+use crate::{KvsClient, KvsEngine, KvsError, Result, WriteBatch};
+use std::collections::HashSet;
+use std::fs::{self, File};
+use std::io::Write;
+use std::net::SocketAddr;
+use std::ops::Bound::{Excluded, Included, Unbounded};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::prelude::*;
+
+/// How often a replica asks its primary for new writes once it has caught up.
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Maximum number of pairs copied at a time when a replica copies the store.
+const COPY_CHUNK_LEN: usize = 256;
+
+/// A replica of a primary `kvs-server`, following its log in a background thread.
+///
+/// The connection to the primary is opened again on the next poll if it fails.
+/// The thread is stopped when the replica is dropped.
+pub struct Replica {
+    position: Arc<Mutex<Option<LogPosition>>>,
+    // stops the background poller when dropped
+    _poller: PeriodicTask,
+}
+
+impl Replica {
+    /// Starts replicating the server at `primary` to the engine.
+    ///
+    /// The position applied up to is persisted in the file at the given path, and
+    /// replication resumes from it if the file exists.
+    pub fn start<E: KvsEngine>(
+        engine: E,
+        primary: SocketAddr,
+        path: impl Into<PathBuf>,
+    ) -> Result<Replica> {
+        let path = path.into();
+        let position = Arc::new(Mutex::new(load_position(&path)?));
+        let mut follower = Follower {
+            engine,
+            primary,
+            path,
+            position: Arc::clone(&position),
+            client: None,
+        };
+        let poller = PeriodicTask::spawn("kvs-replica", POLL_INTERVAL, move || {
+            if let Err(e) = follower.catch_up() {
+                warn!("Replication from {} failed: {}", follower.primary, e);
+                // reconnect on the next poll
+                follower.client = None;
+            }
+        })?;
+        Ok(Replica {
+            position,
+            _poller: poller,
+        })
+    }
+
+    /// Returns the position in the log of the primary the writes are applied up to,
+    /// or `None` if the replica has never copied the store of the primary.
+    pub fn position(&self) -> Option<LogPosition> {
+        *self.position.lock().unwrap()
+    }
+}
+
+/// The state of the background thread of a replica.
+struct Follower<E: KvsEngine> {
+    engine: E,
+    primary: SocketAddr,
+    path: PathBuf,
+    position: Arc<Mutex<Option<LogPosition>>>,
+    // the connection to the primary, if it is open
+    client: Option<KvsClient>,
+}
+
+impl<E: KvsEngine> Follower<E> {
+    /// Applies the writes of the primary until the end of its log.
+    fn catch_up(&mut self) -> Result<()> {
+        loop {
+            let from = self.position.lock().unwrap().unwrap_or_default();
+            let (chunk, client) = self.client()?.read_log(from).wait()?;
+            self.client = Some(client);
+            match chunk {
+                LogChunk::Records { records, next } => {
+                    let caught_up = records.is_empty();
+                    for record in records {
+                        apply(&self.engine, record)?;
+                    }
+                    if next != from {
+                        self.save_position(next)?;
+                    }
+                    if caught_up {
+                        return Ok(());
+                    }
+                }
+                LogChunk::Compacted { next } => {
+                    info!("Copying the store of {}", self.primary);
+                    self.copy()?;
+                    self.save_position(next)?;
+                }
+            }
+        }
+    }
+
+    /// Replaces the content of the engine with the content of the primary.
+    ///
+    /// The pairs are copied in chunks while the primary keeps changing, so they may
+    /// be newer than the position the log is followed from afterwards. Applying the
+    /// writes following that position again makes the replica consistent.
+    fn copy(&mut self) -> Result<()> {
+        let mut start = Unbounded;
+        loop {
+            let (records, client) = self
+                .client()?
+                .scan_records(start.clone(), COPY_CHUNK_LEN)
+                .wait()?;
+            self.client = Some(client);
+            // the chunk covers the keys up to the last one, or all the remaining keys
+            // if it is the last chunk
+            let end = match records.last() {
+                Some(LogRecord::Set { key, .. }) if records.len() == COPY_CHUNK_LEN => {
+                    Included(key.clone())
+                }
+                _ => Unbounded,
+            };
+            let copied: HashSet<&[u8]> = records
+                .iter()
+                .filter_map(|record| match record {
+                    LogRecord::Set { key, .. } => Some(key.as_slice()),
+                    _ => None,
+                })
+                .collect();
+            let stale: Vec<Vec<u8>> = self
+                .engine
+                .scan_bytes(start, end.clone(), None)
+                .wait()?
+                .into_iter()
+                .map(|(key, _)| key)
+                .filter(|key| !copied.contains(key.as_slice()))
+                .collect();
+            for key in stale {
+                apply(&self.engine, LogRecord::Remove { key })?;
+            }
+            for record in records {
+                apply(&self.engine, record)?;
+            }
+            match end {
+                Included(key) => start = Excluded(key),
+                _ => return Ok(()),
+            }
+        }
+    }
+
+    /// Takes the connection to the primary, connecting again if there is none.
+    fn client(&mut self) -> Result<KvsClient> {
+        match self.client.take() {
+            Some(client) => Ok(client),
+            None => KvsClient::connect(self.primary).wait(),
+        }
+    }
+
+    /// Replaces the persisted position atomically.
+    fn save_position(&self, position: LogPosition) -> Result<()> {
+        let tmp_path = self.path.with_extension("tmp");
+        let mut file = File::create(&tmp_path)?;
+        file.write_all(&position.gen.to_le_bytes())?;
+        file.write_all(&position.offset.to_le_bytes())?;
+        file.sync_all()?;
+        fs::rename(&tmp_path, &self.path)?;
+        *self.position.lock().unwrap() = Some(position);
+        Ok(())
+    }
+}
+
+/// Reads the position persisted in the file, or `None` if there is no file.
+fn load_position(path: &Path) -> Result<Option<LogPosition>> {
+    match fs::read(path) {
+        Ok(bytes) => {
+            if bytes.len() != 16 {
+                return Err(KvsError::StringError(format!(
+                    "Invalid replica file {:?}",
+                    path
+                )));
+            }
+            let mut gen = [0; 8];
+            let mut offset = [0; 8];
+            gen.copy_from_slice(&bytes[..8]);
+            offset.copy_from_slice(&bytes[8..]);
+            Ok(Some(LogPosition {
+                gen: u64::from_le_bytes(gen),
+                offset: u64::from_le_bytes(offset),
+            }))
+        }
+        Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Applies a write of the primary to the engine.
+///
+/// Writes may be applied more than once, so removing a missing key is not an error.
+fn apply<E: KvsEngine>(engine: &E, record: LogRecord) -> Result<()> {
+    let res = match record {
+        LogRecord::Set {
+            key,
+            value,
+            expires_at: None,
+        } => engine.set_bytes(key, value).wait(),
+        LogRecord::Set {
+            key,
+            value,
+            expires_at: Some(expires_at),
+        } => {
+            let now = now_millis();
+            if expires_at > now {
+                let ttl = Duration::from_millis(expires_at - now);
+                engine.set_with_ttl_bytes(key, value, ttl).wait()
+            } else {
+                // the key has already expired on the primary too
+                engine.remove_bytes(key).wait()
+            }
+        }
+        LogRecord::Remove { key } => engine.remove_bytes(key).wait(),
+        LogRecord::Batch(records) => {
+            let mut batch = WriteBatch::new();
+            for record in records {
+                match record {
+                    LogRecord::Set { key, value, .. } => batch.set(key, value),
+                    LogRecord::Remove { key } => batch.remove(key),
+                    LogRecord::Batch(_) => return Err(KvsError::UnexpectedCommandType),
+                };
+            }
+            engine.write_batch(batch).wait()
+        }
+    };
+    match res {
+        Err(KvsError::KeyNotFound) => Ok(()),
+        res => res,
+    }
+}
@@ -1,20 +1,96 @@
 use crate::common::{Request, Response};
-use crate::{KvsEngine, KvsError, Result};
+use crate::percolator::TimestampOracle;
+use crate::raft::{NodeId, RaftNode};
+use crate::replication::{ChangeEvent, LogChunk, LogPosition, LogRecord, Replica};
+use crate::{ChangeFeed, KvsEngine, KvsError, ReplicationSource, Result, Transaction};
+use std::collections::HashMap;
 use std::net::SocketAddr;
+use std::ops::Bound;
+use std::sync::Arc;
 use tokio::codec::{FramedRead, FramedWrite, LengthDelimitedCodec};
 use tokio::net::{TcpListener, TcpStream};
 use tokio::prelude::*;
 use tokio_serde_json::{ReadJson, WriteJson};
 
+/// Maximum number of keys or pairs sent in one response frame of a stream.
+const STREAM_CHUNK_LEN: usize = 1024;
+
+type BoxFuture<T> = Box<dyn Future<Item = T, Error = KvsError> + Send>;
+type BoxStream<T> = Box<dyn Stream<Item = T, Error = KvsError> + Send>;
+type ReadLog<E> = fn(&E, LogPosition) -> BoxFuture<LogChunk>;
+type ScanRecords<E> = fn(&E, Bound<Vec<u8>>, usize) -> BoxFuture<Vec<LogRecord>>;
+type Subscribe<E> = fn(&E, u64) -> BoxStream<ChangeEvent>;
+
 /// The server of a key value store.
 pub struct KvsServer<E: KvsEngine> {
     engine: E,
+    oracle: Option<Arc<TimestampOracle>>,
+    raft: Option<RaftMember<E>>,
+    // the replica of another server run by the server, which makes it read-only
+    replica: Option<Arc<Replica>>,
+    extensions: Extensions<E>,
+}
+
+/// The methods of the engine from the traits beyond `KvsEngine` that it
+/// implements, as enabled by the methods of the server bounded on them.
+///
+/// The requests of the traits that are not enabled fail.
+#[derive(Clone)]
+struct Extensions<E> {
+    read_log: Option<ReadLog<E>>,
+    scan_records: Option<ScanRecords<E>>,
+    subscribe: Option<Subscribe<E>>,
+}
+
+/// The Raft node served by a server, and the addresses of the servers of its
+/// cluster.
+#[derive(Clone)]
+struct RaftMember<E: KvsEngine> {
+    node: RaftNode<E>,
+    addrs: Arc<HashMap<NodeId, SocketAddr>>,
 }
 
 impl<E: KvsEngine> KvsServer<E> {
     /// Create a `KvsServer` with a given storage engine.
     pub fn new(engine: E) -> Self {
-        KvsServer { engine }
+        KvsServer {
+            engine,
+            oracle: None,
+            raft: None,
+            replica: None,
+            extensions: Extensions {
+                read_log: None,
+                scan_records: None,
+                subscribe: None,
+            },
+        }
+    }
+
+    /// Makes the server also hand out the timestamps of the given oracle.
+    pub fn with_oracle(mut self, oracle: TimestampOracle) -> Self {
+        self.oracle = Some(Arc::new(oracle));
+        self
+    }
+
+    /// Makes the server serve a node of a Raft cluster instead of its engine, which
+    /// should be the engine of the node.
+    ///
+    /// `addrs` are the addresses of the servers of all the nodes of the cluster.
+    pub fn with_raft(mut self, node: RaftNode<E>, addrs: HashMap<NodeId, SocketAddr>) -> Self {
+        self.raft = Some(RaftMember {
+            node,
+            addrs: Arc::new(addrs),
+        });
+        self
+    }
+
+    /// Makes the server serve a read-only replica of another server, which should be
+    /// replicated to the engine of the server.
+    ///
+    /// Writes are rejected with `KvsError::ReadOnly`.
+    pub fn with_replica(mut self, replica: Replica) -> Self {
+        self.replica = Some(Arc::new(replica));
+        self
     }
 
     /// Run the server listening on the given address
@@ -25,34 +101,97 @@ impl<E: KvsEngine> KvsServer<E> {
             .map_err(|e| error!("IO error: {}", e))
             .for_each(move |tcp| {
                 let engine = self.engine.clone();
-                serve(engine, tcp).map_err(|e| error!("Error on serving client: {}", e))
+                let oracle = self.oracle.clone();
+                let raft = self.raft.clone();
+                let read_only = self.replica.is_some();
+                let extensions = self.extensions.clone();
+                // connections are served concurrently, as one may be left in a
+                // transaction between requests
+                tokio::spawn(
+                    serve(engine, oracle, raft, read_only, extensions, tcp)
+                        .map_err(|e| error!("Error on serving client: {}", e)),
+                );
+                Ok(())
             });
         tokio::run(server);
         Ok(())
     }
 }
 
-fn serve<E: KvsEngine>(engine: E, tcp: TcpStream) -> impl Future<Item = (), Error = KvsError> {
+impl<E: ReplicationSource> KvsServer<E> {
+    /// Makes the server serve the log of its engine to replicas, and the pairs of its
+    /// engine to replicas copying it and to rebalances of a sharded cluster.
+    pub fn with_replication_source(mut self) -> Self {
+        self.extensions.read_log = Some(E::read_log);
+        self.extensions.scan_records = Some(E::scan_records);
+        self
+    }
+}
+
+impl<E: ChangeFeed> KvsServer<E> {
+    /// Makes the server stream the writes committed to its engine to subscribers.
+    pub fn with_change_feed(mut self) -> Self {
+        self.extensions.subscribe = Some(E::subscribe);
+        self
+    }
+}
+
+fn serve<E: KvsEngine>(
+    engine: E,
+    oracle: Option<Arc<TimestampOracle>>,
+    raft: Option<RaftMember<E>>,
+    read_only: bool,
+    extensions: Extensions<E>,
+    tcp: TcpStream,
+) -> impl Future<Item = (), Error = KvsError> {
     let (read_half, write_half) = tcp.split();
     let read_json = ReadJson::new(FramedRead::new(read_half, LengthDelimitedCodec::new()));
+    // the transaction started on the connection
+    let mut txn = None;
     let resp_stream = read_json
         .map_err(KvsError::from)
-        .and_then(
-            move |req| -> Box<dyn Future<Item = Response, Error = KvsError> + Send> {
-                match req {
-                    Request::Get { key } => Box::new(engine.get(key).map(Response::Get)),
-                    Request::Set { key, value } => {
-                        Box::new(engine.set(key, value).map(|_| Response::Set))
+        .map(
+            move |req| -> Box<dyn Stream<Item = Response, Error = KvsError> + Send> {
+                if let Some(raft) = &raft {
+                    return serve_raft(raft, req);
+                }
+                if read_only && is_write(&req) {
+                    return respond(Err(KvsError::ReadOnly));
+                }
+                match (req, txn.as_ref()) {
+                    (Request::Timestamp, _) => respond(match &oracle {
+                        Some(oracle) => oracle.timestamp().map(Response::Timestamp),
+                        None => Err(KvsError::StringError("Not a timestamp oracle".to_owned())),
+                    }),
+                    (Request::Begin, None) => {
+                        txn = Some(engine.begin());
+                        respond(Ok(Response::Begin))
                     }
-                    Request::Remove { key } => {
-                        Box::new(engine.remove(key).map(|_| Response::Remove))
+                    (Request::Commit, Some(_)) => {
+                        let commit = txn.take().unwrap().commit();
+                        Box::new(commit.map(|_| Response::Commit).into_stream())
                     }
+                    (Request::Rollback, Some(_)) => {
+                        txn.take().unwrap().rollback();
+                        respond(Ok(Response::Rollback))
+                    }
+                    (Request::Begin, Some(_)) => respond(Err(KvsError::StringError(
+                        "Transaction already started".to_owned(),
+                    ))),
+                    (Request::Commit, None) | (Request::Rollback, None) => respond(Err(
+                        KvsError::StringError("No transaction started".to_owned()),
+                    )),
+                    (req, Some(txn)) => serve_in_transaction(txn, req),
+                    (req, None) => serve_request(&engine, &extensions, req),
                 }
             },
         )
+        .flatten()
         .then(|resp| -> Result<Response> {
             match resp {
                 Ok(resp) => Ok(resp),
+                Err(KvsError::CasConflict { current }) => Ok(Response::Conflict(current)),
+                Err(KvsError::TransactionConflict) => Ok(Response::TransactionConflict),
                 Err(e) => Ok(Response::Err(format!("{}", e))),
             }
         });
@@ -62,3 +201,181 @@ fn serve<E: KvsEngine>(engine: E, tcp: TcpStream) -> impl Future<Item = (), Erro
         .send_all(resp_stream)
         .map(|_| ())
 }
+
+fn serve_request<E: KvsEngine>(
+    engine: &E,
+    extensions: &Extensions<E>,
+    req: Request,
+) -> Box<dyn Stream<Item = Response, Error = KvsError> + Send> {
+    match req {
+        Request::Get { key } => Box::new(engine.get_bytes(key).map(Response::Get).into_stream()),
+        Request::Set { key, value, ttl } => {
+            let set = match ttl {
+                Some(ttl) => engine.set_with_ttl_bytes(key, value, ttl),
+                None => engine.set_bytes(key, value),
+            };
+            Box::new(set.map(|_| Response::Set).into_stream())
+        }
+        Request::Remove { key } => Box::new(
+            engine
+                .remove_bytes(key)
+                .map(|_| Response::Remove)
+                .into_stream(),
+        ),
+        Request::Scan { start, end, limit } => Box::new(
+            engine
+                .scan_bytes(start, end, limit)
+                .map(Response::Scan)
+                .into_stream(),
+        ),
+        Request::Keys { prefix } => Box::new(
+            engine
+                .keys_bytes(prefix)
+                .chunks(STREAM_CHUNK_LEN)
+                .map(Response::Keys)
+                .chain(stream::once(Ok(Response::End))),
+        ),
+        Request::ScanPrefix { prefix } => Box::new(
+            engine
+                .scan_prefix_bytes(prefix)
+                .chunks(STREAM_CHUNK_LEN)
+                .map(Response::Pairs)
+                .chain(stream::once(Ok(Response::End))),
+        ),
+        Request::Batch { batch } => Box::new(
+            engine
+                .write_batch(batch)
+                .map(|_| Response::Batch)
+                .into_stream(),
+        ),
+        Request::CompareAndSwap { key, expected, new } => Box::new(
+            engine
+                .compare_and_swap_bytes(key, expected, new)
+                .map(|_| Response::CompareAndSwap)
+                .into_stream(),
+        ),
+        Request::SetIfAbsent { key, value, ttl } => {
+            let set = match ttl {
+                Some(ttl) => engine.set_if_absent_with_ttl_bytes(key, value, ttl),
+                None => engine.set_if_absent_bytes(key, value),
+            };
+            Box::new(set.map(|_| Response::Set).into_stream())
+        }
+        Request::ReadLog { from } => match extensions.read_log {
+            Some(read_log) => Box::new(read_log(engine, from).map(Response::ReadLog).into_stream()),
+            None => unsupported(),
+        },
+        Request::ScanRecords { start, limit } => match extensions.scan_records {
+            Some(scan_records) => Box::new(
+                scan_records(engine, start, limit)
+                    .map(Response::ScanRecords)
+                    .into_stream(),
+            ),
+            None => unsupported(),
+        },
+        Request::Subscribe { from_seq } => match extensions.subscribe {
+            Some(subscribe) => Box::new(subscribe(engine, from_seq).map(Response::Change)),
+            None => unsupported(),
+        },
+        Request::Raft { .. } => respond(Err(KvsError::StringError(
+            "Not a node of a Raft cluster".to_owned(),
+        ))),
+        Request::Begin | Request::Commit | Request::Rollback | Request::Timestamp => {
+            unreachable!("transaction and timestamp requests are handled by `serve`")
+        }
+    }
+}
+
+/// Runs a request on the Raft node served by the server.
+///
+/// Only the leader serves gets, sets without a time to live and removes. The other
+/// nodes answer with the address of the leader if they know it.
+fn serve_raft<E: KvsEngine>(
+    raft: &RaftMember<E>,
+    req: Request,
+) -> Box<dyn Stream<Item = Response, Error = KvsError> + Send> {
+    let resp: Box<dyn Future<Item = Response, Error = KvsError> + Send> = match req {
+        Request::Raft { from, message } => {
+            raft.node.step(from, message);
+            return respond(Ok(Response::Raft));
+        }
+        Request::Get { key } => Box::new(raft.node.get_bytes(key).map(Response::Get)),
+        Request::Set {
+            key,
+            value,
+            ttl: None,
+        } => Box::new(raft.node.set_bytes(key, value).map(|_| Response::Set)),
+        Request::Remove { key } => Box::new(raft.node.remove_bytes(key).map(|_| Response::Remove)),
+        _ => {
+            return respond(Err(KvsError::StringError(
+                "Request not supported in a Raft cluster".to_owned(),
+            )))
+        }
+    };
+    let raft = raft.clone();
+    Box::new(
+        resp.or_else(move |e| match e {
+            KvsError::NotLeader => {
+                let leader = raft
+                    .node
+                    .leader()
+                    .filter(|&leader| leader != raft.node.id())
+                    .and_then(|leader| raft.addrs.get(&leader).cloned());
+                Ok(Response::NotLeader(leader))
+            }
+            e => Err(e),
+        })
+        .into_stream(),
+    )
+}
+
+/// Runs a request in the transaction started on the connection.
+///
+/// Only gets, sets without a time to live and removes can be run in a transaction.
+fn serve_in_transaction<E: KvsEngine>(
+    txn: &Transaction<E>,
+    req: Request,
+) -> Box<dyn Stream<Item = Response, Error = KvsError> + Send> {
+    match req {
+        Request::Get { key } => Box::new(txn.get_bytes(key).map(Response::Get).into_stream()),
+        Request::Set {
+            key,
+            value,
+            ttl: None,
+        } => {
+            txn.set_bytes(key, value);
+            respond(Ok(Response::Set))
+        }
+        Request::Remove { key } => {
+            txn.remove_bytes(key);
+            respond(Ok(Response::Remove))
+        }
+        _ => respond(Err(KvsError::StringError(
+            "Request not supported in a transaction".to_owned(),
+        ))),
+    }
+}
+
+/// Returns whether the request changes the engine, which a replica doesn't allow.
+fn is_write(req: &Request) -> bool {
+    match req {
+        Request::Set { .. }
+        | Request::Remove { .. }
+        | Request::Batch { .. }
+        | Request::CompareAndSwap { .. }
+        | Request::SetIfAbsent { .. }
+        | Request::Begin => true,
+        _ => false,
+    }
+}
+
+fn respond(resp: Result<Response>) -> Box<dyn Stream<Item = Response, Error = KvsError> + Send> {
+    Box::new(stream::once(resp))
+}
+
+/// Fails a request of a trait the engine of the server does not implement.
+fn unsupported() -> Box<dyn Stream<Item = Response, Error = KvsError> + Send> {
+    respond(Err(KvsError::StringError(
+        "Request not supported by the engine".to_owned(),
+    )))
+}
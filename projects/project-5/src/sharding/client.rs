@@ -0,0 +1,161 @@
+use super::HashRing;
+use crate::{KvsClient, KvsError};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::prelude::future;
+use tokio::prelude::*;
+
+/// A client of several `kvs-server`s, each storing the keys a `HashRing` places on
+/// it.
+///
+/// One connection is kept to each server. All clients of the servers must use
+/// rings of the same servers.
+pub struct ShardedKvsClient {
+    ring: HashRing,
+    clients: HashMap<SocketAddr, KvsClient>,
+}
+
+impl ShardedKvsClient {
+    /// Connects to all the servers of the ring.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the ring has no servers.
+    pub fn connect(ring: HashRing) -> impl Future<Item = Self, Error = KvsError> {
+        assert!(ring.nodes().next().is_some(), "no servers given");
+        let connects: Vec<_> = ring
+            .nodes()
+            .map(|addr| KvsClient::connect(addr).map(move |client| (addr, client)))
+            .collect();
+        future::join_all(connects).map(move |clients| ShardedKvsClient {
+            ring,
+            clients: clients.into_iter().collect(),
+        })
+    }
+
+    /// Returns the ring placing the keys on the servers.
+    pub fn ring(&self) -> &HashRing {
+        &self.ring
+    }
+
+    /// Get the value of a given key from its server.
+    pub fn get_bytes(
+        self,
+        key: Vec<u8>,
+    ) -> impl Future<Item = (Option<Vec<u8>>, Self), Error = KvsError> {
+        let (client, shard) = self.take_client(&key);
+        client
+            .get_bytes(key)
+            .map(move |(value, client)| (value, shard.put_client(client)))
+    }
+
+    /// Set the value of a key in its server.
+    pub fn set_bytes(
+        self,
+        key: Vec<u8>,
+        value: Vec<u8>,
+    ) -> impl Future<Item = Self, Error = KvsError> {
+        let (client, shard) = self.take_client(&key);
+        client
+            .set_bytes(key, value)
+            .map(move |client| shard.put_client(client))
+    }
+
+    /// Set the value of a key in its server that expires after the given time to
+    /// live.
+    pub fn set_with_ttl_bytes(
+        self,
+        key: Vec<u8>,
+        value: Vec<u8>,
+        ttl: Duration,
+    ) -> impl Future<Item = Self, Error = KvsError> {
+        let (client, shard) = self.take_client(&key);
+        client
+            .set_with_ttl_bytes(key, value, ttl)
+            .map(move |client| shard.put_client(client))
+    }
+
+    /// Set the value of a key in its server only if the key does not exist, making it
+    /// expire after the time to live if one is given.
+    ///
+    /// Returns whether the value was set. The client can still be used if the key
+    /// exists.
+    pub fn set_if_absent_bytes(
+        self,
+        key: Vec<u8>,
+        value: Vec<u8>,
+        ttl: Option<Duration>,
+    ) -> impl Future<Item = (bool, Self), Error = KvsError> {
+        let (client, shard) = self.take_client(&key);
+        client
+            .send_set_if_absent(key, value, ttl)
+            .and_then(move |(res, client)| {
+                let shard = shard.put_client(client);
+                match res {
+                    Ok(()) => Ok((true, shard)),
+                    Err(KvsError::CasConflict { .. }) => Ok((false, shard)),
+                    Err(e) => Err(e),
+                }
+            })
+    }
+
+    /// Remove a key in its server.
+    pub fn remove_bytes(self, key: Vec<u8>) -> impl Future<Item = Self, Error = KvsError> {
+        let (client, shard) = self.take_client(&key);
+        client
+            .remove_bytes(key)
+            .map(move |client| shard.put_client(client))
+    }
+
+    /// Get the string value of a given string key from its server.
+    pub fn get(self, key: String) -> impl Future<Item = (Option<String>, Self), Error = KvsError> {
+        self.get_bytes(key.into_bytes())
+            .and_then(|(value, client)| Ok((value.map(String::from_utf8).transpose()?, client)))
+    }
+
+    /// Set the value of a string key to a string in its server.
+    pub fn set(self, key: String, value: String) -> impl Future<Item = Self, Error = KvsError> {
+        self.set_bytes(key.into_bytes(), value.into_bytes())
+    }
+
+    /// Set the value of a string key to a string in its server that expires after
+    /// the given time to live.
+    pub fn set_with_ttl(
+        self,
+        key: String,
+        value: String,
+        ttl: Duration,
+    ) -> impl Future<Item = Self, Error = KvsError> {
+        self.set_with_ttl_bytes(key.into_bytes(), value.into_bytes(), ttl)
+    }
+
+    /// Remove a string key in its server.
+    pub fn remove(self, key: String) -> impl Future<Item = Self, Error = KvsError> {
+        self.remove_bytes(key.into_bytes())
+    }
+
+    /// Takes the connection to the server of the key out of the client.
+    fn take_client(mut self, key: &[u8]) -> (KvsClient, Shard) {
+        let addr = self.ring.node(key).expect("no servers given");
+        let client = self
+            .clients
+            .remove(&addr)
+            .expect("no connection to the server");
+        (client, Shard { addr, rest: self })
+    }
+}
+
+/// A `ShardedKvsClient` whose connection to a server has been taken out.
+struct Shard {
+    addr: SocketAddr,
+    rest: ShardedKvsClient,
+}
+
+impl Shard {
+    /// Gives the connection back to the client.
+    fn put_client(mut self, client: KvsClient) -> ShardedKvsClient {
+        self.rest.clients.insert(self.addr, client);
+        self.rest
+    }
+}
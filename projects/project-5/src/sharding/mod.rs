@@ -0,0 +1,113 @@
+//! Spreading keys over several independent `kvs-server`s with consistent hashing.
+//!
+//! Each server is given many points, its virtual nodes, on a ring of 32-bit hashes.
+//! A key is stored on the server of the first point following the hash of the key
+//! on the ring. Adding a server to N others only moves the keys falling before its
+//! points, about 1/(N+1) of them, and removing a server only moves its own keys.
+//!
+//! `ShardedKvsClient` reads and writes each key on its server. After servers are
+//! added or removed, `rebalance` moves the keys to their new servers.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::mem;
+use std::net::SocketAddr;
+
+mod client;
+mod rebalance;
+
+pub use self::client::ShardedKvsClient;
+pub use self::rebalance::rebalance;
+
+/// Number of points of each server on a ring by default.
+const DEFAULT_VIRTUAL_NODES: u32 = 128;
+
+/// A consistent hash ring placing keys on servers.
+///
+/// The placement only depends on the set of servers, not on the order they are
+/// added in, so all clients given the same servers place keys the same way.
+#[derive(Debug, Clone)]
+pub struct HashRing {
+    // the servers by the positions of their points
+    points: BTreeMap<u32, SocketAddr>,
+    nodes: BTreeSet<SocketAddr>,
+    virtual_nodes: u32,
+}
+
+impl HashRing {
+    /// Creates a ring of the given servers with 128 points each.
+    pub fn new(nodes: &[SocketAddr]) -> Self {
+        Self::with_virtual_nodes(nodes, DEFAULT_VIRTUAL_NODES)
+    }
+
+    /// Creates a ring of the given servers with the given number of points each.
+    ///
+    /// More points spread the keys more evenly, but make the ring larger.
+    pub fn with_virtual_nodes(nodes: &[SocketAddr], virtual_nodes: u32) -> Self {
+        let mut ring = HashRing {
+            points: BTreeMap::new(),
+            nodes: BTreeSet::new(),
+            virtual_nodes,
+        };
+        for &node in nodes {
+            ring.add(node);
+        }
+        ring
+    }
+
+    /// Adds a server to the ring.
+    pub fn add(&mut self, node: SocketAddr) {
+        if !self.nodes.insert(node) {
+            return;
+        }
+        for i in 0..self.virtual_nodes {
+            // a point shared by several servers goes to the smallest address
+            let owner = self.points.entry(point(node, i)).or_insert(node);
+            *owner = (*owner).min(node);
+        }
+    }
+
+    /// Removes a server from the ring.
+    pub fn remove(&mut self, node: SocketAddr) {
+        if self.nodes.remove(&node) {
+            // the points the server shared with others are given back to them
+            self.points.clear();
+            for node in mem::replace(&mut self.nodes, BTreeSet::new()) {
+                self.add(node);
+            }
+        }
+    }
+
+    /// Returns the server of the key, or `None` if the ring is empty.
+    pub fn node(&self, key: &[u8]) -> Option<SocketAddr> {
+        let hash = hash(key);
+        self.points
+            .range(hash..)
+            .next()
+            .or_else(|| self.points.iter().next())
+            .map(|(_, &node)| node)
+    }
+
+    /// Returns the servers of the ring in address order.
+    pub fn nodes(&self) -> impl Iterator<Item = SocketAddr> + '_ {
+        self.nodes.iter().cloned()
+    }
+}
+
+/// Returns the position of a point of the server on the ring.
+fn point(node: SocketAddr, i: u32) -> u32 {
+    hash(format!("{}#{}", node, i).as_bytes())
+}
+
+/// Hashes the bytes to a position on the ring.
+///
+/// CRC32 changes little between similar inputs, like the names of the points of a
+/// server, so its output is mixed with the finalizer of MurmurHash3.
+fn hash(bytes: &[u8]) -> u32 {
+    let mut h = crc32fast::hash(bytes);
+    h ^= h >> 16;
+    h = h.wrapping_mul(0x85eb_ca6b);
+    h ^= h >> 13;
+    h = h.wrapping_mul(0xc2b2_ae35);
+    h ^= h >> 16;
+    h
+}
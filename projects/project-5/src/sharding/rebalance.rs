@@ -0,0 +1,133 @@
+use super::{HashRing, ShardedKvsClient};
+use crate::engines::now_millis;
+use crate::replication::LogRecord;
+use crate::{KvsClient, KvsError};
+use std::net::SocketAddr;
+use std::ops::Bound::{Excluded, Unbounded};
+use std::time::Duration;
+use tokio::prelude::future::{self, Loop};
+use tokio::prelude::*;
+
+/// Number of pairs read at a time from a server when rebalancing.
+const REBALANCE_CHUNK_LEN: usize = 256;
+
+type BoxFuture<T> = Box<dyn Future<Item = T, Error = KvsError> + Send>;
+
+/// Moves the keys of the servers of the `old` ring that the `new` ring places on
+/// other servers, and returns how many keys were moved.
+///
+/// Clients should switch to the new ring first. They then read and write every key
+/// on its new server, where a key reads as missing until it is moved.
+///
+/// Each key is copied to its new server unless a client has already written it
+/// there, in which case the newer value is kept. The key is then removed from its
+/// old server unless it has changed there in the meantime, in which case the
+/// rebalance fails with `KvsError::CasConflict` and can be run again.
+///
+/// The keys are read from the servers of the `old` ring with the requests of
+/// `KvsServer::with_replication_source`, which the servers must have enabled.
+pub fn rebalance(old: &HashRing, new: HashRing) -> impl Future<Item = usize, Error = KvsError> {
+    let nodes: Vec<SocketAddr> = old.nodes().collect();
+    ShardedKvsClient::connect(new).and_then(move |target| {
+        stream::iter_ok(nodes)
+            .fold((target, 0), |(target, moved), node| {
+                KvsClient::connect(node)
+                    .and_then(move |source| migrate(node, source, target))
+                    .map(move |(target, count)| (target, moved + count))
+            })
+            .map(|(_, moved)| moved)
+    })
+}
+
+/// Moves the keys of the server at `node` that the ring of `target` places on other
+/// servers.
+fn migrate(
+    node: SocketAddr,
+    source: KvsClient,
+    target: ShardedKvsClient,
+) -> BoxFuture<(ShardedKvsClient, usize)> {
+    Box::new(future::loop_fn(
+        (source, target, Unbounded, 0),
+        move |(source, target, start, moved)| {
+            source
+                .scan_records(start, REBALANCE_CHUNK_LEN)
+                .and_then(move |(records, source)| {
+                    let next = match records.last() {
+                        Some(LogRecord::Set { key, .. })
+                            if records.len() == REBALANCE_CHUNK_LEN =>
+                        {
+                            Some(Excluded(key.clone()))
+                        }
+                        _ => None,
+                    };
+                    move_records(node, source, target, records).map(
+                        move |(source, target, count)| match next {
+                            Some(start) => Loop::Continue((source, target, start, moved + count)),
+                            None => Loop::Break((target, moved + count)),
+                        },
+                    )
+                })
+        },
+    ))
+}
+
+/// Moves the pairs of the records read from the server at `node` that the ring of
+/// `target` places on other servers, and returns how many keys were moved.
+fn move_records(
+    node: SocketAddr,
+    source: KvsClient,
+    target: ShardedKvsClient,
+    records: Vec<LogRecord>,
+) -> BoxFuture<(KvsClient, ShardedKvsClient, usize)> {
+    let moving: Vec<_> = records
+        .into_iter()
+        .filter(|record| match record {
+            LogRecord::Set { key, .. } => target.ring().node(key) != Some(node),
+            _ => false,
+        })
+        .collect();
+    Box::new(stream::iter_ok(moving).fold(
+        (source, target, 0),
+        |(source, target, moved), record| {
+            move_record(source, target, record)
+                .map(move |(source, target, count)| (source, target, moved + count))
+        },
+    ))
+}
+
+/// Copies the pair of the record to its new server unless the key is already there,
+/// then removes it from its old server if it is unchanged.
+///
+/// Returns how many keys were moved, which is none if the key has expired or was
+/// already written to its new server.
+fn move_record(
+    source: KvsClient,
+    target: ShardedKvsClient,
+    record: LogRecord,
+) -> BoxFuture<(KvsClient, ShardedKvsClient, usize)> {
+    let (key, value, expires_at) = match record {
+        LogRecord::Set {
+            key,
+            value,
+            expires_at,
+        } => (key, value, expires_at),
+        _ => return Box::new(future::err(KvsError::UnexpectedCommandType)),
+    };
+    let ttl = match expires_at {
+        None => None,
+        Some(expires_at) => {
+            let now = now_millis();
+            if expires_at <= now {
+                // left to the old server to drop
+                return Box::new(future::ok((source, target, 0)));
+            }
+            Some(Duration::from_millis(expires_at - now))
+        }
+    };
+    let copy = target.set_if_absent_bytes(key.clone(), value.clone(), ttl);
+    Box::new(copy.and_then(move |(copied, target)| {
+        source
+            .compare_and_swap_bytes(key, Some(value), None)
+            .map(move |source| (source, target, copied as usize))
+    }))
+}
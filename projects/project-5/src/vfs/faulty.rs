@@ -0,0 +1,195 @@
+use super::{Vfs, VfsFile, VfsLock};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+/// A fault injected into an operation of a `FaultyVfs`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Fault {
+    /// The operation fails without doing anything.
+    Fail,
+    /// An append writes only the given number of bytes, at most, then fails. Any
+    /// other operation fails without doing anything.
+    ShortWrite(usize),
+}
+
+/// A filesystem wrapping another one to inject a fault into one of its operations.
+///
+/// The operations that change files are counted from 0: creating, truncating,
+/// removing and renaming files, appending to them and syncing them or their
+/// directory. Reads and locks are not counted, and no fault is injected into
+/// them. Once the fault is injected, the following operations succeed again.
+///
+/// Running a workload once without fault tells how many operations it makes, and
+/// running it again with a fault at each of them, then crashing a `MemVfs` below,
+/// goes through every point a crash may hit.
+///
+/// ```rust
+/// # use kvs::{KvStore, KvStoreOptions, KvsEngine, Result};
+/// # use kvs::thread_pool::RayonThreadPool;
+/// # use tokio::prelude::*;
+/// # fn try_main() -> Result<()> {
+/// use kvs::vfs::{Fault, FaultyVfs, MemVfs};
+/// let vfs = FaultyVfs::new(MemVfs::new());
+/// let mut options = KvStoreOptions::new();
+/// options.vfs(vfs.clone());
+/// let store: KvStore<RayonThreadPool> = KvStore::open_with_options("/db", 1, &options)?;
+///
+/// vfs.inject(vfs.operations(), Fault::ShortWrite(4));
+/// assert!(store.set("key".to_owned(), "value".to_owned()).wait().is_err());
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Clone)]
+pub struct FaultyVfs {
+    inner: Arc<dyn Vfs>,
+    faults: Arc<Faults>,
+}
+
+#[derive(Debug, Default)]
+struct Faults {
+    // the number of operations counted so far
+    operations: AtomicU64,
+    // the operation to inject a fault into, and the fault
+    next: Mutex<Option<(u64, Fault)>>,
+}
+
+impl Faults {
+    /// Counts an operation and returns the fault to inject into it, if any.
+    fn count(&self) -> Option<Fault> {
+        let op = self.operations.fetch_add(1, Ordering::SeqCst);
+        let mut next = self.next.lock().unwrap();
+        match *next {
+            Some((at, fault)) if at == op => {
+                *next = None;
+                Some(fault)
+            }
+            _ => None,
+        }
+    }
+
+    /// Counts an operation and fails it if a fault is injected into it.
+    fn check(&self) -> io::Result<()> {
+        match self.count() {
+            Some(_) => Err(injected()),
+            None => Ok(()),
+        }
+    }
+}
+
+impl FaultyVfs {
+    /// Wraps the filesystem, without injecting any fault yet.
+    pub fn new(inner: impl Vfs + 'static) -> Self {
+        FaultyVfs {
+            inner: Arc::new(inner),
+            faults: Arc::new(Faults::default()),
+        }
+    }
+
+    /// Injects the fault into the operation with the given number, replacing any
+    /// fault injected before and not hit yet.
+    pub fn inject(&self, operation: u64, fault: Fault) {
+        *self.faults.next.lock().unwrap() = Some((operation, fault));
+    }
+
+    /// Returns how many operations have been counted so far, which is the number of
+    /// the next one.
+    pub fn operations(&self) -> u64 {
+        self.faults.operations.load(Ordering::SeqCst)
+    }
+
+    /// Returns whether a fault is injected and has not been hit yet.
+    pub fn is_pending(&self) -> bool {
+        self.faults.next.lock().unwrap().is_some()
+    }
+}
+
+impl Vfs for FaultyVfs {
+    fn create_dir_all(&self, path: &Path) -> io::Result<()> {
+        self.inner.create_dir_all(path)
+    }
+
+    fn list(&self, dir: &Path) -> io::Result<Vec<PathBuf>> {
+        self.inner.list(dir)
+    }
+
+    fn exists(&self, path: &Path) -> bool {
+        self.inner.exists(path)
+    }
+
+    fn open(&self, path: &Path) -> io::Result<Box<dyn VfsFile>> {
+        Ok(Box::new(FaultyFile {
+            inner: self.inner.open(path)?,
+            faults: Arc::clone(&self.faults),
+        }))
+    }
+
+    fn create(&self, path: &Path) -> io::Result<Box<dyn VfsFile>> {
+        self.faults.check()?;
+        Ok(Box::new(FaultyFile {
+            inner: self.inner.create(path)?,
+            faults: Arc::clone(&self.faults),
+        }))
+    }
+
+    fn truncate(&self, path: &Path, len: u64) -> io::Result<()> {
+        self.faults.check()?;
+        self.inner.truncate(path, len)
+    }
+
+    fn remove(&self, path: &Path) -> io::Result<()> {
+        self.faults.check()?;
+        self.inner.remove(path)
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        self.faults.check()?;
+        self.inner.rename(from, to)
+    }
+
+    fn sync_dir(&self, path: &Path) -> io::Result<()> {
+        self.faults.check()?;
+        self.inner.sync_dir(path)
+    }
+
+    fn lock(&self, path: &Path, owner: &[u8]) -> io::Result<Box<dyn VfsLock>> {
+        self.inner.lock(path, owner)
+    }
+}
+
+#[derive(Debug)]
+struct FaultyFile {
+    inner: Box<dyn VfsFile>,
+    faults: Arc<Faults>,
+}
+
+impl VfsFile for FaultyFile {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        self.inner.read_at(buf, offset)
+    }
+
+    fn append(&self, buf: &[u8]) -> io::Result<()> {
+        match self.faults.count() {
+            Some(Fault::Fail) => Err(injected()),
+            Some(Fault::ShortWrite(len)) => {
+                self.inner.append(&buf[..len.min(buf.len())])?;
+                Err(injected())
+            }
+            None => self.inner.append(buf),
+        }
+    }
+
+    fn len(&self) -> io::Result<u64> {
+        self.inner.len()
+    }
+
+    fn sync_data(&self) -> io::Result<()> {
+        self.faults.check()?;
+        self.inner.sync_data()
+    }
+}
+
+fn injected() -> io::Error {
+    io::Error::new(io::ErrorKind::Other, "injected fault")
+}
@@ -0,0 +1,279 @@
+use super::{Vfs, VfsFile, VfsLock};
+use std::collections::{BTreeMap, BTreeSet};
+use std::io;
+use std::path::{Component, Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+/// A filesystem keeping the files in memory.
+///
+/// Clones share the same files, so a test can keep a clone to inspect the files or
+/// to crash the filesystem while a store uses another one.
+///
+/// ```rust
+/// # use kvs::{KvStore, KvStoreOptions, KvsEngine, Result};
+/// # use kvs::thread_pool::RayonThreadPool;
+/// # use tokio::prelude::*;
+/// # fn try_main() -> Result<()> {
+/// use kvs::vfs::MemVfs;
+/// let vfs = MemVfs::new();
+/// let mut options = KvStoreOptions::new();
+/// options.vfs(vfs.clone());
+/// let store: KvStore<RayonThreadPool> = KvStore::open_with_options("/db", 1, &options)?;
+/// store.set("key".to_owned(), "value".to_owned()).wait()?;
+/// drop(store);
+///
+/// // The write was never synced
+/// vfs.crash();
+/// let store: KvStore<RayonThreadPool> = KvStore::open_with_options("/db", 1, &options)?;
+/// assert_eq!(store.get("key".to_owned()).wait()?, None);
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct MemVfs {
+    state: Arc<Mutex<MemState>>,
+}
+
+#[derive(Debug, Default)]
+struct MemState {
+    dirs: BTreeSet<PathBuf>,
+    files: BTreeMap<PathBuf, Arc<MemFile>>,
+    // the files as of the last sync of their directory, which survive a crash
+    synced_files: BTreeMap<PathBuf, Arc<MemFile>>,
+    // the files locked by a live `MemLock`
+    locks: BTreeSet<PathBuf>,
+}
+
+impl MemVfs {
+    /// Creates an empty filesystem.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Undoes the files created, removed and renamed since their directory was last
+    /// synced, and drops the bytes appended to every file since it was last synced,
+    /// as if the machine crashed.
+    ///
+    /// Directories survive crashes. Handles opened before the crash should not be
+    /// used anymore.
+    pub fn crash(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.files = state.synced_files.clone();
+        for file in state.files.values() {
+            let mut content = file.content.lock().unwrap();
+            let synced = content.synced;
+            content.data.truncate(synced);
+        }
+    }
+
+    /// Returns the content of the file, or `None` if it does not exist.
+    pub fn read(&self, path: impl AsRef<Path>) -> Option<Vec<u8>> {
+        let state = self.state.lock().unwrap();
+        let file = state.files.get(&normalize(path.as_ref()))?;
+        let content = file.content.lock().unwrap();
+        Some(content.data.clone())
+    }
+
+    /// Replaces the content of the file, creating it if it does not exist.
+    ///
+    /// The file and its new content are durable.
+    pub fn write(&self, path: impl AsRef<Path>, data: impl Into<Vec<u8>>) -> io::Result<()> {
+        let data = data.into();
+        let file = self.create_file(path.as_ref())?;
+        let mut state = self.state.lock().unwrap();
+        state
+            .synced_files
+            .insert(normalize(path.as_ref()), Arc::clone(&file));
+        let mut content = file.content.lock().unwrap();
+        content.synced = data.len();
+        content.data = data;
+        Ok(())
+    }
+
+    fn create_file(&self, path: &Path) -> io::Result<Arc<MemFile>> {
+        let path = normalize(path);
+        let mut state = self.state.lock().unwrap();
+        let parent = path.parent().unwrap_or_else(|| Path::new(""));
+        if !state.dirs.contains(parent) {
+            return Err(not_found(&path));
+        }
+        let file = Arc::new(MemFile::default());
+        state.files.insert(path, Arc::clone(&file));
+        Ok(file)
+    }
+
+    fn file(&self, path: &Path) -> io::Result<Arc<MemFile>> {
+        let path = normalize(path);
+        let state = self.state.lock().unwrap();
+        match state.files.get(&path) {
+            Some(file) => Ok(Arc::clone(file)),
+            None => Err(not_found(&path)),
+        }
+    }
+}
+
+impl Vfs for MemVfs {
+    fn create_dir_all(&self, path: &Path) -> io::Result<()> {
+        let path = normalize(path);
+        let mut state = self.state.lock().unwrap();
+        state.dirs.extend(path.ancestors().map(Path::to_owned));
+        Ok(())
+    }
+
+    fn list(&self, dir: &Path) -> io::Result<Vec<PathBuf>> {
+        let dir = normalize(dir);
+        let state = self.state.lock().unwrap();
+        if !state.dirs.contains(&dir) {
+            return Err(not_found(&dir));
+        }
+        Ok(state
+            .files
+            .keys()
+            .filter(|path| path.parent() == Some(&dir))
+            .cloned()
+            .collect())
+    }
+
+    fn exists(&self, path: &Path) -> bool {
+        let state = self.state.lock().unwrap();
+        state.files.contains_key(&normalize(path))
+    }
+
+    fn open(&self, path: &Path) -> io::Result<Box<dyn VfsFile>> {
+        Ok(Box::new(MemHandle(self.file(path)?)))
+    }
+
+    fn create(&self, path: &Path) -> io::Result<Box<dyn VfsFile>> {
+        Ok(Box::new(MemHandle(self.create_file(path)?)))
+    }
+
+    fn truncate(&self, path: &Path, len: u64) -> io::Result<()> {
+        let file = self.file(path)?;
+        let mut content = file.content.lock().unwrap();
+        content.data.resize(len as usize, 0);
+        content.synced = content.data.len();
+        Ok(())
+    }
+
+    fn remove(&self, path: &Path) -> io::Result<()> {
+        let path = normalize(path);
+        let mut state = self.state.lock().unwrap();
+        match state.files.remove(&path) {
+            Some(_) => Ok(()),
+            None => Err(not_found(&path)),
+        }
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        let (from, to) = (normalize(from), normalize(to));
+        let mut state = self.state.lock().unwrap();
+        let file = state.files.remove(&from).ok_or_else(|| not_found(&from))?;
+        state.files.insert(to, file);
+        Ok(())
+    }
+
+    fn sync_dir(&self, path: &Path) -> io::Result<()> {
+        let dir = normalize(path);
+        let mut state = self.state.lock().unwrap();
+        if !state.dirs.contains(&dir) {
+            return Err(not_found(&dir));
+        }
+        let MemState {
+            files,
+            synced_files,
+            ..
+        } = &mut *state;
+        synced_files.retain(|path, _| path.parent() != Some(&dir));
+        for (path, file) in files.iter() {
+            if path.parent() == Some(&dir) {
+                synced_files.insert(path.clone(), Arc::clone(file));
+            }
+        }
+        Ok(())
+    }
+
+    fn lock(&self, path: &Path, owner: &[u8]) -> io::Result<Box<dyn VfsLock>> {
+        let path = normalize(path);
+        if !self.state.lock().unwrap().locks.insert(path.clone()) {
+            return Err(io::ErrorKind::WouldBlock.into());
+        }
+        let lock = MemLock {
+            state: Arc::clone(&self.state),
+            path,
+        };
+        // dropping the lock releases it if the write fails
+        self.write(&lock.path, owner)?;
+        Ok(Box::new(lock))
+    }
+}
+
+/// A lock on a file of a `MemVfs`, released when dropped.
+#[derive(Debug)]
+struct MemLock {
+    state: Arc<Mutex<MemState>>,
+    path: PathBuf,
+}
+
+impl VfsLock for MemLock {}
+
+impl Drop for MemLock {
+    fn drop(&mut self) {
+        self.state.lock().unwrap().locks.remove(&self.path);
+    }
+}
+
+/// A file in memory, shared by its handles.
+#[derive(Debug, Default)]
+struct MemFile {
+    content: Mutex<MemContent>,
+}
+
+#[derive(Debug, Default)]
+struct MemContent {
+    data: Vec<u8>,
+    // the length of the prefix of `data` that survives a crash
+    synced: usize,
+}
+
+#[derive(Debug)]
+struct MemHandle(Arc<MemFile>);
+
+impl VfsFile for MemHandle {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        let content = self.0.content.lock().unwrap();
+        let data = content.data.get(offset as usize..).unwrap_or_default();
+        let len = buf.len().min(data.len());
+        buf[..len].copy_from_slice(&data[..len]);
+        Ok(len)
+    }
+
+    fn append(&self, buf: &[u8]) -> io::Result<()> {
+        let mut content = self.0.content.lock().unwrap();
+        content.data.extend_from_slice(buf);
+        Ok(())
+    }
+
+    fn len(&self) -> io::Result<u64> {
+        Ok(self.0.content.lock().unwrap().data.len() as u64)
+    }
+
+    fn sync_data(&self) -> io::Result<()> {
+        let mut content = self.0.content.lock().unwrap();
+        content.synced = content.data.len();
+        Ok(())
+    }
+}
+
+/// Returns the path without `.` components, so that equal paths compare equal.
+fn normalize(path: &Path) -> PathBuf {
+    path.components()
+        .filter(|component| *component != Component::CurDir)
+        .collect()
+}
+
+fn not_found(path: &Path) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::NotFound,
+        format!("{} not found", path.display()),
+    )
+}
@@ -0,0 +1,93 @@
+//! This module provides the filesystems a `KvStore` keeps its files in. All
+//! filesystems should implement the `Vfs` trait.
+//!
+//! `OsVfs`, the filesystem of the operating system, is the default. `MemVfs` keeps
+//! the files in memory, and can lose what has not been synced as if the machine
+//! crashed. `FaultyVfs` wraps another filesystem to fail or tear one of its writes,
+//! so that crash-consistency tests can go through every point a crash may hit.
+
+use std::fmt::Debug;
+use std::io;
+use std::path::{Path, PathBuf};
+
+mod faulty;
+mod mem;
+mod os;
+
+pub use self::faulty::{Fault, FaultyVfs};
+pub use self::mem::MemVfs;
+pub use self::os::OsVfs;
+
+/// The trait that all filesystems should implement.
+///
+/// Files are only ever appended to, read at given offsets and truncated.
+/// Appended bytes may be lost by a crash until the file is synced, and so may
+/// created, removed and renamed files until their directory is synced. Truncating a
+/// file is durable once it returns.
+pub trait Vfs: Debug + Send + Sync {
+    /// Creates the directory and all its missing parents.
+    fn create_dir_all(&self, path: &Path) -> io::Result<()>;
+
+    /// Returns the paths of the files in the directory, in no particular order.
+    fn list(&self, dir: &Path) -> io::Result<Vec<PathBuf>>;
+
+    /// Returns whether the file exists.
+    fn exists(&self, path: &Path) -> bool;
+
+    /// Opens an existing file for reading.
+    fn open(&self, path: &Path) -> io::Result<Box<dyn VfsFile>>;
+
+    /// Creates a file for reading and appending, or truncates it if it exists.
+    fn create(&self, path: &Path) -> io::Result<Box<dyn VfsFile>>;
+
+    /// Truncates the file to the given length and makes the new length durable.
+    fn truncate(&self, path: &Path, len: u64) -> io::Result<()>;
+
+    /// Removes a file.
+    ///
+    /// Handles opened before keep reading the content of the file.
+    fn remove(&self, path: &Path) -> io::Result<()>;
+
+    /// Renames a file, replacing the file at `to` if there is one.
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()>;
+
+    /// Makes the files created, removed and renamed in the directory durable.
+    fn sync_dir(&self, path: &Path) -> io::Result<()>;
+
+    /// Takes an exclusive advisory lock on the file, creating it if it does not
+    /// exist, then replaces its content with `owner`.
+    ///
+    /// The lock is held until the returned guard is dropped. If another guard holds
+    /// it, even in the same process, this fails with an error of kind `WouldBlock`
+    /// and leaves the file as is.
+    fn lock(&self, path: &Path, owner: &[u8]) -> io::Result<Box<dyn VfsLock>>;
+}
+
+/// A lock on a file taken with `Vfs::lock`, released when dropped.
+pub trait VfsLock: Debug + Send + Sync {}
+
+/// A file opened from a `Vfs`.
+///
+/// Handles can be shared between threads: every read names its offset, and every
+/// write is appended to the end of the file.
+pub trait VfsFile: Debug + Send + Sync {
+    /// Reads bytes from the given offset, returning how many were read, which is 0
+    /// at the end of the file.
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize>;
+
+    /// Appends all the bytes to the end of the file.
+    ///
+    /// Files opened with `Vfs::open` may refuse writes.
+    fn append(&self, buf: &[u8]) -> io::Result<()>;
+
+    /// Returns the length of the file.
+    fn len(&self) -> io::Result<u64>;
+
+    /// Returns whether the file is empty.
+    fn is_empty(&self) -> io::Result<bool> {
+        Ok(self.len()? == 0)
+    }
+
+    /// Makes the content of the file durable.
+    fn sync_data(&self) -> io::Result<()>;
+}
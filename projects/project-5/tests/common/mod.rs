@@ -0,0 +1,819 @@
+//! Tests shared by the engines, instantiated for each of them with `engine_suite!`.
+
+use kvs::thread_pool::RayonThreadPool;
+use kvs::{
+    KvStore, KvsEngine, KvsError, LsmKvsEngine, MemoryKvsEngine, Result, SledKvsEngine, WriteBatch,
+};
+use std::ops::Bound::{Excluded, Included, Unbounded};
+use std::path::Path;
+use std::thread;
+use std::time::{Duration, Instant};
+use tempfile::TempDir;
+use tokio::prelude::*;
+use tokio::runtime::Runtime;
+
+/// An engine the shared tests run against, stored in a temporary directory.
+pub trait TestEngine: KvsEngine {
+    /// Opens the engine stored in the directory, or a new empty one.
+    fn open(dir: &Path, concurrency: u32) -> Result<Self>;
+
+    /// Opens the engine again, as after a restart.
+    fn reopen(self, dir: &Path, concurrency: u32) -> Result<Self> {
+        drop(self);
+        Self::open(dir, concurrency)
+    }
+
+    /// Compacts the engine, if it has anything to compact.
+    fn compact(&self) -> Result<()> {
+        Ok(())
+    }
+}
+
+impl TestEngine for KvStore<RayonThreadPool> {
+    fn open(dir: &Path, concurrency: u32) -> Result<Self> {
+        KvStore::open(dir, concurrency)
+    }
+
+    fn compact(&self) -> Result<()> {
+        KvStore::compact(self).wait()
+    }
+}
+
+impl TestEngine for LsmKvsEngine<RayonThreadPool> {
+    fn open(dir: &Path, concurrency: u32) -> Result<Self> {
+        LsmKvsEngine::open(dir, concurrency)
+    }
+
+    fn compact(&self) -> Result<()> {
+        LsmKvsEngine::compact(self).wait()
+    }
+}
+
+impl TestEngine for MemoryKvsEngine<RayonThreadPool> {
+    fn open(_dir: &Path, concurrency: u32) -> Result<Self> {
+        MemoryKvsEngine::new(concurrency)
+    }
+
+    /// Goes on with the same engine, as a new one would have none of the keys.
+    fn reopen(self, _dir: &Path, _concurrency: u32) -> Result<Self> {
+        Ok(self)
+    }
+}
+
+impl TestEngine for SledKvsEngine<RayonThreadPool> {
+    fn open(dir: &Path, concurrency: u32) -> Result<Self> {
+        SledKvsEngine::new(sled::Db::start_default(dir)?, concurrency)
+    }
+
+    /// Waits for sled to release the lock on the directory, which its background
+    /// threads may still hold for a moment after the engine is dropped.
+    fn reopen(self, dir: &Path, concurrency: u32) -> Result<Self> {
+        drop(self);
+        let deadline = Instant::now() + Duration::from_secs(5);
+        loop {
+            match sled::Db::start_default(dir) {
+                Ok(db) => return SledKvsEngine::new(db, concurrency),
+                Err(_) if Instant::now() < deadline => thread::sleep(Duration::from_millis(10)),
+                Err(e) => return Err(e.into()),
+            }
+        }
+    }
+}
+
+/// Defines a test running each of the shared tests against the engine.
+macro_rules! engine_suite {
+    ($engine:ty) => {
+        engine_suite! {
+            @tests $engine;
+            get_stored_value
+            overwrite_value
+            get_non_existent_value
+            remove_non_existent_key
+            remove_key
+            concurrent_set
+            concurrent_get
+            scan_range
+            prefix_iteration
+            binary_keys_and_values
+            expiring_keys
+            write_batch
+            compare_and_swap
+            set_if_absent_with_ttl
+            concurrent_compare_and_swap
+            transaction
+            transaction_conflict
+            concurrent_transactions
+        }
+    };
+    (@tests $engine:ty; $($name:ident)*) => {
+        $(
+            #[test]
+            fn $name() -> Result<()> {
+                common::$name::<$engine>()
+            }
+        )*
+    };
+}
+
+pub fn get_stored_value<E: TestEngine>() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = E::open(temp_dir.path(), 1)?;
+
+    store.set("key1".to_owned(), "value1".to_owned()).wait()?;
+    store.set("key2".to_owned(), "value2".to_owned()).wait()?;
+
+    assert_eq!(
+        store.get("key1".to_owned()).wait()?,
+        Some("value1".to_owned())
+    );
+    assert_eq!(
+        store.get("key2".to_owned()).wait()?,
+        Some("value2".to_owned())
+    );
+
+    // Open from disk again and check persistent data
+    let store = store.reopen(temp_dir.path(), 1)?;
+    assert_eq!(
+        store.get("key1".to_owned()).wait()?,
+        Some("value1".to_owned())
+    );
+    assert_eq!(
+        store.get("key2".to_owned()).wait()?,
+        Some("value2".to_owned())
+    );
+
+    Ok(())
+}
+
+pub fn overwrite_value<E: TestEngine>() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = E::open(temp_dir.path(), 1)?;
+
+    store.set("key1".to_owned(), "value1".to_owned()).wait()?;
+    assert_eq!(
+        store.get("key1".to_owned()).wait()?,
+        Some("value1".to_owned())
+    );
+    store.set("key1".to_owned(), "value2".to_owned()).wait()?;
+    assert_eq!(
+        store.get("key1".to_owned()).wait()?,
+        Some("value2".to_owned())
+    );
+
+    // Open from disk again and check persistent data
+    let store = store.reopen(temp_dir.path(), 1)?;
+    assert_eq!(
+        store.get("key1".to_owned()).wait()?,
+        Some("value2".to_owned())
+    );
+    store.set("key1".to_owned(), "value3".to_owned()).wait()?;
+    assert_eq!(
+        store.get("key1".to_owned()).wait()?,
+        Some("value3".to_owned())
+    );
+
+    Ok(())
+}
+
+pub fn get_non_existent_value<E: TestEngine>() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = E::open(temp_dir.path(), 1)?;
+
+    store.set("key1".to_owned(), "value1".to_owned()).wait()?;
+    assert_eq!(store.get("key2".to_owned()).wait()?, None);
+
+    // Open from disk again and check persistent data
+    let store = store.reopen(temp_dir.path(), 1)?;
+    assert_eq!(store.get("key2".to_owned()).wait()?, None);
+
+    Ok(())
+}
+
+pub fn remove_non_existent_key<E: TestEngine>() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = E::open(temp_dir.path(), 1)?;
+    assert!(store.remove("key1".to_owned()).wait().is_err());
+    Ok(())
+}
+
+pub fn remove_key<E: TestEngine>() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = E::open(temp_dir.path(), 1)?;
+    store.set("key1".to_owned(), "value1".to_owned()).wait()?;
+    assert!(store.remove("key1".to_owned()).wait().is_ok());
+    assert_eq!(store.get("key1".to_owned()).wait()?, None);
+    Ok(())
+}
+
+pub fn concurrent_set<E: TestEngine>() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+
+    // concurrent set in 8 threads
+    let store = E::open(temp_dir.path(), 8)?;
+    let writer = store.clone();
+    let runtime = Runtime::new()?;
+    let executor = runtime.executor();
+    runtime.block_on_all(future::lazy(move || {
+        for i in 0..10000 {
+            executor.spawn(
+                writer
+                    .set(format!("key{}", i), format!("value{}", i))
+                    .map_err(|_| ()),
+            );
+        }
+        future::ok::<(), KvsError>(())
+    }))?;
+
+    // We only check concurrent set in this test, so we check sequentially here
+    let store = store.reopen(temp_dir.path(), 1)?;
+    for i in 0..10000 {
+        assert_eq!(
+            store.get(format!("key{}", i)).wait()?,
+            Some(format!("value{}", i))
+        );
+    }
+
+    Ok(())
+}
+
+pub fn concurrent_get<E: TestEngine>() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = E::open(temp_dir.path(), 8)?;
+    // We only check concurrent get in this test, so we set sequentially here
+    for i in 0..100 {
+        store
+            .set(format!("key{}", i), format!("value{}", i))
+            .wait()
+            .unwrap();
+    }
+
+    let runtime = Runtime::new()?;
+    let executor = runtime.executor();
+    let reader = store.clone();
+    runtime.block_on_all(future::lazy(move || {
+        for thread_id in 0..100 {
+            for i in 0..100 {
+                let key_id = (i + thread_id) % 100;
+                executor.spawn(
+                    reader
+                        .get(format!("key{}", key_id))
+                        .map(move |res| {
+                            assert_eq!(res, Some(format!("value{}", key_id)));
+                        })
+                        .map_err(|_| ()),
+                );
+            }
+        }
+        future::ok::<(), KvsError>(())
+    }))?;
+
+    // reload from disk and test again
+    let store = store.reopen(temp_dir.path(), 8)?;
+    let runtime = Runtime::new()?;
+    let executor = runtime.executor();
+    let reader = store.clone();
+    runtime.block_on_all(future::lazy(move || {
+        for thread_id in 0..100 {
+            for i in 0..100 {
+                let key_id = (i + thread_id) % 100;
+                executor.spawn(
+                    reader
+                        .get(format!("key{}", key_id))
+                        .map(move |res| {
+                            assert_eq!(res, Some(format!("value{}", key_id)));
+                        })
+                        .map_err(|_| ()),
+                );
+            }
+        }
+        future::ok::<(), KvsError>(())
+    }))?;
+
+    Ok(())
+}
+
+pub fn scan_range<E: TestEngine>() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = E::open(temp_dir.path(), 1)?;
+    for i in 0..10 {
+        store
+            .set(format!("key{}", i), format!("value{}", i))
+            .wait()?;
+    }
+    store.remove("key5".to_owned()).wait()?;
+
+    let pairs = |range: &[usize]| -> Vec<(String, String)> {
+        range
+            .iter()
+            .map(|i| (format!("key{}", i), format!("value{}", i)))
+            .collect()
+    };
+
+    let scan = |start, end, limit| store.scan(start, end, limit).wait();
+    assert_eq!(
+        scan(
+            Included("key2".to_owned()),
+            Excluded("key7".to_owned()),
+            None
+        )?,
+        pairs(&[2, 3, 4, 6])
+    );
+    assert_eq!(
+        scan(
+            Excluded("key2".to_owned()),
+            Included("key7".to_owned()),
+            None
+        )?,
+        pairs(&[3, 4, 6, 7])
+    );
+    assert_eq!(scan(Unbounded, Unbounded, Some(3))?, pairs(&[0, 1, 2]));
+    assert_eq!(
+        scan(Included("key8".to_owned()), Unbounded, None)?,
+        pairs(&[8, 9])
+    );
+    assert_eq!(scan(Included("x".to_owned()), Unbounded, None)?, pairs(&[]));
+
+    // Scan after compaction and reopening
+    store.compact()?;
+    let store = store.reopen(temp_dir.path(), 1)?;
+    assert_eq!(
+        store.scan(Unbounded, Unbounded, None).wait()?,
+        pairs(&[0, 1, 2, 3, 4, 6, 7, 8, 9])
+    );
+
+    Ok(())
+}
+
+pub fn prefix_iteration<E: TestEngine>() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = E::open(temp_dir.path(), 2)?;
+    // More than a chunk of pairs for each prefix
+    for i in 0..300 {
+        store
+            .set(format!("a{:03}", i), format!("value{}", i))
+            .wait()?;
+        store
+            .set(format!("b{:03}", i), format!("value{}", i))
+            .wait()?;
+    }
+    store.set("a".to_owned(), "value".to_owned()).wait()?;
+    store.remove("a100".to_owned()).wait()?;
+
+    let keys = store.keys("a1".to_owned()).collect().wait()?;
+    let expected: Vec<_> = (101..200).map(|i| format!("a{:03}", i)).collect();
+    assert_eq!(keys, expected);
+
+    let pairs = store.scan_prefix("b".to_owned()).collect().wait()?;
+    let expected: Vec<_> = (0..300)
+        .map(|i| (format!("b{:03}", i), format!("value{}", i)))
+        .collect();
+    assert_eq!(pairs, expected);
+
+    assert_eq!(store.keys("a".to_owned()).collect().wait()?.len(), 300);
+    assert_eq!(store.keys(String::new()).collect().wait()?.len(), 600);
+    assert!(store.keys("c".to_owned()).collect().wait()?.is_empty());
+    assert!(store
+        .scan_prefix("c".to_owned())
+        .collect()
+        .wait()?
+        .is_empty());
+
+    Ok(())
+}
+
+pub fn binary_keys_and_values<E: TestEngine>() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = E::open(temp_dir.path(), 1)?;
+    let key = vec![0, 0xff, 0x80];
+    let value = vec![0xfe, 0, 0xc3];
+    store.set_bytes(key.clone(), value.clone()).wait()?;
+    store.set_bytes(vec![0xff], vec![]).wait()?;
+    store.set("key".to_owned(), "value".to_owned()).wait()?;
+
+    assert_eq!(store.get_bytes(key.clone()).wait()?, Some(value.clone()));
+    assert_eq!(store.get_bytes(vec![0xff]).wait()?, Some(vec![]));
+    assert_eq!(
+        store.get_bytes(b"key".to_vec()).wait()?,
+        Some(b"value".to_vec())
+    );
+    match store.get(String::from_utf8_lossy(&key).into_owned()).wait() {
+        Ok(None) => {}
+        res => panic!("unexpected result: {:?}", res),
+    }
+    assert_eq!(
+        store.keys_bytes(vec![0]).collect().wait()?,
+        vec![key.clone()]
+    );
+    store.set_bytes(b"invalid".to_vec(), vec![0xff]).wait()?;
+    match store.get("invalid".to_owned()).wait() {
+        Err(KvsError::Utf8(_)) => {}
+        res => panic!("unexpected result: {:?}", res),
+    }
+
+    // Binary data survives compaction and reopening
+    store.remove_bytes(vec![0xff]).wait()?;
+    store.compact()?;
+    let store = store.reopen(temp_dir.path(), 1)?;
+    assert_eq!(store.get_bytes(key.clone()).wait()?, Some(value));
+    assert_eq!(store.get_bytes(vec![0xff]).wait()?, None);
+    assert_eq!(
+        store
+            .scan_bytes(Unbounded, Unbounded, None)
+            .wait()?
+            .into_iter()
+            .map(|(key, _)| key)
+            .collect::<Vec<_>>(),
+        vec![key, b"invalid".to_vec(), b"key".to_vec()]
+    );
+
+    Ok(())
+}
+
+pub fn expiring_keys<E: TestEngine>() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = E::open(temp_dir.path(), 1)?;
+    let ttl = Duration::from_millis(200);
+    store
+        .set_with_ttl("key1".to_owned(), "value1".to_owned(), ttl)
+        .wait()?;
+    store
+        .set_with_ttl("key2".to_owned(), "value2".to_owned(), ttl)
+        .wait()?;
+    store.set("key2".to_owned(), "value3".to_owned()).wait()?;
+    store
+        .set_with_ttl(
+            "key3".to_owned(),
+            "value4".to_owned(),
+            Duration::from_secs(3600),
+        )
+        .wait()?;
+    assert_eq!(
+        store.get("key1".to_owned()).wait()?,
+        Some("value1".to_owned())
+    );
+
+    thread::sleep(Duration::from_millis(300));
+    assert_eq!(store.get("key1".to_owned()).wait()?, None);
+    assert_eq!(
+        store.get("key2".to_owned()).wait()?,
+        Some("value3".to_owned())
+    );
+    assert_eq!(
+        store.get("key3".to_owned()).wait()?,
+        Some("value4".to_owned())
+    );
+    assert_eq!(
+        store.keys("key".to_owned()).collect().wait()?,
+        vec!["key2".to_owned(), "key3".to_owned()]
+    );
+    match store.remove("key1".to_owned()).wait() {
+        Err(KvsError::KeyNotFound) => {}
+        res => panic!("unexpected result: {:?}", res),
+    }
+
+    // Expired keys stay expired after reopening
+    let store = store.reopen(temp_dir.path(), 1)?;
+    assert_eq!(store.get("key1".to_owned()).wait()?, None);
+    assert_eq!(
+        store.get("key3".to_owned()).wait()?,
+        Some("value4".to_owned())
+    );
+
+    Ok(())
+}
+
+pub fn write_batch<E: TestEngine>() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = E::open(temp_dir.path(), 1)?;
+    store.set("key1".to_owned(), "value1".to_owned()).wait()?;
+
+    let mut batch = WriteBatch::new();
+    batch
+        .set("key2", "value2")
+        .set("key3", "stale")
+        .remove("key1")
+        .remove("missing")
+        .set("key3", "value3");
+    assert_eq!(batch.len(), 5);
+    store.write_batch(batch).wait()?;
+    store.write_batch(WriteBatch::new()).wait()?;
+
+    let check = |store: &E| -> Result<()> {
+        assert_eq!(
+            store
+                .scan(Unbounded, Unbounded, None)
+                .wait()?
+                .into_iter()
+                .collect::<Vec<_>>(),
+            vec![
+                ("key2".to_owned(), "value2".to_owned()),
+                ("key3".to_owned(), "value3".to_owned()),
+            ]
+        );
+        Ok(())
+    };
+    check(&store)?;
+
+    let store = store.reopen(temp_dir.path(), 1)?;
+    check(&store)?;
+
+    // The batch stays atomic after compaction and reopening
+    store.compact()?;
+    check(&store)?;
+    store.remove("key2".to_owned()).wait()?;
+    let store = store.reopen(temp_dir.path(), 1)?;
+    assert_eq!(store.get("key2".to_owned()).wait()?, None);
+    assert_eq!(
+        store.get("key3".to_owned()).wait()?,
+        Some("value3".to_owned())
+    );
+
+    Ok(())
+}
+
+pub fn compare_and_swap<E: TestEngine>() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = E::open(temp_dir.path(), 1)?;
+
+    store
+        .set_if_absent("key1".to_owned(), "value1".to_owned())
+        .wait()?;
+    match store
+        .set_if_absent("key1".to_owned(), "value2".to_owned())
+        .wait()
+    {
+        Err(KvsError::CasConflict { current }) => assert_eq!(current, Some(b"value1".to_vec())),
+        res => panic!("unexpected result: {:?}", res),
+    }
+
+    store
+        .compare_and_swap(
+            "key1".to_owned(),
+            Some("value1".to_owned()),
+            Some("value2".to_owned()),
+        )
+        .wait()?;
+    match store
+        .compare_and_swap(
+            "key1".to_owned(),
+            Some("value1".to_owned()),
+            Some("value3".to_owned()),
+        )
+        .wait()
+    {
+        Err(KvsError::CasConflict { current }) => assert_eq!(current, Some(b"value2".to_vec())),
+        res => panic!("unexpected result: {:?}", res),
+    }
+    store
+        .compare_and_swap("key1".to_owned(), Some("value2".to_owned()), None)
+        .wait()?;
+    store
+        .compare_and_swap("key1".to_owned(), None, None)
+        .wait()?;
+    assert_eq!(store.get("key1".to_owned()).wait()?, None);
+
+    // An expired key compares as absent
+    store
+        .set_with_ttl(
+            "key2".to_owned(),
+            "value".to_owned(),
+            Duration::from_millis(100),
+        )
+        .wait()?;
+    thread::sleep(Duration::from_millis(200));
+    store
+        .set_if_absent("key2".to_owned(), "value2".to_owned())
+        .wait()?;
+
+    let store = store.reopen(temp_dir.path(), 1)?;
+    assert_eq!(store.get("key1".to_owned()).wait()?, None);
+    assert_eq!(
+        store.get("key2".to_owned()).wait()?,
+        Some("value2".to_owned())
+    );
+
+    Ok(())
+}
+
+pub fn set_if_absent_with_ttl<E: TestEngine>() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = E::open(temp_dir.path(), 1)?;
+    let ttl = Duration::from_millis(100);
+
+    store.set("key1".to_owned(), "value1".to_owned()).wait()?;
+    match store
+        .set_if_absent_with_ttl_bytes(b"key1".to_vec(), b"value2".to_vec(), ttl)
+        .wait()
+    {
+        Err(KvsError::CasConflict { current }) => assert_eq!(current, Some(b"value1".to_vec())),
+        res => panic!("unexpected result: {:?}", res),
+    }
+
+    store
+        .set_if_absent_with_ttl_bytes(b"key2".to_vec(), b"value2".to_vec(), ttl)
+        .wait()?;
+    assert_eq!(
+        store.get("key2".to_owned()).wait()?,
+        Some("value2".to_owned())
+    );
+    thread::sleep(Duration::from_millis(200));
+    assert_eq!(store.get("key2".to_owned()).wait()?, None);
+    store
+        .set_if_absent_with_ttl_bytes(
+            b"key2".to_vec(),
+            b"value3".to_vec(),
+            Duration::from_secs(3600),
+        )
+        .wait()?;
+    assert_eq!(
+        store.get("key2".to_owned()).wait()?,
+        Some("value3".to_owned())
+    );
+
+    Ok(())
+}
+
+pub fn concurrent_compare_and_swap<E: TestEngine>() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = E::open(temp_dir.path(), 8)?;
+    store.set("counter".to_owned(), "0".to_owned()).wait()?;
+
+    let handles: Vec<_> = (0..8)
+        .map(|_| {
+            let store = store.clone();
+            thread::spawn(move || {
+                for _ in 0..50 {
+                    loop {
+                        let current = store.get("counter".to_owned()).wait().unwrap().unwrap();
+                        let next = (current.parse::<u32>().unwrap() + 1).to_string();
+                        match store
+                            .compare_and_swap("counter".to_owned(), Some(current), Some(next))
+                            .wait()
+                        {
+                            Ok(()) => break,
+                            Err(KvsError::CasConflict { .. }) => continue,
+                            Err(e) => panic!("unexpected error: {}", e),
+                        }
+                    }
+                    // plain writes to other keys are committed concurrently
+                    store
+                        .set("other".to_owned(), "value".to_owned())
+                        .wait()
+                        .unwrap();
+                }
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap();
+    }
+
+    assert_eq!(
+        store.get("counter".to_owned()).wait()?,
+        Some("400".to_owned())
+    );
+
+    Ok(())
+}
+
+pub fn transaction<E: TestEngine>() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = E::open(temp_dir.path(), 1)?;
+    store.set("key1".to_owned(), "value1".to_owned()).wait()?;
+
+    let txn = store.begin();
+    assert_eq!(
+        txn.get("key1".to_owned()).wait()?,
+        Some("value1".to_owned())
+    );
+    txn.set("key1".to_owned(), "value2".to_owned());
+    txn.set("key2".to_owned(), "value3".to_owned());
+    txn.remove("key3".to_owned());
+    // Reads see the writes of the transaction, but the store doesn't until it commits
+    assert_eq!(
+        txn.get("key1".to_owned()).wait()?,
+        Some("value2".to_owned())
+    );
+    assert_eq!(
+        store.get("key1".to_owned()).wait()?,
+        Some("value1".to_owned())
+    );
+    assert_eq!(store.get("key2".to_owned()).wait()?, None);
+    txn.commit().wait()?;
+    assert_eq!(
+        store.get("key1".to_owned()).wait()?,
+        Some("value2".to_owned())
+    );
+    assert_eq!(
+        store.get("key2".to_owned()).wait()?,
+        Some("value3".to_owned())
+    );
+
+    let txn = store.begin();
+    txn.remove("key1".to_owned());
+    txn.set("key2".to_owned(), "value4".to_owned());
+    txn.rollback();
+    assert_eq!(
+        store.get("key1".to_owned()).wait()?,
+        Some("value2".to_owned())
+    );
+
+    let txn = store.begin();
+    txn.remove("key1".to_owned());
+    txn.commit().wait()?;
+    let store = store.reopen(temp_dir.path(), 1)?;
+    assert_eq!(store.get("key1".to_owned()).wait()?, None);
+    assert_eq!(
+        store.get("key2".to_owned()).wait()?,
+        Some("value3".to_owned())
+    );
+
+    Ok(())
+}
+
+pub fn transaction_conflict<E: TestEngine>() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = E::open(temp_dir.path(), 1)?;
+    store.set("key1".to_owned(), "value1".to_owned()).wait()?;
+
+    // A key read by the transaction is changed before it commits
+    let txn = store.begin();
+    assert_eq!(
+        txn.get("key1".to_owned()).wait()?,
+        Some("value1".to_owned())
+    );
+    assert_eq!(txn.get("key2".to_owned()).wait()?, None);
+    txn.set("key3".to_owned(), "value3".to_owned());
+    store.set("key2".to_owned(), "value2".to_owned()).wait()?;
+    match txn.commit().wait() {
+        Err(KvsError::TransactionConflict) => {}
+        res => panic!("unexpected result: {:?}", res),
+    }
+    assert_eq!(store.get("key3".to_owned()).wait()?, None);
+
+    // Writes to keys the transaction didn't read don't conflict
+    let txn = store.begin();
+    assert_eq!(
+        txn.get("key1".to_owned()).wait()?,
+        Some("value1".to_owned())
+    );
+    txn.set("key2".to_owned(), "value4".to_owned());
+    store.set("key2".to_owned(), "value5".to_owned()).wait()?;
+    txn.commit().wait()?;
+    assert_eq!(
+        store.get("key2".to_owned()).wait()?,
+        Some("value4".to_owned())
+    );
+
+    Ok(())
+}
+
+pub fn concurrent_transactions<E: TestEngine>() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = E::open(temp_dir.path(), 8)?;
+    for i in 0..4 {
+        store
+            .set(format!("account{}", i), "100".to_owned())
+            .wait()?;
+    }
+
+    // Every transaction moves 1 from an account to the next one
+    let handles: Vec<_> = (0..8)
+        .map(|thread_id| {
+            let store = store.clone();
+            thread::spawn(move || {
+                for round in 0..50 {
+                    let from = format!("account{}", (thread_id + round) % 4);
+                    let to = format!("account{}", (thread_id + round + 1) % 4);
+                    loop {
+                        let txn = store.begin();
+                        let balance = |key: &String| -> u32 {
+                            let value = txn.get(key.clone()).wait().unwrap();
+                            value.unwrap().parse().unwrap()
+                        };
+                        let (from_balance, to_balance) = (balance(&from), balance(&to));
+                        txn.set(from.clone(), (from_balance - 1).to_string());
+                        txn.set(to.clone(), (to_balance + 1).to_string());
+                        match txn.commit().wait() {
+                            Ok(()) => break,
+                            Err(KvsError::TransactionConflict) => continue,
+                            Err(e) => panic!("unexpected error: {}", e),
+                        }
+                    }
+                }
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap();
+    }
+
+    let mut total = 0;
+    for i in 0..4 {
+        let value = store.get(format!("account{}", i)).wait()?.unwrap();
+        total += value.parse::<u32>().unwrap();
+    }
+    assert_eq!(total, 400);
+
+    Ok(())
+}
@@ -1,19 +1,85 @@
 use kvs::thread_pool::RayonThreadPool;
-use kvs::{KvStore, KvsEngine, KvsError, Result};
+use kvs::{Durability, KvStore, KvStoreOptions, KvsEngine, KvsError, Result, WriteBatch};
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::ops::Bound::Unbounded;
+use std::process;
+use std::thread;
+use std::time::Duration;
 use tempfile::TempDir;
 use tokio::prelude::*;
 use tokio::runtime::Runtime;
 use walkdir::WalkDir;
 
-// Should get previously stored value
+#[macro_use]
+mod common;
+
+engine_suite!(KvStore<RayonThreadPool>);
+
+// Insert data until total size of the directory decreases.
+// Test data correctness after compaction.
 #[test]
-fn get_stored_value() -> Result<()> {
+fn compaction() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
     let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
 
+    let dir_size = || {
+        let entries = WalkDir::new(temp_dir.path()).into_iter();
+        let len: walkdir::Result<u64> = entries
+            .map(|res| {
+                res.and_then(|entry| entry.metadata())
+                    .map(|metadata| metadata.len())
+            })
+            .sum();
+        len.expect("fail to get directory size")
+    };
+
+    let mut current_size = dir_size();
+    for iter in 0..1000 {
+        for key_id in 0..1000 {
+            let key = format!("key{}", key_id);
+            let value = format!("{}", iter);
+            store.set(key, value).wait()?;
+        }
+
+        let new_size = dir_size();
+        if new_size > current_size {
+            current_size = new_size;
+            continue;
+        }
+        // Compaction triggered
+
+        drop(store);
+        // reopen and check content
+        let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+        for key_id in 0..1000 {
+            let key = format!("key{}", key_id);
+            assert_eq!(store.get(key).wait()?, Some(format!("{}", iter)));
+        }
+        return Ok(());
+    }
+
+    panic!("No compaction detected");
+}
+
+// Should drop a half-written record at the end of the log and keep the rest
+#[test]
+fn recover_torn_tail() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
     store.set("key1".to_owned(), "value1".to_owned()).wait()?;
     store.set("key2".to_owned(), "value2".to_owned()).wait()?;
+    drop(store);
+
+    // Simulate a crash in the middle of appending a record
+    let log = temp_dir.path().join("1.log");
+    let len = fs::metadata(&log)?.len();
+    let mut file = OpenOptions::new().append(true).open(&log)?;
+    file.write_all(&[40, 0, 0, 0, 1, 2, 3, 4, b'{'])?;
+    drop(file);
 
+    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+    assert_eq!(fs::metadata(&log)?.len(), len);
     assert_eq!(
         store.get("key1".to_owned()).wait()?,
         Some("value1".to_owned())
@@ -22,218 +88,815 @@ fn get_stored_value() -> Result<()> {
         store.get("key2".to_owned()).wait()?,
         Some("value2".to_owned())
     );
+    store.set("key3".to_owned(), "value3".to_owned()).wait()?;
 
-    // Open from disk again and check persistent data
     drop(store);
     let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
     assert_eq!(
-        store.get("key1".to_owned()).wait()?,
-        Some("value1".to_owned())
-    );
-    assert_eq!(
-        store.get("key2".to_owned()).wait()?,
-        Some("value2".to_owned())
+        store.get("key3".to_owned()).wait()?,
+        Some("value3".to_owned())
     );
 
     Ok(())
 }
 
-// Should overwrite existent value
+// Should report a record that fails its checksum in the middle of the log
 #[test]
-fn overwrite_value() -> Result<()> {
+fn detect_corrupted_record() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
     let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+    store.set("key1".to_owned(), "value1".to_owned()).wait()?;
+    store.set("key2".to_owned(), "value2".to_owned()).wait()?;
+    drop(store);
+
+    // Flip the last byte of the payload of the first record, after the 8-byte file header
+    let log = temp_dir.path().join("1.log");
+    let mut content = fs::read(&log)?;
+    let first_end = 16 + u32::from_le_bytes([content[8], content[9], content[10], content[11]]);
+    content[first_end as usize - 1] ^= 0xff;
+    fs::write(&log, content)?;
 
+    match KvStore::<RayonThreadPool>::open(temp_dir.path(), 1) {
+        Err(KvsError::Corruption { gen: 1, offset: 8 }) => Ok(()),
+        Err(e) => panic!("unexpected error: {}", e),
+        Ok(_) => panic!("corruption not detected"),
+    }
+}
+
+// Should report a record whose length was corrupted in the middle of the log
+// instead of truncating the records after it
+#[test]
+fn detect_corrupted_length() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
     store.set("key1".to_owned(), "value1".to_owned()).wait()?;
+    store.set("key2".to_owned(), "value2".to_owned()).wait()?;
+    store.set("key3".to_owned(), "value3".to_owned()).wait()?;
+    drop(store);
+
+    // Make the length of the second record run past the end of the log
+    let log = temp_dir.path().join("1.log");
+    let mut content = fs::read(&log)?;
+    let second = 16 + u32::from_le_bytes([content[8], content[9], content[10], content[11]]);
+    content[second as usize + 1] = 0xff;
+    fs::write(&log, &content)?;
+
+    match KvStore::<RayonThreadPool>::open(temp_dir.path(), 1) {
+        Err(KvsError::Corruption { gen: 1, offset }) => assert_eq!(offset, u64::from(second)),
+        Err(e) => panic!("unexpected error: {}", e),
+        Ok(_) => panic!("corruption not detected"),
+    }
+    assert_eq!(fs::read(&log)?, content);
+
+    Ok(())
+}
+
+// Should only take a record cut off by the end of the log for a torn write in the
+// active log
+#[test]
+fn detect_truncated_old_log() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::<RayonThreadPool>::open_with_options(
+        temp_dir.path(),
+        1,
+        KvStoreOptions::new().max_log_size(1024),
+    )?;
+    for key_id in 0..100 {
+        store
+            .set(format!("key{}", key_id), format!("value{}", key_id))
+            .wait()?;
+    }
+    drop(store);
+
+    let log = temp_dir.path().join("1.log");
+    let len = fs::metadata(&log)?.len();
+    OpenOptions::new()
+        .write(true)
+        .open(&log)?
+        .set_len(len - 3)?;
+
+    match KvStore::<RayonThreadPool>::open(temp_dir.path(), 1) {
+        Err(KvsError::Corruption { gen: 1, .. }) => Ok(()),
+        Err(e) => panic!("unexpected error: {}", e),
+        Ok(_) => panic!("corruption not detected"),
+    }
+}
+
+// Should read logs in the old JSON format and rewrite them in the binary format
+// during compaction
+#[test]
+fn upgrade_json_log() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+
+    let mut legacy_log = Vec::new();
+    for payload in &[
+        r#"{"Set":{"key":"key1","value":"value1"}}"#,
+        r#"{"Set":{"key":"key2","value":"value2"}}"#,
+        r#"{"Remove":{"key":"key2"}}"#,
+    ] {
+        legacy_log.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+        legacy_log.extend_from_slice(&crc32fast::hash(payload.as_bytes()).to_le_bytes());
+        legacy_log.extend_from_slice(payload.as_bytes());
+    }
+    fs::write(temp_dir.path().join("1.log"), legacy_log)?;
+
+    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
     assert_eq!(
         store.get("key1".to_owned()).wait()?,
         Some("value1".to_owned())
     );
-    store.set("key1".to_owned(), "value2".to_owned()).wait()?;
+    assert_eq!(store.get("key2".to_owned()).wait()?, None);
+
+    // Write until the old log is compacted away
+    let mut iter = 0;
+    while temp_dir.path().join("1.log").exists() {
+        assert!(iter < 1000, "No compaction detected");
+        for key_id in 0..1000 {
+            store
+                .set(format!("key{}", key_id + 10), format!("{}", iter))
+                .wait()?;
+        }
+        iter += 1;
+    }
+    drop(store);
+
+    for entry in fs::read_dir(temp_dir.path())? {
+        let path = entry?.path();
+        if path.extension() == Some("log".as_ref()) {
+            assert!(fs::read(&path)?.starts_with(b"KVSLOG"));
+        }
+    }
+    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
     assert_eq!(
         store.get("key1".to_owned()).wait()?,
-        Some("value2".to_owned())
+        Some("value1".to_owned())
     );
+    assert_eq!(store.get("key2".to_owned()).wait()?, None);
 
-    // Open from disk again and check persistent data
+    Ok(())
+}
+
+// Should ignore and remove the logs the manifest does not list, left by a crash in
+// the middle of a compaction
+#[test]
+fn ignore_orphaned_logs() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+    store.set("key1".to_owned(), "value1".to_owned()).wait()?;
     drop(store);
+    assert!(temp_dir.path().join("MANIFEST").exists());
+
+    // The header of a log in an unknown format fails the store if it is loaded
+    let orphan_log = temp_dir.path().join("5.log");
+    let orphan_hint = temp_dir.path().join("5.hint.tmp");
+    fs::write(&orphan_log, b"KVSLOG\xff\xff")?;
+    fs::write(&orphan_hint, b"")?;
+
     let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
     assert_eq!(
         store.get("key1".to_owned()).wait()?,
-        Some("value2".to_owned())
+        Some("value1".to_owned())
     );
-    store.set("key1".to_owned(), "value3".to_owned()).wait()?;
+    assert!(!orphan_log.exists());
+    assert!(!orphan_hint.exists());
+    store.set("key2".to_owned(), "value2".to_owned()).wait()?;
+
+    drop(store);
+    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
     assert_eq!(
-        store.get("key1".to_owned()).wait()?,
-        Some("value3".to_owned())
+        store.get("key2".to_owned()).wait()?,
+        Some("value2".to_owned())
     );
 
     Ok(())
 }
 
-// Should get `None` when getting a non-existent key
+// Should refuse to open a store that is already open, naming the process
+// holding it, and open it again once it is dropped
 #[test]
-fn get_non_existent_value() -> Result<()> {
+fn lock_store() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
     let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
-
     store.set("key1".to_owned(), "value1".to_owned()).wait()?;
-    assert_eq!(store.get("key2".to_owned()).wait()?, None);
 
-    // Open from disk again and check persistent data
+    match KvStore::<RayonThreadPool>::open(temp_dir.path(), 1) {
+        Err(KvsError::StoreLocked { pid }) => assert_eq!(pid, Some(process::id())),
+        Err(e) => panic!("unexpected error: {}", e),
+        Ok(_) => panic!("the lock was not detected"),
+    }
+
     drop(store);
     let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
-    assert_eq!(store.get("key2".to_owned()).wait()?, None);
+    assert_eq!(
+        store.get("key1".to_owned()).wait()?,
+        Some("value1".to_owned())
+    );
 
     Ok(())
 }
 
+// Read-only stores should open alongside each other and the writer, see the
+// writes committed before they opened, refuse writes and leave the directory
+// as is
 #[test]
-fn remove_non_existent_key() -> Result<()> {
+fn read_only_store() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
     let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
-    assert!(store.remove("key1".to_owned()).wait().is_err());
+    store.set("key1".to_owned(), "value1".to_owned()).wait()?;
+    store.set("key2".to_owned(), "value2".to_owned()).wait()?;
+
+    let files = || -> Vec<_> {
+        let mut files: Vec<_> = fs::read_dir(temp_dir.path())
+            .expect("fail to read directory")
+            .map(|entry| entry.expect("fail to read directory").file_name())
+            .collect();
+        files.sort();
+        files
+    };
+    let files_before = files();
+
+    let readers = vec![
+        KvStore::<RayonThreadPool>::open_read_only(temp_dir.path(), 1)?,
+        KvStore::<RayonThreadPool>::open_read_only(temp_dir.path(), 1)?,
+    ];
+    for reader in &readers {
+        assert_eq!(
+            reader.get("key1".to_owned()).wait()?,
+            Some("value1".to_owned())
+        );
+        assert_eq!(
+            reader.get("key2".to_owned()).wait()?,
+            Some("value2".to_owned())
+        );
+        match reader.set("key3".to_owned(), "value3".to_owned()).wait() {
+            Err(KvsError::ReadOnly) => {}
+            res => panic!("unexpected result: {:?}", res),
+        }
+        match reader.remove("key1".to_owned()).wait() {
+            Err(KvsError::ReadOnly) => {}
+            res => panic!("unexpected result: {:?}", res),
+        }
+        match reader.compact().wait() {
+            Err(KvsError::ReadOnly) => {}
+            res => panic!("unexpected result: {:?}", res),
+        }
+    }
+    assert_eq!(files(), files_before);
+
+    // Writes committed after opening are not seen
+    store.set("key1".to_owned(), "value3".to_owned()).wait()?;
+    assert_eq!(
+        readers[0].get("key1".to_owned()).wait()?,
+        Some("value1".to_owned())
+    );
+    assert_eq!(store.get("key3".to_owned()).wait()?, None);
+
     Ok(())
 }
 
+// Should fail to open a directory without a store read-only, without creating
+// anything
 #[test]
-fn remove_key() -> Result<()> {
+fn read_only_missing_store() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
-    store.set("key1".to_owned(), "value1".to_owned()).wait()?;
-    assert!(store.remove("key1".to_owned()).wait().is_ok());
-    assert_eq!(store.get("key1".to_owned()).wait()?, None);
+    let missing_dir = temp_dir.path().join("missing");
+
+    for path in &[temp_dir.path(), missing_dir.as_path()] {
+        match KvStore::<RayonThreadPool>::open_read_only(path, 1) {
+            Err(KvsError::Io(ref e)) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => panic!("unexpected error: {}", e),
+            Ok(_) => panic!("opened a missing store"),
+        }
+    }
+    assert_eq!(fs::read_dir(temp_dir.path())?.count(), 0);
+
     Ok(())
 }
 
-// Insert data until total size of the directory decreases.
-// Test data correctness after compaction.
+// Should write a hint file for the compaction log and use it, or fall back to
+// the log if the hint is damaged, when reopening
 #[test]
-fn compaction() -> Result<()> {
+fn compaction_hint() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
     let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
 
-    let dir_size = || {
-        let entries = WalkDir::new(temp_dir.path()).into_iter();
-        let len: walkdir::Result<u64> = entries
-            .map(|res| {
-                res.and_then(|entry| entry.metadata())
-                    .map(|metadata| metadata.len())
-            })
-            .sum();
-        len.expect("fail to get directory size")
+    let hint_files = || -> Vec<_> {
+        fs::read_dir(temp_dir.path())
+            .expect("fail to read directory")
+            .map(|entry| entry.expect("fail to read directory").path())
+            .filter(|path| path.extension() == Some("hint".as_ref()))
+            .collect()
     };
 
-    let mut current_size = dir_size();
-    for iter in 0..1000 {
+    let mut iter = 0;
+    while hint_files().is_empty() {
+        assert!(iter < 1000, "No compaction detected");
         for key_id in 0..1000 {
-            let key = format!("key{}", key_id);
-            let value = format!("{}", iter);
-            store.set(key, value).wait()?;
-        }
-
-        let new_size = dir_size();
-        if new_size > current_size {
-            current_size = new_size;
-            continue;
+            store
+                .set(format!("key{}", key_id), format!("{}", iter))
+                .wait()?;
         }
-        // Compaction triggered
+        iter += 1;
+    }
+    store.remove("key0".to_owned()).wait()?;
+    drop(store);
 
-        drop(store);
-        // reopen and check content
+    let check = || -> Result<()> {
         let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
-        for key_id in 0..1000 {
-            let key = format!("key{}", key_id);
-            assert_eq!(store.get(key).wait()?, Some(format!("{}", iter)));
+        assert_eq!(store.get("key0".to_owned()).wait()?, None);
+        for key_id in 1..1000 {
+            assert_eq!(
+                store.get(format!("key{}", key_id)).wait()?,
+                Some(format!("{}", iter - 1))
+            );
         }
-        return Ok(());
+        Ok(())
+    };
+    check()?;
+
+    let hint = hint_files().pop().unwrap();
+    let len = fs::metadata(&hint)?.len();
+    OpenOptions::new()
+        .write(true)
+        .open(&hint)?
+        .set_len(len - 1)?;
+    check()
+}
+
+// Should keep the latest values when writes race with background compactions
+#[test]
+fn concurrent_set_during_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 4)?;
+
+    // 4 threads overwrite disjoint keys round after round
+    let handles: Vec<_> = (0..4)
+        .map(|thread_id| {
+            let store = store.clone();
+            thread::spawn(move || -> Result<()> {
+                for iter in 0..100 {
+                    for key_id in 0..250 {
+                        let key = format!("key{}", thread_id * 250 + key_id);
+                        store.set(key, format!("{}", iter)).wait()?;
+                    }
+                }
+                Ok(())
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap()?;
     }
 
-    panic!("No compaction detected");
+    for key_id in 0..1000 {
+        assert_eq!(
+            store.get(format!("key{}", key_id)).wait()?,
+            Some("99".to_owned())
+        );
+    }
+
+    drop(store);
+    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+    for key_id in 0..1000 {
+        assert_eq!(
+            store.get(format!("key{}", key_id)).wait()?,
+            Some("99".to_owned())
+        );
+    }
+
+    Ok(())
 }
 
+// Should only compact when asked to if automatic compaction is disabled
 #[test]
-fn concurrent_set() -> Result<()> {
+fn manual_compaction() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::<RayonThreadPool>::open_with_options(
+        temp_dir.path(),
+        1,
+        KvStoreOptions::new()
+            .compaction_threshold(1024)
+            .auto_compaction(false),
+    )?;
 
-    // concurrent set in 8 threads
-    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 8)?;
-    let runtime = Runtime::new()?;
-    let executor = runtime.executor();
-    runtime.block_on_all(future::lazy(move || {
-        for i in 0..10000 {
-            executor.spawn(
-                store
-                    .set(format!("key{}", i), format!("value{}", i))
-                    .map_err(|_| ()),
-            );
+    for iter in 0..100 {
+        for key_id in 0..100 {
+            store
+                .set(format!("key{}", key_id), format!("{}", iter))
+                .wait()?;
         }
-        future::ok::<(), KvsError>(())
-    }))?;
+    }
+    assert!(temp_dir.path().join("1.log").exists());
 
-    // We only check concurrent set in this test, so we check sequentially here
-    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
-    for i in 0..10000 {
+    store.compact().wait()?;
+    assert!(!temp_dir.path().join("1.log").exists());
+    for key_id in 0..100 {
         assert_eq!(
-            store.get(format!("key{}", i)).wait()?,
-            Some(format!("value{}", i))
+            store.get(format!("key{}", key_id)).wait()?,
+            Some("99".to_owned())
         );
     }
 
     Ok(())
 }
 
+// Should serve concurrent reads with fewer readers than threads
 #[test]
-fn concurrent_get() -> Result<()> {
+fn fewer_readers_than_threads() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 8)?;
-    // We only check concurrent get in this test, so we set sequentially here
-    for i in 0..100 {
+    let store = KvStore::<RayonThreadPool>::open_with_options(
+        temp_dir.path(),
+        4,
+        KvStoreOptions::new().readers(1),
+    )?;
+    store.set("key".to_owned(), "value".to_owned()).wait()?;
+
+    let readers: Vec<_> = (0..8)
+        .map(|_| {
+            let store = store.clone();
+            thread::spawn(move || {
+                for _ in 0..100 {
+                    assert_eq!(
+                        store.get("key".to_owned()).wait().unwrap(),
+                        Some("value".to_owned())
+                    );
+                }
+            })
+        })
+        .collect();
+    for reader in readers {
+        reader.join().unwrap();
+    }
+
+    Ok(())
+}
+
+// Should start a new log once the active one is full
+#[test]
+fn rotate_full_log() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::<RayonThreadPool>::open_with_options(
+        temp_dir.path(),
+        4,
+        KvStoreOptions::new().max_log_size(1024).readers(1),
+    )?;
+
+    for key_id in 0..100 {
         store
-            .set(format!("key{}", i), format!("value{}", i))
-            .wait()
-            .unwrap();
+            .set(format!("key{}", key_id), format!("value{}", key_id))
+            .wait()?;
+    }
+    let log_count = fs::read_dir(temp_dir.path())?
+        .filter(|entry| entry.as_ref().unwrap().path().extension() == Some("log".as_ref()))
+        .count();
+    assert!(log_count > 1);
+
+    drop(store);
+    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 4)?;
+    for key_id in 0..100 {
+        assert_eq!(
+            store.get(format!("key{}", key_id)).wait()?,
+            Some(format!("value{}", key_id))
+        );
     }
 
-    let runtime = Runtime::new()?;
-    let executor = runtime.executor();
-    runtime.block_on_all(future::lazy(move || {
-        for thread_id in 0..100 {
-            for i in 0..100 {
-                let key_id = (i + thread_id) % 100;
+    Ok(())
+}
+
+// Should persist concurrent writes with every durability level
+#[test]
+fn durability_levels() -> Result<()> {
+    for durability in &[
+        Durability::None,
+        Durability::Periodic(Duration::from_millis(10)),
+        Durability::EveryWrite,
+        Durability::GroupCommit,
+    ] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let store = KvStore::<RayonThreadPool>::open_with_options(
+            temp_dir.path(),
+            8,
+            KvStoreOptions::new().durability(*durability),
+        )?;
+        let runtime = Runtime::new()?;
+        let executor = runtime.executor();
+        runtime.block_on_all(future::lazy(move || {
+            for i in 0..200 {
                 executor.spawn(
                     store
-                        .get(format!("key{}", key_id))
-                        .map(move |res| {
-                            assert_eq!(res, Some(format!("value{}", key_id)));
-                        })
+                        .set(format!("key{}", i), format!("value{}", i))
                         .map_err(|_| ()),
                 );
             }
+            future::ok::<(), KvsError>(())
+        }))?;
+
+        let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+        for i in 0..200 {
+            assert_eq!(
+                store.get(format!("key{}", i)).wait()?,
+                Some(format!("value{}", i))
+            );
         }
-        future::ok::<(), KvsError>(())
-    }))?;
-
-    // reload from disk and test again
-    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 8)?;
-    let runtime = Runtime::new()?;
-    let executor = runtime.executor();
-    runtime.block_on_all(future::lazy(move || {
-        for thread_id in 0..100 {
-            for i in 0..100 {
-                let key_id = (i + thread_id) % 100;
-                executor.spawn(
-                    store
-                        .get(format!("key{}", key_id))
-                        .map(move |res| {
-                            assert_eq!(res, Some(format!("value{}", key_id)));
-                        })
-                        .map_err(|_| ()),
-                );
+    }
+
+    Ok(())
+}
+
+// Concurrent removes of the same key may be committed in one group, and only
+// the first of them should succeed.
+#[test]
+fn concurrent_remove_same_key() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::<RayonThreadPool>::open_with_options(
+        temp_dir.path(),
+        8,
+        KvStoreOptions::new().durability(Durability::GroupCommit),
+    )?;
+    for i in 0..200 {
+        store.set(format!("key{}", i), "value".to_owned()).wait()?;
+    }
+
+    let removes: Vec<_> = (0..400)
+        .map(|i| {
+            store
+                .remove(format!("key{}", i / 2))
+                .then(Ok::<_, KvsError>)
+        })
+        .collect();
+    let mut runtime = Runtime::new()?;
+    let results = runtime.block_on(future::join_all(removes))?;
+    let removed = results.iter().filter(|res| res.is_ok()).count();
+    assert_eq!(removed, 200);
+    for res in results {
+        match res {
+            Ok(()) | Err(KvsError::KeyNotFound) => {}
+            Err(e) => panic!("unexpected error: {}", e),
+        }
+    }
+
+    drop(store);
+    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+    for i in 0..200 {
+        assert_eq!(store.get(format!("key{}", i)).wait()?, None);
+    }
+
+    Ok(())
+}
+
+// Keys expiring after a compaction should not come back from the hint file
+// or the compaction log.
+#[test]
+fn expire_after_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+    for i in 0..100 {
+        store
+            .set_with_ttl(
+                format!("key{}", i),
+                format!("value{}", i),
+                Duration::from_millis(300),
+            )
+            .wait()?;
+    }
+    store.set("key".to_owned(), "value".to_owned()).wait()?;
+    store.compact().wait()?;
+    drop(store);
+    thread::sleep(Duration::from_millis(400));
+
+    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+    for i in 0..100 {
+        assert_eq!(store.get(format!("key{}", i)).wait()?, None);
+    }
+    assert_eq!(store.keys(String::new()).collect().wait()?, vec!["key"]);
+
+    // The next compaction drops the expired commands for good
+    store.compact().wait()?;
+    let log_len: u64 = WalkDir::new(temp_dir.path())
+        .into_iter()
+        .map(|entry| entry.unwrap())
+        .filter(|entry| entry.path().extension() == Some("log".as_ref()))
+        .map(|entry| entry.metadata().unwrap().len())
+        .sum();
+    assert!(log_len < 200, "expired commands are not compacted");
+
+    Ok(())
+}
+
+#[test]
+fn upgrade_v1_log() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+
+    // Records of version 1 don't have expiry deadlines
+    let mut set = 0u32.to_le_bytes().to_vec();
+    for field in &[&b"key1"[..], &b"value1"[..]] {
+        set.extend_from_slice(&(field.len() as u64).to_le_bytes());
+        set.extend_from_slice(field);
+    }
+    let mut log = b"KVSLOG".to_vec();
+    log.extend_from_slice(&1u16.to_le_bytes());
+    log.extend_from_slice(&(set.len() as u32).to_le_bytes());
+    log.extend_from_slice(&crc32fast::hash(&set).to_le_bytes());
+    log.extend_from_slice(&set);
+    fs::write(temp_dir.path().join("1.log"), log)?;
+
+    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+    assert_eq!(
+        store.get("key1".to_owned()).wait()?,
+        Some("value1".to_owned())
+    );
+    store.compact().wait()?;
+    drop(store);
+    assert!(!temp_dir.path().join("1.log").exists());
+
+    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+    assert_eq!(
+        store.get("key1".to_owned()).wait()?,
+        Some("value1".to_owned())
+    );
+
+    Ok(())
+}
+
+// A batch torn by a crash should be dropped as a whole
+#[test]
+fn recover_torn_batch() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+    store.set("key1".to_owned(), "value1".to_owned()).wait()?;
+    let mut batch = WriteBatch::new();
+    for i in 0..100 {
+        batch.set(format!("batch{}", i), format!("value{}", i));
+    }
+    batch.remove("key1");
+    store.write_batch(batch).wait()?;
+    drop(store);
+
+    // Cut the batch record in the middle
+    let log = temp_dir.path().join("1.log");
+    let len = fs::metadata(&log)?.len();
+    OpenOptions::new()
+        .write(true)
+        .open(&log)?
+        .set_len(len - 100)?;
+
+    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+    assert_eq!(
+        store.get("key1".to_owned()).wait()?,
+        Some("value1".to_owned())
+    );
+    assert_eq!(store.keys("batch".to_owned()).collect().wait()?.len(), 0);
+
+    Ok(())
+}
+
+#[test]
+fn snapshot_reads() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+    store.set("key1".to_owned(), "value1".to_owned()).wait()?;
+    store.set("key2".to_owned(), "value2".to_owned()).wait()?;
+
+    let snapshot = store.snapshot();
+    store.set("key1".to_owned(), "value1b".to_owned()).wait()?;
+    store.remove("key2".to_owned()).wait()?;
+    store.set("key3".to_owned(), "value3".to_owned()).wait()?;
+    let later = store.snapshot();
+    assert!(later.seq() > snapshot.seq());
+    store.set("key1".to_owned(), "value1c".to_owned()).wait()?;
+
+    assert_eq!(
+        snapshot.get("key1".to_owned()).wait()?,
+        Some("value1".to_owned())
+    );
+    assert_eq!(
+        snapshot.get("key2".to_owned()).wait()?,
+        Some("value2".to_owned())
+    );
+    assert_eq!(snapshot.get("key3".to_owned()).wait()?, None);
+    assert_eq!(
+        snapshot.scan(Unbounded, Unbounded, None).wait()?,
+        vec![
+            ("key1".to_owned(), "value1".to_owned()),
+            ("key2".to_owned(), "value2".to_owned()),
+        ]
+    );
+    assert_eq!(
+        later.scan(Unbounded, Unbounded, None).wait()?,
+        vec![
+            ("key1".to_owned(), "value1b".to_owned()),
+            ("key3".to_owned(), "value3".to_owned()),
+        ]
+    );
+
+    // The store itself sees the latest writes only
+    assert_eq!(
+        store.get("key1".to_owned()).wait()?,
+        Some("value1c".to_owned())
+    );
+    assert_eq!(store.get("key2".to_owned()).wait()?, None);
+    assert_eq!(
+        store.keys(String::new()).collect().wait()?,
+        vec!["key1", "key3"]
+    );
+
+    Ok(())
+}
+
+#[test]
+fn snapshot_survives_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+    for i in 0..100 {
+        store
+            .set(format!("key{}", i), format!("value{}", i))
+            .wait()?;
+    }
+    let log_count = || {
+        WalkDir::new(temp_dir.path())
+            .into_iter()
+            .map(|entry| entry.unwrap())
+            .filter(|entry| entry.path().extension() == Some("log".as_ref()))
+            .count()
+    };
+
+    let snapshot = store.snapshot();
+    for i in 0..100 {
+        store.set(format!("key{}", i), format!("new{}", i)).wait()?;
+    }
+    store.remove("key0".to_owned()).wait()?;
+    store.compact().wait()?;
+    let logs_with_snapshot = log_count();
+
+    for i in 0..100 {
+        assert_eq!(
+            snapshot.get(format!("key{}", i)).wait()?,
+            Some(format!("value{}", i))
+        );
+    }
+    assert_eq!(store.get("key0".to_owned()).wait()?, None);
+    assert_eq!(
+        store.get("key1".to_owned()).wait()?,
+        Some("new1".to_owned())
+    );
+
+    // The stale logs are removed once the snapshot is dropped
+    drop(snapshot);
+    assert!(log_count() < logs_with_snapshot);
+    assert_eq!(
+        store.get("key1".to_owned()).wait()?,
+        Some("new1".to_owned())
+    );
+
+    drop(store);
+    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+    assert_eq!(store.get("key0".to_owned()).wait()?, None);
+    for i in 1..100 {
+        assert_eq!(
+            store.get(format!("key{}", i)).wait()?,
+            Some(format!("new{}", i))
+        );
+    }
+    let snapshot = store.snapshot();
+    store.set("key1".to_owned(), "newer".to_owned()).wait()?;
+    assert_eq!(
+        snapshot.get("key1".to_owned()).wait()?,
+        Some("new1".to_owned())
+    );
+
+    Ok(())
+}
+
+#[test]
+fn consistent_snapshot_under_writes() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 4)?;
+    for i in 0..10 {
+        store.set(format!("key{}", i), "0".to_owned()).wait()?;
+    }
+
+    // Every batch sets all keys to the same value
+    let writer = {
+        let store = store.clone();
+        thread::spawn(move || {
+            for round in 1..200 {
+                let mut batch = WriteBatch::default();
+                for i in 0..10 {
+                    batch.set(format!("key{}", i), round.to_string());
+                }
+                store.write_batch(batch).wait().unwrap();
             }
+        })
+    };
+    for _ in 0..50 {
+        let snapshot = store.snapshot();
+        let mut values = Vec::new();
+        for i in 0..10 {
+            values.push(snapshot.get(format!("key{}", i)).wait()?.unwrap());
         }
-        future::ok::<(), KvsError>(())
-    }))?;
+        assert!(values.iter().all(|value| *value == values[0]));
+    }
+    writer.join().unwrap();
 
     Ok(())
 }
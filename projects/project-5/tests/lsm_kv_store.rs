@@ -0,0 +1,374 @@
+use kvs::thread_pool::RayonThreadPool;
+use kvs::{Durability, KvsEngine, KvsError, LsmKvsEngine, LsmOptions, Result, WriteBatch};
+use std::collections::BTreeMap;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::ops::Bound::Unbounded;
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+use tokio::prelude::*;
+use tokio::runtime::Runtime;
+use walkdir::WalkDir;
+
+#[macro_use]
+mod common;
+
+engine_suite!(LsmKvsEngine<RayonThreadPool>);
+
+/// Returns the total size of the files with the given extension in the directory.
+fn files_size(temp_dir: &TempDir, extension: &str) -> u64 {
+    WalkDir::new(temp_dir.path())
+        .into_iter()
+        .map(|entry| entry.unwrap())
+        .filter(|entry| entry.path().extension() == Some(extension.as_ref()))
+        .map(|entry| entry.metadata().unwrap().len())
+        .sum()
+}
+
+/// Options with tiny memtables and tables, so that a few writes fill several levels.
+fn small_options() -> LsmOptions {
+    let mut options = LsmOptions::new();
+    options
+        .memtable_size(4 * 1024)
+        .table_size(4 * 1024)
+        .block_size(512)
+        .level0_tables(2)
+        .level_size_base(16 * 1024);
+    options
+}
+
+// Insert data until total size of the directory decreases.
+// Test data correctness after compaction.
+#[test]
+fn compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = LsmKvsEngine::<RayonThreadPool>::open_with_options(
+        temp_dir.path(),
+        1,
+        LsmOptions::new().memtable_size(64 * 1024),
+    )?;
+
+    let dir_size = || {
+        let entries = WalkDir::new(temp_dir.path()).into_iter();
+        let len: walkdir::Result<u64> = entries
+            .map(|res| {
+                res.and_then(|entry| entry.metadata())
+                    .map(|metadata| metadata.len())
+            })
+            .sum();
+        len.expect("fail to get directory size")
+    };
+
+    let mut current_size = dir_size();
+    for iter in 0..1000 {
+        for key_id in 0..1000 {
+            let key = format!("key{}", key_id);
+            let value = format!("{}", iter);
+            store.set(key, value).wait()?;
+        }
+
+        let new_size = dir_size();
+        if new_size > current_size {
+            current_size = new_size;
+            continue;
+        }
+        // Compaction triggered
+
+        drop(store);
+        // reopen and check content
+        let store = LsmKvsEngine::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+        for key_id in 0..1000 {
+            let key = format!("key{}", key_id);
+            assert_eq!(store.get(key).wait()?, Some(format!("{}", iter)));
+        }
+        return Ok(());
+    }
+
+    panic!("No compaction detected");
+}
+
+// Should ignore a half-written record at the end of the write-ahead log and keep
+// the rest
+#[test]
+fn recover_torn_tail() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = LsmKvsEngine::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+    store.set("key1".to_owned(), "value1".to_owned()).wait()?;
+    store.set("key2".to_owned(), "value2".to_owned()).wait()?;
+    drop(store);
+
+    // Simulate a crash in the middle of appending a record
+    let wal = temp_dir.path().join("1.wal");
+    let mut file = OpenOptions::new().append(true).open(&wal)?;
+    file.write_all(&[40, 0, 0, 0, 1, 2, 3, 4, 5])?;
+    drop(file);
+
+    let store = LsmKvsEngine::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+    // the recovered writes are in a table and the log is gone
+    assert!(!wal.exists());
+    assert_eq!(
+        store.get("key1".to_owned()).wait()?,
+        Some("value1".to_owned())
+    );
+    assert_eq!(
+        store.get("key2".to_owned()).wait()?,
+        Some("value2".to_owned())
+    );
+    store.set("key3".to_owned(), "value3".to_owned()).wait()?;
+
+    drop(store);
+    let store = LsmKvsEngine::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+    assert_eq!(
+        store.get("key3".to_owned()).wait()?,
+        Some("value3".to_owned())
+    );
+
+    Ok(())
+}
+
+// Should report a record that fails its checksum in the middle of the write-ahead log
+#[test]
+fn detect_corrupted_record() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = LsmKvsEngine::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+    store.set("key1".to_owned(), "value1".to_owned()).wait()?;
+    store.set("key2".to_owned(), "value2".to_owned()).wait()?;
+    drop(store);
+
+    // Flip the last byte of the payload of the first record, after the 8-byte file header
+    let wal = temp_dir.path().join("1.wal");
+    let mut content = fs::read(&wal)?;
+    let first_end = 16 + u32::from_le_bytes([content[8], content[9], content[10], content[11]]);
+    content[first_end as usize - 1] ^= 0xff;
+    fs::write(&wal, content)?;
+
+    match LsmKvsEngine::<RayonThreadPool>::open(temp_dir.path(), 1) {
+        Err(KvsError::Corruption { gen: 1, offset: 8 }) => Ok(()),
+        Err(e) => panic!("unexpected error: {}", e),
+        Ok(_) => panic!("corruption not detected"),
+    }
+}
+
+// Should report a record whose length runs past the end of the write-ahead log
+// when whole records follow it, instead of dropping them as a torn tail
+#[test]
+fn detect_corrupted_length() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = LsmKvsEngine::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+    store.set("key1".to_owned(), "value1".to_owned()).wait()?;
+    store.set("key2".to_owned(), "value2".to_owned()).wait()?;
+    drop(store);
+
+    // Corrupt the length of the first record, after the 8-byte file header
+    let wal = temp_dir.path().join("1.wal");
+    let mut content = fs::read(&wal)?;
+    content[11] = 0x7f;
+    fs::write(&wal, content)?;
+
+    match LsmKvsEngine::<RayonThreadPool>::open(temp_dir.path(), 1) {
+        Err(KvsError::Corruption { gen: 1, offset: 8 }) => Ok(()),
+        Err(e) => panic!("unexpected error: {}", e),
+        Ok(_) => panic!("corruption not detected"),
+    }
+}
+
+// Should persist concurrent writes with every durability level
+#[test]
+fn durability_levels() -> Result<()> {
+    for durability in &[
+        Durability::None,
+        Durability::Periodic(Duration::from_millis(10)),
+        Durability::EveryWrite,
+        Durability::GroupCommit,
+    ] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let store = LsmKvsEngine::<RayonThreadPool>::open_with_options(
+            temp_dir.path(),
+            8,
+            LsmOptions::new().durability(*durability),
+        )?;
+        let runtime = Runtime::new()?;
+        let executor = runtime.executor();
+        runtime.block_on_all(future::lazy(move || {
+            for i in 0..200 {
+                executor.spawn(
+                    store
+                        .set(format!("key{}", i), format!("value{}", i))
+                        .map_err(|_| ()),
+                );
+            }
+            future::ok::<(), KvsError>(())
+        }))?;
+
+        let store = LsmKvsEngine::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+        for i in 0..200 {
+            assert_eq!(
+                store.get(format!("key{}", i)).wait()?,
+                Some(format!("value{}", i))
+            );
+        }
+    }
+
+    Ok(())
+}
+
+// Concurrent removes of the same key may be committed in one group, and only
+// the first of them should succeed.
+#[test]
+fn concurrent_remove_same_key() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = LsmKvsEngine::<RayonThreadPool>::open_with_options(
+        temp_dir.path(),
+        8,
+        LsmOptions::new().durability(Durability::GroupCommit),
+    )?;
+    for i in 0..200 {
+        store.set(format!("key{}", i), "value".to_owned()).wait()?;
+    }
+
+    let removes: Vec<_> = (0..400)
+        .map(|i| {
+            store
+                .remove(format!("key{}", i / 2))
+                .then(Ok::<_, KvsError>)
+        })
+        .collect();
+    let mut runtime = Runtime::new()?;
+    let results = runtime.block_on(future::join_all(removes))?;
+    let removed = results.iter().filter(|res| res.is_ok()).count();
+    assert_eq!(removed, 200);
+    for res in results {
+        match res {
+            Ok(()) | Err(KvsError::KeyNotFound) => {}
+            Err(e) => panic!("unexpected error: {}", e),
+        }
+    }
+
+    drop(store);
+    let store = LsmKvsEngine::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+    for i in 0..200 {
+        assert_eq!(store.get(format!("key{}", i)).wait()?, None);
+    }
+
+    Ok(())
+}
+
+// Expired keys should be dropped by the compaction of the last level holding them
+#[test]
+fn expire_after_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = LsmKvsEngine::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+    for i in 0..100 {
+        store
+            .set_with_ttl(
+                format!("key{}", i),
+                format!("value{}", i),
+                Duration::from_millis(300),
+            )
+            .wait()?;
+    }
+    store.set("key".to_owned(), "value".to_owned()).wait()?;
+    store.compact().wait()?;
+    drop(store);
+    thread::sleep(Duration::from_millis(400));
+
+    let store = LsmKvsEngine::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+    for i in 0..100 {
+        assert_eq!(store.get(format!("key{}", i)).wait()?, None);
+    }
+    assert_eq!(store.keys(String::new()).collect().wait()?, vec!["key"]);
+
+    // The next compaction drops the expired keys for good
+    let table_size = files_size(&temp_dir, "sst");
+    store.compact().wait()?;
+    assert!(
+        files_size(&temp_dir, "sst") < table_size / 2,
+        "expired keys are not compacted"
+    );
+
+    Ok(())
+}
+
+// A batch torn by a crash should be dropped as a whole
+#[test]
+fn recover_torn_batch() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = LsmKvsEngine::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+    store.set("key1".to_owned(), "value1".to_owned()).wait()?;
+    let mut batch = WriteBatch::new();
+    for i in 0..100 {
+        batch.set(format!("batch{}", i), format!("value{}", i));
+    }
+    batch.remove("key1");
+    store.write_batch(batch).wait()?;
+    drop(store);
+
+    // Cut the batch record in the middle
+    let wal = temp_dir.path().join("1.wal");
+    let len = fs::metadata(&wal)?.len();
+    OpenOptions::new()
+        .write(true)
+        .open(&wal)?
+        .set_len(len - 100)?;
+
+    let store = LsmKvsEngine::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+    assert_eq!(
+        store.get("key1".to_owned()).wait()?,
+        Some("value1".to_owned())
+    );
+    assert_eq!(store.keys("batch".to_owned()).collect().wait()?.len(), 0);
+
+    Ok(())
+}
+
+// Writes spread over memtables, tables of several levels and compactions should
+// read back like a map
+#[test]
+fn leveled_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store =
+        LsmKvsEngine::<RayonThreadPool>::open_with_options(temp_dir.path(), 4, &small_options())?;
+    let mut expected = BTreeMap::new();
+    for round in 0..10 {
+        for i in 0..500 {
+            let key = format!("key{:04}", (i * 7 + round * 13) % 1000);
+            if i % 5 == 0 {
+                let res = store.remove(key.clone()).wait();
+                assert_eq!(res.is_ok(), expected.remove(&key).is_some());
+            } else {
+                let value = format!("value{}-{}", round, i);
+                store.set(key.clone(), value.clone()).wait()?;
+                expected.insert(key, value);
+            }
+        }
+    }
+    let expected: Vec<_> = expected.into_iter().collect();
+    assert!(fs::read_dir(temp_dir.path())?
+        .filter(|entry| entry.as_ref().unwrap().path().extension() == Some("sst".as_ref()))
+        .nth(4)
+        .is_some());
+
+    let check = |store: &LsmKvsEngine<RayonThreadPool>| -> Result<()> {
+        assert_eq!(store.scan(Unbounded, Unbounded, None).wait()?, expected);
+        for i in 0..1000 {
+            let key = format!("key{:04}", i);
+            let value = expected
+                .binary_search_by(|(other, _)| other.cmp(&key))
+                .ok()
+                .map(|pos| expected[pos].1.clone());
+            assert_eq!(store.get(key).wait()?, value);
+        }
+        Ok(())
+    };
+    check(&store)?;
+
+    drop(store);
+    let store =
+        LsmKvsEngine::<RayonThreadPool>::open_with_options(temp_dir.path(), 4, &small_options())?;
+    check(&store)?;
+    store.compact().wait()?;
+    check(&store)?;
+
+    Ok(())
+}
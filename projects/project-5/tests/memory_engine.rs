@@ -0,0 +1,166 @@
+use kvs::thread_pool::RayonThreadPool;
+use kvs::{KvsEngine, MemoryKvsEngine, Result};
+use std::ops::Bound::{Included, Unbounded};
+use std::thread;
+use std::time::Duration;
+use tokio::prelude::*;
+
+#[macro_use]
+mod common;
+
+engine_suite!(MemoryKvsEngine<RayonThreadPool>);
+
+// Expired keys should be swept out of the map, releasing their memory
+#[test]
+fn sweep_expired_keys() -> Result<()> {
+    let store = MemoryKvsEngine::<RayonThreadPool>::new(1)?;
+    let ttl = Duration::from_millis(200);
+    store
+        .set_with_ttl("key1".to_owned(), "value1".to_owned(), ttl)
+        .wait()?;
+    store
+        .set_with_ttl("key2".to_owned(), "value2".to_owned(), ttl)
+        .wait()?;
+    store.set("key2".to_owned(), "value3".to_owned()).wait()?;
+    store
+        .set_with_ttl(
+            "key3".to_owned(),
+            "value4".to_owned(),
+            Duration::from_secs(3600),
+        )
+        .wait()?;
+
+    thread::sleep(Duration::from_millis(1800));
+    assert_eq!(
+        store
+            .scan_bytes(Unbounded, Unbounded, None)
+            .wait()?
+            .into_iter()
+            .map(|(key, _)| key)
+            .collect::<Vec<_>>(),
+        vec![b"key2".to_vec(), b"key3".to_vec()]
+    );
+    assert_eq!(store.used_memory(), 20);
+
+    Ok(())
+}
+
+// Should evict the least recently set keys once over the memory limit
+#[test]
+fn evict_least_recently_used() -> Result<()> {
+    // room for ten pairs of 10 bytes
+    let store = MemoryKvsEngine::<RayonThreadPool>::with_max_memory(1, 100)?;
+    for i in 0..15 {
+        store
+            .set(format!("key{:02}", i), format!("val{:02}", i))
+            .wait()?;
+    }
+    assert_eq!(store.used_memory(), 100);
+    for i in 0..5 {
+        assert_eq!(store.get(format!("key{:02}", i)).wait()?, None);
+    }
+    for i in 5..15 {
+        assert_eq!(
+            store.get(format!("key{:02}", i)).wait()?,
+            Some(format!("val{:02}", i))
+        );
+    }
+
+    // Overwriting and removing keys frees their memory
+    store.set("key05".to_owned(), "v".to_owned()).wait()?;
+    store.remove("key06".to_owned()).wait()?;
+    assert_eq!(store.used_memory(), 86);
+
+    // A value larger than the limit is evicted at once
+    store.set("big".to_owned(), "x".repeat(200)).wait()?;
+    assert_eq!(store.get("big".to_owned()).wait()?, None);
+    assert!(store.used_memory() <= 100);
+
+    Ok(())
+}
+
+// Should keep the keys read recently over the ones set earlier
+#[test]
+fn get_refreshes_recency() -> Result<()> {
+    let store = MemoryKvsEngine::<RayonThreadPool>::with_max_memory(1, 100)?;
+    for i in 0..10 {
+        store
+            .set(format!("key{:02}", i), format!("val{:02}", i))
+            .wait()?;
+    }
+    assert_eq!(
+        store.get("key00".to_owned()).wait()?,
+        Some("val00".to_owned())
+    );
+    // Scans don't count as uses
+    assert_eq!(store.scan(Unbounded, Unbounded, None).wait()?.len(), 10);
+
+    for i in 10..12 {
+        store
+            .set(format!("key{:02}", i), format!("val{:02}", i))
+            .wait()?;
+    }
+    assert_eq!(
+        store.get("key00".to_owned()).wait()?,
+        Some("val00".to_owned())
+    );
+    assert_eq!(store.get("key01".to_owned()).wait()?, None);
+    assert_eq!(store.get("key02".to_owned()).wait()?, None);
+    assert_eq!(
+        store.get("key03".to_owned()).wait()?,
+        Some("val03".to_owned())
+    );
+
+    Ok(())
+}
+
+// Without a memory limit, no key is evicted
+#[test]
+fn unlimited_memory() -> Result<()> {
+    let store = MemoryKvsEngine::<RayonThreadPool>::new(1)?;
+    for i in 0..1000 {
+        store
+            .set(format!("key{:03}", i), format!("value{}", i))
+            .wait()?;
+    }
+    assert_eq!(store.keys(String::new()).collect().wait()?.len(), 1000);
+
+    Ok(())
+}
+
+// Reads of a key that is only being overwritten should always find it
+#[test]
+fn reads_during_overwrites() -> Result<()> {
+    let store = MemoryKvsEngine::<RayonThreadPool>::new(4)?;
+    store.set("key".to_owned(), "0".to_owned()).wait()?;
+
+    let writer = {
+        let store = store.clone();
+        thread::spawn(move || {
+            for i in 1..2000 {
+                store.set("key".to_owned(), i.to_string()).wait().unwrap();
+            }
+        })
+    };
+    let readers: Vec<_> = (0..3)
+        .map(|_| {
+            let store = store.clone();
+            thread::spawn(move || {
+                for _ in 0..1000 {
+                    assert!(store.get("key".to_owned()).wait().unwrap().is_some());
+                    let pairs = store
+                        .scan(Included("key".to_owned()), Unbounded, None)
+                        .wait()
+                        .unwrap();
+                    assert_eq!(pairs.len(), 1);
+                }
+            })
+        })
+        .collect();
+    writer.join().unwrap();
+    for reader in readers {
+        reader.join().unwrap();
+    }
+
+    Ok(())
+}
@@ -0,0 +1,286 @@
+use kvs::percolator::{PercolatorClient, PercolatorTransaction, TimestampOracle};
+use kvs::thread_pool::RayonThreadPool;
+use kvs::{KvStore, KvsError, KvsServer, Result};
+use rand::Rng;
+use std::net::SocketAddr;
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+use tokio::prelude::*;
+
+/// A timestamp oracle and shards running in the background.
+struct Cluster {
+    client: PercolatorClient,
+    _dirs: Vec<TempDir>,
+}
+
+/// Starts an oracle on the given port and shards on the ports after it.
+fn start_cluster(port: u16, shards: u16, lock_ttl: Duration) -> Result<Cluster> {
+    let addr = |port: u16| -> SocketAddr { format!("127.0.0.1:{}", port).parse().unwrap() };
+    let mut dirs = Vec::new();
+    for i in 0..=shards {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let mut server = KvsServer::new(KvStore::<RayonThreadPool>::open(temp_dir.path(), 2)?);
+        if i == 0 {
+            server = server.with_oracle(TimestampOracle::open(temp_dir.path().join("oracle"))?);
+        }
+        let addr = addr(port + i);
+        thread::spawn(move || server.run(addr).unwrap());
+        dirs.push(temp_dir);
+    }
+    thread::sleep(Duration::from_secs(1));
+
+    let mut client =
+        PercolatorClient::new(addr(port), (1..=shards).map(|i| addr(port + i)).collect());
+    client.lock_ttl(lock_ttl);
+    Ok(Cluster {
+        client,
+        _dirs: dirs,
+    })
+}
+
+/// Where a client crashes while committing.
+#[derive(Debug, Clone, Copy)]
+enum Crash {
+    Never,
+    AfterPrewrite,
+    AfterPrimary,
+}
+
+/// Commits the transaction, stopping at the crash point as if the client crashed.
+fn commit_with_crash(txn: PercolatorTransaction, crash: Crash) -> Result<()> {
+    let prewritten = txn.prewrite().wait()?;
+    if let Crash::AfterPrewrite = crash {
+        return Ok(());
+    }
+    let committed = prewritten.commit_primary().wait()?;
+    if let Crash::AfterPrimary = crash {
+        return Ok(());
+    }
+    committed.commit_secondaries().wait()
+}
+
+/// Gets the value of a key, waiting for the locks of running transactions.
+fn get_waiting(txn: &PercolatorTransaction, key: &str) -> Result<Option<String>> {
+    loop {
+        match txn.get(key.to_owned()).wait() {
+            Err(KvsError::KeyLocked) => thread::sleep(Duration::from_millis(50)),
+            res => return res,
+        }
+    }
+}
+
+fn set_all(client: &PercolatorClient, pairs: &[(&str, &str)]) -> Result<()> {
+    let txn = client.begin().wait()?;
+    for (key, value) in pairs {
+        txn.set(key.to_string(), value.to_string());
+    }
+    txn.commit().wait()
+}
+
+#[test]
+fn transactions_across_shards() -> Result<()> {
+    let cluster = start_cluster(4020, 3, Duration::from_secs(3))?;
+    let client = &cluster.client;
+
+    let txn = client.begin().wait()?;
+    for i in 0..10 {
+        txn.set(format!("key{}", i), format!("value{}", i));
+    }
+    txn.commit().wait()?;
+    let txn = client.begin().wait()?;
+    for i in 0..10 {
+        assert_eq!(
+            txn.get(format!("key{}", i)).wait()?,
+            Some(format!("value{}", i))
+        );
+    }
+
+    // A transaction reads the snapshot at its start
+    let reader = client.begin().wait()?;
+    let txn = client.begin().wait()?;
+    txn.set("key0".to_owned(), "changed".to_owned());
+    txn.remove("key1".to_owned());
+    txn.commit().wait()?;
+    assert_eq!(
+        reader.get("key0".to_owned()).wait()?,
+        Some("value0".to_owned())
+    );
+    assert_eq!(
+        reader.get("key1".to_owned()).wait()?,
+        Some("value1".to_owned())
+    );
+    let reader = client.begin().wait()?;
+    assert_eq!(
+        reader.get("key0".to_owned()).wait()?,
+        Some("changed".to_owned())
+    );
+    assert_eq!(reader.get("key1".to_owned()).wait()?, None);
+
+    // Concurrent writes to the same key conflict
+    let first = client.begin().wait()?;
+    let second = client.begin().wait()?;
+    first.set("key2".to_owned(), "first".to_owned());
+    second.set("key2".to_owned(), "second".to_owned());
+    second.set("key3".to_owned(), "second".to_owned());
+    first.commit().wait()?;
+    match second.commit().wait() {
+        Err(KvsError::TransactionConflict) => {}
+        res => panic!("unexpected result: {:?}", res),
+    }
+    let reader = client.begin().wait()?;
+    assert_eq!(
+        reader.get("key2".to_owned()).wait()?,
+        Some("first".to_owned())
+    );
+    assert_eq!(
+        reader.get("key3".to_owned()).wait()?,
+        Some("value3".to_owned())
+    );
+
+    // Keys locked by a running transaction can't be read or written
+    let running = client.begin().wait()?;
+    running.set("key4".to_owned(), "running".to_owned());
+    let prewritten = running.prewrite().wait()?;
+    let other = client.begin().wait()?;
+    match other.get("key4".to_owned()).wait() {
+        Err(KvsError::KeyLocked) => {}
+        res => panic!("unexpected result: {:?}", res),
+    }
+    other.set("key4".to_owned(), "other".to_owned());
+    match other.commit().wait() {
+        Err(KvsError::TransactionConflict) => {}
+        res => panic!("unexpected result: {:?}", res),
+    }
+    prewritten
+        .commit_primary()
+        .wait()?
+        .commit_secondaries()
+        .wait()?;
+    let reader = client.begin().wait()?;
+    assert_eq!(
+        reader.get("key4".to_owned()).wait()?,
+        Some("running".to_owned())
+    );
+
+    Ok(())
+}
+
+#[test]
+fn crash_after_prewrite() -> Result<()> {
+    let cluster = start_cluster(4030, 2, Duration::from_millis(300))?;
+    let client = &cluster.client;
+    set_all(client, &[("x", "1"), ("y", "1")])?;
+
+    let txn = client.begin().wait()?;
+    txn.set("x".to_owned(), "2".to_owned());
+    txn.set("y".to_owned(), "2".to_owned());
+    commit_with_crash(txn, Crash::AfterPrewrite)?;
+
+    // The locks are kept until they expire
+    let reader = client.begin().wait()?;
+    match reader.get("y".to_owned()).wait() {
+        Err(KvsError::KeyLocked) => {}
+        res => panic!("unexpected result: {:?}", res),
+    }
+    thread::sleep(Duration::from_millis(400));
+
+    // Then the transaction is rolled back, starting from a secondary
+    assert_eq!(reader.get("y".to_owned()).wait()?, Some("1".to_owned()));
+    assert_eq!(reader.get("x".to_owned()).wait()?, Some("1".to_owned()));
+    set_all(client, &[("x", "3"), ("y", "3")])?;
+    let reader = client.begin().wait()?;
+    assert_eq!(reader.get("x".to_owned()).wait()?, Some("3".to_owned()));
+    assert_eq!(reader.get("y".to_owned()).wait()?, Some("3".to_owned()));
+
+    Ok(())
+}
+
+#[test]
+fn crash_after_primary_commit() -> Result<()> {
+    let cluster = start_cluster(4040, 2, Duration::from_secs(3))?;
+    let client = &cluster.client;
+    set_all(client, &[("x", "1"), ("y", "1")])?;
+
+    let txn = client.begin().wait()?;
+    txn.set("x".to_owned(), "2".to_owned());
+    txn.remove("y".to_owned());
+    commit_with_crash(txn, Crash::AfterPrimary)?;
+
+    // The secondary is rolled forward without waiting for its lock to expire
+    let reader = client.begin().wait()?;
+    assert_eq!(reader.get("y".to_owned()).wait()?, None);
+    assert_eq!(reader.get("x".to_owned()).wait()?, Some("2".to_owned()));
+    set_all(client, &[("y", "3")])?;
+    let reader = client.begin().wait()?;
+    assert_eq!(reader.get("y".to_owned()).wait()?, Some("3".to_owned()));
+
+    Ok(())
+}
+
+#[test]
+fn concurrent_transfers_with_crashes() -> Result<()> {
+    let cluster = start_cluster(4050, 3, Duration::from_millis(200))?;
+    let accounts: Vec<_> = (0..6).map(|i| format!("account{}", i)).collect();
+    let initial: Vec<_> = accounts
+        .iter()
+        .map(|account| (account.as_str(), "100"))
+        .collect();
+    set_all(&cluster.client, &initial)?;
+
+    let handles: Vec<_> = (0..4)
+        .map(|_| {
+            let client = cluster.client.clone();
+            let accounts = accounts.clone();
+            thread::spawn(move || -> Result<()> {
+                let mut rng = rand::thread_rng();
+                for _ in 0..15 {
+                    let from = &accounts[rng.gen_range(0, accounts.len())];
+                    let to = &accounts[rng.gen_range(0, accounts.len())];
+                    if from == to {
+                        continue;
+                    }
+                    let crash = match rng.gen_range(0, 4) {
+                        0 => Crash::AfterPrewrite,
+                        1 => Crash::AfterPrimary,
+                        _ => Crash::Never,
+                    };
+                    loop {
+                        let txn = client.begin().wait()?;
+                        let balance = |key: &String| -> Result<u32> {
+                            Ok(get_waiting(&txn, key)?.unwrap().parse().unwrap())
+                        };
+                        let (from_balance, to_balance) = (balance(from)?, balance(to)?);
+                        txn.set(from.clone(), (from_balance - 1).to_string());
+                        txn.set(to.clone(), (to_balance + 1).to_string());
+                        match commit_with_crash(txn, crash) {
+                            Ok(()) => break,
+                            Err(KvsError::TransactionConflict) => {
+                                thread::sleep(Duration::from_millis(20))
+                            }
+                            Err(e) => return Err(e),
+                        }
+                    }
+                }
+                Ok(())
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap()?;
+    }
+
+    // Transactions crashed after prewrite are rolled back and those crashed after
+    // committing the primary are rolled forward, so no money is lost
+    let reader = cluster.client.begin().wait()?;
+    let mut total = 0;
+    for account in &accounts {
+        total += get_waiting(&reader, account)?
+            .unwrap()
+            .parse::<u32>()
+            .unwrap();
+    }
+    assert_eq!(total, 600);
+
+    Ok(())
+}
@@ -0,0 +1,457 @@
+use kvs::raft::{Message, NodeId, RaftNode, RaftOptions, Transport};
+use kvs::thread_pool::RayonThreadPool;
+use kvs::{KvStore, KvsEngine, KvsError, Result};
+use rand::Rng;
+use std::collections::HashMap;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::sync::mpsc::{self, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+use tempfile::TempDir;
+use tokio::prelude::*;
+
+type Engine = KvStore<RayonThreadPool>;
+
+/// Default maximum length of the frames of the connections between servers.
+const MAX_FRAME_LEN: usize = 8 * 1024 * 1024;
+
+/// A network between the nodes of a cluster in the same process, delivering the
+/// messages in a background thread.
+///
+/// Like the connections between servers, it can't carry messages longer than a
+/// frame once encoded.
+#[derive(Clone)]
+struct Network {
+    state: Arc<Mutex<NetworkState>>,
+    sender: Sender<(NodeId, NodeId, Message)>,
+}
+
+#[derive(Default)]
+struct NetworkState {
+    nodes: HashMap<NodeId, RaftNode<Engine>>,
+    // chance that a message is lost
+    drop_rate: f64,
+    // the group of each node, nodes only reach the nodes of their group
+    groups: HashMap<NodeId, usize>,
+}
+
+impl Network {
+    fn new() -> Network {
+        let state: Arc<Mutex<NetworkState>> = Arc::default();
+        let (sender, receiver) = mpsc::channel::<(NodeId, NodeId, Message)>();
+        let delivered = Arc::clone(&state);
+        thread::spawn(move || {
+            for (from, to, msg) in receiver {
+                let node = {
+                    let state = delivered.lock().unwrap();
+                    let group = |id| state.groups.get(&id).cloned().unwrap_or_default();
+                    if group(from) != group(to) || rand::thread_rng().gen_bool(state.drop_rate) {
+                        continue;
+                    }
+                    match state.nodes.get(&to) {
+                        Some(node) => node.clone(),
+                        None => continue,
+                    }
+                };
+                if !fits_in_frame(&msg) {
+                    continue;
+                }
+                node.step(from, msg);
+            }
+        });
+        Network { state, sender }
+    }
+
+    fn transport(&self, id: NodeId) -> MemoryTransport {
+        MemoryTransport {
+            id,
+            sender: self.sender.clone(),
+        }
+    }
+
+    /// Splits the network so that messages are only delivered within each group.
+    fn partition(&self, groups: &[&[NodeId]]) {
+        let mut state = self.state.lock().unwrap();
+        state.groups = groups
+            .iter()
+            .enumerate()
+            .flat_map(|(group, ids)| ids.iter().map(move |&id| (id, group)))
+            .collect();
+    }
+
+    fn heal(&self) {
+        self.state.lock().unwrap().groups.clear();
+    }
+
+    fn set_drop_rate(&self, drop_rate: f64) {
+        self.state.lock().unwrap().drop_rate = drop_rate;
+    }
+}
+
+/// Whether the message fits in a frame once encoded as JSON.
+fn fits_in_frame(msg: &Message) -> bool {
+    // JSON takes a few bytes for each byte of the bincode encoding, which is much
+    // faster to measure
+    bincode::serialized_size(msg).unwrap() as usize <= MAX_FRAME_LEN / 8
+        || serde_json::to_vec(msg).unwrap().len() <= MAX_FRAME_LEN
+}
+
+struct MemoryTransport {
+    id: NodeId,
+    sender: Sender<(NodeId, NodeId, Message)>,
+}
+
+impl Transport for MemoryTransport {
+    fn send(&self, to: NodeId, msg: Message) {
+        let _ = self.sender.send((self.id, to, msg));
+    }
+}
+
+/// A cluster of nodes storing their engines and states in temporary directories.
+struct Cluster {
+    network: Network,
+    members: Vec<NodeId>,
+    options: RaftOptions,
+    dirs: HashMap<NodeId, TempDir>,
+    engines: HashMap<NodeId, Engine>,
+}
+
+impl Cluster {
+    fn start(size: u64, options: RaftOptions) -> Result<Cluster> {
+        let mut cluster = Cluster {
+            network: Network::new(),
+            members: (1..=size).collect(),
+            options,
+            dirs: HashMap::new(),
+            engines: HashMap::new(),
+        };
+        for id in 1..=size {
+            let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+            cluster.dirs.insert(id, temp_dir);
+            cluster.start_node(id)?;
+        }
+        Ok(cluster)
+    }
+
+    fn start_node(&mut self, id: NodeId) -> Result<()> {
+        let path = self.dirs[&id].path();
+        let engine = Engine::open(path, 1)?;
+        let node = RaftNode::open(
+            id,
+            &self.members,
+            path.join("raft"),
+            engine.clone(),
+            self.network.transport(id),
+            &self.options,
+        )?;
+        self.network.state.lock().unwrap().nodes.insert(id, node);
+        self.engines.insert(id, engine);
+        Ok(())
+    }
+
+    /// Stops a node as if it crashed.
+    fn crash(&mut self, id: NodeId) {
+        self.network.state.lock().unwrap().nodes.remove(&id);
+        self.engines.remove(&id);
+        // lets a message being delivered to the node release it
+        thread::sleep(Duration::from_millis(50));
+    }
+
+    /// Stops a node and starts it again, with all its data lost if `wipe`.
+    fn restart(&mut self, id: NodeId, wipe: bool) -> Result<()> {
+        self.crash(id);
+        if wipe {
+            let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+            self.dirs.insert(id, temp_dir);
+        }
+        self.start_node(id)
+    }
+
+    fn node(&self, id: NodeId) -> RaftNode<Engine> {
+        self.network.state.lock().unwrap().nodes[&id].clone()
+    }
+
+    /// Waits for a node of the given ones to be elected leader, and returns it.
+    fn leader_of(&self, ids: &[NodeId]) -> NodeId {
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while Instant::now() < deadline {
+            for &id in ids {
+                if self.node(id).leader() == Some(id) {
+                    return id;
+                }
+            }
+            thread::sleep(Duration::from_millis(20));
+        }
+        panic!("no leader elected among {:?}", ids);
+    }
+
+    fn leader(&self) -> NodeId {
+        let ids: Vec<NodeId> = self.engines.keys().cloned().collect();
+        self.leader_of(&ids)
+    }
+
+    /// Sets a key through the leader, retrying while leaders change.
+    fn set(&self, key: &str, value: &str) -> Result<()> {
+        let deadline = Instant::now() + Duration::from_secs(10);
+        loop {
+            let node = self.node(self.leader());
+            match wait_timeout(node.set(key.to_owned(), value.to_owned())) {
+                Some(Err(KvsError::NotLeader)) | None if Instant::now() < deadline => {}
+                Some(res) => return res,
+                None => panic!("setting {} timed out", key),
+            }
+        }
+    }
+
+    /// Waits for the engine of a node to have the given value for a key.
+    fn wait_for(&self, id: NodeId, key: &str, value: Option<&str>) -> Result<()> {
+        let deadline = Instant::now() + Duration::from_secs(10);
+        let value = value.map(str::to_owned);
+        loop {
+            let current = self.engines[&id].get(key.to_owned()).wait()?;
+            if current == value {
+                return Ok(());
+            }
+            if Instant::now() > deadline {
+                panic!("node {} has {:?} for {}, not {:?}", id, current, key, value);
+            }
+            thread::sleep(Duration::from_millis(20));
+        }
+    }
+}
+
+impl Drop for Cluster {
+    fn drop(&mut self) {
+        // stops the nodes before their directories are removed
+        self.network.state.lock().unwrap().nodes.clear();
+    }
+}
+
+/// Waits for a future for a second in another thread.
+fn wait_timeout<T: Send + 'static>(
+    future: impl Future<Item = T, Error = KvsError> + Send + 'static,
+) -> Option<Result<T>> {
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        let _ = sender.send(future.wait());
+    });
+    receiver.recv_timeout(Duration::from_secs(1)).ok()
+}
+
+fn test_options() -> RaftOptions {
+    let mut options = RaftOptions::new();
+    options
+        .tick(Duration::from_millis(10))
+        .election_ticks(10)
+        .heartbeat_ticks(2);
+    options
+}
+
+#[test]
+fn replication() -> Result<()> {
+    let cluster = Cluster::start(3, test_options())?;
+    let leader = cluster.leader();
+
+    let node = cluster.node(leader);
+    node.set("key1".to_owned(), "value1".to_owned()).wait()?;
+    node.set("key2".to_owned(), "value2".to_owned()).wait()?;
+    node.remove("key2".to_owned()).wait()?;
+    match node.remove("key2".to_owned()).wait() {
+        Err(KvsError::KeyNotFound) => {}
+        res => panic!("unexpected result: {:?}", res),
+    }
+    assert_eq!(
+        node.get("key1".to_owned()).wait()?,
+        Some("value1".to_owned())
+    );
+    for id in 1..=3 {
+        cluster.wait_for(id, "key1", Some("value1"))?;
+        cluster.wait_for(id, "key2", None)?;
+    }
+
+    // followers don't serve reads and writes
+    let follower = cluster.node(leader % 3 + 1);
+    assert_eq!(follower.leader(), Some(leader));
+    match follower.set("key1".to_owned(), "value2".to_owned()).wait() {
+        Err(KvsError::NotLeader) => {}
+        res => panic!("unexpected result: {:?}", res),
+    }
+    match follower.get("key1".to_owned()).wait() {
+        Err(KvsError::NotLeader) => {}
+        res => panic!("unexpected result: {:?}", res),
+    }
+
+    Ok(())
+}
+
+#[test]
+fn leader_failover() -> Result<()> {
+    let mut cluster = Cluster::start(5, test_options())?;
+    cluster.set("key1", "value1")?;
+    let old_leader = cluster.leader();
+    let old_term = cluster.node(old_leader).term();
+
+    cluster.crash(old_leader);
+    let leader = cluster.leader();
+    assert_ne!(leader, old_leader);
+    assert!(cluster.node(leader).term() > old_term);
+    assert_eq!(
+        cluster.node(leader).get("key1".to_owned()).wait()?,
+        Some("value1".to_owned())
+    );
+    cluster.set("key2", "value2")?;
+
+    // a second crash still leaves a majority
+    cluster.crash(leader);
+    cluster.set("key3", "value3")?;
+
+    // the old leader catches up when it comes back
+    cluster.restart(old_leader, false)?;
+    cluster.wait_for(old_leader, "key1", Some("value1"))?;
+    cluster.wait_for(old_leader, "key3", Some("value3"))?;
+
+    Ok(())
+}
+
+#[test]
+fn network_partition() -> Result<()> {
+    let cluster = Cluster::start(5, test_options())?;
+    cluster.set("key", "before")?;
+    let old_leader = cluster.leader();
+    let minority = [old_leader, old_leader % 5 + 1];
+    let majority: Vec<NodeId> = (1..=5).filter(|id| !minority.contains(id)).collect();
+    cluster.network.partition(&[&minority, &majority]);
+
+    // the old leader can't commit without a majority
+    let stale_write = cluster
+        .node(old_leader)
+        .set("key".to_owned(), "minority".to_owned());
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        let _ = sender.send(stale_write.wait());
+    });
+    thread::sleep(Duration::from_millis(500));
+    assert!(receiver.try_recv().is_err());
+
+    // the majority elects a new leader and goes on
+    let leader = cluster.leader_of(&majority);
+    cluster
+        .node(leader)
+        .set("key".to_owned(), "majority".to_owned())
+        .wait()?;
+
+    // the old leader steps down and drops its uncommitted write when healed
+    cluster.network.heal();
+    match receiver.recv_timeout(Duration::from_secs(10)) {
+        Ok(Err(KvsError::NotLeader)) => {}
+        res => panic!("unexpected result: {:?}", res),
+    }
+    for id in 1..=5 {
+        cluster.wait_for(id, "key", Some("majority"))?;
+    }
+
+    Ok(())
+}
+
+#[test]
+fn message_drops() -> Result<()> {
+    let cluster = Cluster::start(3, test_options())?;
+    cluster.network.set_drop_rate(0.2);
+    for i in 0..30 {
+        cluster.set(&format!("key{}", i), &format!("value{}", i))?;
+    }
+    cluster.network.set_drop_rate(0.0);
+    for id in 1..=3 {
+        for i in 0..30 {
+            cluster.wait_for(id, &format!("key{}", i), Some(&format!("value{}", i)))?;
+        }
+    }
+
+    Ok(())
+}
+
+#[test]
+fn damaged_log() -> Result<()> {
+    let mut cluster = Cluster::start(3, test_options())?;
+    let follower = cluster.leader() % 3 + 1;
+    cluster.set("key1", "value1")?;
+    cluster.set("key2", "value2")?;
+    cluster.wait_for(follower, "key2", Some("value2"))?;
+    let log_path = cluster.dirs[&follower].path().join("raft").join("log");
+
+    // an entry torn by a crash is dropped
+    cluster.crash(follower);
+    let mut file = OpenOptions::new().append(true).open(&log_path)?;
+    file.write_all(&[100, 0, 0, 0, 1, 2, 3, 4, 5, 6])?;
+    cluster.restart(follower, false)?;
+    cluster.set("key3", "value3")?;
+    cluster.wait_for(follower, "key3", Some("value3"))?;
+
+    // a damaged entry followed by others is not mistaken for a torn one
+    cluster.crash(follower);
+    let mut bytes = fs::read(&log_path)?;
+    bytes[10] ^= 0xff;
+    fs::write(&log_path, bytes)?;
+    assert!(cluster.restart(follower, false).is_err());
+
+    Ok(())
+}
+
+#[test]
+fn snapshots() -> Result<()> {
+    let mut options = test_options();
+    options.snapshot_threshold(10);
+    let mut cluster = Cluster::start(3, options)?;
+    let leader = cluster.leader();
+    let follower = leader % 3 + 1;
+
+    // the follower misses entries the others drop from their logs
+    cluster.crash(follower);
+    for i in 0..50 {
+        cluster.set(&format!("key{}", i), &format!("value{}", i))?;
+    }
+    cluster.set("key0", "changed")?;
+    cluster.restart(follower, true)?;
+    for i in 1..50 {
+        cluster.wait_for(follower, &format!("key{}", i), Some(&format!("value{}", i)))?;
+    }
+    cluster.wait_for(follower, "key0", Some("changed"))?;
+
+    // nodes restart from their own snapshots
+    for id in 1..=3 {
+        cluster.restart(id, false)?;
+    }
+    cluster.set("key1", "restarted")?;
+    for id in 1..=3 {
+        cluster.wait_for(id, "key0", Some("changed"))?;
+        cluster.wait_for(id, "key1", Some("restarted"))?;
+        cluster.wait_for(id, "key49", Some("value49"))?;
+    }
+
+    Ok(())
+}
+
+#[test]
+fn large_snapshot() -> Result<()> {
+    let mut options = test_options();
+    // loading and installing the snapshot takes a while in debug builds
+    options.snapshot_threshold(10).election_ticks(100);
+    let mut cluster = Cluster::start(3, options)?;
+    let leader = cluster.leader();
+    let follower = leader % 3 + 1;
+
+    // the snapshot sent to the follower takes more than a frame
+    let value = "v".repeat(64 * 1024);
+    cluster.crash(follower);
+    for i in 0..48 {
+        cluster.set(&format!("key{}", i), &value)?;
+    }
+    cluster.restart(follower, true)?;
+    for i in &[0, 24, 47] {
+        cluster.wait_for(follower, &format!("key{}", i), Some(&value))?;
+    }
+
+    Ok(())
+}
@@ -0,0 +1,170 @@
+use kvs::replication::Replica;
+use kvs::thread_pool::RayonThreadPool;
+use kvs::{KvStore, KvStoreOptions, KvsClient, KvsEngine, KvsServer, Result, WriteBatch};
+use std::net::SocketAddr;
+use std::thread;
+use std::time::{Duration, Instant};
+use tempfile::TempDir;
+use tokio::prelude::*;
+
+type Store = KvStore<RayonThreadPool>;
+
+/// Opens a store with small logs, so that writes are spread over several logs.
+fn open_store(temp_dir: &TempDir) -> Result<Store> {
+    KvStore::open_with_options(
+        temp_dir.path(),
+        1,
+        KvStoreOptions::new()
+            .max_log_size(1024)
+            .auto_compaction(false),
+    )
+}
+
+/// Runs a server of the store in the background and waits for it to listen.
+fn start_primary(store: Store, addr: SocketAddr) {
+    thread::spawn(move || {
+        KvsServer::new(store)
+            .with_replication_source()
+            .run(addr)
+            .unwrap()
+    });
+    thread::sleep(Duration::from_secs(1));
+}
+
+/// Waits until the key has the expected value in the store.
+fn wait_for(store: &Store, key: &str, expected: Option<&str>) -> Result<()> {
+    let deadline = Instant::now() + Duration::from_secs(10);
+    loop {
+        let value = store.get(key.to_owned()).wait()?;
+        if value.as_deref() == expected {
+            return Ok(());
+        }
+        assert!(
+            Instant::now() < deadline,
+            "{} is {:?} instead of {:?}",
+            key,
+            value,
+            expected
+        );
+        thread::sleep(Duration::from_millis(50));
+    }
+}
+
+#[test]
+fn replica_follows_primary() -> Result<()> {
+    let primary_dir = TempDir::new().expect("unable to create temporary working directory");
+    let replica_dir = TempDir::new().expect("unable to create temporary working directory");
+    let primary_addr = "127.0.0.1:4070".parse().unwrap();
+    let replica_addr = "127.0.0.1:4071".parse().unwrap();
+    let primary = open_store(&primary_dir)?;
+    primary.set("key0".to_owned(), "value0".to_owned()).wait()?;
+    start_primary(primary.clone(), primary_addr);
+
+    let replica = open_store(&replica_dir)?;
+    let follower = Replica::start(
+        replica.clone(),
+        primary_addr,
+        replica_dir.path().join("replica"),
+    )?;
+    let server = KvsServer::new(replica.clone()).with_replica(follower);
+    thread::spawn(move || server.run(replica_addr).unwrap());
+    wait_for(&replica, "key0", Some("value0"))?;
+
+    // the writes span several logs
+    for i in 1..100 {
+        primary
+            .set(format!("key{}", i), format!("value{}", i))
+            .wait()?;
+    }
+    primary.remove("key0".to_owned()).wait()?;
+    let mut batch = WriteBatch::new();
+    batch.set("key1", "batch").remove("key2");
+    primary.write_batch(batch).wait()?;
+    primary
+        .set_with_ttl(
+            "temp".to_owned(),
+            "value".to_owned(),
+            Duration::from_secs(60),
+        )
+        .wait()?;
+
+    wait_for(&replica, "temp", Some("value"))?;
+    wait_for(&replica, "key0", None)?;
+    wait_for(&replica, "key1", Some("batch"))?;
+    wait_for(&replica, "key2", None)?;
+    wait_for(&replica, "key99", Some("value99"))?;
+
+    // the replica serves reads and rejects writes
+    let client = KvsClient::connect(replica_addr).wait()?;
+    let (value, client) = client.get("key3".to_owned()).wait()?;
+    assert_eq!(value, Some("value3".to_owned()));
+    assert!(client
+        .set("key3".to_owned(), "changed".to_owned())
+        .wait()
+        .is_err());
+    wait_for(&replica, "key3", Some("value3"))?;
+
+    Ok(())
+}
+
+#[test]
+fn replica_resumes_from_its_position() -> Result<()> {
+    let primary_dir = TempDir::new().expect("unable to create temporary working directory");
+    let replica_dir = TempDir::new().expect("unable to create temporary working directory");
+    let primary_addr = "127.0.0.1:4072".parse().unwrap();
+    let primary = open_store(&primary_dir)?;
+    start_primary(primary.clone(), primary_addr);
+
+    let replica = open_store(&replica_dir)?;
+    let state_path = replica_dir.path().join("replica");
+    let follower = Replica::start(replica.clone(), primary_addr, &state_path)?;
+    assert_eq!(follower.position(), None);
+    primary.set("key1".to_owned(), "value1".to_owned()).wait()?;
+    wait_for(&replica, "key1", Some("value1"))?;
+    drop(follower);
+
+    // writes while the replica is stopped are applied after it resumes
+    primary.set("key2".to_owned(), "value2".to_owned()).wait()?;
+    primary.remove("key1".to_owned()).wait()?;
+    let follower = Replica::start(replica.clone(), primary_addr, &state_path)?;
+    assert!(follower.position().is_some());
+    wait_for(&replica, "key1", None)?;
+    wait_for(&replica, "key2", Some("value2"))?;
+
+    Ok(())
+}
+
+#[test]
+fn replica_copies_compacted_store() -> Result<()> {
+    let primary_dir = TempDir::new().expect("unable to create temporary working directory");
+    let replica_dir = TempDir::new().expect("unable to create temporary working directory");
+    let primary_addr = "127.0.0.1:4073".parse().unwrap();
+    let primary = open_store(&primary_dir)?;
+    for i in 0..1000 {
+        primary
+            .set(format!("key{}", i), format!("value{}", i))
+            .wait()?;
+    }
+    primary.compact().wait()?;
+    start_primary(primary.clone(), primary_addr);
+
+    let replica = open_store(&replica_dir)?;
+    let state_path = replica_dir.path().join("replica");
+    let follower = Replica::start(replica.clone(), primary_addr, &state_path)?;
+    wait_for(&replica, "key999", Some("value999"))?;
+    drop(follower);
+
+    // the log the replica stopped at is compacted away while it is stopped
+    primary.remove("key0".to_owned()).wait()?;
+    primary.set("key1".to_owned(), "new".to_owned()).wait()?;
+    primary.compact().wait()?;
+    let _follower = Replica::start(replica.clone(), primary_addr, &state_path)?;
+    wait_for(&replica, "key1", Some("new"))?;
+    wait_for(&replica, "key0", None)?;
+    for i in 2..1000 {
+        let value = replica.get(format!("key{}", i)).wait()?;
+        assert_eq!(value, Some(format!("value{}", i)));
+    }
+
+    Ok(())
+}
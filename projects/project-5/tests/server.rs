@@ -0,0 +1,135 @@
+use kvs::raft::{NodeId, RaftNode, RaftOptions, TcpTransport};
+use kvs::thread_pool::RayonThreadPool;
+use kvs::{KvStore, KvsClient, KvsEngine, KvsError, KvsServer, Result, SledKvsEngine};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::ops::Bound::Unbounded;
+use std::thread;
+use std::time::{Duration, Instant};
+use tempfile::TempDir;
+use tokio::prelude::*;
+
+/// Runs a server with the engine in the background and waits for it to listen.
+fn start_server<E: KvsEngine>(engine: E, addr: SocketAddr) {
+    thread::spawn(move || KvsServer::new(engine).run(addr).unwrap());
+    thread::sleep(Duration::from_secs(1));
+}
+
+fn transactions_over_connection(addr: SocketAddr) -> Result<()> {
+    let client = KvsClient::connect(addr).wait()?;
+    let other = KvsClient::connect(addr).wait()?;
+
+    let client = client.set("key1".to_owned(), "value1".to_owned()).wait()?;
+    let client = client.begin().wait()?;
+    let (value, client) = client.get("key1".to_owned()).wait()?;
+    assert_eq!(value, Some("value1".to_owned()));
+    let client = client.set("key2".to_owned(), "value2".to_owned()).wait()?;
+    let client = client.remove("key1".to_owned()).wait()?;
+    let (value, client) = client.get("key2".to_owned()).wait()?;
+    assert_eq!(value, Some("value2".to_owned()));
+    // The writes of the transaction are not seen by other connections
+    let (value, other) = other.get("key2".to_owned()).wait()?;
+    assert_eq!(value, None);
+    let client = client.commit().wait()?;
+    let (value, other) = other.get("key2".to_owned()).wait()?;
+    assert_eq!(value, Some("value2".to_owned()));
+    let (value, other) = other.get("key1".to_owned()).wait()?;
+    assert_eq!(value, None);
+
+    let client = client.begin().wait()?;
+    let client = client.set("key3".to_owned(), "value3".to_owned()).wait()?;
+    let client = client.rollback().wait()?;
+    let (value, client) = client.get("key3".to_owned()).wait()?;
+    assert_eq!(value, None);
+
+    // A commit fails when a key read has been changed by another connection
+    let client = client.begin().wait()?;
+    let (value, client) = client.get("key2".to_owned()).wait()?;
+    assert_eq!(value, Some("value2".to_owned()));
+    let client = client.set("key3".to_owned(), "value3".to_owned()).wait()?;
+    let other = other.set("key2".to_owned(), "changed".to_owned()).wait()?;
+    match client.commit().wait() {
+        Err(KvsError::TransactionConflict) => {}
+        res => panic!("unexpected result: {:?}", res.map(|_| ())),
+    }
+    let (value, other) = other.get("key3".to_owned()).wait()?;
+    assert_eq!(value, None);
+
+    // Transaction requests out of place fail
+    assert!(other.commit().wait().is_err());
+    let client = KvsClient::connect(addr).wait()?;
+    let client = client.begin().wait()?;
+    assert!(client.begin().wait().is_err());
+
+    Ok(())
+}
+
+#[test]
+fn transactions_kvs_engine() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = "127.0.0.1:4010".parse().unwrap();
+    start_server(KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?, addr);
+    transactions_over_connection(addr)
+}
+
+#[test]
+fn transactions_sled_engine() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = "127.0.0.1:4011".parse().unwrap();
+    let db = sled::Db::start_default(temp_dir.path())?;
+    start_server(SledKvsEngine::<RayonThreadPool>::new(db, 1)?, addr);
+    transactions_over_connection(addr)
+}
+
+#[test]
+fn raft_cluster() -> Result<()> {
+    let addrs: HashMap<NodeId, SocketAddr> = (1..=3)
+        .map(|id| (id, format!("127.0.0.1:{}", 4060 + id).parse().unwrap()))
+        .collect();
+    let members: Vec<NodeId> = addrs.keys().cloned().collect();
+    let mut dirs = Vec::new();
+    for (&id, &addr) in &addrs {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let engine = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+        let node = RaftNode::open(
+            id,
+            &members,
+            temp_dir.path().join("raft"),
+            engine.clone(),
+            TcpTransport::new(id, &addrs)?,
+            &RaftOptions::new(),
+        )?;
+        let server = KvsServer::new(engine).with_raft(node, addrs.clone());
+        thread::spawn(move || server.run(addr).unwrap());
+        dirs.push(temp_dir);
+    }
+    thread::sleep(Duration::from_secs(1));
+
+    // wait for a leader, the write is redirected to it by any node
+    let deadline = Instant::now() + Duration::from_secs(10);
+    loop {
+        let client = KvsClient::connect(addrs[&3]).wait()?;
+        match client.set("key1".to_owned(), "value1".to_owned()).wait() {
+            Err(KvsError::NotLeader) if Instant::now() < deadline => {
+                thread::sleep(Duration::from_millis(100))
+            }
+            res => {
+                res?;
+                break;
+            }
+        }
+    }
+    for addr in addrs.values() {
+        let client = KvsClient::connect(*addr).wait()?;
+        let (value, client) = client.get("key1".to_owned()).wait()?;
+        assert_eq!(value, Some("value1".to_owned()));
+        let client = client.set("key2".to_owned(), addr.to_string()).wait()?;
+        let (value, client) = client.get("key2".to_owned()).wait()?;
+        assert_eq!(value, Some(addr.to_string()));
+        let client = client.remove("key2".to_owned()).wait()?;
+        // other requests are not supported
+        assert!(client.scan(Unbounded, Unbounded, None).wait().is_err());
+    }
+
+    Ok(())
+}
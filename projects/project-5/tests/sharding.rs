@@ -0,0 +1,196 @@
+use kvs::sharding::{self, HashRing, ShardedKvsClient};
+use kvs::thread_pool::RayonThreadPool;
+use kvs::{KvStore, KvsEngine, KvsServer, Result};
+use std::net::SocketAddr;
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+use tokio::prelude::*;
+
+type Store = KvStore<RayonThreadPool>;
+
+fn addrs(ports: &[u16]) -> Vec<SocketAddr> {
+    ports
+        .iter()
+        .map(|port| format!("127.0.0.1:{}", port).parse().unwrap())
+        .collect()
+}
+
+/// Returns the keys of the store.
+fn keys(store: &Store) -> Result<Vec<String>> {
+    let keys = store.keys_bytes(Vec::new()).collect().wait()?;
+    Ok(keys
+        .into_iter()
+        .map(|key| String::from_utf8(key).unwrap())
+        .collect())
+}
+
+#[test]
+fn ring_placement_is_order_independent() {
+    let nodes = addrs(&[5001, 5002, 5003]);
+    let ring = HashRing::new(&nodes);
+    let reversed: Vec<_> = nodes.iter().rev().cloned().collect();
+    let other = HashRing::new(&reversed);
+    for i in 0..1000 {
+        let key = format!("key{}", i);
+        assert_eq!(ring.node(key.as_bytes()), other.node(key.as_bytes()));
+    }
+    assert_eq!(HashRing::new(&[]).node(b"key"), None);
+}
+
+#[test]
+fn adding_node_moves_its_share_of_keys() {
+    let nodes = addrs(&[5001, 5002, 5003, 5004]);
+    let old = HashRing::new(&nodes[..3]);
+    let mut new = old.clone();
+    new.add(nodes[3]);
+
+    let mut moved = 0;
+    for i in 0..10000 {
+        let key = format!("key{}", i);
+        let before = old.node(key.as_bytes()).unwrap();
+        let after = new.node(key.as_bytes()).unwrap();
+        if before != after {
+            // keys only move to the new node
+            assert_eq!(after, nodes[3]);
+            moved += 1;
+        }
+    }
+    let share = f64::from(moved) / 10000.0;
+    assert!(share > 0.15 && share < 0.35, "{} of the keys moved", share);
+}
+
+#[test]
+fn removing_node_moves_only_its_keys() {
+    let nodes = addrs(&[5001, 5002, 5003, 5004]);
+    let old = HashRing::new(&nodes);
+    let mut new = old.clone();
+    new.remove(nodes[1]);
+    assert_eq!(new.nodes().count(), 3);
+
+    for i in 0..10000 {
+        let key = format!("key{}", i);
+        let before = old.node(key.as_bytes()).unwrap();
+        let after = new.node(key.as_bytes()).unwrap();
+        if before != nodes[1] {
+            assert_eq!(before, after);
+        } else {
+            assert_ne!(after, nodes[1]);
+        }
+    }
+}
+
+#[test]
+fn rebalance_moves_keys_to_new_server() -> Result<()> {
+    let nodes = addrs(&[4080, 4081, 4082]);
+    let dirs: Vec<_> = (0..3)
+        .map(|_| TempDir::new().expect("unable to create temporary working directory"))
+        .collect();
+    let mut stores = Vec::new();
+    for (dir, &addr) in dirs.iter().zip(&nodes) {
+        let store = KvStore::<RayonThreadPool>::open(dir.path(), 1)?;
+        stores.push(store.clone());
+        thread::spawn(move || {
+            KvsServer::new(store)
+                .with_replication_source()
+                .run(addr)
+                .unwrap()
+        });
+    }
+    thread::sleep(Duration::from_secs(1));
+
+    let old = HashRing::new(&nodes[..2]);
+    let mut client = ShardedKvsClient::connect(old.clone()).wait()?;
+    for i in 0..200 {
+        client = client
+            .set(format!("key{}", i), format!("value{}", i))
+            .wait()?;
+    }
+    client = client
+        .set_with_ttl(
+            "temp".to_owned(),
+            "value".to_owned(),
+            Duration::from_secs(60),
+        )
+        .wait()?;
+    let (value, _) = client.get("key7".to_owned()).wait()?;
+    assert_eq!(value, Some("value7".to_owned()));
+    assert!(keys(&stores[2])?.is_empty());
+
+    let new = HashRing::new(&nodes);
+    let moved = sharding::rebalance(&old, new.clone()).wait()?;
+    let on_new = keys(&stores[2])?;
+    assert!(!on_new.is_empty());
+    assert_eq!(moved, on_new.len());
+
+    // each server only holds the keys the new ring places on it
+    for (store, &addr) in stores.iter().zip(&nodes) {
+        for key in keys(store)? {
+            assert_eq!(new.node(key.as_bytes()), Some(addr));
+        }
+    }
+
+    let mut client = ShardedKvsClient::connect(new).wait()?;
+    for i in 0..200 {
+        let (value, next) = client.get(format!("key{}", i)).wait()?;
+        assert_eq!(value, Some(format!("value{}", i)));
+        client = next;
+    }
+    let (value, _) = client.get("temp".to_owned()).wait()?;
+    assert_eq!(value, Some("value".to_owned()));
+
+    Ok(())
+}
+
+// A key written through the new ring before the rebalance reaches it should keep
+// its newer value, and be removed from its old server all the same
+#[test]
+fn rebalance_keeps_newer_writes() -> Result<()> {
+    let nodes = addrs(&[4083, 4084]);
+    let dirs: Vec<_> = (0..2)
+        .map(|_| TempDir::new().expect("unable to create temporary working directory"))
+        .collect();
+    let mut stores = Vec::new();
+    for (dir, &addr) in dirs.iter().zip(&nodes) {
+        let store = KvStore::<RayonThreadPool>::open(dir.path(), 1)?;
+        stores.push(store.clone());
+        thread::spawn(move || {
+            KvsServer::new(store)
+                .with_replication_source()
+                .run(addr)
+                .unwrap()
+        });
+    }
+    thread::sleep(Duration::from_secs(1));
+
+    let old = HashRing::new(&nodes[..1]);
+    let new = HashRing::new(&nodes);
+    let moving: Vec<_> = (0..100)
+        .map(|i| format!("key{}", i))
+        .filter(|key| new.node(key.as_bytes()) == Some(nodes[1]))
+        .collect();
+    assert!(moving.len() >= 2);
+    let mut client = ShardedKvsClient::connect(old.clone()).wait()?;
+    for key in &moving {
+        client = client.set(key.clone(), "old".to_owned()).wait()?;
+    }
+
+    // Clients switch to the new ring, and write keys waiting to be moved
+    let mut client = ShardedKvsClient::connect(new.clone()).wait()?;
+    client = client.set(moving[0].clone(), "new".to_owned()).wait()?;
+    client = client
+        .set_with_ttl(moving[1].clone(), "new".to_owned(), Duration::from_secs(60))
+        .wait()?;
+
+    let moved = sharding::rebalance(&old, new).wait()?;
+    assert_eq!(moved, moving.len() - 2);
+    for (i, key) in moving.iter().enumerate() {
+        let (value, next) = client.get(key.clone()).wait()?;
+        let expected = if i < 2 { "new" } else { "old" };
+        assert_eq!(value, Some(expected.to_owned()));
+        client = next;
+    }
+    assert!(keys(&stores[0])?.is_empty());
+
+    Ok(())
+}
@@ -0,0 +1,154 @@
+use kvs::thread_pool::RayonThreadPool;
+use kvs::{KvsEngine, Result, SledKvsEngine};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tempfile::TempDir;
+use tokio::prelude::*;
+
+#[macro_use]
+mod common;
+
+engine_suite!(SledKvsEngine<RayonThreadPool>);
+
+fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_millis() as u64
+}
+
+/// Prefixes the key with its deadline, as listed in the tree of expiries.
+fn expiry_key(expires_at: u64, key: &[u8]) -> Vec<u8> {
+    let mut expiry_key = expires_at.to_be_bytes().to_vec();
+    expiry_key.extend_from_slice(key);
+    expiry_key
+}
+
+// A deadline listed for a key that was then set again without a time to live, as
+// left by a crash, should not make the sweeper remove the key
+#[test]
+fn ignore_stale_expiry() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let db = sled::Db::start_default(temp_dir.path())?;
+    let store = SledKvsEngine::<RayonThreadPool>::new(db.clone(), 1)?;
+    store.set("key1".to_owned(), "value1".to_owned()).wait()?;
+    db.open_tree(b"kvs-expiries".to_vec())?
+        .set(expiry_key(now_millis() - 1000, b"key1"), vec![])?;
+
+    // Give the sweeper time to go through the deadlines
+    thread::sleep(Duration::from_millis(1500));
+    assert_eq!(
+        store.get("key1".to_owned()).wait()?,
+        Some("value1".to_owned())
+    );
+
+    Ok(())
+}
+
+// A store keeping its values in the default tree and their deadlines apart
+// should be upgraded when it is opened
+#[test]
+fn upgrade_legacy_store() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let db = sled::Db::start_default(temp_dir.path())?;
+    let expiries = db.open_tree(b"kvs-expiries".to_vec())?;
+    db.set(b"key1", b"value1".to_vec())?;
+    db.set(b"key2", b"value2".to_vec())?;
+    expiries.set(b"key2", (now_millis() - 1000).to_be_bytes().to_vec())?;
+    db.set(b"key3", b"value3".to_vec())?;
+    expiries.set(b"key3", (now_millis() + 3_600_000).to_be_bytes().to_vec())?;
+
+    for _ in 0..2 {
+        let store = SledKvsEngine::<RayonThreadPool>::new(db.clone(), 1)?;
+        assert_eq!(
+            store.get("key1".to_owned()).wait()?,
+            Some("value1".to_owned())
+        );
+        assert_eq!(store.get("key2".to_owned()).wait()?, None);
+        assert_eq!(
+            store.get("key3".to_owned()).wait()?,
+            Some("value3".to_owned())
+        );
+        assert_eq!(db.iter().count(), 0);
+    }
+
+    Ok(())
+}
+
+// A store listing the deadlines by key should be upgraded to list them by deadline
+#[test]
+fn upgrade_expiries_by_key() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let db = sled::Db::start_default(temp_dir.path())?;
+    let data = db.open_tree(b"kvs-data".to_vec())?;
+    let expiries = db.open_tree(b"kvs-expiries".to_vec())?;
+    db.open_tree(b"kvs-meta".to_vec())?
+        .set(b"format", vec![1])?;
+    for (key, expires_at) in &[
+        (b"key1", now_millis() - 1000),
+        (b"key2", now_millis() + 3_600_000),
+    ] {
+        let mut stored = expires_at.to_be_bytes().to_vec();
+        stored.extend_from_slice(b"value");
+        data.set(key, stored)?;
+        expiries.set(key, expires_at.to_be_bytes().to_vec())?;
+    }
+
+    let store = SledKvsEngine::<RayonThreadPool>::new(db.clone(), 1)?;
+    // Give the sweeper time to go through the deadlines
+    thread::sleep(Duration::from_millis(1500));
+    assert_eq!(data.iter().count(), 1);
+    assert_eq!(expiries.iter().count(), 1);
+    assert_eq!(
+        store.get("key2".to_owned()).wait()?,
+        Some("value".to_owned())
+    );
+
+    Ok(())
+}
+
+// Keys set with a time to live before a restart should still be swept out once
+// they expire
+#[test]
+fn sweep_after_reopen() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let db = sled::Db::start_default(temp_dir.path())?;
+    let store = SledKvsEngine::<RayonThreadPool>::new(db.clone(), 1)?;
+    store
+        .set_with_ttl(
+            "key1".to_owned(),
+            "value1".to_owned(),
+            Duration::from_millis(200),
+        )
+        .wait()?;
+    store
+        .set_with_ttl(
+            "key2".to_owned(),
+            "value2".to_owned(),
+            Duration::from_secs(3600),
+        )
+        .wait()?;
+    drop(store);
+
+    let store = SledKvsEngine::<RayonThreadPool>::new(db.clone(), 1)?;
+    // Give the sweeper time to go through the deadlines
+    thread::sleep(Duration::from_millis(1500));
+    let keys = |name: &[u8]| -> Result<Vec<Vec<u8>>> {
+        let tree = db.open_tree(name.to_vec())?;
+        let mut keys = Vec::new();
+        for item in tree.iter() {
+            keys.push(item?.0.to_vec());
+        }
+        Ok(keys)
+    };
+    assert_eq!(keys(b"kvs-data")?, vec![b"key2".to_vec()]);
+    let expiry_keys = keys(b"kvs-expiries")?;
+    assert_eq!(expiry_keys.len(), 1);
+    assert!(expiry_keys[0].ends_with(b"key2"));
+    assert_eq!(
+        store.get("key2".to_owned()).wait()?,
+        Some("value2".to_owned())
+    );
+
+    Ok(())
+}
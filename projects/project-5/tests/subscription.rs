@@ -0,0 +1,186 @@
+use kvs::replication::ChangeEvent;
+use kvs::thread_pool::RayonThreadPool;
+use kvs::{
+    ChangeFeed, KvStore, KvStoreOptions, KvsClient, KvsEngine, KvsError, KvsServer, Result,
+    WriteBatch,
+};
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+use tokio::prelude::*;
+
+type Store = KvStore<RayonThreadPool>;
+
+/// Opens a store with small logs, so that writes are spread over several logs.
+fn open_store(temp_dir: &TempDir) -> Result<Store> {
+    KvStore::open_with_options(
+        temp_dir.path(),
+        1,
+        KvStoreOptions::new()
+            .max_log_size(1024)
+            .auto_compaction(false),
+    )
+}
+
+fn set(seq: u64, key: &str, value: &str) -> ChangeEvent {
+    ChangeEvent::Set {
+        seq,
+        key: key.as_bytes().to_vec(),
+        value: value.as_bytes().to_vec(),
+        expires_at: None,
+    }
+}
+
+fn remove(seq: u64, key: &str) -> ChangeEvent {
+    ChangeEvent::Remove {
+        seq,
+        key: key.as_bytes().to_vec(),
+    }
+}
+
+#[test]
+fn subscription_catches_up_then_follows_writes() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = open_store(&temp_dir)?;
+    // the writes span several logs
+    for i in 1..=100 {
+        store
+            .set(format!("key{}", i), format!("value{}", i))
+            .wait()?;
+    }
+    store.remove("key1".to_owned()).wait()?;
+
+    let mut changes = store.subscribe(0).wait();
+    for i in 1..=100 {
+        let event = changes.next().unwrap()?;
+        assert_eq!(event, set(i, &format!("key{}", i), &format!("value{}", i)));
+    }
+    assert_eq!(changes.next().unwrap()?, remove(101, "key1"));
+
+    // the writes committed after the subscription are sent as they are committed
+    let mut batch = WriteBatch::new();
+    batch.set("key2", "batch").remove("key3");
+    store.write_batch(batch).wait()?;
+    store
+        .set_with_ttl(
+            "temp".to_owned(),
+            "value".to_owned(),
+            Duration::from_secs(60),
+        )
+        .wait()?;
+    assert_eq!(changes.next().unwrap()?, set(102, "key2", "batch"));
+    assert_eq!(changes.next().unwrap()?, remove(102, "key3"));
+    match changes.next().unwrap()? {
+        ChangeEvent::Set {
+            seq: 103,
+            key,
+            expires_at: Some(_),
+            ..
+        } => assert_eq!(key, b"temp"),
+        event => panic!("unexpected event {:?}", event),
+    }
+
+    Ok(())
+}
+
+#[test]
+fn subscription_resumes_from_seq() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = open_store(&temp_dir)?;
+    for i in 1..=100 {
+        store
+            .set(format!("key{}", i), format!("value{}", i))
+            .wait()?;
+    }
+
+    let mut changes = store.subscribe(60).wait();
+    assert_eq!(changes.next().unwrap()?, set(61, "key61", "value61"));
+
+    // nothing is read from the logs when resuming from the last write
+    let mut changes = store.subscribe(100).wait();
+    store.remove("key1".to_owned()).wait()?;
+    assert_eq!(changes.next().unwrap()?, remove(101, "key1"));
+
+    // sequence numbers go on after the store is reopened
+    drop(changes);
+    drop(store);
+    let store = open_store(&temp_dir)?;
+    let mut changes = store.subscribe(99).wait();
+    store.set("key0".to_owned(), "value0".to_owned()).wait()?;
+    assert_eq!(changes.next().unwrap()?, set(100, "key100", "value100"));
+    assert_eq!(changes.next().unwrap()?, remove(101, "key1"));
+    assert_eq!(changes.next().unwrap()?, set(102, "key0", "value0"));
+
+    Ok(())
+}
+
+#[test]
+fn subscription_fails_after_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = open_store(&temp_dir)?;
+    for i in 1..=100 {
+        store
+            .set(format!("key{}", i), format!("value{}", i))
+            .wait()?;
+    }
+    store.compact().wait()?;
+    store.set("key0".to_owned(), "value0".to_owned()).wait()?;
+
+    let mut changes = store.subscribe(50).wait();
+    match changes.next().unwrap() {
+        Err(KvsError::ChangesCompacted(50)) => {}
+        res => panic!("unexpected result {:?}", res),
+    }
+    assert!(changes.next().is_none());
+
+    // the writes after the compaction are still in the logs
+    let mut changes = store.subscribe(100).wait();
+    assert_eq!(changes.next().unwrap()?, set(101, "key0", "value0"));
+
+    Ok(())
+}
+
+#[test]
+fn client_subscribes_to_server() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = open_store(&temp_dir)?;
+    store.set("key1".to_owned(), "value1".to_owned()).wait()?;
+    let addr = "127.0.0.1:4090".parse().unwrap();
+    let server = KvsServer::new(store.clone()).with_change_feed();
+    thread::spawn(move || server.run(addr).unwrap());
+    thread::sleep(Duration::from_secs(1));
+
+    let client = KvsClient::connect(addr).wait()?;
+    let mut changes = client.subscribe(0).wait();
+    assert_eq!(changes.next().unwrap()?, set(1, "key1", "value1"));
+
+    let client = KvsClient::connect(addr).wait()?;
+    client.remove("key1".to_owned()).wait()?;
+    assert_eq!(changes.next().unwrap()?, remove(2, "key1"));
+
+    // a subscriber resuming after a lost connection gets the writes it missed
+    drop(changes);
+    store.set("key2".to_owned(), "value2".to_owned()).wait()?;
+    let client = KvsClient::connect(addr).wait()?;
+    let mut changes = client.subscribe(2).wait();
+    assert_eq!(changes.next().unwrap()?, set(3, "key2", "value2"));
+
+    Ok(())
+}
+
+// A server not streaming the writes to its engine should fail subscriptions
+#[test]
+fn server_without_change_feed() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = open_store(&temp_dir)?;
+    let addr = "127.0.0.1:4091".parse().unwrap();
+    let server = KvsServer::new(store);
+    thread::spawn(move || server.run(addr).unwrap());
+    thread::sleep(Duration::from_secs(1));
+
+    let client = KvsClient::connect(addr).wait()?;
+    let mut changes = client.subscribe(0).wait();
+    assert!(changes.next().unwrap().is_err());
+
+    Ok(())
+}
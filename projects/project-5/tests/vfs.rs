@@ -0,0 +1,321 @@
+use kvs::thread_pool::RayonThreadPool;
+use kvs::vfs::{Fault, FaultyVfs, MemVfs, Vfs};
+use kvs::{Durability, KvStore, KvStoreOptions, KvsEngine, Result, WriteBatch};
+use std::collections::BTreeMap;
+use std::io;
+use std::ops::Bound::Unbounded;
+use std::path::Path;
+use tokio::prelude::*;
+
+const DIR: &str = "/kvs-vfs-test";
+
+fn options(vfs: impl Vfs + 'static) -> KvStoreOptions {
+    let mut options = KvStoreOptions::new();
+    options
+        .vfs(vfs)
+        .durability(Durability::EveryWrite)
+        .auto_compaction(false);
+    options
+}
+
+fn open(options: &KvStoreOptions) -> Result<KvStore<RayonThreadPool>> {
+    KvStore::open_with_options(DIR, 1, options)
+}
+
+/// Checks that the store holds exactly the pairs of the model.
+fn check(store: &KvStore<RayonThreadPool>, model: &BTreeMap<String, String>) -> Result<()> {
+    let pairs: BTreeMap<_, _> = store
+        .scan(Unbounded, Unbounded, None)
+        .wait()?
+        .into_iter()
+        .collect();
+    assert_eq!(&pairs, model);
+    Ok(())
+}
+
+/// Writes, overwrites and removes keys, applying the writes that succeed to the model.
+fn workload(store: &KvStore<RayonThreadPool>, model: &mut BTreeMap<String, String>) {
+    for i in 0..6 {
+        let (key, value) = (format!("key{}", i % 4), format!("value{}", i));
+        if store.set(key.clone(), value.clone()).wait().is_ok() {
+            model.insert(key, value);
+        }
+    }
+    if store.remove("key1".to_owned()).wait().is_ok() {
+        model.remove("key1");
+    }
+    let mut batch = WriteBatch::new();
+    batch
+        .set("key4", "value6")
+        .remove("key2")
+        .set("key5", "value7");
+    if store.write_batch(batch).wait().is_ok() {
+        model.insert("key4".to_owned(), "value6".to_owned());
+        model.remove("key2");
+        model.insert("key5".to_owned(), "value7".to_owned());
+    }
+    if store
+        .set("key0".to_owned(), "value8".to_owned())
+        .wait()
+        .is_ok()
+    {
+        model.insert("key0".to_owned(), "value8".to_owned());
+    }
+}
+
+// Should keep the files of the store in memory only
+#[test]
+fn mem_vfs_store() -> Result<()> {
+    let vfs = MemVfs::new();
+    let mut options = KvStoreOptions::new();
+    options.vfs(vfs.clone());
+    let store = open(&options)?;
+    store.set("key1".to_owned(), "value1".to_owned()).wait()?;
+    store.set("key2".to_owned(), "value2".to_owned()).wait()?;
+    store.remove("key1".to_owned()).wait()?;
+    store.compact().wait()?;
+    store.set("key3".to_owned(), "value3".to_owned()).wait()?;
+    drop(store);
+    assert!(!Path::new(DIR).exists());
+    assert!(vfs.list(Path::new(DIR))?.len() >= 2);
+
+    let store = open(&options)?;
+    assert_eq!(store.get("key1".to_owned()).wait()?, None);
+    assert_eq!(
+        store.get("key2".to_owned()).wait()?,
+        Some("value2".to_owned())
+    );
+    assert_eq!(
+        store.get("key3".to_owned()).wait()?,
+        Some("value3".to_owned())
+    );
+
+    Ok(())
+}
+
+// Should lose the writes that were not synced before a crash, and only them
+#[test]
+fn crash_loses_unsynced_writes() -> Result<()> {
+    let vfs = MemVfs::new();
+    let mut options = KvStoreOptions::new();
+    options.vfs(vfs.clone());
+    let store = open(&options)?;
+    store.set("key1".to_owned(), "value1".to_owned()).wait()?;
+    drop(store);
+    vfs.crash();
+    let store = open(&options)?;
+    assert_eq!(store.get("key1".to_owned()).wait()?, None);
+    drop(store);
+
+    options.durability(Durability::EveryWrite);
+    let store = open(&options)?;
+    store.set("key1".to_owned(), "value1".to_owned()).wait()?;
+    store.set("key2".to_owned(), "value2".to_owned()).wait()?;
+    drop(store);
+    vfs.crash();
+    let store = open(&options)?;
+    assert_eq!(
+        store.get("key1".to_owned()).wait()?,
+        Some("value1".to_owned())
+    );
+    assert_eq!(
+        store.get("key2".to_owned()).wait()?,
+        Some("value2".to_owned())
+    );
+
+    Ok(())
+}
+
+// Files created, removed and renamed should come back as they were after a crash,
+// until their directory is synced
+#[test]
+fn crash_undoes_unsynced_renames() -> Result<()> {
+    let vfs = MemVfs::new();
+    let dir = Path::new(DIR);
+    vfs.create_dir_all(dir)?;
+    vfs.write(dir.join("a"), "durable")?;
+    let file = vfs.create(&dir.join("b"))?;
+    file.append(b"synced")?;
+    file.sync_data()?;
+    vfs.rename(&dir.join("a"), &dir.join("c"))?;
+    vfs.crash();
+    assert_eq!(vfs.read(dir.join("a")), Some(b"durable".to_vec()));
+    assert_eq!(vfs.read(dir.join("b")), None);
+    assert_eq!(vfs.read(dir.join("c")), None);
+
+    let file = vfs.create(&dir.join("b"))?;
+    file.append(b"synced")?;
+    file.sync_data()?;
+    vfs.rename(&dir.join("a"), &dir.join("c"))?;
+    vfs.sync_dir(dir)?;
+    vfs.crash();
+    assert_eq!(vfs.read(dir.join("a")), None);
+    assert_eq!(vfs.read(dir.join("b")), Some(b"synced".to_vec()));
+    assert_eq!(vfs.read(dir.join("c")), Some(b"durable".to_vec()));
+
+    Ok(())
+}
+
+// A lock should be held until it is dropped, and record its owner
+#[test]
+fn mem_vfs_lock() -> Result<()> {
+    let vfs = MemVfs::new();
+    let dir = Path::new(DIR);
+    vfs.create_dir_all(dir)?;
+    let lock = vfs.lock(&dir.join("LOCK"), b"owner1")?;
+    match vfs.lock(&dir.join("LOCK"), b"owner2") {
+        Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {}
+        res => panic!("unexpected result: {:?}", res),
+    }
+    assert_eq!(vfs.read(dir.join("LOCK")), Some(b"owner1".to_vec()));
+
+    drop(lock);
+    let _lock = vfs.lock(&dir.join("LOCK"), b"owner2")?;
+    assert_eq!(vfs.read(dir.join("LOCK")), Some(b"owner2".to_vec()));
+
+    Ok(())
+}
+
+// A write failing with a short write should be dropped from the log, and the
+// following writes should still be loaded
+#[test]
+fn short_write() -> Result<()> {
+    let vfs = FaultyVfs::new(MemVfs::new());
+    let options = options(vfs.clone());
+    let store = open(&options)?;
+    store.set("key1".to_owned(), "value1".to_owned()).wait()?;
+
+    vfs.inject(vfs.operations(), Fault::ShortWrite(10));
+    assert!(store
+        .set("key2".to_owned(), "value2".to_owned())
+        .wait()
+        .is_err());
+    assert!(!vfs.is_pending());
+    store.set("key3".to_owned(), "value3".to_owned()).wait()?;
+    assert_eq!(store.get("key2".to_owned()).wait()?, None);
+
+    drop(store);
+    let store = open(&options)?;
+    assert_eq!(
+        store.get("key1".to_owned()).wait()?,
+        Some("value1".to_owned())
+    );
+    assert_eq!(store.get("key2".to_owned()).wait()?, None);
+    assert_eq!(
+        store.get("key3".to_owned()).wait()?,
+        Some("value3".to_owned())
+    );
+
+    Ok(())
+}
+
+// Whatever operation of the writes fails, the store should hold the acknowledged
+// writes and only them, before and after a crash
+#[test]
+fn fault_at_every_write_operation() -> Result<()> {
+    // count the operations of the workload
+    let vfs = FaultyVfs::new(MemVfs::new());
+    let store = open(&options(vfs.clone()))?;
+    let start = vfs.operations();
+    workload(&store, &mut BTreeMap::new());
+    let end = vfs.operations();
+    drop(store);
+
+    for op in start..end {
+        for &fault in &[Fault::Fail, Fault::ShortWrite(5)] {
+            let mem = MemVfs::new();
+            let vfs = FaultyVfs::new(mem.clone());
+            let options = options(vfs.clone());
+            let store = open(&options)?;
+            vfs.inject(op, fault);
+            let mut model = BTreeMap::new();
+            workload(&store, &mut model);
+            assert!(!vfs.is_pending());
+            check(&store, &model)?;
+
+            drop(store);
+            let store = open(&options)?;
+            check(&store, &model)?;
+            drop(store);
+            mem.crash();
+            let store = open(&options)?;
+            check(&store, &model)?;
+        }
+    }
+
+    Ok(())
+}
+
+// Whatever operation of a compaction fails, the store should be left as it was,
+// before and after a crash, and the next compaction should succeed
+#[test]
+fn fault_at_every_compaction_operation() -> Result<()> {
+    let prepare = |options: &KvStoreOptions| -> Result<_> {
+        let store = open(options)?;
+        let mut model = BTreeMap::new();
+        workload(&store, &mut model);
+        Ok((store, model))
+    };
+
+    // count the operations of the compaction
+    let vfs = FaultyVfs::new(MemVfs::new());
+    let (store, _) = prepare(&options(vfs.clone()))?;
+    let start = vfs.operations();
+    store.compact().wait()?;
+    let end = vfs.operations();
+    drop(store);
+
+    for op in start..end {
+        for &fault in &[Fault::Fail, Fault::ShortWrite(5)] {
+            let mem = MemVfs::new();
+            let vfs = FaultyVfs::new(mem.clone());
+            let options = options(vfs.clone());
+            let (store, model) = prepare(&options)?;
+            vfs.inject(op, fault);
+            // removing the stale logs fails without failing the compaction
+            let _ = store.compact().wait();
+            assert!(!vfs.is_pending());
+            check(&store, &model)?;
+
+            drop(store);
+            mem.crash();
+            let store = open(&options)?;
+            check(&store, &model)?;
+            store.compact().wait()?;
+            check(&store, &model)?;
+            drop(store);
+            mem.crash();
+            let store = open(&options)?;
+            check(&store, &model)?;
+        }
+    }
+
+    Ok(())
+}
+
+// A fault injected into a later operation should leave the earlier ones alone
+#[test]
+fn count_operations() -> Result<()> {
+    let vfs = FaultyVfs::new(MemVfs::new());
+    let store = open(&options(vfs.clone()))?;
+    let before = vfs.operations();
+    store.set("key1".to_owned(), "value1".to_owned()).wait()?;
+    // appending the record and syncing it
+    assert_eq!(vfs.operations(), before + 2);
+
+    vfs.inject(vfs.operations() + 2, Fault::Fail);
+    store.set("key2".to_owned(), "value2".to_owned()).wait()?;
+    assert!(vfs.is_pending());
+    assert!(store
+        .set("key3".to_owned(), "value3".to_owned())
+        .wait()
+        .is_err());
+    assert!(!vfs.is_pending());
+    assert_eq!(
+        store.get("key2".to_owned()).wait()?,
+        Some("value2".to_owned())
+    );
+    assert_eq!(store.get("key3".to_owned()).wait()?, None);
+
+    Ok(())
+}